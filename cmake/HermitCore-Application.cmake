@@ -8,3 +8,21 @@ add_compile_options(${HERMIT_APP_FLAGS})
 link_directories(${LOCAL_PREFIX_ARCH_LIB_DIR})
 include_directories(BEFORE ${LOCAL_PREFIX_ARCH_INCLUDE_DIR})
 
+
+# Embed the symbol table of the kernel and the application into the given
+# targets, so that backtraces show function names. ksymtab wraps the link
+# command and links the table, which is exactly as large as required.
+# Applications opt in by calling this function for their executables.
+function(hermit_embed_symbols)
+	find_program(KSYMTAB ksymtab
+		PATHS ${LOCAL_PREFIX_DIR}/bin ${CMAKE_INSTALL_PREFIX}/bin)
+
+	if(NOT KSYMTAB)
+		message(WARNING "ksymtab not found, backtraces will not be symbolized")
+		return()
+	endif()
+
+	foreach(TARGET ${ARGN})
+		set_property(TARGET ${TARGET} PROPERTY RULE_LAUNCH_LINK ${KSYMTAB})
+	endforeach()
+endfunction(hermit_embed_symbols)
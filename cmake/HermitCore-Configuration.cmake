@@ -16,3 +16,9 @@ set(DEFAULT_STACK_SIZE 262144 CACHE STRING
 set(MAX_ARGC_ENVC 128 CACHE STRING
         "Maximum number of command line parameters and enviroment variables
         forwarded to uhyve")
+
+option(HERMIT_RUST_NETWORK
+	"Use the network stack written in Rust (smoltcp) instead of lwIP" OFF)
+
+option(HERMIT_PCI_IDS
+	"Print the names of PCI vendors, devices and classes in the boot log (adds about 1 MiB to the kernel)" OFF)
@@ -125,6 +125,13 @@ impl Multiboot {
 		})
 	}
 
+	pub unsafe fn modules_mut(&self) -> Option<&'static mut [Module]> {
+		self.modules_address().map(|address| {
+			let ptr = address as *mut Module;
+			slice::from_raw_parts_mut(ptr, self.header.mods_count as usize)
+		})
+	}
+
 	pub fn memory_map_address(&self) -> Option<usize> {
 		if {self.header.flags}.contains(Flags::MULTIBOOT_INFO_MEM_MAP) {
 			Some(self.header.mmap_addr as usize)
@@ -161,6 +168,13 @@ impl Module {
 	pub fn end_address(&self) -> usize {
 		self.mod_end as usize
 	}
+
+	/// Updates the addresses after the module has been moved to a new physical address.
+	#[inline]
+	pub fn relocate(&mut self, start_address: usize) {
+		self.mod_end = (start_address + self.end_address() - self.start_address()) as u32;
+		self.mod_start = start_address as u32;
+	}
 }
 
 
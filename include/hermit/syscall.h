@@ -68,6 +68,23 @@ struct _HermitSpinlockIrqSave;
 typedef struct _HermitSpinlockIrqSave HermitSpinlockIrqSave;
 
 
+/* Memory statistics returned by sys_meminfo (all sizes in bytes) */
+struct meminfo {
+	size_t physical_total;
+	size_t physical_used;
+	size_t virtual_used;
+	size_t kernel_heap_used;
+	size_t task_heap_size;
+	size_t page_faults;
+};
+
+/* File status as filled in by sys_stat/sys_lstat/sys_fstat (Linux x86_64 layout) */
+struct stat;
+
+/* Buffer for sys_readv/sys_writev */
+struct iovec;
+
+
 typedef void (*entry_point_t)(void*);
 typedef void (*signal_handler_t)(int);
 
@@ -93,9 +110,26 @@ int sys_setprio(tid_t* id, int prio);
 void NORETURN sys_exit(int arg);
 ssize_t sys_read(int fd, char* buf, size_t len);
 ssize_t sys_write(int fd, const char* buf, size_t len);
+ssize_t sys_readv(int fd, const struct iovec* iov, int iovcnt);
+ssize_t sys_writev(int fd, const struct iovec* iov, int iovcnt);
+ssize_t sys_pread(int fd, void* buf, size_t len, off_t offset);
+ssize_t sys_pwrite(int fd, const void* buf, size_t len, off_t offset);
 ssize_t sys_sbrk(ssize_t incr);
+int sys_set_heap_pagesize(size_t page_size);
+int sys_meminfo(struct meminfo* info);
 int sys_open(const char* name, int flags, int mode);
 int sys_close(int fd);
+int sys_unlink(const char* name);
+int sys_mkdir(const char* name, unsigned int mode);
+int sys_rmdir(const char* name);
+int sys_rename(const char* old_name, const char* new_name);
+int sys_access(const char* name, int mode);
+ssize_t sys_getdents(int fd, void* buf, size_t len);
+int sys_truncate(const char* name, size_t length);
+int sys_ftruncate(int fd, size_t length);
+int sys_stat(const char* name, struct stat* st);
+int sys_lstat(const char* name, struct stat* st);
+int sys_fstat(int fd, struct stat* st);
 void sys_putchar(const unsigned char character);
 int sys_lwip_get_errno();
 void sys_lwip_register_tcpip_task(tid_t id);
@@ -129,6 +163,20 @@ int sys_kill(tid_t dest, int signum);
 int sys_signal(signal_handler_t handler);
 unsigned int sys_rand();
 
+/* Flags for sys_getrandom */
+#define GRND_NONBLOCK	0x0001
+#define GRND_RANDOM	0x0002
+
+ssize_t sys_getrandom(void* buf, size_t buflen, unsigned int flags);
+
+/* TCP sockets, only available with the network stack written in Rust (HERMIT_RUST_NETWORK).
+ * Addresses are passed as lwIP's struct sockaddr_in. */
+int sys_socket(int domain, int type, int protocol);
+int sys_bind(int fd, const void* addr, unsigned int addrlen);
+int sys_listen(int fd, int backlog);
+int sys_accept(int fd, void* addr, unsigned int* addrlen);
+int sys_connect(int fd, const void* addr, unsigned int addrlen);
+
 struct ucontext;
 typedef struct ucontext ucontext_t;
 
@@ -0,0 +1,275 @@
+// Copyright (c) 2018 Colin Finck, RWTH Aachen University
+//
+// MIT License
+//
+// Permission is hereby granted, free of charge, to any person obtaining
+// a copy of this software and associated documentation files (the
+// "Software"), to deal in the Software without restriction, including
+// without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to
+// permit persons to whom the Software is furnished to do so, subject to
+// the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE
+// LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION
+// OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
+// WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+
+//! Links a HermitCore application with a compact table of all its function symbols.
+//!
+//! ksymtab is used as a launcher for the link command (see hermit_embed_symbols in HermitCore-Application.cmake).
+//! It runs the link command once, builds the symbol table of the result, and links again with the table
+//! in an additional "ksymtab" section. This section takes exactly the space required for the symbols.
+//! The kernel finds it through the __start_ksymtab and __stop_ksymtab symbols defined by the linker
+//! and uses it to resolve return addresses in backtraces to "function+offset".
+//!
+//! The table only adds data, so the addresses of all functions are the same in both links.
+//! They are verified after the second link nevertheless.
+//!
+//! Format of the table (all values little-endian):
+//!   u32 magic ("KSYM"), u32 number of entries,
+//!   entries sorted by address, each consisting of u64 address, u32 size, u32 name offset,
+//!   NUL-terminated names (name offsets are relative to the start of the table).
+
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Seek, SeekFrom, Write};
+use std::process::{self, Command};
+use std::vec::Vec;
+
+const SECTION_NAME: &str = "ksymtab";
+const SYMBOL_TABLE_MAGIC: u32 = 0x4D59_534B;
+const HEADER_SIZE: usize = 8;
+const ENTRY_SIZE: usize = 16;
+
+
+struct Symbol {
+	address: u64,
+	size: u32,
+	name: String,
+}
+
+struct Section {
+	size: usize,
+	file_offset: u64,
+}
+
+fn read_symbols(fname: &str) -> Vec<Symbol> {
+	let output = Command::new("nm")
+		.arg("--defined-only")
+		.arg("--demangle")
+		.arg("--print-size")
+		.arg(fname)
+		.output()
+		.expect("nm failed to start");
+
+	if !output.status.success() {
+		eprintln!("Unable to read the symbol table of {}", fname);
+		process::exit(1);
+	}
+
+	let mut symbols = Vec::new();
+	let output_string = String::from_utf8_lossy(&output.stdout);
+
+	for line in output_string.lines() {
+		// Lines have the format "address [size] type name", where the name may contain spaces.
+		let mut fields = line.splitn(4, ' ');
+		let address = match fields.next().and_then(|a| u64::from_str_radix(a, 16).ok()) {
+			Some(address) => address,
+			None => continue,
+		};
+
+		let second = fields.next().unwrap_or("");
+		let (size, ty, name) = if second.len() == 1 {
+			// No size is given for this symbol, so the second field is already the type.
+			let rest = fields.collect::<Vec<&str>>().join(" ");
+			(0, second, rest)
+		} else {
+			let size = u32::from_str_radix(second, 16).unwrap_or(0);
+			let ty = fields.next().unwrap_or("");
+			let name = fields.next().unwrap_or("").to_string();
+			(size, ty, name)
+		};
+
+		// We are only interested in functions.
+		if name.is_empty() || !(ty == "T" || ty == "t" || ty == "W" || ty == "w") {
+			continue;
+		}
+
+		symbols.push(Symbol { address, size, name });
+	}
+
+	symbols.sort_by_key(|s| s.address);
+	symbols.dedup_by_key(|s| s.address);
+	symbols
+}
+
+fn find_section(fname: &str) -> Option<Section> {
+	let output = Command::new("objdump")
+		.arg("-h")
+		.arg(fname)
+		.output()
+		.expect("objdump failed to start");
+
+	if !output.status.success() {
+		eprintln!("Unable to determine section names in {}", fname);
+		process::exit(1);
+	}
+
+	let output_string = String::from_utf8_lossy(&output.stdout);
+	for line in output_string.lines() {
+		// Lines have the format "Idx Name Size VMA LMA File-off Algn".
+		let fields: Vec<&str> = line.split_whitespace().collect();
+		if fields.len() >= 6 && fields[1] == SECTION_NAME {
+			return Some(Section {
+				size: usize::from_str_radix(fields[2], 16).expect("Invalid section size"),
+				file_offset: u64::from_str_radix(fields[5], 16).expect("Invalid section offset"),
+			});
+		}
+	}
+
+	None
+}
+
+/// Appends the lowest `size` bytes of a value in little-endian byte order.
+fn push_le(table: &mut Vec<u8>, value: u64, size: usize) {
+	for i in 0..size {
+		table.push((value >> (8 * i)) as u8);
+	}
+}
+
+fn build_table(symbols: &[Symbol]) -> Vec<u8> {
+	let mut table = Vec::new();
+	let mut names = Vec::new();
+	let names_offset = HEADER_SIZE + symbols.len() * ENTRY_SIZE;
+
+	push_le(&mut table, SYMBOL_TABLE_MAGIC as u64, 4);
+	push_le(&mut table, symbols.len() as u64, 4);
+
+	for symbol in symbols {
+		let name_offset = (names_offset + names.len()) as u32;
+		names.extend_from_slice(symbol.name.as_bytes());
+		names.push(0);
+
+		push_le(&mut table, symbol.address, 8);
+		push_le(&mut table, symbol.size as u64, 4);
+		push_le(&mut table, name_offset as u64, 4);
+	}
+
+	table.extend_from_slice(&names);
+	table
+}
+
+/// Runs the link command and exits with its status if it fails.
+fn link(command: &[String], extra_object: Option<&str>) {
+	let mut link = Command::new(&command[0]);
+	link.args(&command[1..]);
+	if let Some(object) = extra_object {
+		link.arg(object);
+	}
+
+	let status = link.status().expect("Link command failed to start");
+	if !status.success() {
+		process::exit(status.code().unwrap_or(1));
+	}
+}
+
+/// Returns the output file of the link command given by "-o".
+fn output_file(command: &[String]) -> Option<String> {
+	for (i, arg) in command.iter().enumerate() {
+		if arg == "-o" {
+			return command.get(i + 1).cloned();
+		} else if arg.len() > 2 && arg.is_char_boundary(2) {
+			let (option, output) = arg.split_at(2);
+			if option == "-o" {
+				return Some(output.to_string());
+			}
+		}
+	}
+
+	None
+}
+
+/// Converts the table into an object file with a single "ksymtab" section.
+fn create_object(table: &[u8], table_file: &str, object_file: &str) {
+	File::create(table_file)
+		.and_then(|mut file| file.write_all(table))
+		.expect("Unable to write the symbol table");
+
+	let status = Command::new("objcopy")
+		.arg("--input-target=binary")
+		.arg("--output-target=elf64-x86-64")
+		.arg("--binary-architecture=i386:x86-64")
+		.arg(format!("--rename-section=.data={},alloc,load,readonly,data,contents", SECTION_NAME))
+		.arg(table_file)
+		.arg(object_file)
+		.status()
+		.expect("objcopy failed to start");
+
+	if !status.success() {
+		eprintln!("Unable to create an object file for the symbol table");
+		process::exit(1);
+	}
+}
+
+fn main() {
+	let mut command: Vec<String> = env::args().collect();
+
+	// remove unneeded programm name
+	command.remove(0);
+
+	if command.is_empty() {
+		eprintln!("Usage: ksymtab <link command>");
+		process::exit(1);
+	}
+
+	// The first link reveals the symbols of the application.
+	link(&command, None);
+	let output = match output_file(&command) {
+		Some(output) => output,
+		None => {
+			eprintln!("Cannot determine the output file of the link command, backtraces will not be symbolized");
+			return;
+		}
+	};
+	let table = build_table(&read_symbols(&output));
+
+	// Link again with the table.
+	let table_file = format!("{}.ksymtab", output);
+	let object_file = format!("{}.ksymtab.o", output);
+	create_object(&table, &table_file, &object_file);
+	link(&command, Some(&object_file));
+	fs::remove_file(&table_file).ok();
+
+	// The second link must not have moved any function.
+	// Otherwise, rewrite the table, which still has the same size, because it contains the same names.
+	let symbols = read_symbols(&output);
+	let final_table = build_table(&symbols);
+	if final_table != table {
+		let section = match find_section(&output) {
+			Some(section) => section,
+			None => {
+				eprintln!("{} has no {} section after linking the symbol table", output, SECTION_NAME);
+				process::exit(1);
+			}
+		};
+
+		if final_table.len() != section.size {
+			eprintln!("Symbols of {} changed between both links", output);
+			process::exit(1);
+		}
+
+		let mut file = OpenOptions::new().write(true).open(&output).expect("Unable to open the application for writing");
+		file.seek(SeekFrom::Start(section.file_offset)).expect("Unable to seek to the symbol table section");
+		file.write_all(&final_table).expect("Unable to write the symbol table");
+	}
+
+	println!("Embedded {} symbols into {}", symbols.len(), output);
+}
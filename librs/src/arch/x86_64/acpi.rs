@@ -59,6 +59,12 @@ const SLP_EN: u16 = 1 << 13;
 
 /// The "Multiple APIC Description Table" (MADT) preserved for get_apic_table().
 static mut MADT: Option<AcpiTable> = None;
+/// The "System Resource Affinity Table" (SRAT) preserved for get_srat().
+static mut SRAT: Option<AcpiTable> = None;
+/// The "System Locality Information Table" (SLIT) preserved for get_slit().
+static mut SLIT: Option<AcpiTable> = None;
+/// The "PCI Express Memory Mapped Configuration Table" (MCFG) preserved for get_mcfg().
+static mut MCFG: Option<AcpiTable> = None;
 /// The PM1A Control I/O Port for powering off the computer through ACPI.
 static mut PM1A_CNT_BLK: Option<u16> = None;
 /// The Sleeping State Type code for powering off the computer through ACPI.
@@ -453,6 +459,18 @@ pub fn get_madt() -> Option<&'static AcpiTable<'static>> {
 	unsafe { MADT.as_ref() }
 }
 
+pub fn get_srat() -> Option<&'static AcpiTable<'static>> {
+	unsafe { SRAT.as_ref() }
+}
+
+pub fn get_slit() -> Option<&'static AcpiTable<'static>> {
+	unsafe { SLIT.as_ref() }
+}
+
+pub fn get_mcfg() -> Option<&'static AcpiTable<'static>> {
+	unsafe { MCFG.as_ref() }
+}
+
 pub fn poweroff() {
 	unsafe {
 		if let (Some(pm1a_cnt_blk), Some(slp_typa)) = (PM1A_CNT_BLK, SLP_TYPA) {
@@ -519,6 +537,30 @@ pub fn init() {
 				"SSDT at {:#X} has invalid checksum", table_physical_address
 			);
 			parse_ssdt(table);
+		} else if table.header.signature() == "SRAT" {
+			// The "System Resource Affinity Table" (SRAT)
+			// Check and save the entire table for the NUMA initialization.
+			assert!(
+				verify_checksum(table.header_start_address(), table.header.length as usize).is_ok(),
+				"SRAT at {:#X} has invalid checksum", table_physical_address
+			);
+			unsafe { SRAT = Some(table); }
+		} else if table.header.signature() == "SLIT" {
+			// The "System Locality Information Table" (SLIT)
+			// Check and save the entire table for the NUMA initialization.
+			assert!(
+				verify_checksum(table.header_start_address(), table.header.length as usize).is_ok(),
+				"SLIT at {:#X} has invalid checksum", table_physical_address
+			);
+			unsafe { SLIT = Some(table); }
+		} else if table.header.signature() == "MCFG" {
+			// The "PCI Express Memory Mapped Configuration Table" (MCFG)
+			// Check and save the entire table for accessing the PCI configuration space.
+			assert!(
+				verify_checksum(table.header_start_address(), table.header.length as usize).is_ok(),
+				"MCFG at {:#X} has invalid checksum", table_physical_address
+			);
+			unsafe { MCFG = Some(table); }
 		}
 	}
 }
@@ -247,6 +247,19 @@ pub extern "C" fn eoi() {
 	local_apic_write(IA32_X2APIC_EOI, APIC_EOI_ACK);
 }
 
+/// Returns the vector of the interrupt currently handled on this core.
+/// Higher vectors preempt lower ones, so this is the highest vector set in the In-Service Register.
+pub fn in_service_vector() -> Option<u8> {
+	for i in (0..8).rev() {
+		let bits = local_apic_read(IA32_X2APIC_ISR0 + i);
+		if bits != 0 {
+			return Some((i * 32 + 31 - bits.leading_zeros()) as u8);
+		}
+	}
+
+	None
+}
+
 pub fn init() {
 	// Detect CPUs and APICs.
 	let local_apic_physical_address = detect_from_uhyve()
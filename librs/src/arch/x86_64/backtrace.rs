@@ -0,0 +1,121 @@
+// Copyright (c) 2018 Colin Finck, RWTH Aachen University
+//
+// MIT License
+//
+// Permission is hereby granted, free of charge, to any person obtaining
+// a copy of this software and associated documentation files (the
+// "Software"), to deal in the Software without restriction, including
+// without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to
+// permit persons to whom the Software is furnished to do so, subject to
+// the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE
+// LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION
+// OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
+// WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+use arch::x86_64::percore::*;
+use core::mem;
+use symbols::SymbolizedAddress;
+
+
+/// Maximum number of frames output in a backtrace.
+const MAX_BACKTRACE_FRAMES: usize = 32;
+
+
+/// The beginning of each stack frame when compiling with frame pointers.
+/// RBP points to this structure.
+#[repr(C)]
+struct StackFrame {
+	/// RBP of the calling function
+	next: usize,
+	/// Address to return to in the calling function
+	return_address: usize,
+}
+
+/// Returns the bounds of the stack of the current task, which contains the given address.
+///
+/// Walking the frame pointer chain must never leave these bounds, otherwise we would cause
+/// a nested fault while reporting the original one.
+fn get_stack_bounds(address: usize) -> Option<(usize, usize)> {
+	let current_task_borrowed = core_scheduler().current_task.try_borrow().ok()?;
+
+	for &(start, end) in [current_task_borrowed.stack_bounds(), current_task_borrowed.ist_bounds()].iter() {
+		if address >= start && address < end {
+			return Some((start, end));
+		}
+	}
+
+	None
+}
+
+/// Returns the current value of the frame pointer (RBP).
+#[inline(always)]
+pub fn get_frame_pointer() -> usize {
+	let frame_pointer: usize;
+	unsafe { asm!("mov %rbp, $0" : "=r"(frame_pointer) ::: "volatile"); }
+	frame_pointer
+}
+
+/// Outputs a backtrace by walking the chain of frame pointers.
+///
+/// # Arguments
+///
+/// * `instruction_pointer` - Address of the innermost instruction (e.g. the faulting one)
+/// * `frame_pointer` - RBP value belonging to the function containing `instruction_pointer`
+pub fn print_backtrace(instruction_pointer: usize, frame_pointer: usize) {
+	error!("Backtrace:");
+	error!("  #0  {}", SymbolizedAddress(instruction_pointer));
+	print_frames(frame_pointer, 1);
+}
+
+/// Outputs a backtrace of the calling function.
+#[inline(never)]
+pub fn print_current_backtrace() {
+	error!("Backtrace:");
+	print_frames(get_frame_pointer(), 0);
+}
+
+/// Outputs the return addresses of all frames in the chain starting at `frame_pointer`.
+/// The first frame is numbered `first_index`.
+fn print_frames(frame_pointer: usize, first_index: usize) {
+	let (stack_start, stack_end) = match get_stack_bounds(frame_pointer) {
+		Some(bounds) => bounds,
+		None => {
+			error!("  Frame pointer {:#X} does not point into a stack of the current task", frame_pointer);
+			return;
+		}
+	};
+
+	let mut current_frame = frame_pointer;
+	for i in first_index..MAX_BACKTRACE_FRAMES {
+		// Stop at the first frame pointer that is misaligned or would leave the stack.
+		if current_frame % mem::align_of::<StackFrame>() != 0 ||
+			current_frame < stack_start ||
+			current_frame + mem::size_of::<StackFrame>() > stack_end {
+			break;
+		}
+
+		let stack_frame = unsafe { &*(current_frame as *const StackFrame) };
+		if stack_frame.return_address == 0 {
+			break;
+		}
+
+		error!("  #{:<2} {}", i, SymbolizedAddress(stack_frame.return_address));
+
+		// Frames of calling functions always lie at higher addresses.
+		// Anything else indicates a corrupted chain.
+		if stack_frame.next <= current_frame {
+			break;
+		}
+
+		current_frame = stack_frame.next;
+	}
+}
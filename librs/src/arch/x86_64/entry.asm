@@ -269,6 +269,54 @@ irq%1:
 %assign i i+1
 %endrep
 
+; Page Fault Exception (#PF)
+; Saves all general-purpose registers, so that the Rust handler gets a complete
+; picture of the faulting context in the ExceptionRegisters structure.
+global page_fault_stub
+extern page_fault_handler
+align 8
+page_fault_stub:
+    push rax
+    push rcx
+    push rdx
+    push rbx
+    push rbp
+    push rsi
+    push rdi
+    push r8
+    push r9
+    push r10
+    push r11
+    push r12
+    push r13
+    push r14
+    push r15
+    cld
+    mov rdi, rsp
+    ; rbx is callee-saved, so use it to restore the stack pointer after
+    ; aligning it to 16 bytes as required by the System V ABI.
+    mov rbx, rsp
+    and rsp, ~0xF
+    call page_fault_handler
+    mov rsp, rbx
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop r11
+    pop r10
+    pop r9
+    pop r8
+    pop rdi
+    pop rsi
+    pop rbp
+    pop rbx
+    pop rdx
+    pop rcx
+    pop rax
+    add rsp, 8                  ; remove the error code pushed by the CPU
+    iretq
+
 
 SECTION .data
 
@@ -24,13 +24,22 @@
 
 use arch::x86_64::idt;
 use arch::x86_64::apic;
-use arch::x86_64::mm::paging;
 use arch::x86_64::percore::*;
 use core::fmt;
 use scheduler;
+use synch::spinlock::SpinlockIrqSave;
 use x86::shared::flags::*;
 
 
+/// First vector handed out for Message Signaled Interrupts, directly following the IOAPIC interrupts.
+const MSI_VECTOR_START: u8 = 64;
+/// Number of vectors for Message Signaled Interrupts, which end before the vectors used by the APIC code.
+const MSI_VECTOR_COUNT: usize = 48;
+
+/// Handlers for the vectors of Message Signaled Interrupts, or None for free vectors.
+static MSI_HANDLERS: SpinlockIrqSave<[Option<fn()>; MSI_VECTOR_COUNT]> = SpinlockIrqSave::new([None; MSI_VECTOR_COUNT]);
+
+
 // Derived from Philipp Oppermann's blog
 // => https://github.com/phil-opp/blog_os/blob/master/src/interrupts/mod.rs
 /// Represents the exception stack frame pushed by the CPU on exception entry.
@@ -71,6 +80,62 @@ impl fmt::Debug for ExceptionStackFrame {
     }
 }
 
+/// All general-purpose registers at the time of an exception, as saved by an exception stub in entry.asm,
+/// followed by the error code and the ExceptionStackFrame pushed by the CPU.
+#[repr(C)]
+pub struct ExceptionRegisters {
+	pub r15: u64,
+	pub r14: u64,
+	pub r13: u64,
+	pub r12: u64,
+	pub r11: u64,
+	pub r10: u64,
+	pub r9: u64,
+	pub r8: u64,
+	pub rdi: u64,
+	pub rsi: u64,
+	pub rbp: u64,
+	pub rbx: u64,
+	pub rdx: u64,
+	pub rcx: u64,
+	pub rax: u64,
+	/// The error code pushed by the CPU.
+	pub error_code: u64,
+	/// The interrupt stack frame pushed by the CPU.
+	pub stack_frame: ExceptionStackFrame,
+}
+
+impl fmt::Debug for ExceptionRegisters {
+	/// Only outputs the general-purpose registers.
+	/// The error code and stack frame are printed separately in a more readable form by the handlers.
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		struct Hex(u64);
+		impl fmt::Debug for Hex {
+			fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+				write!(f, "{:#X}", self.0)
+			}
+		}
+
+		let mut s = f.debug_struct("ExceptionRegisters");
+		s.field("rax", &Hex(self.rax));
+		s.field("rbx", &Hex(self.rbx));
+		s.field("rcx", &Hex(self.rcx));
+		s.field("rdx", &Hex(self.rdx));
+		s.field("rsi", &Hex(self.rsi));
+		s.field("rdi", &Hex(self.rdi));
+		s.field("rbp", &Hex(self.rbp));
+		s.field("r8", &Hex(self.r8));
+		s.field("r9", &Hex(self.r9));
+		s.field("r10", &Hex(self.r10));
+		s.field("r11", &Hex(self.r11));
+		s.field("r12", &Hex(self.r12));
+		s.field("r13", &Hex(self.r13));
+		s.field("r14", &Hex(self.r14));
+		s.field("r15", &Hex(self.r15));
+		s.finish()
+	}
+}
+
 
 /// Enable Interrupts
 #[inline]
@@ -93,6 +158,12 @@ pub fn disable() {
 	unsafe { asm!("cli" :::: "volatile") };
 }
 
+/// Returns whether IRQs are enabled on the current core.
+#[inline]
+pub fn is_enabled() -> bool {
+	flags().contains(FLAGS_IF)
+}
+
 /// Disable IRQs (nested)
 ///
 /// Disable IRQs when unsure if IRQs were enabled at all.
@@ -150,6 +221,8 @@ extern {
 	fn irq29();
 	fn irq30();
 	fn irq31();
+
+	fn page_fault_stub();
 }
 
 pub fn install() {
@@ -175,7 +248,7 @@ pub fn install() {
 	idt::set_gate(11, segment_not_present_exception as usize, 1);
 	idt::set_gate(12, stack_segment_fault_exception as usize, 1);
 	idt::set_gate(13, general_protection_exception as usize, 1);
-	idt::set_gate(14, paging::page_fault_handler as usize, 1);
+	idt::set_gate(14, page_fault_stub as usize, 1);
 	idt::set_gate(15, reserved_exception as usize, 1);
 	idt::set_gate(16, floating_point_exception as usize, 1);
 	idt::set_gate(17, alignment_check_exception as usize, 1);
@@ -239,6 +312,42 @@ pub extern "C" fn irq_install_handler(irq_number: u32, handler: usize)
 	idt::set_gate((32+irq_number) as u8, handler, 1);
 }
 
+/// Reserves a dedicated vector for a Message Signaled Interrupt, which calls `handler`.
+/// The handler does not need to acknowledge the interrupt.
+/// Returns the vector to program into the device or None if all vectors are in use.
+pub fn allocate_msi_vector(handler: fn()) -> Option<u8> {
+	let mut handlers = MSI_HANDLERS.lock();
+	let index = handlers.iter().position(|handler| handler.is_none())?;
+	handlers[index] = Some(handler);
+
+	let vector = MSI_VECTOR_START + index as u8;
+	debug!("Install handler for Message Signaled Interrupt vector {}", vector);
+	idt::set_gate(vector, msi_interrupt as usize, 1);
+	Some(vector)
+}
+
+/// Frees a vector reserved by allocate_msi_vector after the device has stopped using it.
+pub fn free_msi_vector(vector: u8) {
+	idt::set_gate(vector, unknown_interrupt as usize, 1);
+	MSI_HANDLERS.lock()[(vector - MSI_VECTOR_START) as usize] = None;
+}
+
+/// Common entry point of all Message Signaled Interrupts, which dispatches by the vector being serviced.
+extern "x86-interrupt" fn msi_interrupt(_stack_frame: &mut ExceptionStackFrame) {
+	let handler = apic::in_service_vector()
+		.filter(|vector| *vector >= MSI_VECTOR_START)
+		.and_then(|vector| MSI_HANDLERS.lock().get((vector - MSI_VECTOR_START) as usize).cloned())
+		.and_then(|handler| handler);
+
+	// Call the handler without holding the lock, so that it may allocate vectors itself.
+	match handler {
+		Some(handler) => handler(),
+		None => info!("Receive unknown Message Signaled Interrupt"),
+	}
+
+	apic::eoi();
+}
+
 #[no_mangle]
 pub extern "C" fn unhandled_interrupt(irq_number: u64) {
 	info!("Receive unhandled interrupt {}", irq_number);
@@ -25,9 +25,25 @@ pub mod paging;
 pub mod physicalmem;
 pub mod virtualmem;
 
+use self::paging::{BasePageSize, PageSize, PageTableEntryFlags};
+use core::slice;
+
 
 pub fn init() {
 	paging::init();
 	physicalmem::init();
 	virtualmem::init();
 }
+
+/// Maps the initrd passed as a Multiboot module into kernel memory and returns its contents.
+pub fn map_initrd() -> Option<&'static [u8]> {
+	let (start, end) = physicalmem::get_initrd()?;
+
+	let physical_address = align_down!(start, BasePageSize::SIZE);
+	let size = align_up!(end, BasePageSize::SIZE) - physical_address;
+	let virtual_address = virtualmem::allocate(size);
+	paging::map::<BasePageSize>(virtual_address, physical_address, size / BasePageSize::SIZE, PageTableEntryFlags::EXECUTE_DISABLE, false);
+
+	let data = unsafe { slice::from_raw_parts((virtual_address + start - physical_address) as *const u8, end - start) };
+	Some(data)
+}
@@ -22,14 +22,16 @@
 // WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
 
 use arch::x86_64::apic;
+use arch::x86_64::backtrace;
 use arch::x86_64::irq;
 use arch::x86_64::mm::physicalmem;
 use arch::x86_64::mm::virtualmem;
 use arch::x86_64::percore::*;
 use arch::x86_64::processor;
-use core::{fmt, ptr};
+use core::{cmp, fmt, mem, ptr};
 use core::marker::PhantomData;
-use hermit_multiboot::Multiboot;
+use environment;
+use hermit_multiboot::{Module, Multiboot};
 use mm;
 use scheduler;
 use x86::shared::control_regs;
@@ -121,6 +123,11 @@ impl PageTableEntry {
 		(self.physical_address_and_flags & PageTableEntryFlags::PRESENT.bits()) != 0
 	}
 
+	/// Returns whether this entry maps a 2 MiB (PDT) or 1 GiB (PDPT) page instead of referencing a subtable.
+	fn is_huge(&self) -> bool {
+		(self.physical_address_and_flags & PageTableEntryFlags::HUGE_PAGE.bits()) != 0
+	}
+
 	/// Mark this as a valid (present) entry and set address translation and flags.
 	///
 	/// # Arguments
@@ -340,6 +347,7 @@ trait PageTableMethods {
 	fn get_page_table_entry<S: PageSize>(&self, page: Page<S>) -> Option<PageTableEntry>;
 	fn map_page_in_this_table<S: PageSize>(&mut self, page: Page<S>, physical_address: usize, flags: PageTableEntryFlags) -> bool;
 	fn map_page<S: PageSize>(&mut self, page: Page<S>, physical_address: usize, flags: PageTableEntryFlags) -> bool;
+	fn unmap_page<S: PageSize>(&mut self, page: Page<S>) -> bool;
 }
 
 impl<L: PageTableLevel> PageTableMethods for PageTable<L> {
@@ -384,6 +392,25 @@ impl<L: PageTableLevel> PageTableMethods for PageTable<L> {
 	default fn map_page<S: PageSize>(&mut self, page: Page<S>, physical_address: usize, flags: PageTableEntryFlags) -> bool {
 		self.map_page_in_this_table::<S>(page, physical_address, flags)
 	}
+
+	/// Removes the mapping of a single page.
+	/// Returns whether an existing entry was removed. You can use this return value to flush TLBs.
+	///
+	/// This is the default implementation called only for PGT.
+	/// It is overridden by a specialized implementation for all tables with sub tables (all except PGT).
+	default fn unmap_page<S: PageSize>(&mut self, page: Page<S>) -> bool {
+		assert!(L::LEVEL == S::MAP_LEVEL);
+		let index = page.table_index::<L>();
+		let flush = self.entries[index].is_present();
+
+		self.entries[index].physical_address_and_flags = 0;
+
+		if flush {
+			page.flush_from_tlb();
+		}
+
+		flush
+	}
 }
 
 impl<L: PageTableLevelWithSubtables> PageTableMethods for PageTable<L> where L::SubtableLevel: PageTableLevel {
@@ -439,6 +466,28 @@ impl<L: PageTableLevelWithSubtables> PageTableMethods for PageTable<L> where L::
 			self.map_page_in_this_table::<S>(page, physical_address, flags)
 		}
 	}
+
+	/// Removes the mapping of a single page.
+	/// Returns whether an existing entry was removed. You can use this return value to flush TLBs.
+	///
+	/// This is the implementation for all tables with subtables (PML4, PDPT, PDT).
+	/// It overrides the default implementation above.
+	fn unmap_page<S: PageSize>(&mut self, page: Page<S>) -> bool {
+		assert!(L::LEVEL >= S::MAP_LEVEL);
+		let index = page.table_index::<L>();
+
+		if !self.entries[index].is_present() {
+			// Nothing is mapped here.
+			false
+		} else if L::LEVEL > S::MAP_LEVEL {
+			let subtable = self.subtable::<S>(page);
+			subtable.unmap_page::<S>(page)
+		} else {
+			self.entries[index].physical_address_and_flags = 0;
+			page.flush_from_tlb();
+			true
+		}
+	}
 }
 
 impl<L: PageTableLevelWithSubtables> PageTable<L> where L::SubtableLevel: PageTableLevel {
@@ -516,7 +565,93 @@ impl fmt::Display for PageFaultError {
 }
 
 
-pub extern "x86-interrupt" fn page_fault_handler(stack_frame: &mut irq::ExceptionStackFrame, error_code: u64) {
+/// Memory regions a virtual address may belong to, used for diagnostic output.
+enum MemoryRegion {
+	NonCanonical,
+	NullPage,
+	LowMemory,
+	KernelImage,
+	TaskStack,
+	InterruptStack,
+	StackGuard,
+	KernelMemory,
+	TaskHeap,
+	TaskHeapUnallocated,
+	Unmapped,
+}
+
+impl fmt::Display for MemoryRegion {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		let description = match *self {
+			MemoryRegion::NonCanonical => "a non-canonical address",
+			MemoryRegion::NullPage => "the null page (null pointer dereference?)",
+			MemoryRegion::LowMemory => "identity-mapped memory below the kernel",
+			MemoryRegion::KernelImage => "the kernel image",
+			MemoryRegion::TaskStack => "the stack of the current task",
+			MemoryRegion::InterruptStack => "the interrupt stack of the current task",
+			MemoryRegion::StackGuard => "the guard page below the stack of the current task (stack overflow?)",
+			MemoryRegion::KernelMemory => "kernel memory",
+			MemoryRegion::TaskHeap => "the heap of the current task",
+			MemoryRegion::TaskHeapUnallocated => "the task heap area, but beyond the current heap end",
+			MemoryRegion::Unmapped => "unmapped memory",
+		};
+
+		write!(f, "{}", description)
+	}
+}
+
+/// Determines the memory region of the given virtual address from the perspective of the current task.
+fn classify_address(virtual_address: usize) -> MemoryRegion {
+	if !Page::<BasePageSize>::is_valid_address(virtual_address) {
+		return MemoryRegion::NonCanonical;
+	} else if virtual_address < BasePageSize::SIZE {
+		return MemoryRegion::NullPage;
+	}
+
+	// Use try_borrow, because the fault may have happened while the current task was borrowed mutably.
+	if let Ok(current_task_borrowed) = core_scheduler().current_task.try_borrow() {
+		let (stack_start, stack_end) = current_task_borrowed.stack_bounds();
+		let (ist_start, ist_end) = current_task_borrowed.ist_bounds();
+
+		if virtual_address >= stack_start && virtual_address < stack_end {
+			return MemoryRegion::TaskStack;
+		} else if virtual_address >= ist_start && virtual_address < ist_end {
+			return MemoryRegion::InterruptStack;
+		}
+
+		if let Some((guard_start, guard_end)) = current_task_borrowed.stack_guard_bounds() {
+			if virtual_address >= guard_start && virtual_address < guard_end {
+				return MemoryRegion::StackGuard;
+			}
+		}
+
+		if let Some(ref heap) = current_task_borrowed.heap {
+			let heap_borrowed = heap.borrow();
+			if let Some(heap_locked) = heap_borrowed.try_read() {
+				if virtual_address >= heap_locked.start && virtual_address < heap_locked.end {
+					return MemoryRegion::TaskHeap;
+				}
+			}
+		}
+	}
+
+	if virtual_address < mm::kernel_start_address() {
+		MemoryRegion::LowMemory
+	} else if virtual_address < mm::kernel_end_address() {
+		MemoryRegion::KernelImage
+	} else if virtual_address < virtualmem::task_heap_start() {
+		MemoryRegion::KernelMemory
+	} else if virtual_address < virtualmem::task_heap_end() {
+		MemoryRegion::TaskHeapUnallocated
+	} else {
+		MemoryRegion::Unmapped
+	}
+}
+
+/// Handler for the Page Fault (#PF) Exception.
+/// Called from page_fault_stub in entry.asm after all general-purpose registers have been saved.
+#[no_mangle]
+pub extern "C" fn page_fault_handler(registers: &mut irq::ExceptionRegisters) {
 	let virtual_address = unsafe { control_regs::cr2() };
 
 	// Is a heap associated to the current task?
@@ -526,24 +661,16 @@ pub extern "x86-interrupt" fn page_fault_handler(stack_frame: &mut irq::Exceptio
 
 		// Is the requested virtual address within the boundary of that heap?
 		if virtual_address >= heap_locked.start && virtual_address < heap_locked.end {
-			// Then allocate physical memory for a 2 MiB page and map it to this virtual address.
-			let physical_address = physicalmem::allocate_aligned(LargePageSize::SIZE, LargePageSize::SIZE);
-			let root_pagetable = unsafe { &mut *PML4_ADDRESS };
-			let page = Page::<LargePageSize>::including_address(virtual_address);
-
-			debug_mem!("Mapping 2 MiB page for task heap ({:#X} => {:#X})", page.address(), physical_address);
-			root_pagetable.map_page(
-				page,
-				physical_address,
-				PageTableEntryFlags::WRITABLE | PageTableEntryFlags::EXECUTE_DISABLE
-			);
+			// Then allocate physical memory for a page of the configured heap page size and map it to this virtual address.
+			let (page_address, page_size) = map_task_heap_page(virtual_address, heap_locked.page_size);
+			mm::stats::page_fault();
 
 			// If our application is a Go application (detected by the presence of the
 			// weak symbol "runtime_osinit"), we have to return a zeroed page.
 			unsafe {
 				if !runtime_osinit.is_null() {
 					debug_mem!("Go application detected, returning a zeroed page");
-					ptr::write_bytes(page.address() as *mut u8, 0, LargePageSize::SIZE);
+					ptr::write_bytes(page_address as *mut u8, 0, page_size);
 				}
 			}
 
@@ -552,12 +679,112 @@ pub extern "x86-interrupt" fn page_fault_handler(stack_frame: &mut irq::Exceptio
 	}
 
 	// Anything else is an error!
-	let pferror = PageFaultError { bits: error_code };
-	error!("Page Fault (#PF) Exception: {:#?}", stack_frame);
+	let pferror = PageFaultError { bits: registers.error_code };
+	error!("Page Fault (#PF) Exception: {:#?}", registers.stack_frame);
 	error!("virtual_address = {:#X}, page fault error = {}", virtual_address, pferror);
+	error!("virtual_address lies in {}", classify_address(virtual_address));
+
+	if let Ok(current_task_borrowed) = core_scheduler().current_task.try_borrow() {
+		error!("Faulting task: {} (status {:?})", current_task_borrowed.id, current_task_borrowed.status);
+	}
+
+	error!("{:#?}", registers);
+	backtrace::print_backtrace(registers.stack_frame.instruction_pointer as usize, registers.rbp as usize);
 	scheduler::abort();
 }
 
+/// Maps a page of the given size including the given virtual address of a task heap.
+/// Returns the virtual address and size of the page that was actually mapped.
+///
+/// Larger pages gracefully fall back to the next smaller page size if no suitably aligned physical memory
+/// is available or if parts of the surrounding range have already been mapped using smaller pages.
+fn map_task_heap_page(virtual_address: usize, page_size: usize) -> (usize, usize) {
+	let root_pagetable = unsafe { &mut *PML4_ADDRESS };
+	let flags = PageTableEntryFlags::WRITABLE | PageTableEntryFlags::EXECUTE_DISABLE;
+
+	if page_size == HugePageSize::SIZE && processor::supports_1gib_pages() {
+		// A 1 GiB page can only be mapped if there is no PDT for this range yet.
+		let page = Page::<HugePageSize>::including_address(virtual_address);
+		if root_pagetable.get_page_table_entry(page).is_none() {
+			if let Ok(physical_address) = physicalmem::try_allocate_aligned(HugePageSize::SIZE, HugePageSize::SIZE) {
+				debug_mem!("Mapping 1 GiB page for task heap ({:#X} => {:#X})", page.address(), physical_address);
+				root_pagetable.map_page(page, physical_address, flags);
+				return (page.address(), HugePageSize::SIZE);
+			}
+
+			debug_mem!("No 1 GiB aligned physical memory available, falling back to a 2 MiB page");
+		}
+	}
+
+	if page_size >= LargePageSize::SIZE {
+		// A 2 MiB page can only be mapped if there is no PGT for this range yet.
+		let page = Page::<LargePageSize>::including_address(virtual_address);
+		if root_pagetable.get_page_table_entry(page).is_none() {
+			if let Ok(physical_address) = physicalmem::try_allocate_aligned(LargePageSize::SIZE, LargePageSize::SIZE) {
+				debug_mem!("Mapping 2 MiB page for task heap ({:#X} => {:#X})", page.address(), physical_address);
+				root_pagetable.map_page(page, physical_address, flags);
+				return (page.address(), LargePageSize::SIZE);
+			}
+
+			debug_mem!("No 2 MiB aligned physical memory available, falling back to a 4 KiB page");
+		}
+	}
+
+	let physical_address = physicalmem::allocate(BasePageSize::SIZE);
+	let page = Page::<BasePageSize>::including_address(virtual_address);
+
+	debug_mem!("Mapping 4 KiB page for task heap ({:#X} => {:#X})", page.address(), physical_address);
+	root_pagetable.map_page(page, physical_address, flags);
+	(page.address(), BasePageSize::SIZE)
+}
+
+/// Translates a virtual address of a task heap, which may be mapped using 4 KiB, 2 MiB, or 1 GiB pages.
+fn get_task_heap_physical_address(virtual_address: usize) -> usize {
+	let root_pagetable = unsafe { &mut *PML4_ADDRESS };
+
+	// Check the page sizes from the largest to the smallest, because looking up a smaller page
+	// would misinterpret a huge page entry as a subtable.
+	if processor::supports_1gib_pages() {
+		let page = Page::<HugePageSize>::including_address(virtual_address);
+		if let Some(entry) = root_pagetable.get_page_table_entry(page) {
+			if entry.is_huge() {
+				return entry.address() | (virtual_address & (HugePageSize::SIZE - 1));
+			}
+		}
+	}
+
+	let page = Page::<LargePageSize>::including_address(virtual_address);
+	if let Some(entry) = root_pagetable.get_page_table_entry(page) {
+		if entry.is_huge() {
+			return entry.address() | (virtual_address & (LargePageSize::SIZE - 1));
+		}
+	}
+
+	get_physical_address::<BasePageSize>(virtual_address)
+}
+
+/// Returns whether task heaps can be backed by pages of the given size.
+pub fn is_supported_task_heap_page_size(page_size: usize) -> bool {
+	page_size == BasePageSize::SIZE ||
+	page_size == LargePageSize::SIZE ||
+	(page_size == HugePageSize::SIZE && processor::supports_1gib_pages())
+}
+
+/// Returns the page size used to back newly created task heaps.
+/// This is 2 MiB unless a different size has been requested on the command line.
+pub fn default_task_heap_page_size() -> usize {
+	let page_size = environment::get_command_line_heap_page_size();
+
+	if page_size == 0 {
+		LargePageSize::SIZE
+	} else if is_supported_task_heap_page_size(page_size) {
+		page_size
+	} else {
+		warn!("Task heap page size {:#X} is not supported by this CPU, using 2 MiB pages", page_size);
+		LargePageSize::SIZE
+	}
+}
+
 #[inline]
 fn get_page_range<S: PageSize>(virtual_address: usize, count: usize) -> PageIter<S> {
 	let first_page = Page::<S>::including_address(virtual_address);
@@ -597,8 +824,8 @@ pub fn virtual_to_physical(virtual_address: usize) -> usize {
 		// The kernel memory is mapped in 4 KiB pages.
 		get_physical_address::<BasePageSize>(virtual_address)
 	} else if virtual_address < virtualmem::task_heap_end() {
-		// The application memory is mapped in 2 MiB pages.
-		get_physical_address::<LargePageSize>(virtual_address)
+		// The application memory is mapped in pages of the size configured for the respective task heap.
+		get_task_heap_physical_address(virtual_address)
 	} else {
 		// This range is currently unused by HermitCore.
 		panic!("Trying to get the physical address of {:#X}, which is too high", virtual_address);
@@ -618,6 +845,25 @@ pub fn map<S: PageSize>(virtual_address: usize, physical_address: usize, count:
 	root_pagetable.map_pages(range, physical_address, flags, do_ipi);
 }
 
+/// Removes the mappings of `count` pages starting at `virtual_address`.
+/// The physical memory is not freed.
+pub fn unmap<S: PageSize>(virtual_address: usize, count: usize, do_ipi: bool) {
+	debug_mem!("Unmapping virtual address {:#X} ({} pages)", virtual_address, count);
+
+	let range = get_page_range::<S>(virtual_address, count);
+	let root_pagetable = unsafe { &mut *PML4_ADDRESS };
+	let mut send_ipi = false;
+
+	for page in range {
+		send_ipi |= root_pagetable.unmap_page::<S>(page);
+	}
+
+	// You are responsible for not setting do_ipi to true before the APIC has been initialized.
+	if do_ipi && send_ipi {
+		apic::ipi_tlb_flush();
+	}
+}
+
 pub fn identity_map(start_address: usize, end_address: usize) {
 	let first_page = Page::<BasePageSize>::including_address(start_address);
 	let last_page = Page::<BasePageSize>::including_address(end_address);
@@ -643,6 +889,12 @@ pub fn init() {
 			let mb = Multiboot::new(mb_info);
 			let memory_map_address = mb.memory_map_address().expect("Could not find a memory map in the Multiboot information");
 			identity_map(memory_map_address, memory_map_address);
+
+			// And the information about modules, which may contain an initrd.
+			if let Some(modules_address) = mb.modules_address() {
+				let modules_size = mb.modules().unwrap().len() * mem::size_of::<Module>();
+				identity_map(modules_address, modules_address + cmp::max(modules_size, 1) - 1);
+			}
 		}
 
 		if cmdsize > 0 {
@@ -22,10 +22,14 @@
 // WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
 
 use arch::x86_64::mm::paging::{BasePageSize, PageSize};
+use arch::x86_64::numa;
+use arch::x86_64::numa::MAX_NUMA_NODES;
 use collections::Node;
+use core::cmp;
 use hermit_multiboot::Multiboot;
 use mm;
 use mm::freelist::{FreeList, FreeListEntry};
+use mm::stats;
 use mm::{MM_LOCK, POOL};
 
 
@@ -34,7 +38,12 @@ extern "C" {
 	static mb_info: usize;
 }
 
-static mut PHYSICAL_FREE_LIST: FreeList = FreeList::new();
+/// One Free List of physical memory per NUMA node.
+/// All memory is added to the list of node 0 at first and distributed by init_numa().
+static mut PHYSICAL_FREE_LISTS: [FreeList; MAX_NUMA_NODES] = [
+	FreeList::new(), FreeList::new(), FreeList::new(), FreeList::new(),
+	FreeList::new(), FreeList::new(), FreeList::new(), FreeList::new(),
+];
 
 
 fn detect_from_multiboot_info() -> Result<(), ()> {
@@ -65,10 +74,32 @@ fn detect_from_multiboot_info() -> Result<(), ()> {
 				end: m.base_address() + m.length()
 			}
 		);
-		unsafe { PHYSICAL_FREE_LIST.list.push(entry); }
+		stats::add_physical_total(m.base_address() + m.length() - start_address);
+		unsafe { PHYSICAL_FREE_LISTS[0].list.push(entry); }
 	}
 
 	assert!(found_ram, "Could not find any available RAM in the Multiboot Memory Map");
+
+	// Additional modules (like an initrd) may lie in the available RAM and must not be handed out.
+	for m in unsafe { mb.modules() }.into_iter().flat_map(|modules| modules.iter().skip(1)) {
+		let start = align_down!(m.start_address(), BasePageSize::SIZE);
+		let end = align_up!(m.end_address(), BasePageSize::SIZE);
+		if end <= mm::kernel_end_address() {
+			continue;
+		}
+
+		let start = cmp::max(start, mm::kernel_end_address());
+		let result = unsafe {
+			POOL.maintain();
+			PHYSICAL_FREE_LISTS[0].reserve(start, end - start)
+		};
+		if result.is_ok() {
+			stats::physical_allocated(end - start);
+		} else {
+			warn!("Could not reserve the Multiboot module at {:#X} - {:#X}", start, end);
+		}
+	}
+
 	Ok(())
 }
 
@@ -83,40 +114,118 @@ fn detect_from_limits() -> Result<(), ()> {
 			end: unsafe { limit }
 		}
 	);
-	unsafe { PHYSICAL_FREE_LIST.list.push(entry); }
+	stats::add_physical_total(unsafe { limit } - mm::kernel_end_address());
+	unsafe { PHYSICAL_FREE_LISTS[0].list.push(entry); }
 
 	Ok(())
 }
 
+/// Returns the physical start and end address of the initrd, which is the first Multiboot module
+/// after the application.
+pub fn get_initrd() -> Option<(usize, usize)> {
+	if unsafe { mb_info } == 0 {
+		return None;
+	}
+
+	let mb = unsafe { Multiboot::new(mb_info) };
+	unsafe { mb.modules() }
+		.and_then(|modules| modules.iter().nth(1))
+		.and_then(|m| if m.end_address() > m.start_address() { Some((m.start_address(), m.end_address())) } else { None })
+}
+
 pub fn init() {
 	detect_from_multiboot_info()
 		.or_else(|_e| detect_from_limits())
 		.unwrap();
 }
 
+/// Moves all free memory belonging to another NUMA node than node 0 into the Free List of that node.
+/// Must be called after numa::init().
+pub fn init_numa() {
+	let _lock = MM_LOCK.lock();
+
+	for node in 1..numa::node_count() {
+		numa::for_each_memory_range(node, |range_start, range_end| {
+			// Move one free region intersecting the range at a time, because the Free List of node 0
+			// must not be borrowed while it is modified.
+			while let Some((start, end)) = find_intersection(0, range_start, range_end) {
+				unsafe {
+					POOL.maintain();
+					PHYSICAL_FREE_LISTS[0].reserve(start, end - start).unwrap();
+					PHYSICAL_FREE_LISTS[node].deallocate(start, end - start);
+				}
+			}
+		});
+	}
+}
+
+/// Returns the first free region of the given node that intersects the given address range.
+fn find_intersection(node: usize, range_start: usize, range_end: usize) -> Option<(usize, usize)> {
+	for entry in unsafe { PHYSICAL_FREE_LISTS[node].list.iter() } {
+		let (start, end) = {
+			let borrowed = entry.borrow();
+			(borrowed.value.start, borrowed.value.end)
+		};
+
+		if start < range_end && end > range_start {
+			let start = align_up!(cmp::max(start, range_start), BasePageSize::SIZE);
+			let end = align_down!(cmp::min(end, range_end), BasePageSize::SIZE);
+			if start < end {
+				return Some((start, end));
+			}
+		}
+	}
+
+	None
+}
+
+/// Calls `f` for the Free List of every NUMA node, nearest to the calling core first, until `f` succeeds.
+fn allocate_from_nearest_node<F>(mut f: F) -> Result<usize, ()> where F: FnMut(&mut FreeList) -> Result<usize, ()> {
+	let mut nodes = [0; MAX_NUMA_NODES];
+	let count = numa::nodes_by_distance(numa::current_node(), &mut nodes);
+
+	for &node in nodes[..count].iter() {
+		if let Ok(address) = f(unsafe { &mut PHYSICAL_FREE_LISTS[node] }) {
+			return Ok(address);
+		}
+	}
+
+	Err(())
+}
+
 pub fn allocate(size: usize) -> usize {
 	assert!(size > 0);
 	assert!(size % BasePageSize::SIZE == 0, "Size {:#X} is not a multiple of {:#X}", size, BasePageSize::SIZE);
 
 	let _lock = MM_LOCK.lock();
-	let result = unsafe { PHYSICAL_FREE_LIST.allocate(size) };
+	let result = allocate_from_nearest_node(|free_list| free_list.allocate(size));
 	assert!(result.is_ok(), "Could not allocate {:#X} bytes of physical memory", size);
+	stats::physical_allocated(size);
 	result.unwrap()
 }
 
 pub fn allocate_aligned(size: usize, alignment: usize) -> usize {
+	let result = try_allocate_aligned(size, alignment);
+	assert!(result.is_ok(), "Could not allocate {:#X} bytes of physical memory aligned to {} bytes", size, alignment);
+	result.unwrap()
+}
+
+/// Like allocate_aligned, but returns an error instead of panicking if no suitable memory is available.
+/// Useful for callers that can fall back to a smaller alignment.
+pub fn try_allocate_aligned(size: usize, alignment: usize) -> Result<usize, ()> {
 	assert!(size > 0);
 	assert!(alignment > 0);
 	assert!(size % alignment == 0, "Size {:#X} is not a multiple of the given alignment {:#X}", size, alignment);
 	assert!(alignment % BasePageSize::SIZE == 0, "Alignment {:#X} is not a multiple of {:#X}", alignment, BasePageSize::SIZE);
 
 	let _lock = MM_LOCK.lock();
-	let result = unsafe {
-		POOL.maintain();
-		PHYSICAL_FREE_LIST.allocate_aligned(size, alignment)
-	};
-	assert!(result.is_ok(), "Could not allocate {:#X} bytes of physical memory aligned to {} bytes", size, alignment);
-	result.unwrap()
+	unsafe { POOL.maintain(); }
+	let result = allocate_from_nearest_node(|free_list| free_list.allocate_aligned(size, alignment));
+	if result.is_ok() {
+		stats::physical_allocated(size);
+	}
+
+	result
 }
 
 /// This function must only be called from mm::deallocate!
@@ -126,9 +235,20 @@ pub fn deallocate(physical_address: usize, size: usize) {
 	assert!(size > 0);
 	assert!(size % BasePageSize::SIZE == 0, "Size {:#X} is not a multiple of {:#X}", size, BasePageSize::SIZE);
 
-	unsafe { PHYSICAL_FREE_LIST.deallocate(physical_address, size); }
+	// Return the memory to the Free List of the node it belongs to.
+	let node = numa::node_for_address(physical_address);
+	unsafe { PHYSICAL_FREE_LISTS[node].deallocate(physical_address, size); }
+	stats::physical_deallocated(size);
 }
 
 pub fn print_information() {
-	unsafe { PHYSICAL_FREE_LIST.print_information(" PHYSICAL MEMORY FREE LIST "); }
+	let node_count = numa::node_count();
+
+	for node in 0..node_count {
+		if node_count > 1 {
+			info!("NUMA node {}:", node);
+		}
+
+		unsafe { PHYSICAL_FREE_LISTS[node].print_information(" PHYSICAL MEMORY FREE LIST "); }
+	}
 }
@@ -25,6 +25,7 @@ use arch::x86_64::mm::paging::{BasePageSize, PageSize};
 use collections::Node;
 use mm;
 use mm::freelist::{FreeList, FreeListEntry};
+use mm::stats;
 use mm::{MM_LOCK, POOL};
 
 
@@ -57,6 +58,7 @@ pub fn allocate(size: usize) -> usize {
 	let _lock = MM_LOCK.lock();
 	let result = unsafe { KERNEL_FREE_LIST.allocate(size) };
 	assert!(result.is_ok(), "Could not allocate {:#X} bytes of virtual memory", size);
+	stats::virtual_allocated(size);
 	result.unwrap()
 }
 
@@ -72,6 +74,7 @@ pub fn deallocate(virtual_address: usize, size: usize) {
 		POOL.maintain();
 		KERNEL_FREE_LIST.deallocate(virtual_address, size);
 	}
+	stats::virtual_deallocated(size);
 }
 
 pub fn reserve(virtual_address: usize, size: usize) {
@@ -87,6 +90,7 @@ pub fn reserve(virtual_address: usize, size: usize) {
 		KERNEL_FREE_LIST.reserve(virtual_address, size)
 	};
 	assert!(result.is_ok(), "Could not reserve {:#X} bytes of virtual memory at {:#X}", size, virtual_address);
+	stats::virtual_allocated(size);
 }
 
 pub fn print_information() {
@@ -24,10 +24,12 @@
 
 pub mod acpi;
 pub mod apic;
+pub mod backtrace;
 pub mod gdt;
 pub mod idt;
 pub mod irq;
 pub mod mm;
+pub mod numa;
 pub mod percore;
 pub mod pci;
 pub mod pic;
@@ -44,7 +46,9 @@ pub use arch::x86_64::apic::wakeup_core;
 pub use arch::x86_64::gdt::get_boot_stacks;
 pub use arch::x86_64::gdt::set_current_kernel_stack;
 pub use arch::x86_64::percore::PERCORE;
+use arch::x86_64::irq::ExceptionStackFrame;
 use arch::x86_64::serial::SerialPort;
+use console;
 use environment;
 use kernel_message_buffer;
 use synch::spinlock::Spinlock;
@@ -93,6 +97,30 @@ pub fn output_message_byte(byte: u8) {
 	}
 }
 
+extern "x86-interrupt" fn serial_interrupt(_stack_frame: &mut ExceptionStackFrame) {
+	// Drain the receiver FIFO, so that the UART raises the next interrupt.
+	while let Some(byte) = COM1.read_byte() {
+		console::receive_input_byte(byte);
+	}
+
+	apic::eoi();
+}
+
+/// Deliver the input of the serial port to the console.
+fn serial_input_init() {
+	// The serial port is a PCI device, so its interrupt line has been assigned by the firmware.
+	let irq = match pci::get_adapter_by_io_port(SERIAL_PORT_ADDRESS) {
+		Some(ref adapter) if adapter.irq != 0xFF => adapter.irq,
+		_ => {
+			info!("No interrupt line for the serial port at {:#X}, input is not available", SERIAL_PORT_ADDRESS);
+			return;
+		}
+	};
+
+	idt::set_gate(pic::PIC1_INTERRUPT_OFFSET + irq, serial_interrupt as usize, 1);
+	COM1.enable_receive_interrupt();
+}
+
 /// Real Boot Processor initialization as soon as we have put the first Welcome message on the screen.
 pub fn boot_processor_init() {
 	processor::detect_features();
@@ -120,14 +148,21 @@ pub fn boot_processor_init() {
 	processor::print_information();
 
 	if environment::is_single_kernel() && !environment::is_uhyve() {
+		acpi::init();
 		pci::init();
 		pci::print_information();
-		acpi::init();
+		numa::init();
+		numa::print_information();
+		mm::physicalmem::init_numa();
 	}
 
 	apic::init();
 	scheduler::install_timer_handler();
 
+	if environment::is_single_kernel() && !environment::is_uhyve() {
+		serial_input_init();
+	}
+
 	**CPU_ONLINE.lock() += 1;
 }
 
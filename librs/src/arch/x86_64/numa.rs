@@ -0,0 +1,392 @@
+// Copyright (c) 2018 Colin Finck, RWTH Aachen University
+//
+// MIT License
+//
+// Permission is hereby granted, free of charge, to any person obtaining
+// a copy of this software and associated documentation files (the
+// "Software"), to deal in the Software without restriction, including
+// without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to
+// permit persons to whom the Software is furnished to do so, subject to
+// the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE
+// LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION
+// OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
+// WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! Detection of the NUMA topology from the ACPI SRAT and SLIT tables.
+//!
+//! Proximity domains reported by ACPI are mapped to consecutive node numbers starting at 0.
+//! Without any ACPI information, all processors and memory belong to node 0.
+
+use arch::x86_64::acpi;
+use arch::x86_64::percore::*;
+use core::{fmt, mem};
+
+
+/// Maximum number of NUMA nodes supported by HermitCore.
+pub const MAX_NUMA_NODES: usize = 8;
+/// Maximum number of memory ranges that can be assigned to NUMA nodes.
+const MAX_MEMORY_RANGES: usize = 32;
+/// Maximum number of Local APIC IDs that can be assigned to NUMA nodes.
+const MAX_APIC_IDS: usize = 256;
+
+/// Distance of a node to itself as defined by the ACPI Specification.
+const LOCAL_DISTANCE: u8 = 10;
+/// Distance assumed between two different nodes if no SLIT is available.
+const REMOTE_DISTANCE: u8 = 20;
+
+/// Flag of all SRAT affinity structures indicating that the structure is in use.
+const SRAT_FLAG_ENABLED: u32 = 1 << 0;
+
+
+#[derive(Clone, Copy)]
+struct MemoryRange {
+	start: usize,
+	end: usize,
+	node: usize,
+}
+
+/// The topology detected from the ACPI tables.
+struct NumaTopology {
+	/// ACPI Proximity Domain for each node.
+	proximity_domains: [u32; MAX_NUMA_NODES],
+	/// Number of detected nodes.
+	node_count: usize,
+	memory_ranges: [MemoryRange; MAX_MEMORY_RANGES],
+	memory_range_count: usize,
+	/// Node of each Local APIC ID.
+	apic_id_nodes: [u8; MAX_APIC_IDS],
+	/// Relative distances between all nodes.
+	distances: [[u8; MAX_NUMA_NODES]; MAX_NUMA_NODES],
+}
+
+static mut TOPOLOGY: NumaTopology = NumaTopology {
+	proximity_domains: [0; MAX_NUMA_NODES],
+	node_count: 1,
+	memory_ranges: [MemoryRange { start: 0, end: 0, node: 0 }; MAX_MEMORY_RANGES],
+	memory_range_count: 0,
+	apic_id_nodes: [0; MAX_APIC_IDS],
+	distances: [[0; MAX_NUMA_NODES]; MAX_NUMA_NODES],
+};
+
+
+/// The header of the SRAT following the common ACPI table header.
+#[repr(C, packed)]
+struct AcpiSratHeader {
+	table_revision: u32,
+	reserved: u64,
+}
+
+#[repr(C, packed)]
+struct AcpiSratRecordHeader {
+	entry_type: u8,
+	length: u8,
+}
+
+#[repr(C, packed)]
+struct ProcessorLocalApicAffinityRecord {
+	proximity_domain_low: u8,
+	apic_id: u8,
+	flags: u32,
+	local_sapic_eid: u8,
+	proximity_domain_high: [u8; 3],
+	clock_domain: u32,
+}
+
+impl ProcessorLocalApicAffinityRecord {
+	fn proximity_domain(&self) -> u32 {
+		self.proximity_domain_low as u32 |
+			(self.proximity_domain_high[0] as u32) << 8 |
+			(self.proximity_domain_high[1] as u32) << 16 |
+			(self.proximity_domain_high[2] as u32) << 24
+	}
+}
+
+impl fmt::Display for ProcessorLocalApicAffinityRecord {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{{ proximity_domain: {}, ", self.proximity_domain())?;
+		write!(f, "apic_id: {}, ", {self.apic_id})?;
+		write!(f, "flags: {} }}", {self.flags})?;
+		Ok(())
+	}
+}
+
+#[repr(C, packed)]
+struct MemoryAffinityRecord {
+	proximity_domain: u32,
+	reserved1: u16,
+	base_address: u64,
+	length: u64,
+	reserved2: u32,
+	flags: u32,
+	reserved3: u64,
+}
+
+impl fmt::Display for MemoryAffinityRecord {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{{ proximity_domain: {}, ", {self.proximity_domain})?;
+		write!(f, "base_address: {:#X}, ", {self.base_address})?;
+		write!(f, "length: {:#X}, ", {self.length})?;
+		write!(f, "flags: {} }}", {self.flags})?;
+		Ok(())
+	}
+}
+
+#[repr(C, packed)]
+struct ProcessorLocalX2ApicAffinityRecord {
+	reserved1: u16,
+	proximity_domain: u32,
+	x2apic_id: u32,
+	flags: u32,
+	clock_domain: u32,
+	reserved2: u32,
+}
+
+impl fmt::Display for ProcessorLocalX2ApicAffinityRecord {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{{ proximity_domain: {}, ", {self.proximity_domain})?;
+		write!(f, "x2apic_id: {}, ", {self.x2apic_id})?;
+		write!(f, "flags: {} }}", {self.flags})?;
+		Ok(())
+	}
+}
+
+
+/// Returns the node number for an ACPI Proximity Domain and registers a new node if necessary.
+fn get_or_add_node(topology: &mut NumaTopology, proximity_domain: u32) -> Option<usize> {
+	if let Some(node) = topology.proximity_domains[..topology.node_count].iter().position(|&d| d == proximity_domain) {
+		return Some(node);
+	}
+
+	if topology.node_count == MAX_NUMA_NODES {
+		warn!("Ignoring Proximity Domain {}, because only {} NUMA nodes are supported", proximity_domain, MAX_NUMA_NODES);
+		return None;
+	}
+
+	let node = topology.node_count;
+	topology.proximity_domains[node] = proximity_domain;
+	topology.node_count += 1;
+	Some(node)
+}
+
+fn set_apic_id_node(topology: &mut NumaTopology, apic_id: usize, proximity_domain: u32) {
+	if apic_id >= MAX_APIC_IDS {
+		warn!("Ignoring NUMA affinity of Local APIC ID {}", apic_id);
+		return;
+	}
+
+	if let Some(node) = get_or_add_node(topology, proximity_domain) {
+		topology.apic_id_nodes[apic_id] = node as u8;
+	}
+}
+
+fn add_memory_range(topology: &mut NumaTopology, start: usize, end: usize, proximity_domain: u32) {
+	if topology.memory_range_count == MAX_MEMORY_RANGES {
+		warn!("Ignoring NUMA affinity of memory range {:#X} - {:#X}", start, end);
+		return;
+	}
+
+	if let Some(node) = get_or_add_node(topology, proximity_domain) {
+		topology.memory_ranges[topology.memory_range_count] = MemoryRange { start: start, end: end, node: node };
+		topology.memory_range_count += 1;
+	}
+}
+
+fn parse_srat(topology: &mut NumaTopology) -> Result<(), ()> {
+	let srat = acpi::get_srat().ok_or(())?;
+
+	// The first Proximity Domain found gets node 0, so forget the default node.
+	topology.node_count = 0;
+
+	// Jump to the actual table entries (after the table header).
+	let mut current_address = srat.table_start_address() + mem::size_of::<AcpiSratHeader>();
+
+	// Loop through all table entries.
+	while current_address + mem::size_of::<AcpiSratRecordHeader>() <= srat.table_end_address() {
+		let record = unsafe { & *(current_address as *const AcpiSratRecordHeader) };
+		if record.length == 0 {
+			break;
+		}
+
+		let record_address = current_address + mem::size_of::<AcpiSratRecordHeader>();
+
+		match record.entry_type {
+			0 => {
+				// Processor Local APIC/SAPIC Affinity
+				let affinity_record = unsafe { & *(record_address as *const ProcessorLocalApicAffinityRecord) };
+				debug!("Found Processor Local APIC Affinity record: {}", affinity_record);
+
+				if affinity_record.flags & SRAT_FLAG_ENABLED > 0 {
+					set_apic_id_node(topology, affinity_record.apic_id as usize, affinity_record.proximity_domain());
+				}
+			},
+			1 => {
+				// Memory Affinity
+				let affinity_record = unsafe { & *(record_address as *const MemoryAffinityRecord) };
+				debug!("Found Memory Affinity record: {}", affinity_record);
+
+				if affinity_record.flags & SRAT_FLAG_ENABLED > 0 && affinity_record.length > 0 {
+					let start = affinity_record.base_address as usize;
+					let end = start + affinity_record.length as usize;
+					add_memory_range(topology, start, end, affinity_record.proximity_domain);
+				}
+			},
+			2 => {
+				// Processor Local x2APIC Affinity
+				let affinity_record = unsafe { & *(record_address as *const ProcessorLocalX2ApicAffinityRecord) };
+				debug!("Found Processor Local x2APIC Affinity record: {}", affinity_record);
+
+				if affinity_record.flags & SRAT_FLAG_ENABLED > 0 {
+					set_apic_id_node(topology, affinity_record.x2apic_id as usize, affinity_record.proximity_domain);
+				}
+			},
+			_ => {
+				// Just ignore other entries for now.
+			}
+		}
+
+		current_address += record.length as usize;
+	}
+
+	if topology.node_count == 0 {
+		// The SRAT contained no usable entries.
+		topology.node_count = 1;
+		return Err(());
+	}
+
+	Ok(())
+}
+
+fn parse_slit(topology: &mut NumaTopology) -> Result<(), ()> {
+	let slit = acpi::get_slit().ok_or(())?;
+
+	// The table begins with the number of System Localities, followed by a matrix of distances between them.
+	let locality_count = unsafe { *(slit.table_start_address() as *const u64) } as usize;
+	let matrix_address = slit.table_start_address() + mem::size_of::<u64>();
+	if matrix_address + locality_count * locality_count > slit.table_end_address() {
+		warn!("SLIT is too small for {} System Localities", locality_count);
+		return Err(());
+	}
+
+	// System Localities correspond to the Proximity Domains of the SRAT.
+	for i in 0..topology.node_count {
+		for j in 0..topology.node_count {
+			let from = topology.proximity_domains[i] as usize;
+			let to = topology.proximity_domains[j] as usize;
+
+			if from < locality_count && to < locality_count {
+				topology.distances[i][j] = unsafe { *((matrix_address + from * locality_count + to) as *const u8) };
+			}
+		}
+	}
+
+	Ok(())
+}
+
+pub fn init() {
+	let topology = unsafe { &mut TOPOLOGY };
+
+	// Initialize the distances with defaults for the case that no SLIT is available.
+	for i in 0..MAX_NUMA_NODES {
+		for j in 0..MAX_NUMA_NODES {
+			topology.distances[i][j] = if i == j { LOCAL_DISTANCE } else { REMOTE_DISTANCE };
+		}
+	}
+
+	if parse_srat(topology).is_err() {
+		debug!("No NUMA information available, assuming a single node");
+		return;
+	}
+
+	let _ = parse_slit(topology);
+}
+
+/// Returns the number of NUMA nodes.
+pub fn node_count() -> usize {
+	unsafe { TOPOLOGY.node_count }
+}
+
+/// Returns the NUMA node of the core executing this function.
+pub fn current_node() -> usize {
+	node_for_apic_id(core_id() as usize)
+}
+
+/// Returns the NUMA node of the core with the given Local APIC ID.
+pub fn node_for_apic_id(apic_id: usize) -> usize {
+	if apic_id < MAX_APIC_IDS {
+		unsafe { TOPOLOGY.apic_id_nodes[apic_id] as usize }
+	} else {
+		0
+	}
+}
+
+/// Returns the NUMA node of the given physical address.
+/// Memory not described by the SRAT belongs to node 0.
+pub fn node_for_address(physical_address: usize) -> usize {
+	let topology = unsafe { &TOPOLOGY };
+
+	topology.memory_ranges[..topology.memory_range_count]
+		.iter()
+		.find(|r| physical_address >= r.start && physical_address < r.end)
+		.map_or(0, |r| r.node)
+}
+
+/// Calls `f` with the start and end address of every memory range belonging to `node`.
+pub fn for_each_memory_range<F>(node: usize, mut f: F) where F: FnMut(usize, usize) {
+	let topology = unsafe { &TOPOLOGY };
+
+	for r in topology.memory_ranges[..topology.memory_range_count].iter().filter(|r| r.node == node) {
+		f(r.start, r.end);
+	}
+}
+
+/// Returns the relative distance between two NUMA nodes as reported by the SLIT.
+/// The distance of a node to itself is 10.
+pub fn distance(from: usize, to: usize) -> u8 {
+	unsafe { TOPOLOGY.distances[from][to] }
+}
+
+/// Fills `nodes` with all node numbers sorted by their distance to `node`, starting with `node` itself.
+/// Returns the number of valid entries in `nodes`.
+pub fn nodes_by_distance(node: usize, nodes: &mut [usize; MAX_NUMA_NODES]) -> usize {
+	let count = node_count();
+
+	for i in 0..count {
+		nodes[i] = i;
+	}
+
+	// Sort by distance, but keep the requested node in front even if the SLIT contains nonsense.
+	nodes[..count].sort_unstable_by_key(|&n| (n != node, distance(node, n), n));
+	count
+}
+
+pub fn print_information() {
+	let topology = unsafe { &TOPOLOGY };
+
+	infoheader!(" NUMA INFORMATION ");
+	infoentry!("Nodes", topology.node_count);
+
+	for node in 0..topology.node_count {
+		info!("Node {} (Proximity Domain {}):", node, topology.proximity_domains[node]);
+
+		for_each_memory_range(node, |start, end| {
+			info!("  Memory {:#016X} - {:#016X}", start, end);
+		});
+
+		let mut distances = [0u8; MAX_NUMA_NODES];
+		for other in 0..topology.node_count {
+			distances[other] = topology.distances[node][other];
+		}
+		info!("  Distances {:?}", &distances[..topology.node_count]);
+	}
+
+	infofooter!();
+}
@@ -21,36 +21,200 @@
 // OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
 // WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
 
-include!(concat!(env!("CARGO_TARGET_DIR"), "/pcidata.rs"));
-
+use alloc::btree_map::BTreeMap;
 use alloc::vec::Vec;
-use core::{fmt, u8, u32};
+use arch::x86_64::acpi;
+use arch::x86_64::mm::paging::{self, BasePageSize, PageSize, PageTableEntryFlags};
+use arch::x86_64::mm::virtualmem;
+use core::{fmt, mem, ptr, u8, u32};
 use synch::spinlock::Spinlock;
 use x86::shared::io::*;
 
 
-const PCI_MAX_BUS_NUMBER: u8 = 32;
+const PCI_MAX_BUS_NUMBER: usize = 256;
 const PCI_MAX_DEVICE_NUMBER: u8 = 32;
+const PCI_MAX_FUNCTION_NUMBER: u8 = 8;
+
+/// Size of the configuration space of a function, which is accessible through the I/O ports.
+const PCI_CONFIG_SPACE_SIZE: u32 = 0x100;
+/// Size of the extended configuration space of a PCI Express function, which is only accessible through ECAM.
+const PCIE_CONFIG_SPACE_SIZE: u32 = 0x1000;
 
 const PCI_CONFIG_ADDRESS_PORT: u16 = 0xCF8;
 const PCI_CONFIG_ADDRESS_ENABLE: u32 = 1 << 31;
 
 const PCI_CONFIG_DATA_PORT: u16 = 0xCFC;
+const PCI_COMMAND_IO_SPACE: u32 = 1 << 0;
+const PCI_COMMAND_MEMORY_SPACE: u32 = 1 << 1;
 const PCI_COMMAND_BUSMASTER: u32 = 1 << 2;
-
-const PCI_ID_REGISTER:        u32 = 0x00;
-const PCI_COMMAND_REGISTER:   u32 = 0x04;
-const PCI_CLASS_REGISTER:     u32 = 0x08;
-const PCI_BAR0_REGISTER:      u32 = 0x10;
-const PCI_INTERRUPT_REGISTER: u32 = 0x3C;
-
-pub const PCI_BASE_ADDRESS_IO_SPACE: u32 = 1 << 0;
-pub const PCI_BASE_ADDRESS_64BIT:    u32 = 1 << 2;
-pub const PCI_BASE_ADDRESS_MASK:     u32 = 0xFFFF_FFF0;
+const PCI_COMMAND_INTERRUPT_DISABLE: u32 = 1 << 10;
+const PCI_STATUS_CAPABILITIES_LIST: u32 = 1 << 20;
+
+const PCI_ID_REGISTER:           u32 = 0x00;
+const PCI_COMMAND_REGISTER:      u32 = 0x04;
+const PCI_STATUS_REGISTER:       u32 = 0x04;
+const PCI_CLASS_REGISTER:        u32 = 0x08;
+const PCI_HEADER_REGISTER:       u32 = 0x0C;
+const PCI_BAR0_REGISTER:         u32 = 0x10;
+const PCI_BRIDGE_BUS_REGISTER:   u32 = 0x18;
+const PCI_CAPABILITIES_REGISTER: u32 = 0x34;
+const PCI_INTERRUPT_REGISTER:    u32 = 0x3C;
+
+/// Maximum number of capabilities fitting into the configuration space after the header.
+const PCI_MAX_CAPABILITY_COUNT: usize = 48;
+
+const PCI_HEADER_TYPE_MASK: u8 = 0x7F;
+const PCI_HEADER_TYPE_MULTIFUNCTION: u8 = 1 << 7;
+
+pub const PCI_HEADER_TYPE_NORMAL:  u8 = 0;
+pub const PCI_HEADER_TYPE_BRIDGE:  u8 = 1;
+pub const PCI_HEADER_TYPE_CARDBUS: u8 = 2;
+
+pub const PCI_CAPABILITY_ID_MSI:    u8 = 0x05;
+pub const PCI_CAPABILITY_ID_VENDOR: u8 = 0x09;
+pub const PCI_CAPABILITY_ID_MSIX:   u8 = 0x11;
+
+// Bits of the Message Control register in the upper half of the first MSI and MSI-X capability register.
+const MSI_CONTROL_ENABLE: u32          = 1 << 16;
+const MSI_CONTROL_MULTIPLE_ENABLE: u32 = 0b111 << 20;
+const MSI_CONTROL_64BIT: u32           = 1 << 23;
+const MSIX_CONTROL_TABLE_SIZE: u32     = 0x7FF << 16;
+const MSIX_CONTROL_FUNCTION_MASK: u32  = 1 << 30;
+const MSIX_CONTROL_ENABLE: u32         = 1 << 31;
+
+const MSIX_TABLE_BIR_MASK: u32 = 0b111;
+const MSIX_TABLE_ENTRY_SIZE: usize = 16;
+const MSIX_ENTRY_ADDRESS_LOW: usize    = 0;
+const MSIX_ENTRY_ADDRESS_HIGH: usize   = 4;
+const MSIX_ENTRY_DATA: usize           = 8;
+const MSIX_ENTRY_VECTOR_CONTROL: usize = 12;
+const MSIX_ENTRY_MASKED: u32 = 1 << 0;
+
+/// Messages written to this address range are delivered to the Local APIC given in bits 12 to 19.
+const MSI_ADDRESS_BASE: u32 = 0xFEE0_0000;
+const MSI_ADDRESS_DESTINATION_SHIFT: u32 = 12;
+
+const PCI_BASE_ADDRESS_IO_SPACE:         u32 = 1 << 0;
+const PCI_BASE_ADDRESS_MEMORY_TYPE_MASK: u32 = 0b11 << 1;
+const PCI_BASE_ADDRESS_MEMORY_TYPE_64:   u32 = 0b10 << 1;
+const PCI_BASE_ADDRESS_PREFETCHABLE:     u32 = 1 << 3;
+const PCI_BASE_ADDRESS_IO_MASK:          u32 = 0xFFFF_FFFC;
+const PCI_BASE_ADDRESS_MEMORY_MASK:      u32 = 0xFFFF_FFF0;
 
 
 lazy_static! {
 	static ref PCI_ADAPTERS: Spinlock<Vec<PciAdapter>> = Spinlock::new(Vec::new());
+	static ref ECAM_REGIONS: Spinlock<Vec<EcamRegion>> = Spinlock::new(Vec::new());
+}
+
+
+/// The header of the MCFG following the common ACPI table header.
+#[repr(C, packed)]
+struct AcpiMcfgHeader {
+	reserved: u64,
+}
+
+/// An entry of the MCFG describing the memory-mapped configuration space of a range of buses.
+#[repr(C, packed)]
+struct AcpiMcfgAllocation {
+	base_address: u64,
+	segment_group: u16,
+	start_bus: u8,
+	end_bus: u8,
+	reserved: u32,
+}
+
+
+/// Memory-mapped configuration space of a range of buses ("Enhanced Configuration Access Mechanism").
+struct EcamRegion {
+	/// Physical address of the configuration space of bus 0, even if the region starts at a later bus.
+	physical_address: usize,
+	start_bus: u8,
+	end_bus: u8,
+	/// Virtual addresses of the configuration spaces of functions, keyed by their offset from physical_address.
+	/// A function is only mapped on the first access to its extended configuration space.
+	function_addresses: BTreeMap<usize, usize>,
+}
+
+impl EcamRegion {
+	fn new(allocation: &AcpiMcfgAllocation) -> Self {
+		Self {
+			physical_address: allocation.base_address as usize,
+			start_bus: allocation.start_bus,
+			end_bus: allocation.end_bus,
+			function_addresses: BTreeMap::new(),
+		}
+	}
+
+	fn contains(&self, bus: u8) -> bool {
+		bus >= self.start_bus && bus <= self.end_bus
+	}
+
+	/// Returns the virtual address of a register in the configuration space of a function on this region's buses.
+	fn register_address(&mut self, bus: u8, device: u8, function: u8, register: u32) -> usize {
+		let offset = (bus as usize) << 20 | (device as usize) << 15 | (function as usize) << 12;
+		let physical_address = self.physical_address + offset;
+		let virtual_address = *self.function_addresses
+			.entry(offset)
+			.or_insert_with(|| map_mmio(physical_address, PCIE_CONFIG_SPACE_SIZE as usize));
+
+		virtual_address + register as usize
+	}
+}
+
+
+/// A decoded Base Address Register of an adapter.
+#[derive(Clone, Copy)]
+pub enum PciBar {
+	Io {
+		port: u16,
+		size: usize,
+	},
+	Memory {
+		address: usize,
+		size: usize,
+		is_64bit: bool,
+		prefetchable: bool,
+	},
+}
+
+
+/// Memory-mapped registers of a device, mapped uncached into kernel virtual memory.
+/// All accesses are volatile and checked against the bounds of the region.
+#[derive(Clone, Copy)]
+pub struct MmioRegion {
+	address: usize,
+	length: usize,
+}
+
+impl MmioRegion {
+	/// Returns the virtual address of the start of the region.
+	#[allow(dead_code)]
+	pub fn address(&self) -> usize {
+		self.address
+	}
+
+	#[allow(dead_code)]
+	pub fn length(&self) -> usize {
+		self.length
+	}
+
+	fn checked_address<T>(&self, offset: usize) -> usize {
+		assert!(
+			offset <= self.length && mem::size_of::<T>() <= self.length - offset,
+			"MMIO access at offset {:#X} exceeds region of {:#X} bytes", offset, self.length
+		);
+		assert!(offset % mem::align_of::<T>() == 0, "Unaligned MMIO access at offset {:#X}", offset);
+		self.address + offset
+	}
+
+	pub fn read<T: Copy>(&self, offset: usize) -> T {
+		unsafe { ptr::read_volatile(self.checked_address::<T>(offset) as *const T) }
+	}
+
+	pub fn write<T: Copy>(&self, offset: usize, value: T) {
+		unsafe { ptr::write_volatile(self.checked_address::<T>(offset) as *mut T, value); }
+	}
 }
 
 
@@ -58,104 +222,312 @@ lazy_static! {
 pub struct PciAdapter {
 	pub bus: u8,
 	pub device: u8,
+	pub function: u8,
 	pub vendor_id: u16,
 	pub device_id: u16,
 	pub class_id: u8,
 	pub subclass_id: u8,
 	pub programming_interface_id: u8,
-	pub base_addresses: [u32; 6],
-	pub base_sizes: [u32; 6],
+	pub header_type: u8,
+	/// The decoded Base Address Registers, where the second register of a 64-bit memory BAR is None.
+	pub base_addresses: [Option<PciBar>; 6],
 	pub irq: u8,
 }
 
 impl PciAdapter {
-	fn new(bus: u8, device: u8, vendor_id: u16, device_id: u16) -> Self {
-		let class_ids = read_config(bus, device, PCI_CLASS_REGISTER);
-
-		let mut base_addresses: [u32; 6] = [0; 6];
-		let mut base_sizes: [u32; 6] = [0; 6];
-		for i in 0..6 {
+	fn new(bus: u8, device: u8, function: u8, vendor_id: u16, device_id: u16) -> Self {
+		let class_ids = read_config(bus, device, function, PCI_CLASS_REGISTER);
+		let header_type = header_type(bus, device, function) & PCI_HEADER_TYPE_MASK;
+
+		// Bridges only have two Base Address Registers, followed by their bus numbers and windows.
+		let base_address_count = match header_type {
+			PCI_HEADER_TYPE_NORMAL => 6,
+			PCI_HEADER_TYPE_BRIDGE => 2,
+			_ => 0,
+		};
+
+		// Disable decoding while sizing the BARs, so that the device does not respond to the temporary addresses.
+		let command = read_config(bus, device, function, PCI_COMMAND_REGISTER);
+		write_config(bus, device, function, PCI_COMMAND_REGISTER, command & !(PCI_COMMAND_IO_SPACE | PCI_COMMAND_MEMORY_SPACE));
+
+		let mut base_addresses: [Option<PciBar>; 6] = [None; 6];
+		let mut i = 0;
+		while i < base_address_count {
 			let register = PCI_BAR0_REGISTER + ((i as u32) << 2);
-			base_addresses[i] = read_config(bus, device, register);
-
-			if base_addresses[i] > 0 {
-				write_config(bus, device, register, u32::MAX);
-				base_sizes[i] = !(read_config(bus, device, register) & PCI_BASE_ADDRESS_MASK) + 1;
-				write_config(bus, device, register, base_addresses[i]);
-			}
+			let (bar, register_count) = decode_bar(bus, device, function, register, i + 1 < base_address_count);
+			base_addresses[i] = bar;
+			i += register_count;
 		}
 
-		let interrupt_info = read_config(bus, device, PCI_INTERRUPT_REGISTER);
+		write_config(bus, device, function, PCI_COMMAND_REGISTER, command);
+
+		let interrupt_info = read_config(bus, device, function, PCI_INTERRUPT_REGISTER);
 
 		Self {
 			bus: bus,
 			device: device,
+			function: function,
 			vendor_id: vendor_id,
 			device_id: device_id,
 			class_id: (class_ids >> 24) as u8,
 			subclass_id: (class_ids >> 16) as u8,
 			programming_interface_id: (class_ids >> 8) as u8,
+			header_type: header_type,
 			base_addresses: base_addresses,
-			base_sizes: base_sizes,
 			irq: interrupt_info as u8,
 		}
 	}
 
 	pub fn make_bus_master(&self) {
-		let mut command = read_config(self.bus, self.device, PCI_COMMAND_REGISTER);
+		let mut command = self.read_config(PCI_COMMAND_REGISTER);
 		command |= PCI_COMMAND_BUSMASTER;
-		write_config(self.bus, self.device, PCI_COMMAND_REGISTER, command);
+		self.write_config(PCI_COMMAND_REGISTER, command);
 	}
-}
 
-impl fmt::Display for PciAdapter {
-	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-		// Look for the best matching class name in the PCI Database.
-		let mut class_name = "Unknown Class";
-		for ref c in CLASSES {
-			if c.id == self.class_id {
-				class_name = c.name;
-				for ref sc in c.subclasses {
-					if sc.id == self.subclass_id {
-						class_name = sc.name;
-						break;
-					}
-				}
+	/// Returns an iterator over the capabilities in the configuration space of this adapter.
+	pub fn capabilities(&self) -> PciCapabilities {
+		let next = if self.read_config(PCI_STATUS_REGISTER) & PCI_STATUS_CAPABILITIES_LIST > 0 {
+			self.read_config(PCI_CAPABILITIES_REGISTER) & 0xFC
+		} else {
+			0
+		};
+
+		PciCapabilities {
+			adapter: self,
+			next: next,
+			remaining: PCI_MAX_CAPABILITY_COUNT,
+		}
+	}
 
-				break;
-			}
+	/// Returns the first capability with the given ID.
+	pub fn find_capability(&self, id: u8) -> Option<PciCapability> {
+		self.capabilities().find(|capability| capability.id == id)
+	}
+
+	/// Stops the adapter from raising its legacy interrupt line, because it uses Message Signaled Interrupts.
+	fn disable_interrupt_line(&self) {
+		let command = self.read_config(PCI_COMMAND_REGISTER);
+		self.write_config(PCI_COMMAND_REGISTER, command | PCI_COMMAND_INTERRUPT_DISABLE);
+	}
+
+	/// Lets the adapter signal its interrupt through a single MSI to the given vector on the core
+	/// with the given Local APIC ID instead of its legacy interrupt line.
+	pub fn enable_msi(&self, core_id: u32, vector: u8) -> Result<(), ()> {
+		let capability = self.find_capability(PCI_CAPABILITY_ID_MSI).ok_or(())?;
+		let address = msi_address(core_id)?;
+
+		let control = self.read_config(capability.offset);
+		let data_register = if control & MSI_CONTROL_64BIT > 0 {
+			self.write_config(capability.offset + 8, 0);
+			capability.offset + 12
+		} else {
+			capability.offset + 8
+		};
+
+		self.write_config(capability.offset + 4, address);
+		self.write_config(data_register, vector as u32);
+
+		// Enable a single message only.
+		self.write_config(capability.offset, (control & !MSI_CONTROL_MULTIPLE_ENABLE) | MSI_CONTROL_ENABLE);
+		self.disable_interrupt_line();
+		Ok(())
+	}
+
+	/// Switches the adapter to MSI-X with all table entries masked.
+	/// Entries are routed and unmasked through the returned table.
+	pub fn enable_msix(&self) -> Option<MsixTable> {
+		let capability = self.find_capability(PCI_CAPABILITY_ID_MSIX)?;
+		let control = self.read_config(capability.offset);
+		let table_info = self.read_config(capability.offset + 4);
+
+		let bar = (table_info & MSIX_TABLE_BIR_MASK) as usize;
+		let size = ((control & MSIX_CONTROL_TABLE_SIZE) >> 16) as u16 + 1;
+		let offset = (table_info & !MSIX_TABLE_BIR_MASK) as usize;
+
+		let table = MsixTable {
+			region: self.map_bar_range(bar, offset, size as usize * MSIX_TABLE_ENTRY_SIZE)?,
+			size: size,
+		};
+
+		for entry in 0..size {
+			table.mask(entry);
 		}
 
-		// Look for the vendor and device name in the PCI Database.
-		let mut vendor_name = "Unknown Vendor";
-		let mut device_name = "Unknown Device";
-		for ref v in VENDORS {
-			if v.id == self.vendor_id {
-				vendor_name = v.name;
-				for ref d in v.devices {
-					if d.id == self.device_id {
-						device_name = d.name;
-						break;
-					}
-				}
+		self.write_config(capability.offset, (control & !MSIX_CONTROL_FUNCTION_MASK) | MSIX_CONTROL_ENABLE);
+		self.disable_interrupt_line();
+		Some(table)
+	}
 
-				break;
-			}
+	/// Switches the adapter from MSI-X back to its legacy interrupt line.
+	pub fn disable_msix(&self) {
+		if let Some(capability) = self.find_capability(PCI_CAPABILITY_ID_MSIX) {
+			let control = self.read_config(capability.offset);
+			self.write_config(capability.offset, control & !MSIX_CONTROL_ENABLE);
+		}
+
+		let command = self.read_config(PCI_COMMAND_REGISTER);
+		self.write_config(PCI_COMMAND_REGISTER, command & !PCI_COMMAND_INTERRUPT_DISABLE);
+	}
+
+	/// Maps a whole memory BAR uncached into kernel virtual memory.
+	pub fn map_bar(&self, bar: usize) -> Option<MmioRegion> {
+		match self.base_addresses.get(bar).cloned()? {
+			Some(PciBar::Memory { size, .. }) => self.map_bar_range(bar, 0, size),
+			_ => None,
+		}
+	}
+
+	/// Maps `length` bytes starting at `offset` into a memory BAR uncached into kernel virtual memory.
+	/// Returns None if the BAR is no memory BAR or the range exceeds it.
+	pub fn map_bar_range(&self, bar: usize, offset: usize, length: usize) -> Option<MmioRegion> {
+		let (address, size) = match self.base_addresses.get(bar).cloned()? {
+			Some(PciBar::Memory { address, size, .. }) => (address, size),
+			_ => return None,
+		};
+
+		if length == 0 || offset > size || length > size - offset {
+			return None;
+		}
+
+		Some(MmioRegion {
+			address: map_mmio(address + offset, length),
+			length: length,
+		})
+	}
+
+	/// Reads a 32-bit register from the configuration space of this adapter.
+	/// Registers of the extended configuration space (from 0x100) read as all ones without ECAM.
+	pub fn read_config(&self, register: u32) -> u32 {
+		read_config(self.bus, self.device, self.function, register)
+	}
+
+	/// Writes a 32-bit register to the configuration space of this adapter.
+	/// Writes to the extended configuration space (from 0x100) are ignored without ECAM.
+	pub fn write_config(&self, register: u32, data: u32) {
+		write_config(self.bus, self.device, self.function, register, data);
+	}
+}
+
+/// An entry of the capability list of an adapter.
+#[derive(Clone, Copy)]
+pub struct PciCapability {
+	pub id: u8,
+	/// Offset of the capability in the configuration space, where its header is followed by the capability-specific registers.
+	pub offset: u32,
+}
+
+pub struct PciCapabilities<'a> {
+	adapter: &'a PciAdapter,
+	next: u32,
+	/// Guards against a capability list pointing back to itself.
+	remaining: usize,
+}
+
+impl<'a> Iterator for PciCapabilities<'a> {
+	type Item = PciCapability;
+
+	fn next(&mut self) -> Option<PciCapability> {
+		if self.next == 0 || self.remaining == 0 {
+			return None;
 		}
 
+		self.remaining -= 1;
+
+		let header = self.adapter.read_config(self.next);
+		let capability = PciCapability {
+			id: header as u8,
+			offset: self.next,
+		};
+
+		self.next = (header >> 8) & 0xFC;
+		Some(capability)
+	}
+}
+
+
+/// The MSI-X table of an adapter mapped into memory.
+pub struct MsixTable {
+	region: MmioRegion,
+	size: u16,
+}
+
+impl MsixTable {
+	/// Returns the number of interrupts the adapter can signal.
+	pub fn size(&self) -> u16 {
+		self.size
+	}
+
+	fn entry_offset(&self, entry: u16, register: usize) -> usize {
+		assert!(entry < self.size, "MSI-X table entry {} is out of range", entry);
+		entry as usize * MSIX_TABLE_ENTRY_SIZE + register
+	}
+
+	/// Routes the interrupt of the given table entry to a vector on the core with the given Local APIC ID and unmasks it.
+	pub fn set_entry(&self, entry: u16, core_id: u32, vector: u8) -> Result<(), ()> {
+		let address = msi_address(core_id)?;
+		self.mask(entry);
+
+		self.region.write(self.entry_offset(entry, MSIX_ENTRY_ADDRESS_LOW), address);
+		self.region.write(self.entry_offset(entry, MSIX_ENTRY_ADDRESS_HIGH), 0u32);
+		self.region.write(self.entry_offset(entry, MSIX_ENTRY_DATA), vector as u32);
+
+		self.unmask(entry);
+		Ok(())
+	}
+
+	pub fn mask(&self, entry: u16) {
+		let offset = self.entry_offset(entry, MSIX_ENTRY_VECTOR_CONTROL);
+		let control: u32 = self.region.read(offset);
+		self.region.write(offset, control | MSIX_ENTRY_MASKED);
+	}
+
+	pub fn unmask(&self, entry: u16) {
+		let offset = self.entry_offset(entry, MSIX_ENTRY_VECTOR_CONTROL);
+		let control: u32 = self.region.read(offset);
+		self.region.write(offset, control & !MSIX_ENTRY_MASKED);
+	}
+}
+
+
+impl fmt::Display for PciAdapter {
+	#[cfg(feature = "pci-ids")]
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
 		// Output detailed readable information about this device.
-		write!(f, "{:02X}:{:02X} {} [{:02X}{:02X}]: {} {} [{:04X}:{:04X}]",
+		write!(f, "{:02X}:{:02X}.{} {} [{:02X}{:02X}]: {} {} [{:04X}:{:04X}]",
+			self.bus,
+			self.device,
+			self.function,
+			pci_ids::class_name(self.class_id, self.subclass_id).unwrap_or("Unknown Class"),
+			self.class_id,
+			self.subclass_id,
+			pci_ids::vendor_name(self.vendor_id).unwrap_or("Unknown Vendor"),
+			pci_ids::device_name(self.vendor_id, self.device_id).unwrap_or("Unknown Device"),
+			self.vendor_id,
+			self.device_id
+		)?;
+
+		self.fmt_irq(f)
+	}
+
+	#[cfg(not(feature = "pci-ids"))]
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		// Without the PCI Database, we can only output the IDs.
+		write!(f, "{:02X}:{:02X}.{} Class [{:02X}{:02X}]: [{:04X}:{:04X}]",
 			self.bus,
 			self.device,
-			class_name,
+			self.function,
 			self.class_id,
 			self.subclass_id,
-			vendor_name,
-			device_name,
 			self.vendor_id,
 			self.device_id
 		)?;
 
+		self.fmt_irq(f)
+	}
+}
+
+impl PciAdapter {
+	fn fmt_irq(&self, f: &mut fmt::Formatter) -> fmt::Result {
 		// If the devices uses an IRQ, output this one as well.
 		if self.irq != 0 && self.irq != u8::MAX {
 			write!(f, ", IRQ {}", self.irq)?;
@@ -166,22 +538,178 @@ impl fmt::Display for PciAdapter {
 }
 
 
-fn read_config(bus: u8, device: u8, register: u32) -> u32 {
-	let address = PCI_CONFIG_ADDRESS_ENABLE | (bus as u32) << 16 | (device as u32) << 11 | register;
+/// Lookups in the PCI Database generated from pci.ids by pci_ids_parser.
+/// It takes up about 1 MiB, so it can be left out of the kernel by disabling the "pci-ids" feature.
+#[cfg(feature = "pci-ids")]
+mod pci_ids {
+	include!(concat!(env!("CARGO_TARGET_DIR"), "/pcidata.rs"));
+
+	/// Returns the name of the subclass or, if it is not in the database, the name of the class.
+	pub fn class_name(class_id: u8, subclass_id: u8) -> Option<&'static str> {
+		let class = &CLASSES[CLASSES.binary_search_by_key(&class_id, |c| c.id).ok()?];
+		match class.subclasses.binary_search_by_key(&subclass_id, |sc| sc.id) {
+			Ok(index) => Some(class.subclasses[index].name),
+			Err(_) => Some(class.name),
+		}
+	}
+
+	fn vendor(vendor_id: u16) -> Option<&'static Vendor> {
+		VENDORS.binary_search_by_key(&vendor_id, |v| v.id).ok().map(|index| &VENDORS[index])
+	}
+
+	pub fn vendor_name(vendor_id: u16) -> Option<&'static str> {
+		vendor(vendor_id).map(|v| v.name)
+	}
+
+	pub fn device_name(vendor_id: u16, device_id: u16) -> Option<&'static str> {
+		let devices = vendor(vendor_id)?.devices;
+		devices.binary_search_by_key(&device_id, |d| d.id).ok().map(|index| devices[index].name)
+	}
+}
+
+
+/// Maps physical memory of a device uncached into kernel virtual memory and returns the virtual address of `physical_address`.
+fn map_mmio(physical_address: usize, length: usize) -> usize {
+	let start = align_down!(physical_address, BasePageSize::SIZE);
+	let size = align_up!(physical_address + length, BasePageSize::SIZE) - start;
+	let virtual_address = virtualmem::allocate(size);
+	paging::map::<BasePageSize>(
+		virtual_address,
+		start,
+		size / BasePageSize::SIZE,
+		PageTableEntryFlags::WRITABLE | PageTableEntryFlags::CACHE_DISABLE | PageTableEntryFlags::EXECUTE_DISABLE,
+		false
+	);
+
+	virtual_address + physical_address - start
+}
+
+/// Returns the size of a BAR from the value read back after writing all ones.
+fn bar_size(size_mask: u64) -> usize {
+	(!size_mask).wrapping_add(1) as usize
+}
+
+/// Decodes and sizes the Base Address Register at `register`.
+/// Returns the BAR (None if unimplemented or unassigned) and the number of registers it occupies.
+/// `has_upper` tells whether a following register can hold the upper half of a 64-bit BAR.
+fn decode_bar(bus: u8, device: u8, function: u8, register: u32, has_upper: bool) -> (Option<PciBar>, usize) {
+	let value = read_config(bus, device, function, register);
+	write_config(bus, device, function, register, u32::MAX);
+	let size_mask = read_config(bus, device, function, register);
+	write_config(bus, device, function, register, value);
+
+	if size_mask == 0 {
+		return (None, 1);
+	}
+
+	if value & PCI_BASE_ADDRESS_IO_SPACE > 0 {
+		// The upper 16 bits of I/O BARs may be hardwired to zero.
+		let mut size_mask = size_mask & PCI_BASE_ADDRESS_IO_MASK;
+		if size_mask & 0xFFFF_0000 == 0 {
+			size_mask |= 0xFFFF_0000;
+		}
+
+		let port = (value & PCI_BASE_ADDRESS_IO_MASK) as u16;
+		let bar = if port > 0 {
+			Some(PciBar::Io { port: port, size: bar_size(size_mask as u64 | 0xFFFF_FFFF_0000_0000) })
+		} else {
+			None
+		};
+
+		return (bar, 1);
+	}
+
+	let prefetchable = value & PCI_BASE_ADDRESS_PREFETCHABLE > 0;
+	let mut address = (value & PCI_BASE_ADDRESS_MEMORY_MASK) as u64;
+	let mut size_mask = (size_mask & PCI_BASE_ADDRESS_MEMORY_MASK) as u64 | 0xFFFF_FFFF_0000_0000;
+	let is_64bit = value & PCI_BASE_ADDRESS_MEMORY_TYPE_MASK == PCI_BASE_ADDRESS_MEMORY_TYPE_64;
+
+	if is_64bit {
+		if !has_upper {
+			return (None, 1);
+		}
+
+		let upper_register = register + 4;
+		let upper_value = read_config(bus, device, function, upper_register);
+		write_config(bus, device, function, upper_register, u32::MAX);
+		let upper_size_mask = read_config(bus, device, function, upper_register);
+		write_config(bus, device, function, upper_register, upper_value);
+
+		address |= (upper_value as u64) << 32;
+		size_mask = (size_mask & 0xFFFF_FFFF) | (upper_size_mask as u64) << 32;
+	}
+
+	let bar = if address > 0 {
+		Some(PciBar::Memory {
+			address: address as usize,
+			size: bar_size(size_mask),
+			is_64bit: is_64bit,
+			prefetchable: prefetchable,
+		})
+	} else {
+		None
+	};
+
+	(bar, if is_64bit { 2 } else { 1 })
+}
+
+/// Returns the message address delivering an MSI to the Local APIC with the given ID.
+fn msi_address(core_id: u32) -> Result<u32, ()> {
+	// Higher x2APIC IDs can only be reached through interrupt remapping.
+	if core_id > u8::MAX as u32 {
+		error!("Cannot route a Message Signaled Interrupt to Local APIC ID {}", core_id);
+		return Err(());
+	}
+
+	Ok(MSI_ADDRESS_BASE | core_id << MSI_ADDRESS_DESTINATION_SHIFT)
+}
+
+/// Returns the virtual address of a register, if the bus is covered by the memory-mapped configuration space.
+fn ecam_register_address(bus: u8, device: u8, function: u8, register: u32) -> Option<usize> {
+	let mut regions = ECAM_REGIONS.lock();
+	let region = regions.iter_mut().find(|region| region.contains(bus))?;
+	Some(region.register_address(bus, device, function, register))
+}
+
+fn port_address(bus: u8, device: u8, function: u8, register: u32) -> u32 {
+	PCI_CONFIG_ADDRESS_ENABLE | (bus as u32) << 16 | (device as u32) << 11 | (function as u32) << 8 | register
+}
+
+/// Reads a configuration register of a function.
+/// The I/O ports reach the configuration space of every function in the first PCI Segment Group,
+/// so ECAM is only used for the extended configuration space.
+fn read_config(bus: u8, device: u8, function: u8, register: u32) -> u32 {
+	if register >= PCI_CONFIG_SPACE_SIZE {
+		return match ecam_register_address(bus, device, function, register) {
+			Some(address) => unsafe { ptr::read_volatile(address as *const u32) },
+			None => u32::MAX,
+		};
+	}
+
 	unsafe {
-		outl(PCI_CONFIG_ADDRESS_PORT, address);
+		outl(PCI_CONFIG_ADDRESS_PORT, port_address(bus, device, function, register));
 		inl(PCI_CONFIG_DATA_PORT)
 	}
 }
 
-fn write_config(bus: u8, device: u8, register: u32, data: u32) {
-	let address = PCI_CONFIG_ADDRESS_ENABLE | (bus as u32) << 16 | (device as u32) << 11 | register;
+fn write_config(bus: u8, device: u8, function: u8, register: u32, data: u32) {
+	if register >= PCI_CONFIG_SPACE_SIZE {
+		if let Some(address) = ecam_register_address(bus, device, function, register) {
+			unsafe { ptr::write_volatile(address as *mut u32, data); }
+		}
+		return;
+	}
+
 	unsafe {
-		outl(PCI_CONFIG_ADDRESS_PORT, address);
+		outl(PCI_CONFIG_ADDRESS_PORT, port_address(bus, device, function, register));
 		outl(PCI_CONFIG_DATA_PORT, data);
 	}
 }
 
+fn header_type(bus: u8, device: u8, function: u8) -> u8 {
+	(read_config(bus, device, function, PCI_HEADER_REGISTER) >> 16) as u8
+}
+
 pub fn get_adapter(vendor_id: u16, device_id: u16) -> Option<PciAdapter> {
 	let adapters = PCI_ADAPTERS.lock();
 	for adapter in adapters.iter() {
@@ -193,24 +721,117 @@ pub fn get_adapter(vendor_id: u16, device_id: u16) -> Option<PciAdapter> {
 	None
 }
 
+/// Returns the adapter with an I/O Base Address Register covering the given port.
+pub fn get_adapter_by_io_port(port: u16) -> Option<PciAdapter> {
+	let adapters = PCI_ADAPTERS.lock();
+	for adapter in adapters.iter() {
+		for bar in adapter.base_addresses.iter() {
+			if let Some(PciBar::Io { port: start, size }) = *bar {
+				if port as usize >= start as usize && (port as usize) < start as usize + size {
+					return Some((*adapter).clone());
+				}
+			}
+		}
+	}
+
+	None
+}
+
+/// Sets up the memory-mapped configuration space of all buses listed in the ACPI MCFG table.
+fn init_ecam() {
+	let mcfg = match acpi::get_mcfg() {
+		Some(mcfg) => mcfg,
+		None => return,
+	};
+
+	let mut regions = ECAM_REGIONS.lock();
+	let mut current_address = mcfg.table_start_address() + mem::size_of::<AcpiMcfgHeader>();
+
+	while current_address + mem::size_of::<AcpiMcfgAllocation>() <= mcfg.table_end_address() {
+		let allocation = unsafe { & *(current_address as *const AcpiMcfgAllocation) };
+		current_address += mem::size_of::<AcpiMcfgAllocation>();
+
+		// We only support the first PCI Segment Group, which is the only one reachable through the I/O ports.
+		if allocation.segment_group != 0 || allocation.start_bus > allocation.end_bus {
+			continue;
+		}
+
+		debug!(
+			"Found PCI Express ECAM region for buses {:02X} to {:02X} at {:#X}",
+			allocation.start_bus,
+			allocation.end_bus,
+			{ allocation.base_address }
+		);
+		regions.push(EcamRegion::new(allocation));
+	}
+}
+
+fn scan_bus(bus: u8, adapters: &mut Vec<PciAdapter>, scanned_buses: &mut [bool; PCI_MAX_BUS_NUMBER]) {
+	if scanned_buses[bus as usize] {
+		return;
+	}
+
+	scanned_buses[bus as usize] = true;
+
+	for device in 0..PCI_MAX_DEVICE_NUMBER {
+		// Only multi-function devices implement the functions after function 0.
+		let function_count = if read_config(bus, device, 0, PCI_ID_REGISTER) == u32::MAX {
+			0
+		} else if header_type(bus, device, 0) & PCI_HEADER_TYPE_MULTIFUNCTION > 0 {
+			PCI_MAX_FUNCTION_NUMBER
+		} else {
+			1
+		};
+
+		for function in 0..function_count {
+			let device_vendor_id = read_config(bus, device, function, PCI_ID_REGISTER);
+			if device_vendor_id == u32::MAX {
+				continue;
+			}
+
+			let device_id = (device_vendor_id >> 16) as u16;
+			let vendor_id = device_vendor_id as u16;
+			let adapter = PciAdapter::new(bus, device, function, vendor_id, device_id);
+			adapters.push(adapter);
+
+			// Continue with the devices behind a PCI-to-PCI bridge.
+			if adapter.header_type == PCI_HEADER_TYPE_BRIDGE {
+				let secondary_bus = (read_config(bus, device, function, PCI_BRIDGE_BUS_REGISTER) >> 8) as u8;
+				if secondary_bus > bus {
+					scan_bus(secondary_bus, adapters, scanned_buses);
+				}
+			}
+		}
+	}
+}
+
 pub fn init() {
+	init_ecam();
+
 	debug!("Scanning PCI Busses 0 to {}", PCI_MAX_BUS_NUMBER-1);
 	let mut adapters = PCI_ADAPTERS.lock();
-
-	// HermitCore only uses PCI for network devices.
-	// Therefore, multifunction devices as well as additional bridges are not scanned.
-	// We also limit scanning to the first 8 buses.
-	for bus in 0..PCI_MAX_BUS_NUMBER {
-		for device in 0..PCI_MAX_DEVICE_NUMBER {
-			let device_vendor_id = read_config(bus, device, PCI_ID_REGISTER);
-			if device_vendor_id != u32::MAX {
-				let device_id = (device_vendor_id >> 16) as u16;
-				let vendor_id = device_vendor_id as u16;
-
-				adapters.push(PciAdapter::new(bus, device, vendor_id, device_id));
+	let mut scanned_buses = [false; PCI_MAX_BUS_NUMBER];
+
+	// Start at bus 0 and follow all bridges from there.
+	scan_bus(0, &mut adapters, &mut scanned_buses);
+
+	// Root buses of further host bridges are not announced on bus 0, so probe the remaining buses as well.
+	// If the MCFG lists the buses of the system, only those can hold devices.
+	let mut present_buses = [true; PCI_MAX_BUS_NUMBER];
+	{
+		let regions = ECAM_REGIONS.lock();
+		if !regions.is_empty() {
+			for bus in 0..PCI_MAX_BUS_NUMBER {
+				present_buses[bus] = regions.iter().any(|region| region.contains(bus as u8));
 			}
 		}
 	}
+
+	for bus in 1..PCI_MAX_BUS_NUMBER {
+		if present_buses[bus] {
+			scan_bus(bus as u8, &mut adapters, &mut scanned_buses);
+		}
+	}
 }
 
 pub fn print_information() {
@@ -28,6 +28,7 @@ use arch::x86_64::irq;
 use arch::x86_64::percore::*;
 use arch::x86_64::pic;
 use arch::x86_64::pit;
+use console;
 use core::{fmt, u32};
 use core::sync::atomic::spin_loop_hint;
 use environment;
@@ -48,6 +49,9 @@ const IA32_MISC_ENABLE_ENHANCED_SPEEDSTEP: u64 = 1 << 16;
 const IA32_MISC_ENABLE_SPEEDSTEP_LOCK: u64 = 1 << 20;
 const IA32_MISC_ENABLE_TURBO_DISABLE: u64 = 1 << 38;
 
+/// Number of attempts to get a value from RDSEED or RDRAND before giving up.
+const HARDWARE_RANDOM_RETRIES: usize = 10;
+
 
 static mut CPU_FREQUENCY: CpuFrequency = CpuFrequency::new();
 static mut CPU_SPEEDSTEP: CpuSpeedStep = CpuSpeedStep::new();
@@ -57,6 +61,7 @@ static mut MEASUREMENT_TIMER_TICKS: u64 = 0;
 static mut SUPPORTS_1GIB_PAGES: bool = false;
 static mut SUPPORTS_AVX: bool = false;
 static mut SUPPORTS_RDRAND: bool = false;
+static mut SUPPORTS_RDSEED: bool = false;
 static mut SUPPORTS_X2APIC: bool = false;
 static mut SUPPORTS_XSAVE: bool = false;
 static mut TIMESTAMP_FUNCTION: unsafe fn() -> u64 = get_timestamp_rdtsc;
@@ -399,6 +404,7 @@ impl fmt::Display for CpuFeaturePrinter {
 			if extended_feature_info.has_hle() { write!(f, "HLE ")?; }
 			if extended_feature_info.has_qm() { write!(f, "CQM ")?; }
 			if extended_feature_info.has_mpx() { write!(f, "MPX ")?; }
+			if extended_feature_info.has_rdseet() { write!(f, "RDSEED ")?; }
 		}
 
 		Ok(())
@@ -514,6 +520,7 @@ pub fn detect_features() {
 		SUPPORTS_1GIB_PAGES = extended_function_info.has_1gib_pages();
 		SUPPORTS_AVX = feature_info.has_avx();
 		SUPPORTS_RDRAND = feature_info.has_rdrand();
+		SUPPORTS_RDSEED = cpuid.get_extended_feature_info().map_or(false, |info| info.has_rdseet());
 		SUPPORTS_X2APIC = feature_info.has_x2apic();
 		SUPPORTS_XSAVE = feature_info.has_xsave();
 
@@ -622,14 +629,35 @@ pub fn print_information() {
 	infofooter!();
 }
 
-pub fn generate_random_number() -> Option<u32> {
-	if unsafe { SUPPORTS_RDRAND } {
-		let value: u32;
-		unsafe { asm!("rdrand $0" : "=r"(value) ::: "volatile"); }
-		Some(value)
-	} else {
-		None
+/// Returns 64 bits of hardware entropy for seeding a random number generator.
+/// RDSEED is preferred, because RDRAND only delivers the output of a generator reseeded from time to time.
+/// Both instructions may fail temporarily when the entropy source is exhausted, so we retry a few times.
+pub fn generate_random_seed() -> Option<u64> {
+	for _ in 0..HARDWARE_RANDOM_RETRIES {
+		if unsafe { SUPPORTS_RDSEED } {
+			let value: u64;
+			let success: u8;
+			unsafe { asm!("rdseed $0; setc $1" : "=r"(value), "=r"(success) :: "cc" : "volatile"); }
+			if success != 0 {
+				return Some(value);
+			}
+		}
+
+		if unsafe { SUPPORTS_RDRAND } {
+			let value: u64;
+			let success: u8;
+			unsafe { asm!("rdrand $0; setc $1" : "=r"(value), "=r"(success) :: "cc" : "volatile"); }
+			if success != 0 {
+				return Some(value);
+			}
+		} else if unsafe { !SUPPORTS_RDSEED } {
+			return None;
+		}
+
+		spin_loop_hint();
 	}
+
+	None
 }
 
 #[inline]
@@ -684,6 +712,7 @@ pub fn halt() {
 /// Shutdown the system
 pub fn shutdown() -> ! {
 	info!("Shutting down system");
+	console::flush();
 	acpi::poweroff();
 
 	loop {
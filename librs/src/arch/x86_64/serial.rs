@@ -26,7 +26,10 @@ use environment;
 use x86::shared::io::*;
 
 const UART_TX: u16 = 0;
+const UART_RX: u16 = 0;
+
 const UART_IER: u16 = 1;
+const UART_IER_RECEIVED_DATA_AVAILABLE: u8 = 0x01;
 
 const UART_DLL: u16 = 0;
 const UART_DLM: u16 = 1;
@@ -40,7 +43,13 @@ const UART_LCR: u16 = 3;
 const UART_LCR_WORD_LENGTH_8BITS:    u8 = 0x03;
 const UART_LCR_DIVISOR_LATCH_ACCESS: u8 = 0x80;
 
+const UART_MCR: u16 = 4;
+const UART_MCR_DTR:  u8 = 0x01;
+const UART_MCR_RTS:  u8 = 0x02;
+const UART_MCR_OUT2: u8 = 0x08;
+
 const UART_LSR: u16 = 5;
+const UART_LSR_DATA_READY:                        u8 = 0x01;
 const UART_LSR_EMPTY_TRANSMITTER_HOLDING_REGISTER: u8 = 0x20;
 
 
@@ -83,6 +92,23 @@ impl SerialPort {
 		self.write_to_register(UART_TX, byte);
 	}
 
+	/// Returns the next received byte or None if the receiver buffer is empty.
+	pub fn read_byte(&self) -> Option<u8> {
+		if self.read_from_register(UART_LSR) & UART_LSR_DATA_READY == 0 {
+			None
+		} else {
+			Some(self.read_from_register(UART_RX))
+		}
+	}
+
+	/// Raise an interrupt whenever a byte has been received.
+	pub fn enable_receive_interrupt(&self) {
+		// OUT2 connects the interrupt output of the UART to the interrupt line.
+		let mcr = self.read_from_register(UART_MCR);
+		self.write_to_register(UART_MCR, mcr | UART_MCR_OUT2);
+		self.write_to_register(UART_IER, UART_IER_RECEIVED_DATA_AVAILABLE);
+	}
+
 	pub fn init(&self, baudrate: u32) {
 		// The virtual serial port is always initialized in uhyve.
 		if environment::is_uhyve() {
@@ -105,5 +131,8 @@ impl SerialPort {
 
 		// Enable and clear FIFOs.
 		self.write_to_register(UART_FCR, UART_FCR_ENABLE_FIFO | UART_FCR_CLEAR_RECEIVER_FIFO | UART_FCR_CLEAR_TRANSMITTER_FIFO);
+
+		// Signal that we are ready and let the UART drive its interrupt line.
+		self.write_to_register(UART_MCR, UART_MCR_DTR | UART_MCR_RTS | UART_MCR_OUT2);
 	}
 }
@@ -24,8 +24,21 @@
 
 use arch;
 use core::fmt;
+use core::fmt::Write;
+use core::str;
+use drivers::virtio;
+use synch::semaphore::Semaphore;
 use synch::spinlock::SpinlockIrqSave;
 
+/// Maximum length of a line that is being edited, including the terminating newline.
+const INPUT_LINE_SIZE: usize = 256;
+/// Size of the ring buffer holding completed lines, which have not been read yet.
+const INPUT_BUFFER_SIZE: usize = 1024;
+
+const ASCII_BACKSPACE: u8 = 0x08;
+const ASCII_DELETE: u8 = 0x7F;
+const ASCII_END_OF_TRANSMISSION: u8 = 0x04;
+
 pub struct Console;
 
 /// A collection of methods that are required to format
@@ -33,17 +46,204 @@ pub struct Console;
 impl fmt::Write for Console {
 	/// Print a single character.
 	fn write_char(&mut self, c: char) -> fmt::Result {
-		arch::output_message_byte(c as u8);
+		output_bytes(&[c as u8]);
 		Ok(())
 	}
 
 	/// Print a string of characters.
 	fn write_str(&mut self, s: &str) -> fmt::Result {
-		for character in s.chars() {
-			self.write_char(character).unwrap();
-		}
+		output_bytes(s.as_bytes());
 		Ok(())
 	}
 }
 
 pub static CONSOLE: SpinlockIrqSave<Console> = SpinlockIrqSave::new(Console);
+
+/// Outputs bytes on the virtio console if there is one, or on the output of the architecture otherwise.
+fn output_bytes(bytes: &[u8]) {
+	if !virtio::console::write(bytes) {
+		for &byte in bytes {
+			arch::output_message_byte(byte);
+		}
+	}
+}
+
+/// Outputs bytes written by the application.
+/// Unlike kernel messages, which are written with CONSOLE locked and interrupts disabled,
+/// this blocks the calling task while the virtio console is busy.
+pub fn write_application_output(bytes: &[u8]) {
+	if !virtio::console::write(bytes) {
+		CONSOLE.lock().write_str(unsafe { str::from_utf8_unchecked(bytes) }).unwrap();
+	}
+}
+
+/// Waits until all buffered output has been transmitted.
+pub fn flush() {
+	virtio::console::flush();
+}
+
+/// Makes the console output never wait for a lock held by the panicking code.
+pub fn enter_panic() {
+	virtio::console::enter_panic();
+}
+
+
+/// Input received by the console, processed by a canonical line discipline.
+/// Bytes are collected into a line, which can be edited with backspace.
+/// Only when the line is completed by a newline (or Ctrl-D), it is handed over to readers.
+struct ConsoleInput {
+	/// The line currently being edited.
+	line: [u8; INPUT_LINE_SIZE],
+	line_length: usize,
+	/// Ring buffer of completed lines.
+	buffer: [u8; INPUT_BUFFER_SIZE],
+	head: usize,
+	count: usize,
+	/// Ctrl-D has been typed on an empty line, so the next read returns end-of-file.
+	end_of_file: bool,
+}
+
+impl ConsoleInput {
+	const fn new() -> Self {
+		Self {
+			line: [0; INPUT_LINE_SIZE],
+			line_length: 0,
+			buffer: [0; INPUT_BUFFER_SIZE],
+			head: 0,
+			count: 0,
+			end_of_file: false,
+		}
+	}
+
+	/// Processes a received byte and returns true if a reader needs to be woken up.
+	fn receive_byte(&mut self, byte: u8) -> bool {
+		// Serial terminals send CR when pressing Enter.
+		let byte = if byte == b'\r' { b'\n' } else { byte };
+
+		match byte {
+			b'\n' => {
+				self.line[self.line_length] = byte;
+				self.line_length += 1;
+				echo(byte);
+				self.complete_line();
+				true
+			},
+			ASCII_END_OF_TRANSMISSION => {
+				if self.line_length == 0 {
+					self.end_of_file = true;
+				} else {
+					self.complete_line();
+				}
+				true
+			},
+			ASCII_BACKSPACE | ASCII_DELETE => {
+				if self.line_length > 0 {
+					self.line_length -= 1;
+
+					// Overwrite the erased character on the terminal.
+					echo(ASCII_BACKSPACE);
+					echo(b' ');
+					echo(ASCII_BACKSPACE);
+				}
+				false
+			},
+			_ => {
+				// Always leave room for the terminating newline.
+				if self.line_length < INPUT_LINE_SIZE - 1 {
+					self.line[self.line_length] = byte;
+					self.line_length += 1;
+					echo(byte);
+				}
+				false
+			}
+		}
+	}
+
+	/// Moves the edited line into the buffer of completed lines.
+	/// If the buffer is full, the remainder of the line is discarded.
+	fn complete_line(&mut self) {
+		for i in 0..self.line_length {
+			if self.count == INPUT_BUFFER_SIZE {
+				warn!("Console input buffer is full, discarding input");
+				break;
+			}
+
+			let tail = (self.head + self.count) % INPUT_BUFFER_SIZE;
+			self.buffer[tail] = self.line[i];
+			self.count += 1;
+		}
+
+		self.line_length = 0;
+	}
+
+	/// Copies completed input into `buf`, but not beyond the end of a line.
+	fn read(&mut self, buf: &mut [u8]) -> usize {
+		let mut length = 0;
+
+		while length < buf.len() && self.count > 0 {
+			let byte = self.buffer[self.head];
+			self.head = (self.head + 1) % INPUT_BUFFER_SIZE;
+			self.count -= 1;
+
+			buf[length] = byte;
+			length += 1;
+
+			if byte == b'\n' {
+				break;
+			}
+		}
+
+		length
+	}
+}
+
+/// Wakes up tasks waiting for console input.
+struct InputWakeup(Semaphore);
+
+// The semaphore only accesses its queue of waiting tasks under its lock.
+unsafe impl Send for InputWakeup {}
+unsafe impl Sync for InputWakeup {}
+
+static CONSOLE_INPUT: SpinlockIrqSave<ConsoleInput> = SpinlockIrqSave::new(ConsoleInput::new());
+
+lazy_static! {
+	static ref INPUT_WAKEUP: InputWakeup = InputWakeup(Semaphore::new(0));
+}
+
+fn echo(byte: u8) {
+	CONSOLE.lock().write_char(byte as char).unwrap();
+}
+
+/// Hands over a byte received by the console device, usually called from its interrupt handler.
+pub fn receive_input_byte(byte: u8) {
+	let wakeup = CONSOLE_INPUT.lock().receive_byte(byte);
+
+	if wakeup {
+		INPUT_WAKEUP.0.release();
+	}
+}
+
+/// Reads at most one line of console input into `buf`, blocking until a line has been completed.
+/// Returns the number of bytes read, or 0 at end-of-file.
+pub fn read_input(buf: &mut [u8]) -> usize {
+	if buf.is_empty() {
+		return 0;
+	}
+
+	loop {
+		{
+			let mut input = CONSOLE_INPUT.lock();
+
+			if input.count > 0 {
+				return input.read(buf);
+			} else if input.end_of_file {
+				input.end_of_file = false;
+				return 0;
+			}
+		}
+
+		// Every completed line releases the semaphore, so we cannot miss one that arrives
+		// between checking the buffer and waiting here.
+		INPUT_WAKEUP.0.acquire(None);
+	}
+}
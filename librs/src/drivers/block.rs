@@ -0,0 +1,91 @@
+// Copyright (c) 2018 Stefan Lankes, RWTH Aachen University
+//                    Colin Finck, RWTH Aachen University
+//
+// MIT License
+//
+// Permission is hereby granted, free of charge, to any person obtaining
+// a copy of this software and associated documentation files (the
+// "Software"), to deal in the Software without restriction, including
+// without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to
+// permit persons to whom the Software is furnished to do so, subject to
+// the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE
+// LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION
+// OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
+// WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! Block devices, which are accessed in units of sectors.
+
+use alloc::arc::Arc;
+use alloc::btree_map::BTreeMap;
+use alloc::string::String;
+use errno::*;
+use synch::spinlock::Spinlock;
+
+
+/// Size of a sector in bytes, in which all block devices are addressed.
+pub const SECTOR_SIZE: usize = 512;
+
+
+pub trait BlockDeviceInterface : Send + Sync {
+	/// Returns the size of the device in sectors.
+	fn capacity(&self) -> u64;
+
+	fn is_read_only(&self) -> bool {
+		false
+	}
+
+	/// Reads the sectors starting at `sector` into `buf`, whose length must be a multiple of SECTOR_SIZE.
+	fn read(&self, sector: u64, buf: &mut [u8]) -> Result<(), i32>;
+
+	/// Writes `buf`, whose length must be a multiple of SECTOR_SIZE, to the sectors starting at `sector`.
+	fn write(&self, sector: u64, buf: &[u8]) -> Result<(), i32>;
+
+	/// Ensures that all completed writes have reached persistent storage.
+	fn flush(&self) -> Result<(), i32> {
+		Ok(())
+	}
+}
+
+
+lazy_static! {
+	/// Registered block devices by their name (e.g. "vda").
+	static ref BLOCK_DEVICES: Spinlock<BTreeMap<String, Arc<BlockDeviceInterface>>> = Spinlock::new(BTreeMap::new());
+}
+
+
+/// Checks that a request of `len` bytes starting at `sector` covers whole sectors within the device.
+pub fn check_request(device: &BlockDeviceInterface, sector: u64, len: usize) -> Result<(), i32> {
+	if len % SECTOR_SIZE != 0 {
+		return Err(-EINVAL);
+	}
+
+	let count = (len / SECTOR_SIZE) as u64;
+	match sector.checked_add(count) {
+		Some(end) if end <= device.capacity() => Ok(()),
+		_ => Err(-EINVAL),
+	}
+}
+
+pub fn register(name: &str, device: Arc<BlockDeviceInterface>) {
+	info!("Block device {}: {} sectors ({} MiB){}",
+		name,
+		device.capacity(),
+		device.capacity() * SECTOR_SIZE as u64 / (1024 * 1024),
+		if device.is_read_only() { ", read-only" } else { "" }
+	);
+
+	BLOCK_DEVICES.lock().insert(String::from(name), device);
+}
+
+pub fn get(name: &str) -> Option<Arc<BlockDeviceInterface>> {
+	BLOCK_DEVICES.lock().get(name).cloned()
+}
@@ -0,0 +1,351 @@
+// Copyright (c) 2018 Stefan Lankes, RWTH Aachen University
+//                    Colin Finck, RWTH Aachen University
+//
+// MIT License
+//
+// Permission is hereby granted, free of charge, to any person obtaining
+// a copy of this software and associated documentation files (the
+// "Software"), to deal in the Software without restriction, including
+// without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to
+// permit persons to whom the Software is furnished to do so, subject to
+// the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE
+// LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION
+// OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
+// WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! Driver for virtio block devices.
+//! Tasks submit requests into the virtqueue and sleep until the interrupt handler reports their completion.
+
+use alloc::arc::Arc;
+use alloc::vec::Vec;
+use arch::mm::paging::{self, BasePageSize, PageSize, PageTableEntryFlags};
+use core::{cmp, ptr};
+use drivers::block::{self, BlockDeviceInterface, SECTOR_SIZE};
+use drivers::virtio::*;
+use drivers::virtio::pci::VirtioPciTransport;
+use drivers::virtio::virtqueue::{Segment, Virtqueue};
+use errno::*;
+use mm;
+use synch::semaphore::Semaphore;
+use synch::spinlock::SpinlockIrqSave;
+
+
+const VIRTIO_BLK_F_RO:    u64 = 1 << 5;
+const VIRTIO_BLK_F_FLUSH: u64 = 1 << 9;
+
+/// Capacity of the device in 512-byte sectors, as a little-endian 64-bit value.
+const VIRTIO_BLK_CONFIG_CAPACITY: u16 = 0;
+
+const VIRTIO_BLK_T_IN:    u32 = 0;
+const VIRTIO_BLK_T_OUT:   u32 = 1;
+const VIRTIO_BLK_T_FLUSH: u32 = 4;
+
+const VIRTIO_BLK_S_OK:     u8 = 0;
+const VIRTIO_BLK_S_UNSUPP: u8 = 2;
+
+const REQUEST_QUEUE: u16 = 0;
+
+/// Maximum number of requests in flight.
+const MAX_REQUESTS: usize = 16;
+/// Every request is a chain of up to three descriptors: header, data and status.
+const DESCRIPTORS_PER_REQUEST: u16 = 3;
+/// Size of the DMA memory holding the header and status of a request.
+const REQUEST_HEADER_SIZE: usize = 32;
+/// Offset of the status byte written by the device within this memory.
+const REQUEST_STATUS_OFFSET: usize = 16;
+/// Size of the data buffer of each request. Larger transfers are split into several requests.
+const REQUEST_BUFFER_SIZE: usize = 32 * 1024;
+
+
+#[repr(C)]
+struct RequestHeader {
+	request_type: u32,
+	reserved: u32,
+	sector: u64,
+}
+
+/// Data transferred by a request.
+enum Transfer<'a> {
+	None,
+	In(&'a mut [u8]),
+	Out(&'a [u8]),
+}
+
+/// DMA memory of one request in flight.
+struct RequestSlot {
+	header: usize,
+	physical_header: usize,
+	buffer: usize,
+	physical_buffer: usize,
+}
+
+struct RequestQueue {
+	transport: VirtioPciTransport,
+	queue: Virtqueue,
+	slots: Vec<RequestSlot>,
+	free_slots: Vec<usize>,
+}
+
+/// A semaphore shared between tasks and the interrupt handler.
+struct SharedSemaphore(Semaphore);
+
+// All state of the semaphore, including its queue of waiting tasks, is protected by its own lock.
+unsafe impl Send for SharedSemaphore {}
+unsafe impl Sync for SharedSemaphore {}
+
+pub struct VirtioBlock {
+	requests: SpinlockIrqSave<RequestQueue>,
+	/// Counts the request slots that are not in flight.
+	available_slots: SharedSemaphore,
+	/// Released by the interrupt handler when the request of the respective slot has completed.
+	completions: Vec<SharedSemaphore>,
+	capacity: u64,
+	read_only: bool,
+	supports_flush: bool,
+}
+
+impl VirtioBlock {
+	/// Submits a request and blocks the current task until the device has completed it.
+	fn request(&self, request_type: u32, sector: u64, transfer: Transfer) -> Result<(), i32> {
+		self.available_slots.0.acquire(None);
+
+		let slot = {
+			let mut requests = self.requests.lock();
+			let slot = requests.free_slots.pop().expect("No free request slot");
+			let header = requests.slots[slot].header;
+			let physical_header = requests.slots[slot].physical_header;
+			let buffer = requests.slots[slot].buffer;
+			let physical_buffer = requests.slots[slot].physical_buffer;
+
+			unsafe {
+				ptr::write_volatile(header as *mut RequestHeader, RequestHeader {
+					request_type: request_type,
+					reserved: 0,
+					sector: sector,
+				});
+				ptr::write_volatile((header + REQUEST_STATUS_OFFSET) as *mut u8, u8::max_value());
+			}
+
+			let header_segment = Segment {
+				physical_address: physical_header,
+				length: REQUEST_STATUS_OFFSET,
+				writable: false,
+			};
+			let status_segment = Segment {
+				physical_address: physical_header + REQUEST_STATUS_OFFSET,
+				length: 1,
+				writable: true,
+			};
+
+			// There are enough descriptors for every slot, so adding never fails.
+			let added = match transfer {
+				Transfer::None => requests.queue.add(&[header_segment, status_segment], slot),
+				Transfer::In(ref data) => {
+					let data_segment = Segment { physical_address: physical_buffer, length: data.len(), writable: true };
+					requests.queue.add(&[header_segment, data_segment, status_segment], slot)
+				},
+				Transfer::Out(data) => {
+					unsafe { ptr::copy_nonoverlapping(data.as_ptr(), buffer as *mut u8, data.len()); }
+					let data_segment = Segment { physical_address: physical_buffer, length: data.len(), writable: false };
+					requests.queue.add(&[header_segment, data_segment, status_segment], slot)
+				},
+			};
+			assert!(added);
+
+			requests.queue.notify();
+			slot
+		};
+
+		self.completions[slot].0.acquire(None);
+
+		let status = {
+			let mut requests = self.requests.lock();
+			let header = requests.slots[slot].header;
+			let buffer = requests.slots[slot].buffer;
+			let status = unsafe { ptr::read_volatile((header + REQUEST_STATUS_OFFSET) as *const u8) };
+
+			if let Transfer::In(data) = transfer {
+				unsafe { ptr::copy_nonoverlapping(buffer as *const u8, data.as_mut_ptr(), data.len()); }
+			}
+
+			requests.free_slots.push(slot);
+			status
+		};
+
+		self.available_slots.0.release();
+
+		match status {
+			VIRTIO_BLK_S_OK => Ok(()),
+			VIRTIO_BLK_S_UNSUPP => Err(-EOPNOTSUPP),
+			_ => Err(-EIO),
+		}
+	}
+
+	fn handle_interrupt(&self) {
+		let mut requests = self.requests.lock();
+		if requests.transport.read_isr() & VIRTIO_ISR_QUEUE == 0 {
+			return;
+		}
+
+		while let Some((slot, _)) = requests.queue.pop_used() {
+			self.completions[slot].0.release();
+		}
+	}
+}
+
+impl BlockDeviceInterface for VirtioBlock {
+	fn capacity(&self) -> u64 {
+		self.capacity
+	}
+
+	fn is_read_only(&self) -> bool {
+		self.read_only
+	}
+
+	fn read(&self, sector: u64, buf: &mut [u8]) -> Result<(), i32> {
+		block::check_request(self, sector, buf.len())?;
+
+		let mut sector = sector;
+		for chunk in buf.chunks_mut(REQUEST_BUFFER_SIZE) {
+			let count = (chunk.len() / SECTOR_SIZE) as u64;
+			self.request(VIRTIO_BLK_T_IN, sector, Transfer::In(chunk))?;
+			sector += count;
+		}
+
+		Ok(())
+	}
+
+	fn write(&self, sector: u64, buf: &[u8]) -> Result<(), i32> {
+		if self.read_only {
+			return Err(-EROFS);
+		}
+
+		block::check_request(self, sector, buf.len())?;
+
+		let mut sector = sector;
+		for chunk in buf.chunks(REQUEST_BUFFER_SIZE) {
+			self.request(VIRTIO_BLK_T_OUT, sector, Transfer::Out(chunk))?;
+			sector += (chunk.len() / SECTOR_SIZE) as u64;
+		}
+
+		Ok(())
+	}
+
+	fn flush(&self) -> Result<(), i32> {
+		// Without the flush feature, the device does not cache writes.
+		if !self.supports_flush {
+			return Ok(());
+		}
+
+		self.request(VIRTIO_BLK_T_FLUSH, 0, Transfer::None)
+	}
+}
+
+
+lazy_static! {
+	static ref VIRTIO_BLOCK: SpinlockIrqSave<Option<Arc<VirtioBlock>>> = SpinlockIrqSave::new(None);
+}
+
+
+fn virtio_blk_interrupt() {
+	let device = match *VIRTIO_BLOCK.lock() {
+		Some(ref device) => device.clone(),
+		None => return,
+	};
+
+	device.handle_interrupt();
+}
+
+/// Looks for a virtio block device and registers it as "vda".
+pub fn init() {
+	let (adapter, mut transport) = match find_device(VIRTIO_ID_BLOCK) {
+		Some(device) => device,
+		None => return,
+	};
+
+	let features = match transport.negotiate_features(VIRTIO_BLK_F_RO | VIRTIO_BLK_F_FLUSH) {
+		Some(features) => features,
+		None => {
+			warn!("virtio block device does not accept our features");
+			return;
+		}
+	};
+
+	let interrupt = setup_interrupt(&adapter, &mut transport, virtio_blk_interrupt);
+
+	let queue = match Virtqueue::new(&transport, REQUEST_QUEUE) {
+		Some(queue) => queue,
+		None => {
+			warn!("virtio block device has no request queue");
+			transport.add_status(VIRTIO_STATUS_FAILED);
+			return;
+		}
+	};
+
+	// Without room for a single request, every task using the device would wait forever.
+	if queue.size() < DESCRIPTORS_PER_REQUEST {
+		warn!("virtio block device has a request queue of only {} descriptors", queue.size());
+		transport.add_status(VIRTIO_STATUS_FAILED);
+		return;
+	}
+
+	let mut capacity = 0;
+	for i in 0..8 {
+		capacity |= (transport.read_device_config(VIRTIO_BLK_CONFIG_CAPACITY + i) as u64) << (8 * i);
+	}
+
+	// Allocate physically contiguous memory for the requests.
+	let slot_count = cmp::min(MAX_REQUESTS, (queue.size() / DESCRIPTORS_PER_REQUEST) as usize);
+	let headers = mm::allocate(align_up!(slot_count * REQUEST_HEADER_SIZE, BasePageSize::SIZE), PageTableEntryFlags::EXECUTE_DISABLE);
+	let buffers = mm::allocate(slot_count * REQUEST_BUFFER_SIZE, PageTableEntryFlags::EXECUTE_DISABLE);
+	let physical_headers = paging::virtual_to_physical(headers);
+	let physical_buffers = paging::virtual_to_physical(buffers);
+
+	let mut slots = Vec::with_capacity(slot_count);
+	let mut completions = Vec::with_capacity(slot_count);
+	for i in 0..slot_count {
+		slots.push(RequestSlot {
+			header: headers + i * REQUEST_HEADER_SIZE,
+			physical_header: physical_headers + i * REQUEST_HEADER_SIZE,
+			buffer: buffers + i * REQUEST_BUFFER_SIZE,
+			physical_buffer: physical_buffers + i * REQUEST_BUFFER_SIZE,
+		});
+		completions.push(SharedSemaphore(Semaphore::new(0)));
+	}
+
+	info!(
+		"Found virtio block device at {:02X}:{:02X} ({} interface), {}",
+		adapter.bus,
+		adapter.device,
+		if transport.is_modern() { "modern" } else { "legacy" },
+		interrupt
+	);
+
+	let device = Arc::new(VirtioBlock {
+		requests: SpinlockIrqSave::new(RequestQueue {
+			transport: transport,
+			queue: queue,
+			slots: slots,
+			free_slots: (0..slot_count).collect(),
+		}),
+		available_slots: SharedSemaphore(Semaphore::new(slot_count as isize)),
+		completions: completions,
+		capacity: capacity,
+		read_only: features & VIRTIO_BLK_F_RO != 0,
+		supports_flush: features & VIRTIO_BLK_F_FLUSH != 0,
+	});
+
+	*VIRTIO_BLOCK.lock() = Some(device.clone());
+	device.requests.lock().transport.add_status(VIRTIO_STATUS_DRIVER_OK);
+
+	block::register("vda", device);
+}
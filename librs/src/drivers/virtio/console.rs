@@ -0,0 +1,265 @@
+// Copyright (c) 2018 Stefan Lankes, RWTH Aachen University
+//                    Colin Finck, RWTH Aachen University
+//
+// MIT License
+//
+// Permission is hereby granted, free of charge, to any person obtaining
+// a copy of this software and associated documentation files (the
+// "Software"), to deal in the Software without restriction, including
+// without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to
+// permit persons to whom the Software is furnished to do so, subject to
+// the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE
+// LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION
+// OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
+// WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! Driver for virtio console devices, which can take over the output of kernel messages.
+//! Output is collected in a ring buffer and transmitted in the background, with the device
+//! interrupting us when a transmission has completed.
+//! Writers finding the ring buffer full wait for that interrupt, unless they run with interrupts disabled.
+
+use arch::irq;
+use arch::mm::paging::{self, PageTableEntryFlags};
+use core::cmp;
+use core::sync::atomic::{spin_loop_hint, AtomicBool, Ordering};
+use drivers::virtio::*;
+use drivers::virtio::pci::VirtioPciTransport;
+use drivers::virtio::virtqueue::{Segment, Virtqueue};
+use mm;
+use synch::semaphore::Semaphore;
+use synch::spinlock::{SpinlockIrqSave, SpinlockIrqSaveGuard};
+
+
+/// The transmit queue of port 0.
+const TRANSMIT_QUEUE: u16 = 1;
+
+/// Size of the ring buffer collecting output, which is transmitted directly from there.
+const TRANSMIT_BUFFER_SIZE: usize = 16 * 1024;
+
+
+struct VirtioConsole {
+	transport: VirtioPciTransport,
+	tx: Virtqueue,
+	buffer: usize,
+	physical_buffer: usize,
+	/// Offset of the oldest byte in the ring buffer, which has not been transmitted yet.
+	head: usize,
+	/// Number of bytes in the ring buffer.
+	count: usize,
+	/// Number of bytes starting at head, which the device currently transmits.
+	in_flight: usize,
+	/// Number of tasks waiting on TRANSMIT_WAKEUP for room in the ring buffer.
+	waiting_writers: usize,
+}
+
+impl VirtioConsole {
+	/// Copies as many bytes into the ring buffer as fit and returns their number.
+	fn write(&mut self, bytes: &[u8]) -> usize {
+		let length = cmp::min(bytes.len(), TRANSMIT_BUFFER_SIZE - self.count);
+
+		for (i, &byte) in bytes[..length].iter().enumerate() {
+			let offset = (self.head + self.count + i) % TRANSMIT_BUFFER_SIZE;
+			unsafe { *((self.buffer + offset) as *mut u8) = byte; }
+		}
+
+		self.count += length;
+		if length > 0 && self.in_flight == 0 {
+			self.transmit();
+		}
+
+		length
+	}
+
+	/// Hands over the contiguous part of the output starting at head to the device.
+	fn transmit(&mut self) {
+		let length = cmp::min(self.count, TRANSMIT_BUFFER_SIZE - self.head);
+		let segment = Segment {
+			physical_address: self.physical_buffer + self.head,
+			length: length,
+			writable: false,
+		};
+
+		// Only one transmission is in flight at a time, so there is always a free descriptor.
+		self.tx.add(&[segment], 0);
+		self.tx.notify();
+		self.in_flight = length;
+	}
+
+	/// Processes completed transmissions and starts transmitting the output collected meanwhile.
+	fn complete(&mut self) {
+		while let Some(_) = self.tx.pop_used() {
+			self.head = (self.head + self.in_flight) % TRANSMIT_BUFFER_SIZE;
+			self.count -= self.in_flight;
+			self.in_flight = 0;
+		}
+
+		if self.in_flight == 0 && self.count > 0 {
+			self.transmit();
+		}
+	}
+
+	/// Busy-waits for progress of the device.
+	fn poll(&mut self) {
+		let in_flight = self.in_flight;
+		self.complete();
+
+		if self.in_flight == in_flight {
+			spin_loop_hint();
+		}
+	}
+}
+
+/// Wakes up tasks waiting for room in the ring buffer.
+struct TransmitWakeup(Semaphore);
+
+// The semaphore only accesses its queue of waiting tasks under its lock.
+unsafe impl Send for TransmitWakeup {}
+unsafe impl Sync for TransmitWakeup {}
+
+static VIRTIO_CONSOLE: SpinlockIrqSave<Option<VirtioConsole>> = SpinlockIrqSave::new(None);
+
+/// Set when the kernel panics, so that output never waits for a lock possibly held by the panicking code.
+static PANICKING: AtomicBool = AtomicBool::new(false);
+
+lazy_static! {
+	static ref TRANSMIT_WAKEUP: TransmitWakeup = TransmitWakeup(Semaphore::new(0));
+}
+
+
+/// Locks the virtio console, but gives up instead of waiting for the lock after a panic.
+fn lock_console() -> Option<SpinlockIrqSaveGuard<'static, Option<VirtioConsole>>> {
+	if PANICKING.load(Ordering::SeqCst) {
+		VIRTIO_CONSOLE.try_lock()
+	} else {
+		Some(VIRTIO_CONSOLE.lock())
+	}
+}
+
+fn virtio_console_interrupt() {
+	let mut waiting_writers = 0;
+
+	if let Some(ref mut console) = *VIRTIO_CONSOLE.lock() {
+		if console.transport.read_isr() & VIRTIO_ISR_QUEUE != 0 {
+			let count = console.count;
+			console.complete();
+
+			if console.count < count {
+				waiting_writers = console.waiting_writers;
+				console.waiting_writers = 0;
+			}
+		}
+	}
+
+	for _ in 0..waiting_writers {
+		TRANSMIT_WAKEUP.0.release();
+	}
+}
+
+/// Outputs bytes on the virtio console.
+/// Returns false if there is no virtio console (or it is locked after a panic), so that the caller can use another output.
+pub fn write(mut bytes: &[u8]) -> bool {
+	// Waiting for the device interrupt is only possible if the caller has not disabled interrupts.
+	let can_block = irq::is_enabled() && !PANICKING.load(Ordering::SeqCst);
+
+	loop {
+		{
+			let mut guard = match lock_console() {
+				Some(guard) => guard,
+				None => return false,
+			};
+			let console = match *guard {
+				Some(ref mut console) => console,
+				None => return false,
+			};
+
+			let written = console.write(bytes);
+			bytes = &bytes[written..];
+			if bytes.is_empty() {
+				return true;
+			}
+
+			// The ring buffer is full, so we have to wait for the device.
+			if !can_block {
+				console.poll();
+				continue;
+			}
+
+			console.waiting_writers += 1;
+		}
+
+		// The interrupt handler releases the semaphore for every writer counted above,
+		// so we cannot miss room that becomes available before we wait here.
+		TRANSMIT_WAKEUP.0.acquire(None);
+	}
+}
+
+/// Waits until all output has been transmitted.
+pub fn flush() {
+	if let Some(mut guard) = lock_console() {
+		if let Some(ref mut console) = *guard {
+			while console.count > 0 {
+				console.poll();
+			}
+		}
+	}
+}
+
+/// Makes all further output give up instead of waiting for a lock, called when the kernel panics.
+pub fn enter_panic() {
+	PANICKING.store(true, Ordering::SeqCst);
+}
+
+/// Looks for a virtio console device and redirects all further kernel messages to it.
+pub fn init() {
+	let (adapter, mut transport) = match find_device(VIRTIO_ID_CONSOLE) {
+		Some(device) => device,
+		None => return,
+	};
+
+	if transport.negotiate_features(0).is_none() {
+		warn!("virtio console device does not accept our features");
+		return;
+	}
+
+	let interrupt = setup_interrupt(&adapter, &mut transport, virtio_console_interrupt);
+
+	let tx = match Virtqueue::new(&transport, TRANSMIT_QUEUE) {
+		Some(queue) => queue,
+		None => {
+			warn!("virtio console device has no transmit queue");
+			transport.add_status(VIRTIO_STATUS_FAILED);
+			return;
+		}
+	};
+
+	let buffer = mm::allocate(TRANSMIT_BUFFER_SIZE, PageTableEntryFlags::EXECUTE_DISABLE);
+
+	info!(
+		"Found virtio console device at {:02X}:{:02X} ({} interface), {}, continuing output there",
+		adapter.bus,
+		adapter.device,
+		if transport.is_modern() { "modern" } else { "legacy" },
+		interrupt
+	);
+
+	transport.add_status(VIRTIO_STATUS_DRIVER_OK);
+	*VIRTIO_CONSOLE.lock() = Some(VirtioConsole {
+		transport: transport,
+		tx: tx,
+		buffer: buffer,
+		physical_buffer: paging::virtual_to_physical(buffer),
+		head: 0,
+		count: 0,
+		in_flight: 0,
+		waiting_writers: 0,
+	});
+}
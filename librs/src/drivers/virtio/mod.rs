@@ -0,0 +1,170 @@
+// Copyright (c) 2018 Stefan Lankes, RWTH Aachen University
+//                    Colin Finck, RWTH Aachen University
+//
+// MIT License
+//
+// Permission is hereby granted, free of charge, to any person obtaining
+// a copy of this software and associated documentation files (the
+// "Software"), to deal in the Software without restriction, including
+// without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to
+// permit persons to whom the Software is furnished to do so, subject to
+// the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE
+// LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION
+// OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
+// WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! Drivers for paravirtualized virtio devices, as specified by
+//! https://docs.oasis-open.org/virtio/virtio/v1.0/virtio-v1.0.html
+
+pub mod blk;
+pub mod console;
+pub mod net;
+pub mod pci;
+pub mod rng;
+pub mod virtqueue;
+
+use alloc::vec::Vec;
+use arch::apic;
+use arch::irq::{self, irq_install_handler, ExceptionStackFrame};
+use arch::pci::{self, PciAdapter};
+use arch::percore;
+use core::fmt;
+use drivers::virtio::pci::VirtioPciTransport;
+use synch::spinlock::SpinlockIrqSave;
+
+/// PCI vendor ID of all virtio devices.
+pub const VIRTIO_VENDOR_ID: u16 = 0x1AF4;
+
+/// Modern devices have the PCI device ID 0x1040 plus their virtio device type.
+const VIRTIO_PCI_MODERN_DEVICE_ID_BASE: u16 = 0x1040;
+
+// Virtio device types.
+pub const VIRTIO_ID_NET:     u16 = 1;
+pub const VIRTIO_ID_BLOCK:   u16 = 2;
+pub const VIRTIO_ID_CONSOLE: u16 = 3;
+pub const VIRTIO_ID_RNG:     u16 = 4;
+
+// Bits of the device status register.
+pub const VIRTIO_STATUS_ACKNOWLEDGE: u8 = 1;
+pub const VIRTIO_STATUS_DRIVER:      u8 = 2;
+pub const VIRTIO_STATUS_DRIVER_OK:   u8 = 4;
+pub const VIRTIO_STATUS_FEATURES_OK: u8 = 8;
+pub const VIRTIO_STATUS_FAILED:      u8 = 0x80;
+
+/// Feature bit of devices complying to virtio 1.0 (and not the legacy interface).
+pub const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+
+/// Bit of the ISR status register signalling that a virtqueue has been used.
+pub const VIRTIO_ISR_QUEUE: u8 = 1;
+
+
+lazy_static! {
+	/// Interrupt handlers of all virtio devices.
+	/// Devices may share an IRQ, so every handler has to check the ISR status of its device.
+	static ref INTERRUPT_HANDLERS: SpinlockIrqSave<Vec<fn()>> = SpinlockIrqSave::new(Vec::new());
+}
+
+
+extern "x86-interrupt" fn virtio_interrupt(_stack_frame: &mut ExceptionStackFrame) {
+	for handler in INTERRUPT_HANDLERS.lock().iter() {
+		handler();
+	}
+
+	apic::eoi();
+}
+
+/// Calls `handler` whenever the given IRQ is raised.
+fn register_interrupt_handler(irq: u8, handler: fn()) {
+	INTERRUPT_HANDLERS.lock().push(handler);
+	irq_install_handler(irq as u32, virtio_interrupt as usize);
+}
+
+/// How a device signals the completions of its virtqueues.
+#[derive(Clone, Copy)]
+pub enum VirtioInterrupt {
+	/// A dedicated vector of this core reached through the first MSI-X table entry.
+	Msix(u8),
+	/// The legacy interrupt line, which may be shared with other devices.
+	Line(u8),
+}
+
+impl fmt::Display for VirtioInterrupt {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			VirtioInterrupt::Msix(vector) => write!(f, "MSI-X vector {}", vector),
+			VirtioInterrupt::Line(irq) => write!(f, "IRQ {}", irq),
+		}
+	}
+}
+
+/// Lets `handler` process the completions of the virtqueues of a device.
+/// MSI-X is preferred, falling back to the legacy interrupt line if the device or the system does not support it.
+/// Must be called before setting up the virtqueues, which are assigned to the MSI-X table entry at that time.
+pub fn setup_interrupt(adapter: &PciAdapter, transport: &mut VirtioPciTransport, handler: fn()) -> VirtioInterrupt {
+	if let Some(vector) = irq::allocate_msi_vector(handler) {
+		if let Some(table) = adapter.enable_msix() {
+			if table.set_entry(0, percore::core_id(), vector).is_ok() {
+				transport.set_msix_entry(0);
+				return VirtioInterrupt::Msix(vector);
+			}
+
+			adapter.disable_msix();
+		}
+
+		irq::free_msi_vector(vector);
+	}
+
+	register_interrupt_handler(adapter.irq, handler);
+	VirtioInterrupt::Line(adapter.irq)
+}
+
+/// Returns the PCI device ID of a transitional device of the given type.
+fn transitional_device_id(device_type: u16) -> Option<u16> {
+	match device_type {
+		VIRTIO_ID_NET => Some(0x1000),
+		VIRTIO_ID_BLOCK => Some(0x1001),
+		VIRTIO_ID_CONSOLE => Some(0x1003),
+		VIRTIO_ID_RNG => Some(0x1005),
+		_ => None,
+	}
+}
+
+/// Looks for a device of the given virtio type on the PCI bus and sets up its transport.
+/// The device is reset and made bus master, so that its driver can negotiate features next.
+pub fn find_device(device_type: u16) -> Option<(PciAdapter, VirtioPciTransport)> {
+	let adapter = transitional_device_id(device_type)
+		.and_then(|device_id| pci::get_adapter(VIRTIO_VENDOR_ID, device_id))
+		.or_else(|| pci::get_adapter(VIRTIO_VENDOR_ID, VIRTIO_PCI_MODERN_DEVICE_ID_BASE + device_type))?;
+
+	let transport = match VirtioPciTransport::new(&adapter) {
+		Some(transport) => transport,
+		None => {
+			warn!("Found virtio device {:02X}:{:02X}, but neither its modern nor its legacy interface is usable", adapter.bus, adapter.device);
+			return None;
+		}
+	};
+
+	adapter.make_bus_master();
+	transport.reset();
+	transport.add_status(VIRTIO_STATUS_ACKNOWLEDGE);
+	transport.add_status(VIRTIO_STATUS_DRIVER);
+
+	Some((adapter, transport))
+}
+
+/// Initializes all supported virtio devices, which are not handled by a network stack.
+pub fn init() {
+	// Bring up the console first, so that it takes over the remaining boot messages.
+	console::init();
+	blk::init();
+	rng::init();
+}
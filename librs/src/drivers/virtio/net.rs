@@ -0,0 +1,287 @@
+// Copyright (c) 2018 Stefan Lankes, RWTH Aachen University
+//                    Colin Finck, RWTH Aachen University
+//
+// MIT License
+//
+// Permission is hereby granted, free of charge, to any person obtaining
+// a copy of this software and associated documentation files (the
+// "Software"), to deal in the Software without restriction, including
+// without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to
+// permit persons to whom the Software is furnished to do so, subject to
+// the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE
+// LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION
+// OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
+// WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! Driver for virtio network devices.
+//! The driver only moves Ethernet frames, while the lwIP netif in netif.c or the device in net/device.rs
+//! connects it to the network stack.
+
+use alloc::vec::Vec;
+use arch::mm::paging::{self, PageTableEntryFlags};
+use core::{cmp, ptr, slice};
+use drivers::virtio::*;
+use drivers::virtio::pci::VirtioPciTransport;
+use drivers::virtio::virtqueue::{Segment, Virtqueue};
+use errno::*;
+use fs::IoVec;
+use mm;
+#[cfg(feature = "smoltcp")]
+use net;
+use synch::spinlock::SpinlockIrqSave;
+
+
+/// The device reports its MAC address in the device-specific configuration.
+const VIRTIO_NET_F_MAC: u64 = 1 << 5;
+
+const VIRTIO_NET_CONFIG_MAC: u16 = 0;
+
+const RX_QUEUE: u16 = 0;
+const TX_QUEUE: u16 = 1;
+
+/// Every frame is preceded by a struct virtio_net_hdr, which has an additional field in virtio 1.0.
+const VIRTIO_NET_HEADER_LENGTH_LEGACY: usize = 10;
+const VIRTIO_NET_HEADER_LENGTH: usize = 12;
+
+/// Size of each buffer, enough for the header and a full Ethernet frame.
+const BUFFER_SIZE: usize = 2048;
+const ETHERNET_MTU: u16 = 1500;
+
+
+/// Physically contiguous buffers of BUFFER_SIZE bytes, one for each descriptor of a virtqueue.
+struct BufferPool {
+	buffers: usize,
+	physical_buffers: usize,
+}
+
+impl BufferPool {
+	fn new(count: usize) -> Self {
+		let buffers = mm::allocate(count * BUFFER_SIZE, PageTableEntryFlags::EXECUTE_DISABLE);
+
+		Self {
+			buffers: buffers,
+			physical_buffers: paging::virtual_to_physical(buffers),
+		}
+	}
+
+	fn buffer(&self, index: usize) -> *mut u8 {
+		(self.buffers + index * BUFFER_SIZE) as *mut u8
+	}
+
+	fn segment(&self, index: usize, length: usize, writable: bool) -> Segment {
+		Segment {
+			physical_address: self.physical_buffers + index * BUFFER_SIZE,
+			length: length,
+			writable: writable,
+		}
+	}
+}
+
+struct VirtioNet {
+	transport: VirtioPciTransport,
+	rx: Virtqueue,
+	rx_buffers: BufferPool,
+	tx: Virtqueue,
+	tx_buffers: BufferPool,
+	/// Transmit buffers, which are not owned by the device.
+	free_tx_buffers: Vec<usize>,
+	header_length: usize,
+}
+
+impl VirtioNet {
+	fn reclaim_tx_buffers(&mut self) {
+		while let Some((index, _)) = self.tx.pop_used() {
+			self.free_tx_buffers.push(index);
+		}
+	}
+}
+
+static VIRTIO_NET: SpinlockIrqSave<Option<VirtioNet>> = SpinlockIrqSave::new(None);
+
+#[cfg(not(feature = "smoltcp"))]
+extern "C" {
+	fn virtio_netif_notify();
+}
+
+/// Lets the network stack fetch the received frames.
+#[cfg(not(feature = "smoltcp"))]
+fn notify_network_stack() {
+	unsafe { virtio_netif_notify(); }
+}
+
+#[cfg(feature = "smoltcp")]
+fn notify_network_stack() {
+	net::notify();
+}
+
+
+fn virtio_net_interrupt() {
+	let isr = match *VIRTIO_NET.lock() {
+		Some(ref net) => net.transport.read_isr(),
+		None => 0,
+	};
+
+	// The interrupt line may be shared, so only react to our own interrupts.
+	if isr & VIRTIO_ISR_QUEUE != 0 {
+		notify_network_stack();
+	}
+}
+
+/// Looks for a virtio network device and brings it up.
+/// On success, the MAC address and MTU of the device are returned in `mac` and `mtu`.
+#[no_mangle]
+pub extern "C" fn virtio_net_init(mac: *mut u8, mtu: *mut u16) -> i32 {
+	let (adapter, mut transport) = match find_device(VIRTIO_ID_NET) {
+		Some(device) => device,
+		None => return -ENODEV,
+	};
+
+	let features = match transport.negotiate_features(VIRTIO_NET_F_MAC) {
+		Some(features) => features,
+		None => {
+			warn!("virtio network device does not accept our features");
+			return -EIO;
+		}
+	};
+
+	let interrupt = setup_interrupt(&adapter, &mut transport, virtio_net_interrupt);
+
+	let (mut rx, mut tx) = match (Virtqueue::new(&transport, RX_QUEUE), Virtqueue::new(&transport, TX_QUEUE)) {
+		(Some(rx), Some(tx)) => (rx, tx),
+		_ => {
+			warn!("virtio network device has no receive or transmit queue");
+			transport.add_status(VIRTIO_STATUS_FAILED);
+			return -EIO;
+		}
+	};
+
+	// All receive buffers belong to the device.
+	let rx_buffers = BufferPool::new(rx.size() as usize);
+	for index in 0..rx.size() as usize {
+		rx.add(&[rx_buffers.segment(index, BUFFER_SIZE, true)], index);
+	}
+
+	// Completed transmissions are reclaimed when sending the next frame.
+	let tx_buffers = BufferPool::new(tx.size() as usize);
+	tx.disable_interrupts();
+	let free_tx_buffers = (0..tx.size() as usize).rev().collect();
+
+	let mac = unsafe { slice::from_raw_parts_mut(mac, 6) };
+	if features & VIRTIO_NET_F_MAC != 0 {
+		for i in 0..mac.len() {
+			mac[i] = transport.read_device_config(VIRTIO_NET_CONFIG_MAC + i as u16);
+		}
+	} else {
+		// Make up a locally administered address.
+		mac.copy_from_slice(&[0x02, 0x00, 0x00, 0x00, 0x00, 0x01]);
+	}
+	unsafe { *mtu = ETHERNET_MTU; }
+
+	info!(
+		"Found virtio network device at {:02X}:{:02X} ({} interface), {}, MAC {:02X}:{:02X}:{:02X}:{:02X}:{:02X}:{:02X}",
+		adapter.bus,
+		adapter.device,
+		if transport.is_modern() { "modern" } else { "legacy" },
+		interrupt,
+		mac[0], mac[1], mac[2], mac[3], mac[4], mac[5]
+	);
+
+	*VIRTIO_NET.lock() = Some(VirtioNet {
+		transport: transport,
+		rx: rx,
+		rx_buffers: rx_buffers,
+		tx: tx,
+		tx_buffers: tx_buffers,
+		free_tx_buffers: free_tx_buffers,
+		header_length: if features & VIRTIO_F_VERSION_1 != 0 { VIRTIO_NET_HEADER_LENGTH } else { VIRTIO_NET_HEADER_LENGTH_LEGACY },
+	});
+
+	let guard = VIRTIO_NET.lock();
+	let net = guard.as_ref().unwrap();
+	net.transport.add_status(VIRTIO_STATUS_DRIVER_OK);
+	net.rx.notify();
+
+	0
+}
+
+/// Sends an Ethernet frame gathered from `iovcnt` buffers.
+/// Returns 0 on success or -ENOBUFS if all transmit buffers are still in use.
+#[no_mangle]
+pub extern "C" fn virtio_net_transmit(iov: *const IoVec, iovcnt: usize) -> i32 {
+	let iov = unsafe { slice::from_raw_parts(iov, iovcnt) };
+	let mut guard = VIRTIO_NET.lock();
+	let net = match *guard {
+		Some(ref mut net) => net,
+		None => return -ENODEV,
+	};
+
+	let frame_length = iov.iter().fold(0, |length, vec| length + vec.iov_len);
+	if net.header_length + frame_length > BUFFER_SIZE {
+		return -EMSGSIZE;
+	}
+
+	net.reclaim_tx_buffers();
+	let index = match net.free_tx_buffers.pop() {
+		Some(index) => index,
+		None => return -ENOBUFS,
+	};
+
+	// We use no offloading, so the header is all zeros.
+	let buffer = net.tx_buffers.buffer(index);
+	unsafe {
+		ptr::write_bytes(buffer, 0, net.header_length);
+
+		let mut offset = net.header_length;
+		for vec in iov {
+			ptr::copy_nonoverlapping(vec.iov_base, buffer.offset(offset as isize), vec.iov_len);
+			offset += vec.iov_len;
+		}
+	}
+
+	// Every buffer needs a single descriptor, so there is always one free.
+	let segment = net.tx_buffers.segment(index, net.header_length + frame_length, false);
+	net.tx.add(&[segment], index);
+	net.tx.notify();
+
+	0
+}
+
+/// Copies the next received Ethernet frame into `buf`.
+/// Returns the length of the frame or 0 if there is none. Frames exceeding `len` are truncated.
+#[no_mangle]
+pub extern "C" fn virtio_net_receive(buf: *mut u8, len: usize) -> isize {
+	let mut guard = VIRTIO_NET.lock();
+	let net = match *guard {
+		Some(ref mut net) => net,
+		None => return -ENODEV as isize,
+	};
+
+	let (index, used_length) = match net.rx.pop_used() {
+		Some(used) => used,
+		None => return 0,
+	};
+
+	let frame_length = cmp::min(used_length.saturating_sub(net.header_length), len);
+	unsafe {
+		ptr::copy_nonoverlapping(net.rx_buffers.buffer(index).offset(net.header_length as isize), buf, frame_length);
+	}
+
+	// Give the buffer back to the device.
+	// Notifying is expensive, so only do it once all received frames have been fetched.
+	let segment = net.rx_buffers.segment(index, BUFFER_SIZE, true);
+	net.rx.add(&[segment], index);
+	if !net.rx.has_used() {
+		net.rx.notify();
+	}
+
+	frame_length as isize
+}
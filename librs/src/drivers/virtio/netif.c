@@ -0,0 +1,195 @@
+/*
+ * Copyright (c) 2018, Stefan Lankes, RWTH Aachen University
+ * All rights reserved.
+ *
+ * Redistribution and use in source and binary forms, with or without
+ * modification, are permitted provided that the following conditions are met:
+ *    * Redistributions of source code must retain the above copyright
+ *      notice, this list of conditions and the following disclaimer.
+ *    * Redistributions in binary form must reproduce the above copyright
+ *      notice, this list of conditions and the following disclaimer in the
+ *      documentation and/or other materials provided with the distribution.
+ *    * Neither the name of the University nor the names of its contributors
+ *      may be used to endorse or promote products derived from this
+ *      software without specific prior written permission.
+ *
+ * THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS" AND
+ * ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE IMPLIED
+ * WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+ * DISCLAIMED. IN NO EVENT SHALL THE REGENTS OR CONTRIBUTORS BE LIABLE FOR ANY
+ * DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL DAMAGES
+ * (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES;
+ * LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND
+ * ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT
+ * (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+ * SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+ */
+
+/*
+ * lwIP network interface for the virtio network driver in net.rs.
+ * All functions touching the netif run in the context of the tcpip thread.
+ */
+
+#include <hermit/stddef.h>
+#include <hermit/errno.h>
+
+#include <lwip/opt.h>
+#include <lwip/dhcp.h>
+#include <lwip/netifapi.h>
+#include <lwip/pbuf.h>
+#include <lwip/stats.h>
+#include <lwip/sys.h>
+#include <lwip/tcpip.h>
+#include <netif/etharp.h>
+#if LWIP_IPV6
+#include <lwip/ethip6.h>
+#endif
+
+/* maximum number of pbufs in a chain passed to the driver */
+#define VIRTIO_NET_MAX_SEGMENTS	16
+/* maximum length of an Ethernet frame without FCS */
+#define VIRTIO_NET_FRAME_SIZE	1514
+/* give up waiting for a DHCP lease after this time */
+#define DHCP_TIMEOUT_MSECS	20000
+
+/* layout of fs::IoVec */
+typedef struct {
+	void *iov_base;
+	size_t iov_len;
+} virtio_iovec_t;
+
+extern int virtio_net_init(uint8_t *mac, uint16_t *mtu);
+extern int virtio_net_transmit(const virtio_iovec_t *iov, size_t iovcnt);
+extern ssize_t virtio_net_receive(uint8_t *buf, size_t len);
+
+static struct netif virtio_netif;
+static volatile int virtio_netif_polling = 0;
+static volatile int virtio_netif_removed = 0;
+static uint8_t rx_frame[VIRTIO_NET_FRAME_SIZE];
+
+static err_t virtio_netif_output(struct netif *netif, struct pbuf *p)
+{
+	virtio_iovec_t iov[VIRTIO_NET_MAX_SEGMENTS];
+	size_t iovcnt = 0;
+	struct pbuf *q;
+
+	for (q = p; q != NULL; q = q->next) {
+		if (iovcnt == VIRTIO_NET_MAX_SEGMENTS) {
+			LINK_STATS_INC(link.memerr);
+			LINK_STATS_INC(link.drop);
+			return ERR_MEM;
+		}
+
+		iov[iovcnt].iov_base = q->payload;
+		iov[iovcnt].iov_len = q->len;
+		iovcnt++;
+	}
+
+	if (virtio_net_transmit(iov, iovcnt) < 0) {
+		LINK_STATS_INC(link.drop);
+		return ERR_MEM;
+	}
+
+	LINK_STATS_INC(link.xmit);
+	return ERR_OK;
+}
+
+static void virtio_netif_poll(void *ctx)
+{
+	struct netif *netif = (struct netif *) ctx;
+	struct pbuf *p;
+	ssize_t len;
+
+	/* interrupts from now on have to schedule another poll */
+	virtio_netif_polling = 0;
+
+	/* a poll may still have been queued when the interface was removed */
+	if (virtio_netif_removed)
+		return;
+
+	while ((len = virtio_net_receive(rx_frame, sizeof(rx_frame))) > 0) {
+		p = pbuf_alloc(PBUF_RAW, len, PBUF_POOL);
+		if (p == NULL) {
+			LINK_STATS_INC(link.memerr);
+			LINK_STATS_INC(link.drop);
+			continue;
+		}
+
+		pbuf_take(p, rx_frame, len);
+		LINK_STATS_INC(link.recv);
+
+		if (netif->input(p, netif) != ERR_OK)
+			pbuf_free(p);
+	}
+}
+
+/* called by the interrupt handler of the driver */
+void virtio_netif_notify(void)
+{
+	if (virtio_netif_polling || virtio_netif_removed)
+		return;
+
+	virtio_netif_polling = 1;
+	if (tcpip_callback_with_block(virtio_netif_poll, &virtio_netif, 0) != ERR_OK)
+		virtio_netif_polling = 0;
+}
+
+static err_t virtio_netif_init(struct netif *netif)
+{
+	uint16_t mtu;
+
+	if (virtio_net_init(netif->hwaddr, &mtu) < 0)
+		return ERR_IF;
+
+	netif->name[0] = 'e';
+	netif->name[1] = 'n';
+	netif->hwaddr_len = ETHARP_HWADDR_LEN;
+	netif->mtu = mtu;
+	netif->flags = NETIF_FLAG_BROADCAST | NETIF_FLAG_ETHARP | NETIF_FLAG_LINK_UP;
+	netif->output = etharp_output;
+	netif->linkoutput = virtio_netif_output;
+#if LWIP_IPV6
+	netif->output_ip6 = ethip6_output;
+	netif_create_ip6_linklocal_address(netif, 1);
+#endif
+
+	return ERR_OK;
+}
+
+int init_virtio_netif(void)
+{
+	ip4_addr_t ipaddr;
+	ip4_addr_t netmask;
+	ip4_addr_t gw;
+	int mscnt = 0;
+
+	/* the addresses are assigned by DHCP */
+	IP4_ADDR(&ipaddr, 0, 0, 0, 0);
+	IP4_ADDR(&netmask, 0, 0, 0, 0);
+	IP4_ADDR(&gw, 0, 0, 0, 0);
+
+	/* frames are only received in the tcpip thread, so they can be passed to ethernet_input directly */
+	if (netifapi_netif_add(&virtio_netif, &ipaddr, &netmask, &gw, NULL, virtio_netif_init, ethernet_input) != ERR_OK)
+		return -ENODEV;
+
+	netifapi_netif_set_default(&virtio_netif);
+	netifapi_netif_set_up(&virtio_netif);
+	netifapi_dhcp_start(&virtio_netif);
+
+	while (!dhcp_supplied_address(&virtio_netif)) {
+		if (mscnt >= DHCP_TIMEOUT_MSECS) {
+			netifapi_dhcp_stop(&virtio_netif);
+			netifapi_netif_set_down(&virtio_netif);
+
+			/* another interface may be tried next, so remove this one from lwIP's list and stop polling it */
+			virtio_netif_removed = 1;
+			netifapi_netif_remove(&virtio_netif);
+			return -ETIMEDOUT;
+		}
+
+		sys_msleep(DHCP_FINE_TIMER_MSECS);
+		mscnt += DHCP_FINE_TIMER_MSECS;
+	}
+
+	return 0;
+}
@@ -0,0 +1,383 @@
+// Copyright (c) 2018 Stefan Lankes, RWTH Aachen University
+//                    Colin Finck, RWTH Aachen University
+//
+// MIT License
+//
+// Permission is hereby granted, free of charge, to any person obtaining
+// a copy of this software and associated documentation files (the
+// "Software"), to deal in the Software without restriction, including
+// without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to
+// permit persons to whom the Software is furnished to do so, subject to
+// the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE
+// LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION
+// OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
+// WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! PCI transport of virtio devices, supporting both the legacy interface over I/O ports
+//! and the modern interface over memory-mapped capability structures.
+
+use arch::mm::paging::{BasePageSize, PageSize};
+use arch::pci::{MmioRegion, PciAdapter, PciBar, PCI_CAPABILITY_ID_VENDOR};
+use drivers::virtio::*;
+use x86::shared::io::*;
+
+
+// Registers of the legacy interface, relative to the I/O port in BAR0.
+const VIRTIO_PCI_HOST_FEATURES:  u16 = 0;
+const VIRTIO_PCI_GUEST_FEATURES: u16 = 4;
+const VIRTIO_PCI_QUEUE_PFN:      u16 = 8;
+const VIRTIO_PCI_QUEUE_NUM:      u16 = 12;
+const VIRTIO_PCI_QUEUE_SEL:      u16 = 14;
+const VIRTIO_PCI_QUEUE_NOTIFY:   u16 = 16;
+const VIRTIO_PCI_STATUS:         u16 = 18;
+const VIRTIO_PCI_ISR:            u16 = 19;
+const VIRTIO_PCI_CONFIG:         u16 = 20;
+
+// Registers of the legacy interface, which only exist while MSI-X is enabled.
+// They move the device-specific configuration behind them.
+const VIRTIO_MSI_CONFIG_VECTOR:  u16 = 20;
+const VIRTIO_MSI_QUEUE_VECTOR:   u16 = 22;
+const VIRTIO_PCI_CONFIG_MSIX:    u16 = 24;
+
+/// The legacy interface takes the address of a virtqueue as a page frame number of this size.
+const VIRTIO_PCI_QUEUE_ADDR_SHIFT: usize = 12;
+
+// Types of the vendor-specific capabilities describing the modern interface.
+const VIRTIO_PCI_CAP_COMMON_CFG: u8 = 1;
+const VIRTIO_PCI_CAP_NOTIFY_CFG: u8 = 2;
+const VIRTIO_PCI_CAP_ISR_CFG:    u8 = 3;
+const VIRTIO_PCI_CAP_DEVICE_CFG: u8 = 4;
+
+// Fields of the common configuration structure of the modern interface.
+const VIRTIO_COMMON_DEVICE_FEATURE_SELECT: usize = 0;
+const VIRTIO_COMMON_DEVICE_FEATURE:        usize = 4;
+const VIRTIO_COMMON_DRIVER_FEATURE_SELECT: usize = 8;
+const VIRTIO_COMMON_DRIVER_FEATURE:        usize = 12;
+const VIRTIO_COMMON_MSIX_CONFIG:           usize = 16;
+const VIRTIO_COMMON_STATUS:                usize = 20;
+const VIRTIO_COMMON_QUEUE_SELECT:          usize = 22;
+const VIRTIO_COMMON_QUEUE_SIZE:            usize = 24;
+const VIRTIO_COMMON_QUEUE_MSIX_VECTOR:     usize = 26;
+const VIRTIO_COMMON_QUEUE_ENABLE:          usize = 28;
+const VIRTIO_COMMON_QUEUE_NOTIFY_OFF:      usize = 30;
+const VIRTIO_COMMON_QUEUE_DESC:            usize = 32;
+const VIRTIO_COMMON_QUEUE_DRIVER:          usize = 40;
+const VIRTIO_COMMON_QUEUE_DEVICE:          usize = 48;
+
+
+/// Value of an MSI-X vector register assigning no MSI-X table entry.
+const VIRTIO_MSI_NO_VECTOR: u16 = 0xFFFF;
+
+
+/// Location of a structure of the modern interface, as found in a vendor-specific PCI capability.
+#[derive(Clone, Copy)]
+struct Capability {
+	bar: u8,
+	offset: u32,
+	length: u32,
+}
+
+/// How to notify the device about new buffers in a virtqueue.
+#[derive(Clone, Copy)]
+pub enum QueueNotifier {
+	Port(u16, u16),
+	/// The notification register at the given offset into the notification structure.
+	Mmio(MmioRegion, usize, u16),
+}
+
+impl QueueNotifier {
+	pub fn notify(&self) {
+		match *self {
+			QueueNotifier::Port(port, index) => unsafe { outw(port, index); },
+			QueueNotifier::Mmio(region, offset, index) => region.write(offset, index),
+		}
+	}
+}
+
+/// The transport of a device, which signals the completions of all its virtqueues
+/// through the MSI-X table entry `msix_entry` if set, or its legacy interrupt line otherwise.
+pub enum VirtioPciTransport {
+	Legacy {
+		port: u16,
+		msix_entry: Option<u16>,
+	},
+	Modern {
+		common: MmioRegion,
+		notify: MmioRegion,
+		notify_multiplier: u32,
+		isr: MmioRegion,
+		device: Option<MmioRegion>,
+		msix_entry: Option<u16>,
+	},
+}
+
+impl VirtioPciTransport {
+	/// Sets up the transport for the given adapter.
+	/// The modern interface is preferred, but transitional devices can also be driven over the legacy one.
+	pub fn new(adapter: &PciAdapter) -> Option<Self> {
+		if let Some(transport) = Self::new_modern(adapter) {
+			return Some(transport);
+		}
+
+		match adapter.base_addresses[0] {
+			Some(PciBar::Io { port, .. }) => Some(VirtioPciTransport::Legacy { port: port, msix_entry: None }),
+			_ => None,
+		}
+	}
+
+	fn new_modern(adapter: &PciAdapter) -> Option<Self> {
+		let mut common = None;
+		let mut notify = None;
+		let mut notify_multiplier = 0;
+		let mut isr = None;
+		let mut device = None;
+
+		// Walk the capability list and record the virtio structures.
+		for pci_capability in adapter.capabilities().filter(|c| c.id == PCI_CAPABILITY_ID_VENDOR) {
+			let offset = pci_capability.offset;
+			let cfg_type = (adapter.read_config(offset) >> 24) as u8;
+			let capability = Capability {
+				bar: adapter.read_config(offset + 4) as u8,
+				offset: adapter.read_config(offset + 8),
+				length: adapter.read_config(offset + 12),
+			};
+
+			match cfg_type {
+				VIRTIO_PCI_CAP_COMMON_CFG => common = common.or(Some(capability)),
+				VIRTIO_PCI_CAP_NOTIFY_CFG => if notify.is_none() {
+					notify = Some(capability);
+					notify_multiplier = adapter.read_config(offset + 16);
+				},
+				VIRTIO_PCI_CAP_ISR_CFG => isr = isr.or(Some(capability)),
+				VIRTIO_PCI_CAP_DEVICE_CFG => device = device.or(Some(capability)),
+				_ => {},
+			}
+		}
+
+		// The device-specific configuration is optional.
+		let (common, notify, isr) = match (common, notify, isr) {
+			(Some(common), Some(notify), Some(isr)) => (common, notify, isr),
+			_ => return None,
+		};
+
+		Some(VirtioPciTransport::Modern {
+			common: map_capability(adapter, common)?,
+			notify: map_capability(adapter, notify)?,
+			notify_multiplier: notify_multiplier,
+			isr: map_capability(adapter, isr)?,
+			device: match device {
+				Some(device) => Some(map_capability(adapter, device)?),
+				None => None,
+			},
+			msix_entry: None,
+		})
+	}
+
+	pub fn is_modern(&self) -> bool {
+		match *self {
+			VirtioPciTransport::Legacy { .. } => false,
+			VirtioPciTransport::Modern { .. } => true,
+		}
+	}
+
+	pub fn status(&self) -> u8 {
+		match *self {
+			VirtioPciTransport::Legacy { port, .. } => unsafe { inb(port + VIRTIO_PCI_STATUS) },
+			VirtioPciTransport::Modern { common, .. } => common.read::<u8>(VIRTIO_COMMON_STATUS),
+		}
+	}
+
+	pub fn set_status(&self, status: u8) {
+		match *self {
+			VirtioPciTransport::Legacy { port, .. } => unsafe { outb(port + VIRTIO_PCI_STATUS, status) },
+			VirtioPciTransport::Modern { common, .. } => common.write::<u8>(VIRTIO_COMMON_STATUS, status),
+		}
+	}
+
+	/// Sets the given bits in the device status register.
+	pub fn add_status(&self, status: u8) {
+		let old_status = self.status();
+		self.set_status(old_status | status);
+	}
+
+	pub fn reset(&self) {
+		self.set_status(0);
+	}
+
+	pub fn device_features(&self) -> u64 {
+		match *self {
+			VirtioPciTransport::Legacy { port, .. } => unsafe { inl(port + VIRTIO_PCI_HOST_FEATURES) as u64 },
+			VirtioPciTransport::Modern { common, .. } => {
+				common.write::<u32>(VIRTIO_COMMON_DEVICE_FEATURE_SELECT, 0);
+				let low = common.read::<u32>(VIRTIO_COMMON_DEVICE_FEATURE);
+				common.write::<u32>(VIRTIO_COMMON_DEVICE_FEATURE_SELECT, 1);
+				let high = common.read::<u32>(VIRTIO_COMMON_DEVICE_FEATURE);
+				(high as u64) << 32 | low as u64
+			},
+		}
+	}
+
+	pub fn set_driver_features(&self, features: u64) {
+		match *self {
+			VirtioPciTransport::Legacy { port, .. } => {
+				// The legacy interface only knows the lower 32 feature bits.
+				assert!(features & VIRTIO_F_VERSION_1 == 0);
+				unsafe { outl(port + VIRTIO_PCI_GUEST_FEATURES, features as u32); }
+			},
+			VirtioPciTransport::Modern { common, .. } => {
+				common.write::<u32>(VIRTIO_COMMON_DRIVER_FEATURE_SELECT, 0);
+				common.write::<u32>(VIRTIO_COMMON_DRIVER_FEATURE, features as u32);
+				common.write::<u32>(VIRTIO_COMMON_DRIVER_FEATURE_SELECT, 1);
+				common.write::<u32>(VIRTIO_COMMON_DRIVER_FEATURE, (features >> 32) as u32);
+			},
+		}
+	}
+
+	/// Negotiates the features supported by both the driver and the device and returns them.
+	/// Devices driven over the modern interface always negotiate VIRTIO_F_VERSION_1.
+	/// On failure, the device is marked as failed.
+	pub fn negotiate_features(&self, driver_features: u64) -> Option<u64> {
+		let device_features = self.device_features();
+		let mut features = device_features & driver_features;
+
+		if self.is_modern() {
+			if device_features & VIRTIO_F_VERSION_1 == 0 {
+				self.add_status(VIRTIO_STATUS_FAILED);
+				return None;
+			}
+
+			features |= VIRTIO_F_VERSION_1;
+		} else {
+			features &= !VIRTIO_F_VERSION_1;
+		}
+
+		self.set_driver_features(features);
+
+		// Only the modern interface lets the device reject the features.
+		if self.is_modern() {
+			self.add_status(VIRTIO_STATUS_FEATURES_OK);
+			if self.status() & VIRTIO_STATUS_FEATURES_OK == 0 {
+				self.add_status(VIRTIO_STATUS_FAILED);
+				return None;
+			}
+		}
+
+		Some(features)
+	}
+
+	/// Lets the virtqueues set up afterwards signal their completions through the given MSI-X table entry,
+	/// while configuration changes are not signalled at all.
+	/// Must be called after enabling MSI-X on the adapter, which changes the register layout of the legacy interface.
+	pub fn set_msix_entry(&mut self, entry: u16) {
+		match *self {
+			VirtioPciTransport::Legacy { port, ref mut msix_entry } => {
+				unsafe { outw(port + VIRTIO_MSI_CONFIG_VECTOR, VIRTIO_MSI_NO_VECTOR); }
+				*msix_entry = Some(entry);
+			},
+			VirtioPciTransport::Modern { common, ref mut msix_entry, .. } => {
+				common.write::<u16>(VIRTIO_COMMON_MSIX_CONFIG, VIRTIO_MSI_NO_VECTOR);
+				*msix_entry = Some(entry);
+			},
+		}
+	}
+
+	/// Returns the maximum number of entries of the given virtqueue, or 0 if it does not exist.
+	pub fn queue_size(&self, index: u16) -> u16 {
+		match *self {
+			VirtioPciTransport::Legacy { port, .. } => unsafe {
+				outw(port + VIRTIO_PCI_QUEUE_SEL, index);
+				inw(port + VIRTIO_PCI_QUEUE_NUM)
+			},
+			VirtioPciTransport::Modern { common, .. } => {
+				common.write::<u16>(VIRTIO_COMMON_QUEUE_SELECT, index);
+				common.read::<u16>(VIRTIO_COMMON_QUEUE_SIZE)
+			},
+		}
+	}
+
+	/// Hands over the physical addresses of the parts of a virtqueue to the device.
+	/// The legacy interface requires the layout defined by the specification, starting at a page boundary.
+	pub fn setup_queue(&self, index: u16, size: u16, descriptors: usize, available: usize, used: usize) -> QueueNotifier {
+		match *self {
+			VirtioPciTransport::Legacy { port, msix_entry } => {
+				assert!(descriptors % BasePageSize::SIZE == 0);
+				assert!(available == descriptors + 16 * size as usize);
+				assert!(used == align_up!(available + 6 + 2 * size as usize, BasePageSize::SIZE));
+
+				unsafe {
+					outw(port + VIRTIO_PCI_QUEUE_SEL, index);
+					if let Some(entry) = msix_entry {
+						outw(port + VIRTIO_MSI_QUEUE_VECTOR, entry);
+						if inw(port + VIRTIO_MSI_QUEUE_VECTOR) != entry {
+							warn!("virtio device cannot signal virtqueue {} through MSI-X", index);
+						}
+					}
+					outl(port + VIRTIO_PCI_QUEUE_PFN, (descriptors >> VIRTIO_PCI_QUEUE_ADDR_SHIFT) as u32);
+				}
+
+				QueueNotifier::Port(port + VIRTIO_PCI_QUEUE_NOTIFY, index)
+			},
+			VirtioPciTransport::Modern { common, notify, notify_multiplier, msix_entry, .. } => {
+				common.write::<u16>(VIRTIO_COMMON_QUEUE_SELECT, index);
+				common.write::<u16>(VIRTIO_COMMON_QUEUE_SIZE, size);
+				if let Some(entry) = msix_entry {
+					common.write::<u16>(VIRTIO_COMMON_QUEUE_MSIX_VECTOR, entry);
+					if common.read::<u16>(VIRTIO_COMMON_QUEUE_MSIX_VECTOR) != entry {
+						warn!("virtio device cannot signal virtqueue {} through MSI-X", index);
+					}
+				}
+				write_u64(&common, VIRTIO_COMMON_QUEUE_DESC, descriptors as u64);
+				write_u64(&common, VIRTIO_COMMON_QUEUE_DRIVER, available as u64);
+				write_u64(&common, VIRTIO_COMMON_QUEUE_DEVICE, used as u64);
+				let notify_offset = common.read::<u16>(VIRTIO_COMMON_QUEUE_NOTIFY_OFF);
+				common.write::<u16>(VIRTIO_COMMON_QUEUE_ENABLE, 1);
+
+				QueueNotifier::Mmio(notify, notify_offset as usize * notify_multiplier as usize, index)
+			},
+		}
+	}
+
+	/// Reads and thereby acknowledges the interrupt status.
+	/// An MSI-X table entry is only used by the virtqueues of this device and the status is not updated for it,
+	/// so every interrupt is reported as a used virtqueue then.
+	pub fn read_isr(&self) -> u8 {
+		match *self {
+			VirtioPciTransport::Legacy { msix_entry: Some(_), .. } | VirtioPciTransport::Modern { msix_entry: Some(_), .. } => VIRTIO_ISR_QUEUE,
+			VirtioPciTransport::Legacy { port, .. } => unsafe { inb(port + VIRTIO_PCI_ISR) },
+			VirtioPciTransport::Modern { isr, .. } => isr.read::<u8>(0),
+		}
+	}
+
+	/// Reads a byte from the device-specific configuration.
+	pub fn read_device_config(&self, offset: u16) -> u8 {
+		match *self {
+			VirtioPciTransport::Legacy { port, msix_entry } => {
+				let config = if msix_entry.is_some() { VIRTIO_PCI_CONFIG_MSIX } else { VIRTIO_PCI_CONFIG };
+				unsafe { inb(port + config + offset) }
+			},
+			VirtioPciTransport::Modern { device, .. } => {
+				device.expect("Device has no device-specific configuration").read::<u8>(offset as usize)
+			},
+		}
+	}
+}
+
+fn write_u64(region: &MmioRegion, offset: usize, value: u64) {
+	// 64-bit fields are written as two 32-bit halves, which every device has to accept.
+	region.write::<u32>(offset, value as u32);
+	region.write::<u32>(offset + 4, (value >> 32) as u32);
+}
+
+/// Maps the structure described by a capability uncached into kernel memory.
+fn map_capability(adapter: &PciAdapter, capability: Capability) -> Option<MmioRegion> {
+	adapter.map_bar_range(capability.bar as usize, capability.offset as usize, capability.length as usize)
+}
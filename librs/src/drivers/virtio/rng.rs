@@ -0,0 +1,136 @@
+// Copyright (c) 2018 Stefan Lankes, RWTH Aachen University
+//                    Colin Finck, RWTH Aachen University
+//
+// MIT License
+//
+// Permission is hereby granted, free of charge, to any person obtaining
+// a copy of this software and associated documentation files (the
+// "Software"), to deal in the Software without restriction, including
+// without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to
+// permit persons to whom the Software is furnished to do so, subject to
+// the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE
+// LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION
+// OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
+// WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! Driver for virtio entropy devices, which pass randomness of the host to us.
+//! The device is only read while seeding the kernel's random number generator,
+//! so requests are simply polled for completion.
+
+use arch::mm::paging::{self, BasePageSize, PageSize, PageTableEntryFlags};
+use core::cmp;
+use core::sync::atomic::spin_loop_hint;
+use drivers::virtio::*;
+use drivers::virtio::virtqueue::{Segment, Virtqueue};
+use mm;
+use synch::spinlock::SpinlockIrqSave;
+
+
+/// The only queue of an entropy device.
+const REQUEST_QUEUE: u16 = 0;
+
+/// Number of polling iterations before we give up waiting for the device.
+const POLL_ITERATIONS: usize = 1_000_000;
+
+
+struct VirtioRng {
+	queue: Virtqueue,
+	buffer: usize,
+	physical_buffer: usize,
+	/// Whether the device still has to fill the buffer for a request, which we gave up waiting for.
+	pending: bool,
+}
+
+impl VirtioRng {
+	fn read(&mut self, buf: &mut [u8]) -> usize {
+		if !self.pending {
+			let segment = Segment {
+				physical_address: self.physical_buffer,
+				length: cmp::min(buf.len(), BasePageSize::SIZE),
+				writable: true,
+			};
+
+			self.queue.add(&[segment], 0);
+			self.queue.notify();
+			self.pending = true;
+		}
+
+		for _ in 0..POLL_ITERATIONS {
+			if let Some((_, length)) = self.queue.pop_used() {
+				self.pending = false;
+
+				// Requests from an earlier call may have been shorter than this one.
+				let length = cmp::min(length, buf.len());
+				let source = unsafe { ::core::slice::from_raw_parts(self.buffer as *const u8, length) };
+				buf[..length].copy_from_slice(source);
+				return length;
+			}
+
+			spin_loop_hint();
+		}
+
+		0
+	}
+}
+
+static VIRTIO_RNG: SpinlockIrqSave<Option<VirtioRng>> = SpinlockIrqSave::new(None);
+
+
+/// Fills the beginning of buf with randomness of the host.
+/// Returns the number of bytes read, which is zero if there is no virtio entropy device.
+pub fn read(buf: &mut [u8]) -> usize {
+	match *VIRTIO_RNG.lock() {
+		Some(ref mut rng) => rng.read(buf),
+		None => 0,
+	}
+}
+
+/// Looks for a virtio entropy device.
+pub fn init() {
+	let (adapter, transport) = match find_device(VIRTIO_ID_RNG) {
+		Some(device) => device,
+		None => return,
+	};
+
+	if transport.negotiate_features(0).is_none() {
+		warn!("virtio entropy device does not accept our features");
+		return;
+	}
+
+	let mut queue = match Virtqueue::new(&transport, REQUEST_QUEUE) {
+		Some(queue) => queue,
+		None => {
+			warn!("virtio entropy device has no request queue");
+			transport.add_status(VIRTIO_STATUS_FAILED);
+			return;
+		}
+	};
+
+	// We poll for completed requests.
+	queue.disable_interrupts();
+	let buffer = mm::allocate(BasePageSize::SIZE, PageTableEntryFlags::EXECUTE_DISABLE);
+
+	info!(
+		"Found virtio entropy device at {:02X}:{:02X} ({} interface)",
+		adapter.bus,
+		adapter.device,
+		if transport.is_modern() { "modern" } else { "legacy" }
+	);
+
+	transport.add_status(VIRTIO_STATUS_DRIVER_OK);
+	*VIRTIO_RNG.lock() = Some(VirtioRng {
+		queue: queue,
+		buffer: buffer,
+		physical_buffer: paging::virtual_to_physical(buffer),
+		pending: false,
+	});
+}
@@ -0,0 +1,248 @@
+// Copyright (c) 2018 Stefan Lankes, RWTH Aachen University
+//                    Colin Finck, RWTH Aachen University
+//
+// MIT License
+//
+// Permission is hereby granted, free of charge, to any person obtaining
+// a copy of this software and associated documentation files (the
+// "Software"), to deal in the Software without restriction, including
+// without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to
+// permit persons to whom the Software is furnished to do so, subject to
+// the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE
+// LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION
+// OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
+// WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! Split virtqueues shared by all virtio drivers.
+//! The rings are laid out as required by the legacy interface, which the modern one accepts as well.
+
+use alloc::vec::Vec;
+use arch::mm::paging::{self, BasePageSize, PageSize, PageTableEntryFlags};
+use core::ptr;
+use core::sync::atomic::{fence, Ordering};
+use drivers::virtio::pci::{QueueNotifier, VirtioPciTransport};
+use mm;
+
+
+// Flags of a descriptor.
+const VIRTQ_DESC_F_NEXT:  u16 = 1;
+const VIRTQ_DESC_F_WRITE: u16 = 2;
+
+/// Tells the device not to interrupt us when it has used a buffer.
+const VIRTQ_AVAIL_F_NO_INTERRUPT: u16 = 1;
+/// Tells us not to notify the device when we have made a buffer available.
+const VIRTQ_USED_F_NO_NOTIFY: u16 = 1;
+
+
+#[repr(C)]
+struct Descriptor {
+	address: u64,
+	length: u32,
+	flags: u16,
+	next: u16,
+}
+
+#[repr(C)]
+struct UsedElement {
+	id: u32,
+	length: u32,
+}
+
+/// A physically contiguous buffer, which becomes one descriptor of a chain.
+pub struct Segment {
+	pub physical_address: usize,
+	pub length: usize,
+	/// The device writes into this buffer instead of reading from it.
+	pub writable: bool,
+}
+
+pub struct Virtqueue {
+	size: u16,
+	descriptors: usize,
+	available: usize,
+	used: usize,
+	last_used_index: u16,
+	notifier: QueueNotifier,
+	/// Unused descriptors are linked through their next field, starting at free_head.
+	free_head: u16,
+	free_count: u16,
+	/// Value passed by the driver for each chain in flight, indexed by its head descriptor.
+	tokens: Vec<usize>,
+}
+
+impl Virtqueue {
+	/// Allocates the rings for the queue with the given index and hands them over to the device.
+	pub fn new(transport: &VirtioPciTransport, index: u16) -> Option<Self> {
+		let size = transport.queue_size(index);
+		if size == 0 {
+			return None;
+		}
+
+		let descriptors_size = 16 * size as usize;
+		let available_size = 6 + 2 * size as usize;
+		let used_offset = align_up!(descriptors_size + available_size, BasePageSize::SIZE);
+		let used_size = 6 + 8 * size as usize;
+		let rings_size = used_offset + align_up!(used_size, BasePageSize::SIZE);
+
+		// The allocation is physically contiguous.
+		let descriptors = mm::allocate(rings_size, PageTableEntryFlags::EXECUTE_DISABLE);
+		unsafe { ptr::write_bytes(descriptors as *mut u8, 0, rings_size); }
+
+		let physical_descriptors = paging::virtual_to_physical(descriptors);
+		let notifier = transport.setup_queue(
+			index,
+			size,
+			physical_descriptors,
+			physical_descriptors + descriptors_size,
+			physical_descriptors + used_offset
+		);
+
+		let mut tokens = Vec::with_capacity(size as usize);
+		tokens.resize(size as usize, 0);
+
+		let mut queue = Self {
+			size: size,
+			descriptors: descriptors,
+			available: descriptors + descriptors_size,
+			used: descriptors + used_offset,
+			last_used_index: 0,
+			notifier: notifier,
+			free_head: 0,
+			free_count: size,
+			tokens: tokens,
+		};
+
+		for id in 0..size - 1 {
+			queue.descriptor(id).next = id + 1;
+		}
+
+		Some(queue)
+	}
+
+	pub fn size(&self) -> u16 {
+		self.size
+	}
+
+	/// Returns the number of descriptors, which are not part of a chain in flight.
+	pub fn free_descriptors(&self) -> u16 {
+		self.free_count
+	}
+
+	fn descriptor(&mut self, id: u16) -> &mut Descriptor {
+		assert!(id < self.size);
+		unsafe { &mut *((self.descriptors + id as usize * 16) as *mut Descriptor) }
+	}
+
+	/// Chains the given segments and makes them available to the device.
+	/// The device processes the readable segments first, so they must precede the writable ones.
+	/// `token` is returned by pop_used when the device has finished with the chain.
+	/// Returns false if there are not enough free descriptors. The device is only notified by notify.
+	pub fn add(&mut self, segments: &[Segment], token: usize) -> bool {
+		assert!(!segments.is_empty());
+		if segments.len() > self.free_count as usize {
+			return false;
+		}
+
+		// The chain simply follows the list of free descriptors.
+		let head = self.free_head;
+		let mut id = head;
+		for (i, segment) in segments.iter().enumerate() {
+			let descriptor = self.descriptor(id);
+			descriptor.address = segment.physical_address as u64;
+			descriptor.length = segment.length as u32;
+			descriptor.flags = if segment.writable { VIRTQ_DESC_F_WRITE } else { 0 };
+
+			if i + 1 < segments.len() {
+				descriptor.flags |= VIRTQ_DESC_F_NEXT;
+			}
+
+			id = descriptor.next;
+		}
+
+		self.free_head = id;
+		self.free_count -= segments.len() as u16;
+		self.tokens[head as usize] = token;
+		self.push_available(head);
+
+		true
+	}
+
+	fn push_available(&mut self, id: u16) {
+		unsafe {
+			let index_address = (self.available + 2) as *mut u16;
+			let index = ptr::read_volatile(index_address);
+			ptr::write_volatile((self.available + 4 + 2 * (index % self.size) as usize) as *mut u16, id);
+
+			// The device must see the descriptors and the ring entry before the new index.
+			fence(Ordering::SeqCst);
+			ptr::write_volatile(index_address, index.wrapping_add(1));
+		}
+	}
+
+	/// Checks whether the device has finished with any chain.
+	pub fn has_used(&self) -> bool {
+		let used_index = unsafe { ptr::read_volatile((self.used + 2) as *const u16) };
+		used_index != self.last_used_index
+	}
+
+	/// Polls for the next chain the device has finished with and frees its descriptors.
+	/// Returns the token of the chain and the number of bytes written by the device.
+	pub fn pop_used(&mut self) -> Option<(usize, usize)> {
+		if !self.has_used() {
+			return None;
+		}
+
+		// Only read the ring entry after the index announcing it.
+		fence(Ordering::SeqCst);
+		let element = unsafe {
+			ptr::read_volatile((self.used + 4 + 8 * (self.last_used_index % self.size) as usize) as *const UsedElement)
+		};
+		self.last_used_index = self.last_used_index.wrapping_add(1);
+
+		// Return the chain to the list of free descriptors.
+		let head = element.id as u16;
+		let mut id = head;
+		let mut count = 1;
+		while self.descriptor(id).flags & VIRTQ_DESC_F_NEXT != 0 {
+			id = self.descriptor(id).next;
+			count += 1;
+		}
+
+		let free_head = self.free_head;
+		self.descriptor(id).next = free_head;
+		self.free_head = head;
+		self.free_count += count;
+
+		Some((self.tokens[head as usize], element.length as usize))
+	}
+
+	/// Asks the device to interrupt us whenever it has used a chain.
+	pub fn enable_interrupts(&mut self) {
+		unsafe { ptr::write_volatile(self.available as *mut u16, 0); }
+	}
+
+	/// Asks the device not to interrupt us, because we poll for used chains.
+	/// This is only a hint and interrupts may still arrive.
+	pub fn disable_interrupts(&mut self) {
+		unsafe { ptr::write_volatile(self.available as *mut u16, VIRTQ_AVAIL_F_NO_INTERRUPT); }
+	}
+
+	/// Tells the device about newly added chains, unless it has asked us not to.
+	pub fn notify(&self) {
+		fence(Ordering::SeqCst);
+
+		let used_flags = unsafe { ptr::read_volatile(self.used as *const u16) };
+		if used_flags & VIRTQ_USED_F_NO_NOTIFY == 0 {
+			self.notifier.notify();
+		}
+	}
+}
@@ -36,6 +36,7 @@ extern "C" {
 }
 
 static mut COMMAND_LINE_CPU_FREQUENCY: u16 = 0;
+static mut COMMAND_LINE_HEAP_PAGE_SIZE: usize = 0;
 static mut IS_PROXY: bool = false;
 
 
@@ -55,6 +56,18 @@ unsafe fn parse_command_line() {
 		COMMAND_LINE_CPU_FREQUENCY = mhz_str.parse().expect("Could not parse -freq command line as number");
 	}
 
+	// Check for the -heap-pagesize option (one of 4K, 2M, or 1G).
+	if let Some(pagesize_index) = cmdline_str.find("-heap-pagesize") {
+		let cmdline_pagesize_str = cmdline_str.split_at(pagesize_index + "-heap-pagesize".len()).1;
+		let pagesize_str = cmdline_pagesize_str.split(' ').next().expect("Invalid -heap-pagesize command line");
+		COMMAND_LINE_HEAP_PAGE_SIZE = match pagesize_str {
+			"4K" | "4k" => 4 * 1024,
+			"2M" | "2m" => 2 * 1024 * 1024,
+			"1G" | "1g" => 1024 * 1024 * 1024,
+			_ => panic!("Could not parse -heap-pagesize command line (expected 4K, 2M, or 1G)")
+		};
+	}
+
 	// Check for the -proxy option.
 	IS_PROXY = cmdline_str.find("-proxy").is_some();
 }
@@ -78,6 +91,11 @@ pub fn get_command_line_cpu_frequency() -> u16 {
 	unsafe { COMMAND_LINE_CPU_FREQUENCY }
 }
 
+/// Returns the page size in bytes requested for task heaps on the command line or 0 if none has been given.
+pub fn get_command_line_heap_page_size() -> usize {
+	unsafe { COMMAND_LINE_HEAP_PAGE_SIZE }
+}
+
 /// Whether HermitCore shall communicate with the "proxy" application over a network interface.
 /// Only valid after calling init()!
 pub fn is_proxy() -> bool {
@@ -0,0 +1,70 @@
+// Copyright (c) 2018 Colin Finck, RWTH Aachen University
+//
+// MIT License
+//
+// Permission is hereby granted, free of charge, to any person obtaining
+// a copy of this software and associated documentation files (the
+// "Software"), to deal in the Software without restriction, including
+// without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to
+// permit persons to whom the Software is furnished to do so, subject to
+// the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE
+// LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION
+// OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
+// WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+use fs::{ObjectInterface, Stat, S_IFCHR};
+use syscalls;
+
+
+/// Preferred I/O block size reported for the console.
+const CONSOLE_BLOCK_SIZE: i64 = 1024;
+
+
+/// A standard stream (stdin, stdout, or stderr).
+/// Requests are forwarded to the active syscall interface, which either outputs to the kernel console
+/// or to the standard streams of the host.
+pub struct Console {
+	fd: i32,
+}
+
+impl Console {
+	pub const fn new(fd: i32) -> Self {
+		Self { fd: fd }
+	}
+}
+
+impl ObjectInterface for Console {
+	fn read(&self, buf: *mut u8, len: usize) -> isize {
+		syscalls::get_interface().read(self.fd, buf, len)
+	}
+
+	fn write(&self, buf: *const u8, len: usize) -> isize {
+		syscalls::get_interface().write(self.fd, buf, len)
+	}
+
+	fn fstat(&self, stat: &mut Stat) -> i32 {
+		// Always report a character device, even if the stream is redirected on the host.
+		// Runtimes only use this to decide about buffering.
+		*stat = Stat {
+			st_mode: S_IFCHR | 0o620,
+			st_nlink: 1,
+			st_blksize: CONSOLE_BLOCK_SIZE,
+			..Default::default()
+		};
+		0
+	}
+
+	fn close(&self) -> i32 {
+		// The standard streams of the host are never closed.
+		0
+	}
+}
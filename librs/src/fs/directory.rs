@@ -0,0 +1,139 @@
+// Copyright (c) 2018 Colin Finck, RWTH Aachen University
+//
+// MIT License
+//
+// Permission is hereby granted, free of charge, to any person obtaining
+// a copy of this software and associated documentation files (the
+// "Software"), to deal in the Software without restriction, including
+// without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to
+// permit persons to whom the Software is furnished to do so, subject to
+// the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE
+// LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION
+// OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
+// WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+
+//! Directories of file systems in the kernel opened through sys_open.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::ptr;
+use errno::*;
+use fs::{ObjectInterface, Stat, SEEK_CUR, SEEK_SET, DT_DIR};
+use synch::spinlock::Spinlock;
+
+
+/// Size of the fixed part of a "struct linux_dirent64" (d_ino, d_off, d_reclen, d_type).
+const DIRENT_HEADER_SIZE: usize = 19;
+
+
+/// An entry of a directory as returned by FileSystemInterface::readdir.
+pub struct DirectoryEntry {
+	pub ino: u64,
+	/// One of the DT_* constants
+	pub file_type: u8,
+	pub name: String,
+}
+
+/// An open directory.
+/// The entries are read when opening the directory, so later changes are not visible until it is opened again.
+pub struct DirectoryObject {
+	stat: Stat,
+	entries: Vec<DirectoryEntry>,
+	/// Index of the next entry returned by getdents
+	position: Spinlock<usize>,
+}
+
+impl DirectoryObject {
+	/// Creates the object for a directory with the given status and entries.
+	/// `parent_ino` is the inode number reported for the ".." entry.
+	pub fn new(stat: Stat, parent_ino: u64, entries: Vec<DirectoryEntry>) -> Self {
+		let mut all_entries = Vec::with_capacity(entries.len() + 2);
+		all_entries.push(DirectoryEntry { ino: stat.st_ino, file_type: DT_DIR, name: String::from(".") });
+		all_entries.push(DirectoryEntry { ino: parent_ino, file_type: DT_DIR, name: String::from("..") });
+		all_entries.extend(entries);
+
+		Self {
+			stat: stat,
+			entries: all_entries,
+			position: Spinlock::new(0),
+		}
+	}
+}
+
+impl ObjectInterface for DirectoryObject {
+	fn read(&self, _buf: *mut u8, _len: usize) -> isize {
+		-EISDIR as isize
+	}
+
+	fn write(&self, _buf: *const u8, _len: usize) -> isize {
+		-EBADF as isize
+	}
+
+	fn lseek(&self, offset: isize, whence: i32) -> isize {
+		// The offset of a directory is the index of an entry, as in the d_off field.
+		let mut position = self.position.lock();
+
+		let new_position = match whence {
+			SEEK_SET => offset,
+			SEEK_CUR => match (*position as isize).checked_add(offset) {
+				Some(new_position) => new_position,
+				None => return -EOVERFLOW as isize,
+			},
+			_ => return -EINVAL as isize,
+		};
+
+		if new_position < 0 {
+			return -EINVAL as isize;
+		}
+
+		*position = new_position as usize;
+		new_position
+	}
+
+	fn fstat(&self, stat: &mut Stat) -> i32 {
+		*stat = self.stat;
+		0
+	}
+
+	fn getdents(&self, buf: *mut u8, len: usize) -> isize {
+		let mut position = self.position.lock();
+		let mut written = 0;
+
+		while let Some(entry) = self.entries.get(*position) {
+			let reclen = align_up!(DIRENT_HEADER_SIZE + entry.name.len() + 1, 8);
+			if written + reclen > len {
+				break;
+			}
+
+			unsafe {
+				let record = buf.offset(written as isize);
+				ptr::write_unaligned(record as *mut u64, entry.ino);
+				ptr::write_unaligned(record.offset(8) as *mut i64, (*position + 1) as i64);
+				ptr::write_unaligned(record.offset(16) as *mut u16, reclen as u16);
+				*record.offset(18) = entry.file_type;
+				ptr::copy_nonoverlapping(entry.name.as_ptr(), record.offset(DIRENT_HEADER_SIZE as isize), entry.name.len());
+				ptr::write_bytes(record.offset((DIRENT_HEADER_SIZE + entry.name.len()) as isize), 0, reclen - DIRENT_HEADER_SIZE - entry.name.len());
+			}
+
+			written += reclen;
+			*position += 1;
+		}
+
+		// The buffer must be large enough for at least one entry.
+		if written == 0 && *position < self.entries.len() {
+			return -EINVAL as isize;
+		}
+
+		written as isize
+	}
+}
@@ -0,0 +1,80 @@
+// Copyright (c) 2018 Colin Finck, RWTH Aachen University
+//
+// MIT License
+//
+// Permission is hereby granted, free of charge, to any person obtaining
+// a copy of this software and associated documentation files (the
+// "Software"), to deal in the Software without restriction, including
+// without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to
+// permit persons to whom the Software is furnished to do so, subject to
+// the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE
+// LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION
+// OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
+// WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+use fs::{IoVec, ObjectInterface, Stat};
+use syscalls;
+
+
+/// A file on the host, which is accessed through the proxy or uhyve.
+pub struct HostFile {
+	/// File descriptor of this file on the host
+	host_fd: i32,
+}
+
+impl HostFile {
+	pub const fn new(host_fd: i32) -> Self {
+		Self { host_fd: host_fd }
+	}
+}
+
+impl ObjectInterface for HostFile {
+	fn read(&self, buf: *mut u8, len: usize) -> isize {
+		syscalls::get_interface().read(self.host_fd, buf, len)
+	}
+
+	fn write(&self, buf: *const u8, len: usize) -> isize {
+		syscalls::get_interface().write(self.host_fd, buf, len)
+	}
+
+	fn lseek(&self, offset: isize, whence: i32) -> isize {
+		syscalls::get_interface().lseek(self.host_fd, offset, whence)
+	}
+
+	fn readv(&self, iov: &[IoVec]) -> isize {
+		syscalls::get_interface().readv(self.host_fd, iov)
+	}
+
+	fn writev(&self, iov: &[IoVec]) -> isize {
+		syscalls::get_interface().writev(self.host_fd, iov)
+	}
+
+	fn pread(&self, buf: *mut u8, len: usize, offset: isize) -> isize {
+		syscalls::get_interface().pread(self.host_fd, buf, len, offset)
+	}
+
+	fn pwrite(&self, buf: *const u8, len: usize, offset: isize) -> isize {
+		syscalls::get_interface().pwrite(self.host_fd, buf, len, offset)
+	}
+
+	fn fstat(&self, stat: &mut Stat) -> i32 {
+		syscalls::get_interface().fstat(self.host_fd, stat)
+	}
+
+	fn getdents(&self, buf: *mut u8, len: usize) -> isize {
+		syscalls::get_interface().getdents(self.host_fd, buf, len)
+	}
+
+	fn close(&self) -> i32 {
+		syscalls::get_interface().close(self.host_fd)
+	}
+}
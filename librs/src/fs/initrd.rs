@@ -0,0 +1,395 @@
+// Copyright (c) 2018 Colin Finck, RWTH Aachen University
+//
+// MIT License
+//
+// Permission is hereby granted, free of charge, to any person obtaining
+// a copy of this software and associated documentation files (the
+// "Software"), to deal in the Software without restriction, including
+// without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to
+// permit persons to whom the Software is furnished to do so, subject to
+// the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE
+// LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION
+// OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
+// WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! A read-only file system for an initrd archive passed as a Multiboot module.
+//! Supported archive formats are "newc" CPIO (as created by `cpio -H newc`) and POSIX tar.
+//! File contents are not copied, but directly referenced in the mapped archive.
+
+use alloc::arc::Arc;
+use alloc::btree_map::*;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::{cmp, ptr, str};
+use errno::*;
+use fs::*;
+use synch::spinlock::Spinlock;
+
+
+/// Magic number at the beginning of a "newc" CPIO header (without and with checksums).
+const CPIO_NEWC_MAGIC: &[u8] = b"070701";
+const CPIO_CRC_MAGIC: &[u8] = b"070702";
+/// Size of a "newc" CPIO header.
+const CPIO_HEADER_SIZE: usize = 110;
+/// Name of the last entry of a CPIO archive.
+const CPIO_TRAILER: &str = "TRAILER!!!";
+
+/// Size of a tar header and the alignment of all file data.
+const TAR_BLOCK_SIZE: usize = 512;
+/// Offset of the "ustar" magic in a tar header.
+const TAR_MAGIC_OFFSET: usize = 257;
+const TAR_TYPE_REGULAR: u8 = b'0';
+const TAR_TYPE_REGULAR_OLD: u8 = 0;
+const TAR_TYPE_DIRECTORY: u8 = b'5';
+
+/// Block size reported by stat().
+const INITRD_BLOCK_SIZE: usize = 4096;
+
+
+struct InitrdNode {
+	ino: usize,
+	mode: u32,
+	data: &'static [u8],
+}
+
+impl InitrdNode {
+	fn stat(&self) -> Stat {
+		Stat {
+			st_ino: self.ino as u64,
+			st_nlink: if self.mode & S_IFMT == S_IFDIR { 2 } else { 1 },
+			st_mode: self.mode,
+			st_size: self.data.len() as i64,
+			st_blksize: INITRD_BLOCK_SIZE as i64,
+			st_blocks: (align_up!(self.data.len(), 512) / 512) as i64,
+			..Default::default()
+		}
+	}
+}
+
+
+/// An open file of the initrd.
+struct InitrdFileObject {
+	data: &'static [u8],
+	stat: Stat,
+	offset: Spinlock<usize>,
+}
+
+impl InitrdFileObject {
+	/// Copies up to `len` bytes starting at `offset` into the buffer and returns the number of copied bytes.
+	fn read_at(&self, buf: *mut u8, len: usize, offset: usize) -> usize {
+		if offset >= self.data.len() {
+			return 0;
+		}
+
+		let count = cmp::min(len, self.data.len() - offset);
+		unsafe { ptr::copy_nonoverlapping(self.data[offset..].as_ptr(), buf, count); }
+		count
+	}
+}
+
+impl ObjectInterface for InitrdFileObject {
+	fn read(&self, buf: *mut u8, len: usize) -> isize {
+		let mut offset = self.offset.lock();
+		let count = self.read_at(buf, len, *offset);
+		*offset += count;
+		count as isize
+	}
+
+	fn write(&self, _buf: *const u8, _len: usize) -> isize {
+		-EBADF as isize
+	}
+
+	fn pread(&self, buf: *mut u8, len: usize, offset: isize) -> isize {
+		if offset < 0 {
+			return -EINVAL as isize;
+		}
+
+		self.read_at(buf, len, offset as usize) as isize
+	}
+
+	fn pwrite(&self, _buf: *const u8, _len: usize, _offset: isize) -> isize {
+		-EBADF as isize
+	}
+
+	fn fstat(&self, stat: &mut Stat) -> i32 {
+		*stat = self.stat;
+		0
+	}
+
+	fn lseek(&self, offset: isize, whence: i32) -> isize {
+		let mut current_offset = self.offset.lock();
+
+		let base = match whence {
+			SEEK_SET => 0,
+			SEEK_CUR => *current_offset as isize,
+			SEEK_END => self.data.len() as isize,
+			_ => return -EINVAL as isize,
+		};
+
+		let new_offset = match base.checked_add(offset) {
+			Some(new_offset) => new_offset,
+			None => return -EOVERFLOW as isize,
+		};
+		if new_offset < 0 {
+			return -EINVAL as isize;
+		}
+
+		*current_offset = new_offset as usize;
+		new_offset
+	}
+}
+
+
+/// Parses a number of the given radix from an ASCII field of an archive header.
+/// Tar fields may be terminated by NUL characters or spaces.
+fn parse_number(field: &[u8], radix: u32) -> Result<usize, ()> {
+	let text = str::from_utf8(field).map_err(|_| ())?;
+	let text = text.trim_matches(|c| c == '\0' || c == ' ');
+	if text.is_empty() {
+		return Ok(0);
+	}
+
+	usize::from_str_radix(text, radix).map_err(|_| ())
+}
+
+/// Parses a NUL-terminated name from an archive header.
+fn parse_name(field: &[u8]) -> Result<&str, ()> {
+	let length = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+	str::from_utf8(&field[..length]).map_err(|_| ())
+}
+
+fn is_cpio_archive(archive: &[u8]) -> bool {
+	archive.starts_with(CPIO_NEWC_MAGIC) || archive.starts_with(CPIO_CRC_MAGIC)
+}
+
+fn is_tar_archive(archive: &[u8]) -> bool {
+	archive.len() >= TAR_MAGIC_OFFSET + 5 && &archive[TAR_MAGIC_OFFSET..TAR_MAGIC_OFFSET + 5] == b"ustar"
+}
+
+/// Checks whether the first sector of an archive identifies it as a supported one.
+pub fn is_archive(first_sector: &[u8]) -> bool {
+	is_cpio_archive(first_sector) || is_tar_archive(first_sector)
+}
+
+
+pub struct Initrd {
+	/// All files and directories by their normalized path relative to the root directory, e.g. "etc/config"
+	nodes: BTreeMap<String, InitrdNode>,
+	next_ino: usize,
+}
+
+impl Initrd {
+	/// Creates the file system from the contents of a CPIO or tar archive.
+	pub fn new(archive: &'static [u8]) -> Result<Self, ()> {
+		let mut initrd = Self { nodes: BTreeMap::new(), next_ino: 1 };
+
+		// The root directory always exists.
+		let ino = initrd.allocate_ino();
+		initrd.nodes.insert(String::new(), InitrdNode { ino: ino, mode: S_IFDIR | 0o555, data: &[] });
+
+		if is_cpio_archive(archive) {
+			initrd.parse_cpio(archive)?;
+		} else if is_tar_archive(archive) {
+			initrd.parse_tar(archive)?;
+		} else {
+			warn!("The initrd is neither a newc CPIO nor a tar archive");
+			return Err(());
+		}
+
+		info!("Found {} files and directories in the initrd", initrd.nodes.len() - 1);
+		Ok(initrd)
+	}
+
+	fn parse_cpio(&mut self, archive: &'static [u8]) -> Result<(), ()> {
+		let mut offset = 0;
+
+		while offset + CPIO_HEADER_SIZE <= archive.len() {
+			let header = &archive[offset..offset + CPIO_HEADER_SIZE];
+			if !header.starts_with(CPIO_NEWC_MAGIC) && !header.starts_with(CPIO_CRC_MAGIC) {
+				warn!("Invalid CPIO header at offset {:#X}", offset);
+				return Err(());
+			}
+
+			let mode = parse_number(&header[14..22], 16)? as u32;
+			let file_size = parse_number(&header[54..62], 16)?;
+			let name_size = parse_number(&header[94..102], 16)?;
+
+			// The name follows the header and the data is aligned to 4 bytes.
+			let name_start = offset + CPIO_HEADER_SIZE;
+			let data_start = align_up!(name_start + name_size, 4);
+			let data_end = data_start + file_size;
+			if name_size == 0 || data_end > archive.len() {
+				warn!("Truncated CPIO entry at offset {:#X}", offset);
+				return Err(());
+			}
+
+			let name = parse_name(&archive[name_start..name_start + name_size])?;
+			if name == CPIO_TRAILER {
+				break;
+			}
+
+			self.add_node(name, mode, &archive[data_start..data_end]);
+			offset = align_up!(data_end, 4);
+		}
+
+		Ok(())
+	}
+
+	fn parse_tar(&mut self, archive: &'static [u8]) -> Result<(), ()> {
+		let mut offset = 0;
+
+		while offset + TAR_BLOCK_SIZE <= archive.len() {
+			let header = &archive[offset..offset + TAR_BLOCK_SIZE];
+
+			// The archive ends with blocks full of zeros.
+			if header.iter().all(|&b| b == 0) {
+				break;
+			}
+
+			let mode = parse_number(&header[100..108], 8)? as u32 & 0o7777;
+			let file_size = parse_number(&header[124..136], 8)?;
+			let typeflag = header[156];
+
+			let data_start = offset + TAR_BLOCK_SIZE;
+			let data_end = data_start + file_size;
+			if data_end > archive.len() {
+				warn!("Truncated tar entry at offset {:#X}", offset);
+				return Err(());
+			}
+
+			// POSIX tar splits long paths into a prefix and a name.
+			let name = parse_name(&header[0..100])?;
+			let prefix = parse_name(&header[345..500])?;
+			let mut path = String::from(prefix);
+			if !path.is_empty() {
+				path.push('/');
+			}
+			path.push_str(name);
+
+			match typeflag {
+				TAR_TYPE_REGULAR | TAR_TYPE_REGULAR_OLD => self.add_node(&path, S_IFREG | mode, &archive[data_start..data_end]),
+				TAR_TYPE_DIRECTORY => self.add_node(&path, S_IFDIR | mode, &[]),
+				_ => debug!("Skipping tar entry \"{}\" of type {}", path, typeflag),
+			}
+
+			offset = data_start + align_up!(file_size, TAR_BLOCK_SIZE);
+		}
+
+		Ok(())
+	}
+
+	fn allocate_ino(&mut self) -> usize {
+		let ino = self.next_ino;
+		self.next_ino += 1;
+		ino
+	}
+
+	fn add_node(&mut self, path: &str, mode: u32, data: &'static [u8]) {
+		// Archive entries are relative to the root directory, e.g. "./etc/config/".
+		let path = normalize_path(path.trim_left_matches('/'));
+		let path = &path as &str;
+		if path.is_empty() || path == ".." || path.starts_with("../") {
+			return;
+		}
+
+		// Only regular files and directories are supported.
+		let file_type = mode & S_IFMT;
+		if file_type != S_IFREG && file_type != S_IFDIR {
+			debug!("Skipping initrd entry \"{}\" with mode {:o}", path, mode);
+			return;
+		}
+
+		// Archives do not necessarily contain entries for all parent directories.
+		let mut index = 0;
+		while let Some(position) = path[index..].find('/') {
+			let parent = &path[..index + position];
+			if !self.nodes.contains_key(parent) {
+				let ino = self.allocate_ino();
+				self.nodes.insert(String::from(parent), InitrdNode { ino: ino, mode: S_IFDIR | 0o555, data: &[] });
+			}
+
+			index += position + 1;
+		}
+
+		// An entry repeated in the archive replaces the previous one, but keeps its inode number.
+		let existing_ino = self.nodes.get(path).map(|node| node.ino);
+		let ino = match existing_ino {
+			Some(ino) => ino,
+			None => self.allocate_ino(),
+		};
+
+		self.nodes.insert(String::from(path), InitrdNode { ino: ino, mode: mode, data: data });
+	}
+}
+
+impl FileSystemInterface for Initrd {
+	fn open(&self, path: &str, flags: i32, _mode: u32) -> Result<Arc<ObjectInterface>, i32> {
+		let node = match self.nodes.get(path) {
+			Some(node) => node,
+			None => return Err(if flags & O_CREAT != 0 { -EROFS } else { -ENOENT }),
+		};
+
+		if flags & O_ACCMODE != O_RDONLY || flags & O_TRUNC != 0 {
+			return Err(-EROFS);
+		}
+
+		if node.mode & S_IFMT == S_IFDIR {
+			return Err(-EISDIR);
+		}
+
+		if flags & O_CREAT != 0 && flags & O_EXCL != 0 {
+			return Err(-EEXIST);
+		}
+
+		Ok(Arc::new(InitrdFileObject { data: node.data, stat: node.stat(), offset: Spinlock::new(0) }))
+	}
+
+	fn stat(&self, path: &str) -> Result<Stat, i32> {
+		self.nodes.get(path).map(|node| node.stat()).ok_or(-ENOENT)
+	}
+
+	fn readdir(&self, path: &str) -> Result<Vec<DirectoryEntry>, i32> {
+		match self.nodes.get(path) {
+			Some(node) if node.mode & S_IFMT == S_IFDIR => {},
+			Some(_) => return Err(-ENOTDIR),
+			None => return Err(-ENOENT),
+		}
+
+		// Direct children are all nodes below the directory without a further slash.
+		let prefix_len = if path.is_empty() { 0 } else { path.len() + 1 };
+		let entries = self.nodes.iter()
+			.filter(|&(child_path, _)| {
+				child_path.len() > prefix_len
+					&& child_path.starts_with(path)
+					&& (path.is_empty() || child_path[path.len()..].starts_with('/'))
+					&& !child_path[prefix_len..].contains('/')
+			})
+			.map(|(child_path, node)| DirectoryEntry {
+				ino: node.ino as u64,
+				file_type: if node.mode & S_IFMT == S_IFDIR { DT_DIR } else { DT_REG },
+				name: String::from(&child_path[prefix_len..]),
+			})
+			.collect();
+
+		Ok(entries)
+	}
+
+	fn access(&self, path: &str, mode: i32) -> Result<(), i32> {
+		self.stat(path)?;
+
+		if mode & W_OK != 0 {
+			Err(-EROFS)
+		} else {
+			Ok(())
+		}
+	}
+}
@@ -0,0 +1,538 @@
+// Copyright (c) 2018 Colin Finck, RWTH Aachen University
+//
+// MIT License
+//
+// Permission is hereby granted, free of charge, to any person obtaining
+// a copy of this software and associated documentation files (the
+// "Software"), to deal in the Software without restriction, including
+// without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to
+// permit persons to whom the Software is furnished to do so, subject to
+// the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE
+// LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION
+// OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
+// WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! Virtual File System layer of HermitCore.
+//!
+//! Every file descriptor of the application refers to an object implementing the ObjectInterface trait.
+//! The system calls look up the object in the descriptor table and let it handle the request,
+//! so they don't need to know whether a descriptor refers to the console, a file on the host, or a socket.
+//!
+//! File systems implemented in the kernel (like the tmpfs) are mounted at a path.
+//! Paths below a mount point are handled by the mounted file system, all other paths by the host.
+
+mod console;
+mod directory;
+mod hostfile;
+mod initrd;
+#[cfg(not(feature = "smoltcp"))]
+mod socket;
+mod tmpfs;
+
+pub use self::console::Console;
+pub use self::directory::{DirectoryEntry, DirectoryObject};
+pub use self::hostfile::HostFile;
+pub use self::initrd::Initrd;
+#[cfg(not(feature = "smoltcp"))]
+pub use self::socket::Socket;
+pub use self::tmpfs::Tmpfs;
+use alloc::arc::Arc;
+use alloc::btree_map::*;
+use alloc::string::String;
+use alloc::vec::Vec;
+use arch;
+use arch::mm::paging::{BasePageSize, PageSize, PageTableEntryFlags};
+use core::{slice, str};
+use drivers::block::{self, BlockDeviceInterface, SECTOR_SIZE};
+use environment;
+use errno::*;
+use mm;
+use synch::spinlock::Spinlock;
+#[cfg(not(feature = "smoltcp"))]
+use syscalls::LWIP_FD_BIT;
+
+
+/// Standard descriptors installed at boot.
+const STDIN_FILENO: i32 = 0;
+const STDOUT_FILENO: i32 = 1;
+const STDERR_FILENO: i32 = 2;
+
+/// Maximum length of a path including the terminating NUL character.
+const PATH_MAX: usize = 4096;
+
+/// Disks larger than this are not read into memory to be mounted as an archive.
+const DISK_ARCHIVE_MAX_SIZE: usize = 64 * 1024 * 1024;
+/// Number of bytes read from a disk at once.
+const DISK_READ_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Maximum number of buffers passed to readv() and writev().
+pub const IOV_MAX: usize = 1024;
+
+// Flags for open() as used by the C library.
+// They are identical to the Linux ones, because they are passed unmodified to the host.
+pub const O_ACCMODE: i32 = 0o0000003;
+pub const O_RDONLY: i32 = 0o0000000;
+pub const O_WRONLY: i32 = 0o0000001;
+pub const O_RDWR: i32 = 0o0000002;
+pub const O_CREAT: i32 = 0o0000100;
+pub const O_EXCL: i32 = 0o0000200;
+pub const O_TRUNC: i32 = 0o0001000;
+pub const O_APPEND: i32 = 0o0002000;
+pub const O_DIRECTORY: i32 = 0o0200000;
+
+// File types in the st_mode field of Stat.
+pub const S_IFMT: u32 = 0o170000;
+pub const S_IFSOCK: u32 = 0o140000;
+pub const S_IFREG: u32 = 0o100000;
+pub const S_IFDIR: u32 = 0o040000;
+pub const S_IFCHR: u32 = 0o020000;
+
+// File types in the d_type field of a directory entry.
+pub const DT_UNKNOWN: u8 = 0;
+pub const DT_DIR: u8 = 4;
+pub const DT_REG: u8 = 8;
+
+// Permissions checked by access().
+pub const F_OK: i32 = 0;
+pub const X_OK: i32 = 1;
+pub const W_OK: i32 = 2;
+pub const R_OK: i32 = 4;
+
+// Reference points for lseek().
+pub const SEEK_SET: i32 = 0;
+pub const SEEK_CUR: i32 = 1;
+pub const SEEK_END: i32 = 2;
+
+
+/// File status as returned by stat().
+/// This is the layout of "struct stat" on x86_64 Linux, which is also used by the host.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Stat {
+	pub st_dev: u64,
+	pub st_ino: u64,
+	pub st_nlink: u64,
+	pub st_mode: u32,
+	pub st_uid: u32,
+	pub st_gid: u32,
+	pub __pad0: i32,
+	pub st_rdev: u64,
+	pub st_size: i64,
+	pub st_blksize: i64,
+	pub st_blocks: i64,
+	pub st_atime: i64,
+	pub st_atime_nsec: i64,
+	pub st_mtime: i64,
+	pub st_mtime_nsec: i64,
+	pub st_ctime: i64,
+	pub st_ctime_nsec: i64,
+	pub __unused: [i64; 3],
+}
+
+/// A buffer for vectored I/O as in "struct iovec".
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct IoVec {
+	pub iov_base: *mut u8,
+	pub iov_len: usize,
+}
+
+/// An IPv4 address and port of a socket.
+#[derive(Clone, Copy, Debug)]
+pub struct SocketAddress {
+	/// Address in network byte order, all zeros for any address
+	pub address: [u8; 4],
+	pub port: u16,
+}
+
+/// A file system mounted at a path.
+struct MountPoint {
+	/// Absolute path of the mount point without a trailing slash
+	path: String,
+	fs: Arc<FileSystemInterface>,
+}
+
+
+lazy_static! {
+	/// The descriptor table of the application, mapping file descriptors to objects.
+	static ref OBJECTS: Spinlock<BTreeMap<i32, Arc<ObjectInterface>>> = {
+		let mut objects: BTreeMap<i32, Arc<ObjectInterface>> = BTreeMap::new();
+		objects.insert(STDIN_FILENO, Arc::new(Console::new(STDIN_FILENO)));
+		objects.insert(STDOUT_FILENO, Arc::new(Console::new(STDOUT_FILENO)));
+		objects.insert(STDERR_FILENO, Arc::new(Console::new(STDERR_FILENO)));
+		Spinlock::new(objects)
+	};
+
+	/// All mounted file systems.
+	static ref MOUNTS: Spinlock<Vec<MountPoint>> = Spinlock::new(Vec::new());
+}
+
+
+/// Operations supported by an object referenced through a file descriptor.
+/// Objects use interior mutability, because they may be shared between several tasks.
+pub trait ObjectInterface : Send + Sync {
+	fn read(&self, _buf: *mut u8, _len: usize) -> isize {
+		-EINVAL as isize
+	}
+
+	fn write(&self, _buf: *const u8, _len: usize) -> isize {
+		-EINVAL as isize
+	}
+
+	fn lseek(&self, _offset: isize, _whence: i32) -> isize {
+		-ESPIPE as isize
+	}
+
+	/// Reads into several buffers, one after another.
+	fn readv(&self, iov: &[IoVec]) -> isize {
+		transfer_vectored(iov, |buf, len| self.read(buf, len))
+	}
+
+	/// Writes several buffers, one after another.
+	fn writev(&self, iov: &[IoVec]) -> isize {
+		transfer_vectored(iov, |buf, len| self.write(buf, len))
+	}
+
+	/// Reads from the given offset without changing the current file offset.
+	fn pread(&self, _buf: *mut u8, _len: usize, _offset: isize) -> isize {
+		-ESPIPE as isize
+	}
+
+	/// Writes at the given offset without changing the current file offset.
+	fn pwrite(&self, _buf: *const u8, _len: usize, _offset: isize) -> isize {
+		-ESPIPE as isize
+	}
+
+	/// Returns information about the object.
+	fn fstat(&self, _stat: &mut Stat) -> i32 {
+		-EINVAL
+	}
+
+	/// Fills the buffer with "struct linux_dirent64" records for the next entries of a directory
+	/// and returns the number of bytes written (0 at the end of the directory).
+	fn getdents(&self, _buf: *mut u8, _len: usize) -> isize {
+		-ENOTDIR as isize
+	}
+
+	/// Truncates or extends the object to the given length.
+	fn truncate(&self, _length: usize) -> i32 {
+		-EINVAL
+	}
+
+	/// Called when the file descriptor referring to this object is removed from the descriptor table.
+	fn close(&self) -> i32 {
+		0
+	}
+
+	/// Assigns a local address to a socket.
+	fn bind(&self, _address: SocketAddress) -> i32 {
+		-ENOTSOCK
+	}
+
+	/// Lets a socket accept incoming connections.
+	fn listen(&self, _backlog: i32) -> i32 {
+		-ENOTSOCK
+	}
+
+	/// Waits for an incoming connection on a listening socket.
+	/// Returns the socket of the new connection and the address of the peer.
+	fn accept(&self) -> Result<(Arc<ObjectInterface>, SocketAddress), i32> {
+		Err(-ENOTSOCK)
+	}
+
+	/// Connects a socket to the given address and waits until the connection has been established.
+	fn connect(&self, _address: SocketAddress) -> i32 {
+		-ENOTSOCK
+	}
+}
+
+/// Operations supported by a file system mounted into the VFS.
+/// All paths are relative to the mount point and never begin with a slash.
+pub trait FileSystemInterface : Send + Sync {
+	fn open(&self, path: &str, flags: i32, mode: u32) -> Result<Arc<ObjectInterface>, i32>;
+	fn stat(&self, path: &str) -> Result<Stat, i32>;
+
+	/// Like stat, but does not follow a symbolic link at the end of the path.
+	/// The default implementation is suitable for all file systems without symbolic links.
+	fn lstat(&self, path: &str) -> Result<Stat, i32> {
+		self.stat(path)
+	}
+
+	/// Returns the entries of a directory, excluding "." and "..".
+	fn readdir(&self, _path: &str) -> Result<Vec<DirectoryEntry>, i32> {
+		Err(-ENOTDIR)
+	}
+
+	/// Checks whether the path exists and may be accessed with the given permissions.
+	/// The kernel does not know about users, so only the existence of the path is checked by default.
+	fn access(&self, path: &str, _mode: i32) -> Result<(), i32> {
+		self.stat(path).map(|_| ())
+	}
+
+	fn unlink(&self, _path: &str) -> Result<(), i32> {
+		Err(-EROFS)
+	}
+
+	fn mkdir(&self, _path: &str, _mode: u32) -> Result<(), i32> {
+		Err(-EROFS)
+	}
+
+	fn rmdir(&self, _path: &str) -> Result<(), i32> {
+		Err(-EROFS)
+	}
+
+	fn rename(&self, _old_path: &str, _new_path: &str) -> Result<(), i32> {
+		Err(-EROFS)
+	}
+
+	fn truncate(&self, _path: &str, _length: usize) -> Result<(), i32> {
+		Err(-EROFS)
+	}
+}
+
+
+/// Mounts the given file system at an absolute path.
+pub fn mount(path: &str, fs: Arc<FileSystemInterface>) -> Result<(), i32> {
+	if !path.starts_with('/') {
+		return Err(-EINVAL);
+	}
+
+	// Mounting at the root directory results in an empty path, which matches every absolute path.
+	let trimmed_path = path.trim_right_matches('/');
+
+	let mut mounts = MOUNTS.lock();
+	if mounts.iter().any(|m| m.path == trimmed_path) {
+		return Err(-EBUSY);
+	}
+
+	info!("Mounting a file system at \"{}\"", path);
+	mounts.push(MountPoint { path: String::from(trimmed_path), fs: fs });
+	Ok(())
+}
+
+/// Converts a path into its normalized form by dropping empty and "." components and resolving ".." components.
+/// For example, "/a//b/./c/../d/" becomes "/a/b/d". Relative paths stay relative.
+/// This is done once in the VFS, so that the file systems only see normalized paths.
+pub fn normalize_path(path: &str) -> String {
+	let mut components: Vec<&str> = Vec::new();
+
+	for component in path.split('/') {
+		match component {
+			"" | "." => {},
+			".." => {
+				// Going up from the root directory stays in the root directory.
+				if components.pop().is_none() && !path.starts_with('/') {
+					components.push(component);
+				}
+			},
+			_ => components.push(component),
+		}
+	}
+
+	let mut normalized = String::with_capacity(path.len());
+	for (i, component) in components.iter().enumerate() {
+		if i > 0 || path.starts_with('/') {
+			normalized.push('/');
+		}
+
+		normalized.push_str(component);
+	}
+
+	if normalized.is_empty() && path.starts_with('/') {
+		normalized.push('/');
+	}
+
+	normalized
+}
+
+/// Returns the file system responsible for the given absolute path and the path relative to its mount point.
+/// Returns None if the path is not handled by a file system in the kernel.
+pub fn find_mount(path: &str) -> Option<(Arc<FileSystemInterface>, String)> {
+	let path = normalize_path(path);
+	let mounts = MOUNTS.lock();
+
+	// The longest matching mount point wins, so that file systems can be mounted within others.
+	mounts.iter()
+		.filter(|m| path == m.path || (path.starts_with(&m.path as &str) && path[m.path.len()..].starts_with('/')))
+		.max_by_key(|m| m.path.len())
+		.map(|m| (m.fs.clone(), String::from(path[m.path.len()..].trim_matches('/'))))
+}
+
+/// Transfers the buffers one after another using `f` until all buffers have been transferred or `f` returns less than requested.
+/// This is the fallback for objects and interfaces without native support for vectored I/O.
+pub fn transfer_vectored<F>(iov: &[IoVec], mut f: F) -> isize where F: FnMut(*mut u8, usize) -> isize {
+	let mut total: isize = 0;
+
+	for vec in iov {
+		if vec.iov_len == 0 {
+			continue;
+		}
+
+		let ret = f(vec.iov_base, vec.iov_len);
+		if ret < 0 {
+			// Report an error only if nothing has been transferred yet.
+			return if total > 0 { total } else { ret };
+		}
+
+		total += ret;
+		if (ret as usize) < vec.iov_len {
+			break;
+		}
+	}
+
+	total
+}
+
+/// Converts a NUL-terminated path passed by the application into a string slice.
+pub unsafe fn path_from_c_str<'a>(path: *const u8) -> Result<&'a str, i32> {
+	if path.is_null() {
+		return Err(-EFAULT);
+	}
+
+	let mut len = 0;
+	while *path.offset(len as isize) != 0 {
+		len += 1;
+		if len == PATH_MAX {
+			return Err(-ENAMETOOLONG);
+		}
+	}
+
+	str::from_utf8(slice::from_raw_parts(path, len)).map_err(|_| -EINVAL)
+}
+
+/// Reads a disk containing a CPIO or tar archive into memory, which is never freed like the one of an initrd.
+/// Returns None if the disk contains no archive or is too large.
+fn read_disk_archive(disk: &BlockDeviceInterface) -> Option<&'static [u8]> {
+	let mut first_sector = [0u8; SECTOR_SIZE];
+	disk.read(0, &mut first_sector).ok()?;
+	if !initrd::is_archive(&first_sector) {
+		return None;
+	}
+
+	let size = (disk.capacity() as usize).checked_mul(SECTOR_SIZE)?;
+	if size > DISK_ARCHIVE_MAX_SIZE {
+		warn!("The archive on the disk is larger than {} bytes and not mounted", DISK_ARCHIVE_MAX_SIZE);
+		return None;
+	}
+
+	let allocated_size = align_up!(size, BasePageSize::SIZE);
+	let address = mm::allocate(allocated_size, PageTableEntryFlags::EXECUTE_DISABLE);
+	let archive = unsafe { slice::from_raw_parts_mut(address as *mut u8, size) };
+
+	for (i, chunk) in archive.chunks_mut(DISK_READ_CHUNK_SIZE).enumerate() {
+		let sector = (i * DISK_READ_CHUNK_SIZE / SECTOR_SIZE) as u64;
+		if let Err(e) = disk.read(sector, chunk) {
+			warn!("Unable to read the archive from the disk (error code {})", e);
+			mm::deallocate(address, allocated_size);
+			return None;
+		}
+	}
+
+	Some(archive)
+}
+
+/// Mounts the file systems provided by the kernel.
+pub fn init() {
+	let has_host = environment::is_proxy() || environment::is_uhyve();
+	let mut has_root = has_host;
+
+	// An initrd becomes the root file system if there is no host to access files on.
+	// Otherwise, it is mounted below the host's file system.
+	if let Some(archive) = arch::mm::map_initrd() {
+		if let Ok(initrd) = Initrd::new(archive) {
+			let path = if has_root { "/initrd" } else { "/" };
+			mount(path, Arc::new(initrd)).unwrap();
+			has_root = true;
+		}
+	}
+
+	// A disk image containing an archive is mounted like an initrd.
+	if let Some(disk) = block::get("vda") {
+		if let Some(archive) = read_disk_archive(&*disk) {
+			if let Ok(initrd) = Initrd::new(archive) {
+				let path = if has_root { "/disk" } else { "/" };
+				mount(path, Arc::new(initrd)).unwrap();
+			}
+		}
+	}
+
+	mount("/tmp", Arc::new(Tmpfs::new())).unwrap();
+}
+
+
+/// Returns whether the file descriptor refers to a socket created by lwIP.
+#[cfg(not(feature = "smoltcp"))]
+fn is_lwip_fd(fd: i32) -> bool {
+	fd & LWIP_FD_BIT != 0
+}
+
+/// Without lwIP, all sockets are created through the descriptor table.
+#[cfg(feature = "smoltcp")]
+fn is_lwip_fd(_fd: i32) -> bool {
+	false
+}
+
+/// Adds an object to the descriptor table and returns the lowest available file descriptor for it.
+pub fn insert_object(object: Arc<ObjectInterface>) -> Result<i32, i32> {
+	let mut objects = OBJECTS.lock();
+
+	// The table is sorted, so the first gap in the sequence of descriptors is the lowest free one.
+	let mut fd = 0;
+	for &key in objects.keys() {
+		if key != fd {
+			break;
+		}
+
+		fd += 1;
+	}
+
+	if is_lwip_fd(fd) {
+		return Err(-EMFILE);
+	}
+
+	objects.insert(fd, object);
+	Ok(fd)
+}
+
+/// Adds an object to the descriptor table under a file descriptor chosen by someone else (like lwIP).
+pub fn insert_object_at(fd: i32, object: Arc<ObjectInterface>) -> Result<(), i32> {
+	let mut objects = OBJECTS.lock();
+
+	if objects.contains_key(&fd) {
+		return Err(-EBUSY);
+	}
+
+	objects.insert(fd, object);
+	Ok(())
+}
+
+/// Returns the object referenced by the given file descriptor.
+pub fn get_object(fd: i32) -> Result<Arc<ObjectInterface>, i32> {
+	OBJECTS.lock().get(&fd).cloned().ok_or(-EBADF)
+}
+
+/// Removes the given file descriptor from the descriptor table without closing the object,
+/// which has already been closed by other means.
+#[cfg(not(feature = "smoltcp"))]
+pub fn remove_object(fd: i32) -> Option<Arc<ObjectInterface>> {
+	OBJECTS.lock().remove(&fd)
+}
+
+/// Removes the given file descriptor from the descriptor table and closes the object.
+pub fn close_object(fd: i32) -> i32 {
+	let object = match OBJECTS.lock().remove(&fd) {
+		Some(object) => object,
+		None => return -EBADF,
+	};
+
+	object.close()
+}
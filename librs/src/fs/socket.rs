@@ -0,0 +1,92 @@
+// Copyright (c) 2018 Colin Finck, RWTH Aachen University
+//
+// MIT License
+//
+// Permission is hereby granted, free of charge, to any person obtaining
+// a copy of this software and associated documentation files (the
+// "Software"), to deal in the Software without restriction, including
+// without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to
+// permit persons to whom the Software is furnished to do so, subject to
+// the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE
+// LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION
+// OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
+// WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+use fs::{ObjectInterface, Stat, S_IFSOCK};
+use syscalls::LWIP_LOCK;
+use syscalls::{lwip_close_unregistered, sys_lwip_get_errno};
+
+extern "C" {
+	fn lwip_write(fd: i32, buf: *const u8, len: usize) -> i32;
+	fn lwip_read(fd: i32, buf: *mut u8, len: usize) -> i32;
+}
+
+
+/// A socket of the lwIP TCP/IP stack.
+pub struct Socket {
+	/// Socket descriptor within lwIP (without LWIP_FD_BIT)
+	lwip_fd: i32,
+}
+
+impl Socket {
+	pub const fn new(lwip_fd: i32) -> Self {
+		Self { lwip_fd: lwip_fd }
+	}
+}
+
+impl ObjectInterface for Socket {
+	fn read(&self, buf: *mut u8, len: usize) -> isize {
+		// take lock to protect LwIP
+		let _guard = LWIP_LOCK.lock();
+
+		let ret = unsafe { lwip_read(self.lwip_fd, buf, len) };
+		if ret < 0 {
+			return -sys_lwip_get_errno() as isize;
+		}
+
+		ret as isize
+	}
+
+	fn write(&self, buf: *const u8, len: usize) -> isize {
+		// take lock to protect LwIP
+		let _guard = LWIP_LOCK.lock();
+
+		let ret = unsafe { lwip_write(self.lwip_fd, buf, len) };
+		if ret < 0 {
+			return -sys_lwip_get_errno() as isize;
+		}
+
+		ret as isize
+	}
+
+	fn fstat(&self, stat: &mut Stat) -> i32 {
+		*stat = Stat {
+			st_mode: S_IFSOCK | 0o777,
+			st_nlink: 1,
+			..Default::default()
+		};
+		0
+	}
+
+	fn close(&self) -> i32 {
+		// take lock to protect LwIP
+		let _guard = LWIP_LOCK.lock();
+
+		// The descriptor has already been removed, so lwIP is called directly instead of through lwip_close().
+		let ret = unsafe { lwip_close_unregistered(self.lwip_fd) };
+		if ret < 0 {
+			return -sys_lwip_get_errno();
+		}
+
+		ret
+	}
+}
@@ -0,0 +1,516 @@
+// Copyright (c) 2018 Colin Finck, RWTH Aachen University
+//
+// MIT License
+//
+// Permission is hereby granted, free of charge, to any person obtaining
+// a copy of this software and associated documentation files (the
+// "Software"), to deal in the Software without restriction, including
+// without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to
+// permit persons to whom the Software is furnished to do so, subject to
+// the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE
+// LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION
+// OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
+// WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! A file system keeping all files and directories in memory.
+//! Its contents are lost when the application exits.
+
+use alloc::arc::Arc;
+use alloc::btree_map::*;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::{cmp, isize, ptr};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use errno::*;
+use fs::*;
+use mm::stats;
+use synch::spinlock::Spinlock;
+
+
+/// Block size reported by stat().
+const TMPFS_BLOCK_SIZE: usize = 4096;
+
+/// Files must stay addressable by the signed offsets of lseek() and pwrite().
+const TMPFS_MAX_FILE_SIZE: usize = isize::MAX as usize;
+
+
+/// A regular file in the tmpfs.
+/// Open file objects keep a reference, so that the contents survive an unlink until the file is closed.
+struct TmpFile {
+	ino: usize,
+	mode: u32,
+	data: Spinlock<Vec<u8>>,
+}
+
+impl TmpFile {
+	fn stat(&self) -> Stat {
+		let size = self.data.lock().len();
+
+		Stat {
+			st_ino: self.ino as u64,
+			st_nlink: 1,
+			st_mode: S_IFREG | self.mode,
+			st_size: size as i64,
+			st_blksize: TMPFS_BLOCK_SIZE as i64,
+			st_blocks: (align_up!(size, TMPFS_BLOCK_SIZE) / 512) as i64,
+			..Default::default()
+		}
+	}
+
+	fn truncate(&self, length: usize) -> Result<(), i32> {
+		if length > TMPFS_MAX_FILE_SIZE {
+			return Err(-EFBIG);
+		}
+
+		resize(&mut self.data.lock(), length)
+	}
+}
+
+struct TmpDirectory {
+	ino: usize,
+	mode: u32,
+	entries: BTreeMap<String, TmpNode>,
+}
+
+impl TmpDirectory {
+	fn stat(&self) -> Stat {
+		Stat {
+			st_ino: self.ino as u64,
+			st_nlink: 2,
+			st_mode: S_IFDIR | self.mode,
+			st_blksize: TMPFS_BLOCK_SIZE as i64,
+			..Default::default()
+		}
+	}
+}
+
+enum TmpNode {
+	File(Arc<TmpFile>),
+	Directory(TmpDirectory),
+}
+
+
+/// An open file of the tmpfs.
+struct TmpFileObject {
+	file: Arc<TmpFile>,
+	flags: i32,
+	offset: Spinlock<usize>,
+}
+
+impl ObjectInterface for TmpFileObject {
+	fn read(&self, buf: *mut u8, len: usize) -> isize {
+		if self.flags & O_ACCMODE == O_WRONLY {
+			return -EBADF as isize;
+		}
+
+		let mut offset = self.offset.lock();
+		let count = read_at(&self.file.data.lock(), buf, len, *offset);
+		*offset += count;
+		count as isize
+	}
+
+	fn write(&self, buf: *const u8, len: usize) -> isize {
+		if self.flags & O_ACCMODE == O_RDONLY {
+			return -EBADF as isize;
+		}
+
+		// The number of written bytes must fit into the return value.
+		if len > isize::MAX as usize {
+			return -EINVAL as isize;
+		}
+
+		let mut offset = self.offset.lock();
+		let mut data = self.file.data.lock();
+		if self.flags & O_APPEND != 0 {
+			*offset = data.len();
+		}
+
+		if let Err(e) = write_at(&mut data, buf, len, *offset) {
+			return e as isize;
+		}
+
+		*offset += len;
+		len as isize
+	}
+
+	fn pread(&self, buf: *mut u8, len: usize, offset: isize) -> isize {
+		if self.flags & O_ACCMODE == O_WRONLY {
+			return -EBADF as isize;
+		}
+
+		if offset < 0 {
+			return -EINVAL as isize;
+		}
+
+		read_at(&self.file.data.lock(), buf, len, offset as usize) as isize
+	}
+
+	fn pwrite(&self, buf: *const u8, len: usize, offset: isize) -> isize {
+		if self.flags & O_ACCMODE == O_RDONLY {
+			return -EBADF as isize;
+		}
+
+		if offset < 0 || len > isize::MAX as usize {
+			return -EINVAL as isize;
+		}
+
+		// Like on Linux, O_APPEND makes pwrite append the data regardless of the offset.
+		let mut data = self.file.data.lock();
+		let offset = if self.flags & O_APPEND != 0 { data.len() } else { offset as usize };
+		match write_at(&mut data, buf, len, offset) {
+			Ok(()) => len as isize,
+			Err(e) => e as isize,
+		}
+	}
+
+	fn lseek(&self, offset: isize, whence: i32) -> isize {
+		let mut current_offset = self.offset.lock();
+
+		let base = match whence {
+			SEEK_SET => 0,
+			SEEK_CUR => *current_offset as isize,
+			SEEK_END => self.file.data.lock().len() as isize,
+			_ => return -EINVAL as isize,
+		};
+
+		let new_offset = match base.checked_add(offset) {
+			Some(new_offset) => new_offset,
+			None => return -EOVERFLOW as isize,
+		};
+
+		if new_offset < 0 {
+			return -EINVAL as isize;
+		}
+
+		*current_offset = new_offset as usize;
+		new_offset
+	}
+
+	fn fstat(&self, stat: &mut Stat) -> i32 {
+		*stat = self.file.stat();
+		0
+	}
+
+	fn truncate(&self, length: usize) -> i32 {
+		if self.flags & O_ACCMODE == O_RDONLY {
+			return -EBADF;
+		}
+
+		match self.file.truncate(length) {
+			Ok(()) => 0,
+			Err(e) => e,
+		}
+	}
+}
+
+
+/// Copies up to `len` bytes starting at `offset` from the file contents into the buffer.
+/// Returns the number of copied bytes, which is 0 at the end of the file.
+fn read_at(data: &[u8], buf: *mut u8, len: usize, offset: usize) -> usize {
+	if offset >= data.len() {
+		return 0;
+	}
+
+	let count = cmp::min(len, data.len() - offset);
+	unsafe { ptr::copy_nonoverlapping(data[offset..].as_ptr(), buf, count); }
+	count
+}
+
+/// Resizes the file contents to `length` bytes and fills new space with zeros.
+/// Growth that does not fit into the free physical memory fails with ENOSPC instead of panicking the kernel.
+fn resize(data: &mut Vec<u8>, length: usize) -> Result<(), i32> {
+	if length > data.capacity() {
+		// The contents may be copied into a new buffer of the full length before the old one is freed.
+		let info = stats::get();
+		if length > info.physical_total.saturating_sub(info.physical_used) {
+			return Err(-ENOSPC);
+		}
+
+		let additional = length - data.len();
+		data.reserve_exact(additional);
+	}
+
+	data.resize(length, 0);
+	Ok(())
+}
+
+/// Copies `len` bytes from the buffer into the file contents at `offset`.
+/// Writing behind the end of the file fills the gap with zeros.
+fn write_at(data: &mut Vec<u8>, buf: *const u8, len: usize, offset: usize) -> Result<(), i32> {
+	let end = match offset.checked_add(len) {
+		Some(end) if end <= TMPFS_MAX_FILE_SIZE => end,
+		_ => return Err(-EFBIG),
+	};
+
+	if end > data.len() {
+		resize(data, end)?;
+	}
+
+	unsafe { ptr::copy_nonoverlapping(buf, data[offset..].as_mut_ptr(), len); }
+	Ok(())
+}
+
+/// Splits a path relative to the mount point into the path of the parent directory and the name of the last component.
+fn split_parent(path: &str) -> Result<(&str, &str), i32> {
+	match path.rfind('/') {
+		Some(index) => Ok((&path[..index], &path[index + 1..])),
+		None if !path.is_empty() => Ok(("", path)),
+		None => Err(-EEXIST),
+	}
+}
+
+/// Checks a name for a new directory entry.
+fn check_name(name: &str) -> Result<(), i32> {
+	if name.is_empty() || name == "." || name == ".." {
+		Err(-EINVAL)
+	} else {
+		Ok(())
+	}
+}
+
+
+pub struct Tmpfs {
+	root: Spinlock<TmpDirectory>,
+	next_ino: AtomicUsize,
+}
+
+impl Tmpfs {
+	pub fn new() -> Self {
+		Self {
+			root: Spinlock::new(TmpDirectory { ino: 1, mode: 0o777, entries: BTreeMap::new() }),
+			next_ino: AtomicUsize::new(2),
+		}
+	}
+
+	fn allocate_ino(&self) -> usize {
+		self.next_ino.fetch_add(1, Ordering::SeqCst)
+	}
+
+	/// Walks the path starting at the given directory and returns the directory it refers to.
+	fn find_directory<'a>(directory: &'a mut TmpDirectory, path: &str) -> Result<&'a mut TmpDirectory, i32> {
+		let mut current = directory;
+
+		for component in path.split('/').filter(|c| !c.is_empty() && *c != ".") {
+			if component == ".." {
+				// Keeping track of parents would require references in both directions.
+				// Applications should pass normalized paths anyway.
+				return Err(-EINVAL);
+			}
+
+			current = match { current }.entries.get_mut(component) {
+				Some(&mut TmpNode::Directory(ref mut subdirectory)) => subdirectory,
+				Some(&mut TmpNode::File(_)) => return Err(-ENOTDIR),
+				None => return Err(-ENOENT),
+			};
+		}
+
+		Ok(current)
+	}
+}
+
+impl FileSystemInterface for Tmpfs {
+	fn open(&self, path: &str, flags: i32, mode: u32) -> Result<Arc<ObjectInterface>, i32> {
+		let mut root = self.root.lock();
+
+		if path.is_empty() {
+			// This is the root directory of the tmpfs, which cannot be opened as a file.
+			return Err(-EISDIR);
+		}
+
+		let (parent_path, name) = split_parent(path)?;
+		let parent = Tmpfs::find_directory(&mut root, parent_path)?;
+
+		let file = match parent.entries.get(name) {
+			Some(&TmpNode::File(ref file)) => {
+				if flags & O_CREAT != 0 && flags & O_EXCL != 0 {
+					return Err(-EEXIST);
+				}
+
+				if flags & O_DIRECTORY != 0 {
+					return Err(-ENOTDIR);
+				}
+
+				Some(file.clone())
+			},
+			Some(&TmpNode::Directory(_)) => return Err(-EISDIR),
+			None => None,
+		};
+
+		let file = match file {
+			Some(file) => {
+				if flags & O_TRUNC != 0 && flags & O_ACCMODE != O_RDONLY {
+					file.truncate(0)?;
+				}
+
+				file
+			},
+			None => {
+				if flags & O_CREAT == 0 {
+					return Err(-ENOENT);
+				}
+
+				check_name(name)?;
+				let file = Arc::new(TmpFile {
+					ino: self.allocate_ino(),
+					mode: mode & 0o7777,
+					data: Spinlock::new(Vec::new()),
+				});
+				parent.entries.insert(String::from(name), TmpNode::File(file.clone()));
+				file
+			}
+		};
+
+		Ok(Arc::new(TmpFileObject { file: file, flags: flags, offset: Spinlock::new(0) }))
+	}
+
+	fn stat(&self, path: &str) -> Result<Stat, i32> {
+		let mut root = self.root.lock();
+
+		if path.is_empty() {
+			return Ok(root.stat());
+		}
+
+		let (parent_path, name) = split_parent(path)?;
+		let parent = Tmpfs::find_directory(&mut root, parent_path)?;
+
+		match parent.entries.get(name) {
+			Some(&TmpNode::File(ref file)) => Ok(file.stat()),
+			Some(&TmpNode::Directory(ref directory)) => Ok(directory.stat()),
+			None => Err(-ENOENT),
+		}
+	}
+
+	fn readdir(&self, path: &str) -> Result<Vec<DirectoryEntry>, i32> {
+		let mut root = self.root.lock();
+		let directory = Tmpfs::find_directory(&mut root, path)?;
+
+		let entries = directory.entries.iter().map(|(name, node)| {
+			let (ino, file_type) = match *node {
+				TmpNode::File(ref file) => (file.ino, DT_REG),
+				TmpNode::Directory(ref subdirectory) => (subdirectory.ino, DT_DIR),
+			};
+
+			DirectoryEntry { ino: ino as u64, file_type: file_type, name: name.clone() }
+		}).collect();
+
+		Ok(entries)
+	}
+
+	fn unlink(&self, path: &str) -> Result<(), i32> {
+		let mut root = self.root.lock();
+		let (parent_path, name) = split_parent(path).map_err(|_| -EISDIR)?;
+		let parent = Tmpfs::find_directory(&mut root, parent_path)?;
+
+		match parent.entries.get(name) {
+			Some(&TmpNode::File(_)) => {},
+			Some(&TmpNode::Directory(_)) => return Err(-EISDIR),
+			None => return Err(-ENOENT),
+		}
+
+		parent.entries.remove(name);
+		Ok(())
+	}
+
+	fn mkdir(&self, path: &str, mode: u32) -> Result<(), i32> {
+		let mut root = self.root.lock();
+		let (parent_path, name) = split_parent(path)?;
+		let parent = Tmpfs::find_directory(&mut root, parent_path)?;
+
+		check_name(name)?;
+		if parent.entries.contains_key(name) {
+			return Err(-EEXIST);
+		}
+
+		let directory = TmpDirectory { ino: self.allocate_ino(), mode: mode & 0o7777, entries: BTreeMap::new() };
+		parent.entries.insert(String::from(name), TmpNode::Directory(directory));
+		Ok(())
+	}
+
+	fn rmdir(&self, path: &str) -> Result<(), i32> {
+		let mut root = self.root.lock();
+		let (parent_path, name) = split_parent(path).map_err(|_| -EBUSY)?;
+		let parent = Tmpfs::find_directory(&mut root, parent_path)?;
+
+		match parent.entries.get(name) {
+			Some(&TmpNode::Directory(ref directory)) => {
+				if !directory.entries.is_empty() {
+					return Err(-ENOTEMPTY);
+				}
+			},
+			Some(&TmpNode::File(_)) => return Err(-ENOTDIR),
+			None => return Err(-ENOENT),
+		}
+
+		parent.entries.remove(name);
+		Ok(())
+	}
+
+	fn rename(&self, old_path: &str, new_path: &str) -> Result<(), i32> {
+		let mut root = self.root.lock();
+		let (old_parent_path, old_name) = split_parent(old_path).map_err(|_| -EBUSY)?;
+		let (new_parent_path, new_name) = split_parent(new_path).map_err(|_| -EBUSY)?;
+		check_name(new_name)?;
+
+		// A directory cannot be moved into itself.
+		if new_path.starts_with(old_path) && new_path[old_path.len()..].starts_with('/') {
+			return Err(-EINVAL);
+		}
+
+		// Take the node out of the old directory first.
+		// This is necessary, because we cannot hold references to both parent directories at the same time.
+		let node = {
+			let old_parent = Tmpfs::find_directory(&mut root, old_parent_path)?;
+			old_parent.entries.remove(old_name).ok_or(-ENOENT)?
+		};
+
+		// An existing destination is replaced if it has the same type (and is empty for directories).
+		let result = match Tmpfs::find_directory(&mut root, new_parent_path) {
+			Ok(new_parent) => {
+				match (&node, new_parent.entries.get(new_name)) {
+					(_, None) => Ok(()),
+					(&TmpNode::File(_), Some(&TmpNode::File(_))) => Ok(()),
+					(&TmpNode::Directory(_), Some(&TmpNode::Directory(ref d))) if d.entries.is_empty() => Ok(()),
+					(&TmpNode::Directory(_), Some(&TmpNode::Directory(_))) => Err(-ENOTEMPTY),
+					(&TmpNode::File(_), Some(&TmpNode::Directory(_))) => Err(-EISDIR),
+					(&TmpNode::Directory(_), Some(&TmpNode::File(_))) => Err(-ENOTDIR),
+				}
+			},
+			Err(e) => Err(e),
+		};
+
+		// Insert the node into the new directory or put it back where it was.
+		match result {
+			Ok(()) => {
+				let new_parent = Tmpfs::find_directory(&mut root, new_parent_path).unwrap();
+				new_parent.entries.insert(String::from(new_name), node);
+				Ok(())
+			},
+			Err(e) => {
+				let old_parent = Tmpfs::find_directory(&mut root, old_parent_path).unwrap();
+				old_parent.entries.insert(String::from(old_name), node);
+				Err(e)
+			}
+		}
+	}
+
+	fn truncate(&self, path: &str, length: usize) -> Result<(), i32> {
+		let mut root = self.root.lock();
+		let (parent_path, name) = split_parent(path).map_err(|_| -EISDIR)?;
+		let parent = Tmpfs::find_directory(&mut root, parent_path)?;
+
+		match parent.entries.get(name) {
+			Some(&TmpNode::File(ref file)) => file.truncate(length),
+			Some(&TmpNode::Directory(_)) => Err(-EISDIR),
+			None => Err(-ENOENT),
+		}
+	}
+}
@@ -63,6 +63,8 @@ extern crate hermit_multiboot;
 extern crate lazy_static;
 
 extern crate raw_cpuid;
+#[cfg(feature = "smoltcp")]
+extern crate smoltcp;
 extern crate spin;
 extern crate x86;
 
@@ -76,12 +78,17 @@ mod logging;
 mod arch;
 mod collections;
 mod console;
+mod drivers;
 mod environment;
 mod errno;
+mod fs;
 mod kernel_message_buffer;
 mod mm;
+#[cfg(feature = "smoltcp")]
+mod net;
 mod runtime_glue;
 mod scheduler;
+mod symbols;
 mod synch;
 mod syscalls;
 
@@ -90,6 +97,7 @@ pub use arch::*;
 pub use syscalls::*;
 
 use arch::percore::*;
+#[cfg(not(feature = "smoltcp"))]
 use processor::get_frequency;
 use core::ptr;
 use mm::allocator;
@@ -105,8 +113,13 @@ extern "C" {
 	static image_size: u64;
 
 	fn libc_start(argc: i32, argv: *mut *mut u8, env: *mut *mut u8);
+}
+
+#[cfg(not(feature = "smoltcp"))]
+extern "C" {
 	fn init_lwip();
 	fn init_rtl8139_netif(freq: u32) -> i32;
+	fn init_virtio_netif() -> i32;
 	fn init_uhyve_netif() -> i32;
 }
 
@@ -120,11 +133,13 @@ unsafe fn sections_init() {
 	);
 }
 
-extern "C" fn initd(_arg: usize) {
+/// Brings up lwIP and the network interface suitable for the environment.
+/// Returns 0 on success.
+#[cfg(not(feature = "smoltcp"))]
+fn init_network() -> i32 {
 	// initialize LwIP library
 	unsafe { init_lwip(); }
 
-	// Initialize the specific network interface.
 	let mut err = 0;
 
 	if environment::is_uhyve() {
@@ -136,14 +151,59 @@ extern "C" fn initd(_arg: usize) {
 		info!("HermitCore is running side-by-side to Linux!");
 		//unsafe { init_mmnif_netif(); }
 	} else {
-		// Initialize the RTL8139 interface using DHCP.
-		err = unsafe { init_rtl8139_netif(get_frequency() as u32) };
+		// Initialize a virtio network interface using DHCP and fall back to an RTL8139 one.
+		err = unsafe { init_virtio_netif() };
+		if err != 0 {
+			err = unsafe { init_rtl8139_netif(get_frequency() as u32) };
+		}
+	}
+
+	err
+}
+
+/// Brings up the network stack written in Rust on a virtio network device.
+/// Returns 0 on success.
+#[cfg(feature = "smoltcp")]
+fn init_network() -> i32 {
+	if environment::is_single_kernel() && !environment::is_uhyve() {
+		net::init()
+	} else {
+		// There is no driver for the uhyve-net and mmnif interfaces yet.
+		info!("The network stack written in Rust is only available for virtio network devices");
+		-errno::ENODEV
+	}
+}
+
+extern "C" fn initd(_arg: usize) {
+	// The proxy is only reachable through lwIP and would otherwise be ignored silently.
+	#[cfg(feature = "smoltcp")]
+	{
+		if environment::is_proxy() {
+			error!("The proxy requires lwIP, but this kernel uses the network stack written in Rust");
+			arch::processor::shutdown();
+		}
 	}
 
+	// Bring up the devices found on the PCI bus.
+	if environment::is_single_kernel() && !environment::is_uhyve() {
+		drivers::init();
+	}
+
+	// Mount the file systems provided by the kernel.
+	fs::init();
+
+	// Initialize the specific network interface.
+	let err = init_network();
+
 	// Check if a network interface has been initialized.
 	if err == 0 {
 		info!("Successfully initialized a network interface!");
-		syscalls::enable_networking();
+
+		// Without the host, the application could neither access its files nor report its results.
+		if let Err(err) = syscalls::enable_networking() {
+			error!("Unable to set up the interface to the host (error code {})", err);
+			arch::processor::shutdown();
+		}
 
 		if environment::is_proxy() {
 			// TODO: Get argc, argv, environ over "proxy", call libc_start, and return.
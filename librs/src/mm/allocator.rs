@@ -115,6 +115,7 @@ fn alloc_system(layout: Layout) -> *mut Opaque {
 	debug_mem!("Allocating {} bytes using the System Allocator", layout.size());
 
 	let size = align_up!(layout.size(), BasePageSize::SIZE);
+	mm::stats::kernel_heap_allocated(size);
 	mm::allocate(size, PageTableEntryFlags::EXECUTE_DISABLE) as *mut Opaque
 }
 
@@ -124,6 +125,7 @@ fn dealloc_system(virtual_address: usize, layout: Layout) {
 
 	let size = align_up!(layout.size(), BasePageSize::SIZE);
 	mm::deallocate(virtual_address, size);
+	mm::stats::kernel_heap_deallocated(size);
 }
 
 pub fn init() {
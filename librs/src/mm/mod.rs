@@ -25,6 +25,7 @@ pub mod allocator;
 pub mod freelist;
 mod mmlock;
 mod nodepool;
+pub mod stats;
 
 use arch;
 use arch::mm::paging::{BasePageSize, PageSize, PageTableEntryFlags};
@@ -73,6 +74,7 @@ pub fn init() {
 pub fn print_information() {
 	arch::mm::physicalmem::print_information();
 	arch::mm::virtualmem::print_information();
+	self::stats::print_information();
 }
 
 pub fn allocate(size: usize, extra_flags: PageTableEntryFlags) -> usize {
@@ -102,3 +104,38 @@ pub fn deallocate(virtual_address: usize, size: usize) {
 		panic!("No page table entry for virtual address {:#X}", virtual_address);
 	}
 }
+
+/// Allocates memory like allocate, but preceded by an unmapped guard page, so that overflowing a stack
+/// in this memory results in a page fault instead of silently overwriting other memory.
+/// Must be freed with deallocate_with_guard.
+pub fn allocate_with_guard(size: usize, extra_flags: PageTableEntryFlags) -> usize {
+	let _lock = MM_LOCK.lock();
+
+	let physical_address = arch::mm::physicalmem::allocate(size);
+	let guard_address = arch::mm::virtualmem::allocate(size + BasePageSize::SIZE);
+	let virtual_address = guard_address + BasePageSize::SIZE;
+	let count = size / BasePageSize::SIZE;
+
+	// The virtual memory may still be mapped from an earlier allocation.
+	arch::mm::paging::unmap::<BasePageSize>(guard_address, 1, true);
+	arch::mm::paging::map::<BasePageSize>(
+		virtual_address,
+		physical_address,
+		count,
+		PageTableEntryFlags::WRITABLE | extra_flags,
+		true
+	);
+
+	virtual_address
+}
+
+pub fn deallocate_with_guard(virtual_address: usize, size: usize) {
+	let _lock = MM_LOCK.lock();
+
+	if let Some(entry) = arch::mm::paging::get_page_table_entry::<BasePageSize>(virtual_address) {
+		arch::mm::virtualmem::deallocate(virtual_address - BasePageSize::SIZE, size + BasePageSize::SIZE);
+		arch::mm::physicalmem::deallocate(entry.address(), size);
+	} else {
+		panic!("No page table entry for virtual address {:#X}", virtual_address);
+	}
+}
@@ -0,0 +1,119 @@
+// Copyright (c) 2018 Colin Finck, RWTH Aachen University
+//
+// MIT License
+//
+// Permission is hereby granted, free of charge, to any person obtaining
+// a copy of this software and associated documentation files (the
+// "Software"), to deal in the Software without restriction, including
+// without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to
+// permit persons to whom the Software is furnished to do so, subject to
+// the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE
+// LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION
+// OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
+// WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! Runtime accounting of memory usage.
+//!
+//! The counters are updated by the physical and virtual memory managers, the kernel allocator,
+//! and the page fault handler. They can be queried by applications through sys_meminfo.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+
+/// Total amount of physical memory managed by the kernel in bytes.
+static PHYSICAL_TOTAL: AtomicUsize = AtomicUsize::new(0);
+/// Physical memory currently allocated in bytes.
+static PHYSICAL_USED: AtomicUsize = AtomicUsize::new(0);
+/// Kernel virtual memory currently allocated in bytes.
+static VIRTUAL_USED: AtomicUsize = AtomicUsize::new(0);
+/// Memory currently allocated through the kernel heap allocator in bytes (rounded up to pages).
+static KERNEL_HEAP_USED: AtomicUsize = AtomicUsize::new(0);
+/// Number of page faults resolved by mapping a task heap page.
+static PAGE_FAULTS: AtomicUsize = AtomicUsize::new(0);
+
+
+/// Memory statistics as returned by sys_meminfo.
+/// Keep in sync with "struct meminfo" in include/hermit/syscall.h!
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MemInfo {
+	/// Total amount of physical memory managed by the kernel in bytes
+	pub physical_total: usize,
+	/// Physical memory currently in use in bytes
+	pub physical_used: usize,
+	/// Kernel virtual memory currently in use in bytes
+	pub virtual_used: usize,
+	/// Memory currently allocated from the kernel heap in bytes
+	pub kernel_heap_used: usize,
+	/// Size of the heap of the calling task in bytes
+	pub task_heap_size: usize,
+	/// Number of page faults that have been resolved since boot
+	pub page_faults: usize,
+}
+
+
+pub fn add_physical_total(size: usize) {
+	PHYSICAL_TOTAL.fetch_add(size, Ordering::Relaxed);
+}
+
+pub fn physical_allocated(size: usize) {
+	PHYSICAL_USED.fetch_add(size, Ordering::Relaxed);
+}
+
+pub fn physical_deallocated(size: usize) {
+	PHYSICAL_USED.fetch_sub(size, Ordering::Relaxed);
+}
+
+pub fn virtual_allocated(size: usize) {
+	VIRTUAL_USED.fetch_add(size, Ordering::Relaxed);
+}
+
+pub fn virtual_deallocated(size: usize) {
+	VIRTUAL_USED.fetch_sub(size, Ordering::Relaxed);
+}
+
+pub fn kernel_heap_allocated(size: usize) {
+	KERNEL_HEAP_USED.fetch_add(size, Ordering::Relaxed);
+}
+
+pub fn kernel_heap_deallocated(size: usize) {
+	KERNEL_HEAP_USED.fetch_sub(size, Ordering::Relaxed);
+}
+
+pub fn page_fault() {
+	PAGE_FAULTS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Returns a snapshot of all counters.
+/// The task heap size is left for the caller, because it depends on the current task.
+pub fn get() -> MemInfo {
+	MemInfo {
+		physical_total: PHYSICAL_TOTAL.load(Ordering::Relaxed),
+		physical_used: PHYSICAL_USED.load(Ordering::Relaxed),
+		virtual_used: VIRTUAL_USED.load(Ordering::Relaxed),
+		kernel_heap_used: KERNEL_HEAP_USED.load(Ordering::Relaxed),
+		task_heap_size: 0,
+		page_faults: PAGE_FAULTS.load(Ordering::Relaxed),
+	}
+}
+
+pub fn print_information() {
+	let info = get();
+
+	infoheader!(" MEMORY STATISTICS ");
+	infoentry!("Total physical memory", "{} KiB", info.physical_total >> 10);
+	infoentry!("Used physical memory", "{} KiB", info.physical_used >> 10);
+	infoentry!("Used virtual memory", "{} KiB", info.virtual_used >> 10);
+	infoentry!("Kernel heap", "{} KiB", info.kernel_heap_used >> 10);
+	infoentry!("Page faults", info.page_faults);
+	infofooter!();
+}
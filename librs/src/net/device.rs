@@ -0,0 +1,98 @@
+// Copyright (c) 2018 Stefan Lankes, RWTH Aachen University
+//                    Colin Finck, RWTH Aachen University
+//
+// MIT License
+//
+// Permission is hereby granted, free of charge, to any person obtaining
+// a copy of this software and associated documentation files (the
+// "Software"), to deal in the Software without restriction, including
+// without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to
+// permit persons to whom the Software is furnished to do so, subject to
+// the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE
+// LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION
+// OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
+// WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! smoltcp device on top of the virtio network driver.
+
+use alloc::vec::Vec;
+use drivers::virtio::net::{virtio_net_receive, virtio_net_transmit};
+use fs::IoVec;
+use smoltcp::phy::{self, Device, DeviceCapabilities};
+use smoltcp::time::Instant;
+use smoltcp::{Error, Result};
+
+
+/// Largest Ethernet frame without the frame check sequence.
+const ETHERNET_FRAME_SIZE: usize = 1514;
+
+
+/// The virtio network device, which is brought up by virtio_net_init.
+pub struct VirtioNetDevice;
+
+impl<'a> Device<'a> for VirtioNetDevice {
+	type RxToken = RxToken;
+	type TxToken = TxToken;
+
+	fn receive(&'a mut self) -> Option<(Self::RxToken, Self::TxToken)> {
+		let mut frame = Vec::with_capacity(ETHERNET_FRAME_SIZE);
+		frame.resize(ETHERNET_FRAME_SIZE, 0);
+
+		let length = virtio_net_receive(frame.as_mut_ptr(), frame.len());
+		if length <= 0 {
+			return None;
+		}
+
+		frame.truncate(length as usize);
+		Some((RxToken { frame: frame }, TxToken))
+	}
+
+	fn transmit(&'a mut self) -> Option<Self::TxToken> {
+		Some(TxToken)
+	}
+
+	fn capabilities(&self) -> DeviceCapabilities {
+		let mut capabilities = DeviceCapabilities::default();
+		capabilities.max_transmission_unit = ETHERNET_FRAME_SIZE;
+		capabilities
+	}
+}
+
+/// A frame fetched from the receive queue.
+pub struct RxToken {
+	frame: Vec<u8>,
+}
+
+impl phy::RxToken for RxToken {
+	fn consume<R, F>(self, _timestamp: Instant, f: F) -> Result<R> where F: FnOnce(&[u8]) -> Result<R> {
+		f(&self.frame)
+	}
+}
+
+/// Permission to send a frame, which is copied into a transmit buffer of the driver.
+pub struct TxToken;
+
+impl phy::TxToken for TxToken {
+	fn consume<R, F>(self, _timestamp: Instant, len: usize, f: F) -> Result<R> where F: FnOnce(&mut [u8]) -> Result<R> {
+		let mut frame = Vec::with_capacity(len);
+		frame.resize(len, 0);
+		let result = f(&mut frame)?;
+
+		let iov = IoVec { iov_base: frame.as_mut_ptr(), iov_len: frame.len() };
+		if virtio_net_transmit(&iov, 1) < 0 {
+			// All transmit buffers are in use, so smoltcp has to try again later.
+			return Err(Error::Exhausted);
+		}
+
+		Ok(result)
+	}
+}
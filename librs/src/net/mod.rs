@@ -0,0 +1,282 @@
+// Copyright (c) 2018 Stefan Lankes, RWTH Aachen University
+//                    Colin Finck, RWTH Aachen University
+//
+// MIT License
+//
+// Permission is hereby granted, free of charge, to any person obtaining
+// a copy of this software and associated documentation files (the
+// "Software"), to deal in the Software without restriction, including
+// without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to
+// permit persons to whom the Software is furnished to do so, subject to
+// the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE
+// LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION
+// OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
+// WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! Network stack written in Rust on top of smoltcp, which replaces lwIP when the "smoltcp" feature is enabled.
+//!
+//! A single network task owns the interface: It polls the device whenever the driver signals new frames,
+//! a socket has been changed, or a timer of smoltcp expires.
+//! Tasks using a socket never spin. If an operation cannot complete yet, the task blocks until the next poll
+//! of the network task and then tries again.
+
+mod device;
+pub mod tcp;
+
+use alloc::btree_map::BTreeMap;
+use alloc::vec::Vec;
+use arch;
+use arch::percore::*;
+use core::cmp;
+use core::sync::atomic::{AtomicU32, Ordering};
+use drivers::virtio::net::virtio_net_init;
+use errno::*;
+use scheduler;
+use scheduler::task::{PriorityTaskQueue, HIGH_PRIO};
+use self::device::VirtioNetDevice;
+use smoltcp::dhcp::{Dhcpv4Client, Dhcpv4Config};
+use smoltcp::iface::{EthernetInterface, EthernetInterfaceBuilder, NeighborCache, Routes};
+use smoltcp::socket::{RawPacketMetadata, RawSocketBuffer, SocketSet};
+use smoltcp::time::{Duration, Instant};
+use smoltcp::wire::{EthernetAddress, IpCidr, Ipv4Address, Ipv4Cidr};
+use synch::semaphore::Semaphore;
+use synch::spinlock::{Spinlock, SpinlockIrqSave};
+use syscalls::{sys_msleep, Tid};
+
+
+/// Time to wait for an address from a DHCP server, like the lwIP netif does.
+const DHCP_TIMEOUT_MSECS: u32 = 20000;
+const DHCP_CHECK_INTERVAL_MSECS: u32 = 100;
+
+/// Sizes of the buffers of the raw socket used by the DHCP client.
+const DHCP_RX_BUFFER_SIZE: usize = 900;
+const DHCP_TX_BUFFER_SIZE: usize = 600;
+
+
+/// The Ethernet interface with all sockets.
+struct NetworkInterface {
+	iface: EthernetInterface<'static, 'static, 'static, VirtioNetDevice>,
+	sockets: SocketSet<'static, 'static, 'static>,
+	dhcp: Dhcpv4Client,
+	/// Address assigned by the DHCP server
+	address: Option<Ipv4Cidr>,
+}
+
+impl NetworkInterface {
+	/// Processes all received frames, sends out pending ones, and runs the DHCP client.
+	/// Returns the time until smoltcp needs to be polled again.
+	fn poll(&mut self) -> Duration {
+		let timestamp = now();
+
+		// Errors only concern single frames, which are dropped.
+		if let Err(e) = self.iface.poll(&mut self.sockets, timestamp) {
+			debug!("Polling the network interface failed: {}", e);
+		}
+
+		match self.dhcp.poll(&mut self.iface, &mut self.sockets, timestamp) {
+			Ok(Some(config)) => self.configure(config),
+			Ok(None) => {},
+			Err(e) => debug!("DHCP client failed: {}", e),
+		}
+
+		// Remove the sockets, which have been closed by the application and by the peer.
+		self.sockets.prune();
+
+		let dhcp_delay = self.dhcp.next_poll(timestamp);
+		match self.iface.poll_delay(&self.sockets, timestamp) {
+			Some(delay) => cmp::min(delay, dhcp_delay),
+			None => dhcp_delay,
+		}
+	}
+
+	fn configure(&mut self, config: Dhcpv4Config) {
+		if let Some(cidr) = config.address {
+			if self.address != Some(cidr) {
+				info!("DHCP server assigned the address {}", cidr);
+				self.iface.update_ip_addrs(|addrs| addrs[0] = IpCidr::Ipv4(cidr));
+				self.address = Some(cidr);
+			}
+		}
+
+		if let Some(router) = config.router {
+			if self.iface.routes_mut().add_default_ipv4_route(router).is_err() {
+				warn!("Could not add the default route via {}", router);
+			}
+		}
+	}
+}
+
+/// Wakes up the network task.
+struct PollWakeup(Semaphore);
+
+/// Tasks waiting for a socket operation to complete.
+struct SocketWaiters(SpinlockIrqSave<PriorityTaskQueue>);
+
+// The semaphore and the queue only access the waiting tasks under their locks.
+unsafe impl Send for PollWakeup {}
+unsafe impl Sync for PollWakeup {}
+unsafe impl Send for SocketWaiters {}
+unsafe impl Sync for SocketWaiters {}
+
+static NETWORK: Spinlock<Option<NetworkInterface>> = Spinlock::new(None);
+static NETWORK_TASK_ID: AtomicU32 = AtomicU32::new(0);
+
+lazy_static! {
+	static ref POLL_WAKEUP: PollWakeup = PollWakeup(Semaphore::new(0));
+	static ref SOCKET_WAITERS: SocketWaiters = SocketWaiters(SpinlockIrqSave::new(PriorityTaskQueue::new()));
+}
+
+
+/// Returns the time since boot in the resolution of smoltcp.
+fn now() -> Instant {
+	let millis = arch::processor::get_timestamp() / (arch::processor::get_frequency() as u64 * 1000);
+	Instant::from_millis(millis as i64)
+}
+
+fn raw_socket_buffer(size: usize) -> RawSocketBuffer<'static, 'static> {
+	let mut metadata = Vec::with_capacity(1);
+	metadata.push(RawPacketMetadata::EMPTY);
+
+	let mut payload = Vec::with_capacity(size);
+	payload.resize(size, 0);
+
+	RawSocketBuffer::new(metadata, payload)
+}
+
+fn wake_waiting_tasks() {
+	let mut waiters = SOCKET_WAITERS.0.lock();
+
+	while let Some(task) = waiters.pop() {
+		let core_scheduler = scheduler::get_scheduler(task.borrow().core_id);
+		core_scheduler.blocked_tasks.lock().custom_wakeup(task);
+	}
+}
+
+extern "C" fn network_task(_arg: usize) {
+	loop {
+		let delay = NETWORK.lock().as_mut().unwrap().poll();
+
+		// Every poll may have changed the state of a socket.
+		wake_waiting_tasks();
+
+		// Sleep until the driver or a socket operation calls notify or the next timer of smoltcp expires.
+		let ticks = cmp::max(delay.total_millis() as usize * arch::processor::TIMER_FREQUENCY / 1000, 1);
+		POLL_WAKEUP.0.acquire(Some(arch::processor::update_timer_ticks() + ticks));
+	}
+}
+
+/// Returns the ID of the network task or 0 if it has not been started.
+pub fn get_network_task_id() -> Tid {
+	NETWORK_TASK_ID.load(Ordering::SeqCst)
+}
+
+/// Lets the network task poll the interface.
+/// Called by the interrupt handler of the driver and after changing a socket.
+pub fn notify() {
+	POLL_WAKEUP.0.release();
+}
+
+/// Runs `f` on the sockets without blocking.
+/// Returns -ENETDOWN if there is no network interface.
+pub fn with_sockets<T, F>(f: F) -> Result<T, i32> where F: FnOnce(&mut SocketSet<'static, 'static, 'static>) -> T {
+	let result = match *NETWORK.lock() {
+		Some(ref mut network) => f(&mut network.sockets),
+		None => return Err(-ENETDOWN),
+	};
+
+	notify();
+	Ok(result)
+}
+
+/// Runs `f` on the sockets until it returns a result and blocks the current task in between.
+/// `f` is called again after every poll of the network task.
+/// Must only be called for sockets, which have been created by `with_sockets`.
+pub fn block_on<T, F>(mut f: F) -> T where F: FnMut(&mut SocketSet<'static, 'static, 'static>) -> Option<T> {
+	let core_scheduler = core_scheduler();
+
+	loop {
+		let result = {
+			let mut guard = NETWORK.lock();
+			let result = f(&mut guard.as_mut().unwrap().sockets);
+
+			if result.is_none() {
+				// Register the task while still holding the lock, so that it cannot miss the next poll.
+				core_scheduler.blocked_tasks.lock().add(core_scheduler.current_task.clone(), None);
+				SOCKET_WAITERS.0.lock().push(core_scheduler.current_task.clone());
+			}
+
+			result
+		};
+
+		match result {
+			Some(result) => {
+				// The operation may have queued data or changed the state of the connection.
+				notify();
+				return result;
+			},
+			None => core_scheduler.scheduler(),
+		}
+	}
+}
+
+/// Brings up the virtio network device, starts the network task, and waits for an address from a DHCP server.
+/// Returns 0 on success.
+pub fn init() -> i32 {
+	let mut mac = [0u8; 6];
+	let mut mtu = 0u16;
+	let ret = virtio_net_init(mac.as_mut_ptr(), &mut mtu);
+	if ret < 0 {
+		return ret;
+	}
+
+	// The address is assigned by DHCP.
+	let mut ip_addrs = Vec::with_capacity(1);
+	ip_addrs.push(IpCidr::new(Ipv4Address::UNSPECIFIED.into(), 0));
+
+	let iface = EthernetInterfaceBuilder::new(VirtioNetDevice)
+		.ethernet_addr(EthernetAddress(mac))
+		.neighbor_cache(NeighborCache::new(BTreeMap::new()))
+		.ip_addrs(ip_addrs)
+		.routes(Routes::new(BTreeMap::new()))
+		.finalize();
+
+	let mut sockets = SocketSet::new(Vec::new());
+	let dhcp = Dhcpv4Client::new(
+		&mut sockets,
+		raw_socket_buffer(DHCP_RX_BUFFER_SIZE),
+		raw_socket_buffer(DHCP_TX_BUFFER_SIZE),
+		now()
+	);
+
+	*NETWORK.lock() = Some(NetworkInterface {
+		iface: iface,
+		sockets: sockets,
+		dhcp: dhcp,
+		address: None,
+	});
+
+	let task_id = core_scheduler().spawn(network_task, 0, HIGH_PRIO, None);
+	NETWORK_TASK_ID.store(task_id.into(), Ordering::SeqCst);
+
+	let mut waited = 0;
+	while NETWORK.lock().as_ref().unwrap().address.is_none() {
+		if waited >= DHCP_TIMEOUT_MSECS {
+			// The network task keeps asking for an address in the background.
+			return -ETIMEDOUT;
+		}
+
+		sys_msleep(DHCP_CHECK_INTERVAL_MSECS);
+		waited += DHCP_CHECK_INTERVAL_MSECS;
+	}
+
+	0
+}
@@ -0,0 +1,319 @@
+// Copyright (c) 2018 Stefan Lankes, RWTH Aachen University
+//                    Colin Finck, RWTH Aachen University
+//
+// MIT License
+//
+// Permission is hereby granted, free of charge, to any person obtaining
+// a copy of this software and associated documentation files (the
+// "Software"), to deal in the Software without restriction, including
+// without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to
+// permit persons to whom the Software is furnished to do so, subject to
+// the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE
+// LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION
+// OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
+// WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! TCP sockets of the network stack written in Rust.
+//!
+//! A listening socket holds a single smoltcp socket, so only one pending connection is accepted at a time.
+//! When accept() returns a connection, a new smoltcp socket takes over listening on the port.
+
+use alloc::arc::Arc;
+use alloc::vec::Vec;
+use core::slice;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use errno::*;
+use fs::{ObjectInterface, SocketAddress, Stat, S_IFSOCK};
+use net;
+use smoltcp::Error;
+use smoltcp::socket::{SocketHandle, TcpSocket, TcpSocketBuffer};
+use smoltcp::wire::{IpAddress, IpEndpoint, Ipv4Address};
+use synch::spinlock::Spinlock;
+
+
+/// Size of the receive and the transmit buffer of every socket.
+const SOCKET_BUFFER_SIZE: usize = 65536;
+
+/// Range of ports for sockets, which have not been bound to a port, as suggested by IANA.
+const EPHEMERAL_PORT_START: usize = 49152;
+const EPHEMERAL_PORT_COUNT: usize = 16384;
+
+static NEXT_EPHEMERAL_PORT: AtomicUsize = AtomicUsize::new(0);
+
+
+fn ephemeral_port() -> u16 {
+	(EPHEMERAL_PORT_START + NEXT_EPHEMERAL_PORT.fetch_add(1, Ordering::SeqCst) % EPHEMERAL_PORT_COUNT) as u16
+}
+
+fn socket_buffer() -> TcpSocketBuffer<'static> {
+	let mut buffer = Vec::with_capacity(SOCKET_BUFFER_SIZE);
+	buffer.resize(SOCKET_BUFFER_SIZE, 0);
+	TcpSocketBuffer::new(buffer)
+}
+
+fn new_tcp_socket() -> TcpSocket<'static> {
+	TcpSocket::new(socket_buffer(), socket_buffer())
+}
+
+fn to_endpoint(address: SocketAddress) -> IpEndpoint {
+	IpEndpoint::new(IpAddress::Ipv4(Ipv4Address(address.address)), address.port)
+}
+
+fn to_socket_address(endpoint: IpEndpoint) -> SocketAddress {
+	let address = match endpoint.addr {
+		IpAddress::Ipv4(address) => address.0,
+		_ => [0; 4],
+	};
+
+	SocketAddress { address: address, port: endpoint.port }
+}
+
+struct SocketState {
+	/// The smoltcp socket, which is replaced when accepting a connection
+	handle: SocketHandle,
+	/// Address assigned by bind() or when listening or connecting, port 0 if none yet
+	local: SocketAddress,
+	listening: bool,
+	connected: bool,
+}
+
+/// A TCP socket referenced through a file descriptor.
+pub struct Socket {
+	state: Spinlock<SocketState>,
+}
+
+impl Socket {
+	pub fn new() -> Result<Self, i32> {
+		let handle = net::with_sockets(|sockets| sockets.add(new_tcp_socket()))?;
+		Ok(Self::from_handle(handle, SocketAddress { address: [0; 4], port: 0 }, false))
+	}
+
+	fn from_handle(handle: SocketHandle, local: SocketAddress, connected: bool) -> Self {
+		Self {
+			state: Spinlock::new(SocketState {
+				handle: handle,
+				local: local,
+				listening: false,
+				connected: connected,
+			}),
+		}
+	}
+
+	/// Returns the smoltcp socket of an established connection.
+	/// The handle is copied, so that no lock is held while blocking.
+	fn connected_handle(&self) -> Result<SocketHandle, i32> {
+		let state = self.state.lock();
+		if state.connected {
+			Ok(state.handle)
+		} else {
+			Err(-ENOTCONN)
+		}
+	}
+}
+
+impl ObjectInterface for Socket {
+	fn read(&self, buf: *mut u8, len: usize) -> isize {
+		let handle = match self.connected_handle() {
+			Ok(handle) => handle,
+			Err(e) => return e as isize,
+		};
+
+		let buf = unsafe { slice::from_raw_parts_mut(buf, len) };
+		net::block_on(|sockets| {
+			let mut socket = sockets.get::<TcpSocket>(handle);
+
+			if socket.can_recv() {
+				Some(socket.recv_slice(&mut buf[..]).map(|n| n as isize).unwrap_or(-ENOTCONN as isize))
+			} else if !socket.is_open() {
+				// The connection has been reset (or has timed out) without an orderly close.
+				Some(-ECONNRESET as isize)
+			} else if !socket.may_recv() {
+				// The peer has closed its half of the connection.
+				Some(0)
+			} else {
+				None
+			}
+		})
+	}
+
+	fn write(&self, buf: *const u8, len: usize) -> isize {
+		let handle = match self.connected_handle() {
+			Ok(handle) => handle,
+			Err(e) => return e as isize,
+		};
+
+		let buf = unsafe { slice::from_raw_parts(buf, len) };
+		net::block_on(|sockets| {
+			let mut socket = sockets.get::<TcpSocket>(handle);
+
+			if !socket.may_send() {
+				Some(-EPIPE as isize)
+			} else if socket.can_send() {
+				Some(socket.send_slice(buf).map(|n| n as isize).unwrap_or(-EPIPE as isize))
+			} else {
+				None
+			}
+		})
+	}
+
+	fn fstat(&self, stat: &mut Stat) -> i32 {
+		*stat = Stat {
+			st_mode: S_IFSOCK | 0o777,
+			st_nlink: 1,
+			..Default::default()
+		};
+		0
+	}
+
+	fn close(&self) -> i32 {
+		// Other tasks may still be blocked on this socket, so it is only released when dropped.
+		let handle = self.state.lock().handle;
+		net::with_sockets(|sockets| sockets.get::<TcpSocket>(handle).close()).err().unwrap_or(0)
+	}
+
+	fn bind(&self, address: SocketAddress) -> i32 {
+		let mut state = self.state.lock();
+		if state.local.port != 0 {
+			return -EINVAL;
+		}
+
+		state.local = address;
+		if state.local.port == 0 {
+			state.local.port = ephemeral_port();
+		}
+
+		0
+	}
+
+	fn listen(&self, _backlog: i32) -> i32 {
+		let mut state = self.state.lock();
+		if state.connected {
+			return -EISCONN;
+		} else if state.listening {
+			return 0;
+		}
+
+		if state.local.port == 0 {
+			state.local.port = ephemeral_port();
+		}
+
+		let handle = state.handle;
+		let endpoint = to_endpoint(state.local);
+		match net::with_sockets(|sockets| sockets.get::<TcpSocket>(handle).listen(endpoint)) {
+			Ok(Ok(())) => {
+				state.listening = true;
+				0
+			},
+			Ok(Err(_)) => -EINVAL,
+			Err(e) => e,
+		}
+	}
+
+	fn accept(&self) -> Result<(Arc<ObjectInterface>, SocketAddress), i32> {
+		loop {
+			let (handle, local) = {
+				let state = self.state.lock();
+				if !state.listening {
+					return Err(-EINVAL);
+				}
+
+				(state.handle, state.local)
+			};
+
+			// A connection in the SYN-RECEIVED state falls back to LISTEN when it is reset.
+			let result = net::block_on(|sockets| {
+				let socket = sockets.get::<TcpSocket>(handle);
+
+				if socket.may_send() {
+					Some(Ok(socket.remote_endpoint()))
+				} else if !socket.is_open() {
+					Some(Err(-ECONNABORTED))
+				} else {
+					None
+				}
+			});
+
+			// Several tasks may wait for the same connection, so only the first one to get here takes it.
+			let mut state = self.state.lock();
+			if state.handle != handle {
+				continue;
+			}
+
+			let remote = result?;
+
+			// Listen on the port again with a new smoltcp socket and hand out the current one for the connection.
+			let mut listener = new_tcp_socket();
+			if listener.listen(to_endpoint(local)).is_err() {
+				return Err(-EINVAL);
+			}
+
+			state.handle = net::with_sockets(|sockets| sockets.add(listener))?;
+
+			let connection: Arc<ObjectInterface> = Arc::new(Socket::from_handle(handle, local, true));
+			return Ok((connection, to_socket_address(remote)));
+		}
+	}
+
+	fn connect(&self, address: SocketAddress) -> i32 {
+		let (handle, local_port) = {
+			let mut state = self.state.lock();
+			if state.connected {
+				return -EISCONN;
+			} else if state.listening {
+				return -EINVAL;
+			}
+
+			if state.local.port == 0 {
+				state.local.port = ephemeral_port();
+			}
+
+			(state.handle, state.local.port)
+		};
+
+		let remote = to_endpoint(address);
+		match net::with_sockets(|sockets| sockets.get::<TcpSocket>(handle).connect(remote, local_port)) {
+			Ok(Ok(())) => {},
+			Ok(Err(Error::Illegal)) => return -EISCONN,
+			Ok(Err(_)) => return -EADDRNOTAVAIL,
+			Err(e) => return e,
+		}
+
+		let ret = net::block_on(|sockets| {
+			let socket = sockets.get::<TcpSocket>(handle);
+
+			if socket.may_send() {
+				Some(0)
+			} else if !socket.is_open() {
+				Some(-ECONNREFUSED)
+			} else {
+				None
+			}
+		});
+
+		if ret == 0 {
+			self.state.lock().connected = true;
+		}
+
+		ret
+	}
+}
+
+impl Drop for Socket {
+	fn drop(&mut self) {
+		// The network task removes the smoltcp socket as soon as the connection has been closed.
+		let handle = self.state.lock().handle;
+		net::with_sockets(|sockets| {
+			sockets.get::<TcpSocket>(handle).close();
+			sockets.release(handle);
+		}).ok();
+	}
+}
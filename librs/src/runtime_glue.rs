@@ -28,6 +28,7 @@
 #![allow(private_no_mangle_fns)]
 
 use arch;
+use console;
 use core::panic::PanicInfo;
 
 #[lang = "eh_personality"]
@@ -37,6 +38,7 @@ extern "C" fn eh_personality() {}
 #[panic_implementation]
 #[no_mangle]
 fn panic(info: &PanicInfo) -> ! {
+	console::enter_panic();
 	print!("[{}][!!!PANIC!!!] ", arch::percore::core_id());
 
 	if let Some(location) = info.location() {
@@ -48,6 +50,8 @@ fn panic(info: &PanicInfo) -> ! {
 	}
 
 	print!("\n");
+	arch::backtrace::print_current_backtrace();
+	console::flush();
 
 	loop {
 		arch::processor::halt();
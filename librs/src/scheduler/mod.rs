@@ -36,6 +36,10 @@ use core::sync::atomic::{AtomicI32, AtomicU32, AtomicUsize, Ordering};
 use scheduler::task::*;
 use synch::spinlock::*;
 use syscalls::*;
+#[cfg(feature = "smoltcp")]
+use net::get_network_task_id;
+#[cfg(not(feature = "smoltcp"))]
+use syscalls::get_lwip_tcpip_task_id as get_network_task_id;
 
 extern "C" {
 	fn switch(old_stack: *mut usize, new_stack: usize);
@@ -263,9 +267,9 @@ impl PerCoreScheduler {
 				(borrowed.id, borrowed.last_stack_pointer)
 			};
 
-			// If this is the Boot Processor and only the lwIP TCP/IP task is left, it's time to shut down the OS.
-			if core_id() == 0 && new_id.into() == get_lwip_tcpip_task_id() && NO_TASKS.load(Ordering::SeqCst) == 1 {
-				debug!("Only lwIP TCP/IP task is left");
+			// If this is the Boot Processor and only the task of the network stack is left, it's time to shut down the OS.
+			if core_id() == 0 && new_id.into() == get_network_task_id() && NO_TASKS.load(Ordering::SeqCst) == 1 {
+				debug!("Only the network task is left");
 				sys_shutdown();
 			}
 
@@ -36,6 +36,9 @@ use scheduler;
 use spin::RwLock;
 
 
+/// Initial stack pointers are set this many bytes below the end of a stack (see entry.asm and gdt.rs).
+const STACK_POINTER_OFFSET: usize = 0x10;
+
 /// The status of the task - used for scheduling
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub enum TaskStatus {
@@ -184,6 +187,8 @@ impl PriorityTaskQueue {
 pub struct TaskHeap {
 	pub start: usize,
 	pub end: usize,
+	/// Size of the pages that back this heap when a page fault occurs (4 KiB, 2 MiB, or 1 GiB)
+	pub page_size: usize,
 }
 
 pub struct TaskTLS {
@@ -254,8 +259,8 @@ impl Drop for Task {
 			debug!("Deallocating stack {:#X} and IST {:#X} for task {}", self.stack as usize, self.ist as usize, self.id);
 
 			// deallocate stacks
-			mm::deallocate(self.stack as usize, DEFAULT_STACK_SIZE);
-			mm::deallocate(self.ist as usize, DEFAULT_STACK_SIZE);
+			mm::deallocate_with_guard(self.stack as usize, DEFAULT_STACK_SIZE);
+			mm::deallocate(self.ist as usize, KERNEL_STACK_SIZE);
 		}
 	}
 }
@@ -264,7 +269,8 @@ impl Task {
 	#[inline]
 	fn allocate_stacks() -> (usize, usize) {
 		// Allocate an executable stack to possibly support dynamically generated code on the stack (see https://security.stackexchange.com/a/47825).
-		let stack = mm::allocate(DEFAULT_STACK_SIZE, PageTableEntryFlags::empty());
+		// A guard page below the stack turns a stack overflow into a page fault.
+		let stack = mm::allocate_with_guard(DEFAULT_STACK_SIZE, PageTableEntryFlags::empty());
 		let ist = mm::allocate(KERNEL_STACK_SIZE, PageTableEntryFlags::EXECUTE_DISABLE);
 		(stack, ist)
 	}
@@ -282,7 +288,11 @@ impl Task {
 			core_id: core_id,
 			stack: stack,
 			ist: ist,
-			heap: heap_start.map(|start| Rc::new(RefCell::new(RwLock::new(TaskHeap { start: start, end: start })))),
+			heap: heap_start.map(|start| Rc::new(RefCell::new(RwLock::new(TaskHeap {
+				start: start,
+				end: start,
+				page_size: arch::mm::paging::default_task_heap_page_size(),
+			})))),
 			tls: None,
 			last_wakeup_reason: WakeupReason::Custom,
 			lwip_errno: 0,
@@ -309,6 +319,38 @@ impl Task {
 		}
 	}
 
+	/// Returns the start and end address of the stack of this task.
+	pub fn stack_bounds(&self) -> (usize, usize) {
+		if self.status == TaskStatus::TaskIdle {
+			// The idle task uses the boot stack, for which we only know the initial stack pointer.
+			let end = self.stack + STACK_POINTER_OFFSET;
+			(end - KERNEL_STACK_SIZE, end)
+		} else {
+			(self.stack, self.stack + DEFAULT_STACK_SIZE)
+		}
+	}
+
+	/// Returns the start and end address of the unmapped guard page below the stack of this task.
+	/// The boot stack used by the idle task has no guard page.
+	pub fn stack_guard_bounds(&self) -> Option<(usize, usize)> {
+		if self.status == TaskStatus::TaskIdle {
+			None
+		} else {
+			Some((self.stack - BasePageSize::SIZE, self.stack))
+		}
+	}
+
+	/// Returns the start and end address of the stack used for interrupt handling (IST1) of this task.
+	pub fn ist_bounds(&self) -> (usize, usize) {
+		if self.status == TaskStatus::TaskIdle {
+			// The idle task uses the boot IST, for which we only know the initial stack pointer.
+			let end = self.ist + STACK_POINTER_OFFSET;
+			(end - KERNEL_STACK_SIZE, end)
+		} else {
+			(self.ist, self.ist + KERNEL_STACK_SIZE)
+		}
+	}
+
 	pub fn clone(tid: TaskId, core_id: u32, task: &Task) -> Task {
 		let (stack, ist) = Task::allocate_stacks();
 		debug!("Allocating stack {:#X} and IST {:#X} for task {} cloned from task {}", stack, ist, tid, task.id);
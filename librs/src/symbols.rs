@@ -0,0 +1,138 @@
+// Copyright (c) 2018 Colin Finck, RWTH Aachen University
+//
+// MIT License
+//
+// Permission is hereby granted, free of charge, to any person obtaining
+// a copy of this software and associated documentation files (the
+// "Software"), to deal in the Software without restriction, including
+// without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to
+// permit persons to whom the Software is furnished to do so, subject to
+// the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE
+// LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION
+// OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
+// WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! Symbol table of the kernel and the application for symbolized backtraces.
+//!
+//! The table is linked into the "ksymtab" section by the "ksymtab" tool, which wraps the link command of the application.
+//! Its bounds are given by the __start_ksymtab and __stop_ksymtab symbols defined by the linker.
+//! Applications linked without the tool have no such section and get unsymbolized backtraces.
+
+use core::{fmt, mem, ptr, slice, str};
+
+
+/// Magic number at the beginning of the symbol table ("KSYM").
+const SYMBOL_TABLE_MAGIC: u32 = 0x4D59_534B;
+
+
+#[repr(C)]
+struct SymbolTableHeader {
+	magic: u32,
+	/// Number of SymbolTableEntry structures following the header
+	count: u32,
+}
+
+/// An entry of the symbol table. All entries are sorted by address.
+#[repr(C)]
+struct SymbolTableEntry {
+	address: u64,
+	/// Size of the function or 0 if unknown
+	size: u32,
+	/// Offset of the NUL-terminated name, relative to the start of the symbol table
+	name_offset: u32,
+}
+
+extern "C" {
+	#[linkage = "extern_weak"]
+	static __start_ksymtab: *const u8;
+	#[linkage = "extern_weak"]
+	static __stop_ksymtab: *const u8;
+}
+
+
+/// Returns the symbol table or None if the application has been linked without one.
+fn symbol_table() -> Option<&'static [u8]> {
+	let (start, stop) = unsafe { (__start_ksymtab, __stop_ksymtab) };
+	if start.is_null() || (stop as usize) < (start as usize) {
+		return None;
+	}
+
+	Some(unsafe { slice::from_raw_parts(start, stop as usize - start as usize) })
+}
+
+/// Reads the entry at the given index.
+/// The section created by the tool has no alignment, so all fields are read unaligned.
+fn read_entry(table: &[u8], index: usize) -> SymbolTableEntry {
+	let offset = mem::size_of::<SymbolTableHeader>() + index * mem::size_of::<SymbolTableEntry>();
+	unsafe { ptr::read_unaligned(table[offset..].as_ptr() as *const SymbolTableEntry) }
+}
+
+/// Returns the name of the function containing the given address and the offset into that function.
+pub fn lookup(address: usize) -> Option<(&'static str, usize)> {
+	let table = symbol_table()?;
+	if table.len() < mem::size_of::<SymbolTableHeader>() {
+		return None;
+	}
+
+	let header = unsafe { ptr::read_unaligned(table.as_ptr() as *const SymbolTableHeader) };
+	let count = header.count as usize;
+	let entries_size = mem::size_of::<SymbolTableHeader>() + count * mem::size_of::<SymbolTableEntry>();
+	if header.magic != SYMBOL_TABLE_MAGIC || count == 0 || entries_size > table.len() {
+		return None;
+	}
+
+	// Find the entry with the highest address that is lower than or equal to the given one.
+	let address = address as u64;
+	let mut low = 0;
+	let mut high = count;
+	while low < high {
+		let middle = low + (high - low) / 2;
+		if read_entry(table, middle).address <= address {
+			low = middle + 1;
+		} else {
+			high = middle;
+		}
+	}
+
+	if low == 0 {
+		return None;
+	}
+
+	let entry = read_entry(table, low - 1);
+	let offset = (address - entry.address) as usize;
+	if entry.size > 0 && offset >= entry.size as usize {
+		// The address lies behind the end of the function, so it belongs to no known symbol.
+		return None;
+	}
+
+	// Get the NUL-terminated name.
+	let name_bytes = table.get(entry.name_offset as usize..)?;
+	let name_length = name_bytes.iter().position(|&b| b == 0)?;
+	let name = str::from_utf8(&name_bytes[..name_length]).ok()?;
+
+	Some((name, offset))
+}
+
+/// Formats an address along with "function+offset" if the symbol table knows about it.
+pub struct SymbolizedAddress(pub usize);
+
+impl fmt::Display for SymbolizedAddress {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{:#018X}", self.0)?;
+
+		if let Some((name, offset)) = lookup(self.0) {
+			write!(f, " {}+{:#X}", name, offset)?;
+		}
+
+		Ok(())
+	}
+}
@@ -263,6 +263,28 @@ impl<T: ?Sized> SpinlockIrqSave<T>
 			data: unsafe { &mut *self.data.get() },
 		}
 	}
+
+	/// Acquires the lock only if it is free, for code paths that must not wait for it (like a panic).
+	pub fn try_lock(&self) -> Option<SpinlockIrqSaveGuard<T>>
+	{
+		let irq = irq::nested_disable();
+
+		// The lock is free if no ticket has been drawn beyond the one currently served.
+		let ticket = self.dequeue.load(Ordering::SeqCst);
+		if self.queue.compare_exchange(ticket.wrapping_sub(1), ticket, Ordering::SeqCst, Ordering::SeqCst).is_err() {
+			irq::nested_enable(irq);
+			return None;
+		}
+
+		self.irq.store(irq, Ordering::SeqCst);
+		Some(SpinlockIrqSaveGuard
+		{
+			//queue: &self.queue,
+			dequeue: &self.dequeue,
+			irq: &self.irq,
+			data: unsafe { &mut *self.data.get() },
+		})
+	}
 }
 
 impl<T: ?Sized + fmt::Debug> fmt::Debug for SpinlockIrqSave<T>
@@ -23,22 +23,26 @@
 // WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
 
 mod generic;
+#[cfg(not(feature = "smoltcp"))]
 mod proxy;
 mod uhyve;
 
 pub use self::generic::*;
+#[cfg(not(feature = "smoltcp"))]
 pub use self::proxy::*;
 pub use self::uhyve::*;
 use arch;
 use console;
-use core::fmt::Write;
-use core::{isize, slice, str};
+use core::{isize, slice};
 use errno::*;
+use fs;
+use fs::{IoVec, Stat, SEEK_CUR, SEEK_SET};
 
 
 pub trait SyscallInterface : Send + Sync {
-	fn init(&self) {
+	fn init(&self) -> Result<(), i32> {
 		// Interface-specific initialization steps.
+		Ok(())
 	}
 
 	fn shutdown(&self) -> ! {
@@ -60,9 +64,14 @@ pub trait SyscallInterface : Send + Sync {
 		-EINVAL
 	}
 
-	fn read(&self, _fd: i32, _buf: *mut u8, _len: usize) -> isize {
-		debug!("read is unimplemented, returning -ENOSYS");
-		-ENOSYS as isize
+	fn read(&self, fd: i32, buf: *mut u8, len: usize) -> isize {
+		if fd != 0 {
+			debug!("read is only implemented for stdin");
+			return -EBADF as isize;
+		}
+
+		let slice = unsafe { slice::from_raw_parts_mut(buf, len) };
+		console::read_input(slice) as isize
 	}
 
 	fn write(&self, fd: i32, buf: *const u8, len: usize) -> isize {
@@ -75,7 +84,7 @@ pub trait SyscallInterface : Send + Sync {
 
 		unsafe {
 			let slice = slice::from_raw_parts(buf, len);
-			console::CONSOLE.lock().write_str(str::from_utf8_unchecked(slice)).unwrap();
+			console::write_application_output(slice);
 		}
 
 		len as isize
@@ -86,12 +95,93 @@ pub trait SyscallInterface : Send + Sync {
 		-ENOSYS as isize
 	}
 
-	fn stat(&self, _file: *const u8, _st: usize) -> i32 {
+	fn readv(&self, fd: i32, iov: &[IoVec]) -> isize {
+		fs::transfer_vectored(iov, |buf, len| self.read(fd, buf, len))
+	}
+
+	fn writev(&self, fd: i32, iov: &[IoVec]) -> isize {
+		fs::transfer_vectored(iov, |buf, len| self.write(fd, buf, len))
+	}
+
+	fn pread(&self, fd: i32, buf: *mut u8, len: usize, offset: isize) -> isize {
+		transfer_at(self, fd, offset, || self.read(fd, buf, len))
+	}
+
+	fn pwrite(&self, fd: i32, buf: *const u8, len: usize, offset: isize) -> isize {
+		transfer_at(self, fd, offset, || self.write(fd, buf, len))
+	}
+
+	fn stat(&self, _name: *const u8, _stat: &mut Stat) -> i32 {
 		debug!("stat is unimplemented");
 		-ENOSYS
 	}
 
+	fn lstat(&self, _name: *const u8, _stat: &mut Stat) -> i32 {
+		debug!("lstat is unimplemented");
+		-ENOSYS
+	}
+
+	fn fstat(&self, _fd: i32, _stat: &mut Stat) -> i32 {
+		debug!("fstat is unimplemented");
+		-ENOSYS
+	}
+
+	fn unlink(&self, _name: *const u8) -> i32 {
+		debug!("unlink is unimplemented");
+		-ENOSYS
+	}
+
+	fn mkdir(&self, _name: *const u8, _mode: u32) -> i32 {
+		debug!("mkdir is unimplemented");
+		-ENOSYS
+	}
+
+	fn rmdir(&self, _name: *const u8) -> i32 {
+		debug!("rmdir is unimplemented");
+		-ENOSYS
+	}
+
+	fn rename(&self, _old_name: *const u8, _new_name: *const u8) -> i32 {
+		debug!("rename is unimplemented");
+		-ENOSYS
+	}
+
+	fn getdents(&self, _fd: i32, _buf: *mut u8, _len: usize) -> isize {
+		debug!("getdents is unimplemented");
+		-ENOSYS as isize
+	}
+
+	fn access(&self, _name: *const u8, _mode: i32) -> i32 {
+		debug!("access is unimplemented");
+		-ENOSYS
+	}
+
 	fn putchar(&self, character: u8) {
-		arch::output_message_byte(character);
+		console::write_application_output(&[character]);
+	}
+}
+
+/// Emulates pread() and pwrite() for interfaces without positioned transfers.
+/// The descriptor is moved to `offset` for the transfer and afterwards back to its original offset.
+/// Unlike a native implementation, this is not atomic with respect to other users of the same descriptor.
+fn transfer_at<I, F>(interface: &I, fd: i32, offset: isize, transfer: F) -> isize
+	where I: SyscallInterface + ?Sized, F: FnOnce() -> isize
+{
+	if offset < 0 {
+		return -EINVAL as isize;
 	}
+
+	let original_offset = interface.lseek(fd, 0, SEEK_CUR);
+	if original_offset < 0 {
+		return original_offset;
+	}
+
+	let ret = interface.lseek(fd, offset, SEEK_SET);
+	if ret < 0 {
+		return ret;
+	}
+
+	let ret = transfer();
+	interface.lseek(fd, original_offset, SEEK_SET);
+	ret
 }
@@ -22,23 +22,56 @@
 // OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
 // WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
 
+//! Interface to the proxy running on the host, which performs system calls on behalf of HermitCore.
+//!
+//! Each message carries its tag and length in a header (see Message) and the version of this wire format
+//! is negotiated after HERMIT_MAGIC, so that kernels and proxies of different versions detect the mismatch
+//! instead of misinterpreting the stream.
+//! Proxies without this negotiation send LEGACY_HERMIT_MAGIC followed by arbitrary data,
+//! so the magic number has been changed along with the introduction of the wire format.
+//!
+//! Several tasks may have requests in flight at the same time.
+//! Every request carries an id, which the proxy repeats in the header of its reply.
+//! There is no dedicated task receiving the replies. Instead, one of the waiting tasks becomes the receiver,
+//! dispatches all replies to the waiting tasks, and hands over this role when its own reply has arrived.
+//!
+//! lwIP is not built for concurrent calls on the same socket (there is no LWIP_NETCONN_FULLDUPLEX),
+//! so each call is protected by LWIP_LOCK. This lock disables interrupts and must never be held while waiting
+//! for the proxy. Therefore, the socket is only used with MSG_DONTWAIT and tasks yield while it is not ready.
+//!
+//! If the connection breaks, all pending and future requests fail with EIO instead of waiting forever.
+
+use alloc::arc::Arc;
+use alloc::btree_map::*;
+use alloc::vec::Vec;
 use arch;
-use core::mem;
+use core::{cmp, mem, slice};
+use core::sync::atomic::{AtomicBool, AtomicIsize, AtomicUsize, Ordering};
+use errno::*;
+use fs::{IoVec, Stat};
 use scheduler;
-use syscalls::{LWIP_FD_BIT,LWIP_LOCK};
+use synch::semaphore::Semaphore;
+use synch::spinlock::SpinlockIrqSave;
+use syscalls::{LWIP_LOCK, sys_lwip_get_errno, sys_yield};
 use syscalls::interfaces::SyscallInterface;
-use syscalls::lwip::sys_lwip_get_errno;
 
 extern "C" {
 	fn get_proxy_socket() -> i32;
-	fn lwip_write(fd: i32, buf: *const u8, len: usize) -> i32;
-	fn lwip_read(fd: i32, buf: *mut u8, len: usize) -> i32;
+	fn lwip_send(fd: i32, buf: *const u8, len: usize, flags: i32) -> isize;
+	fn lwip_recv(fd: i32, buf: *mut u8, len: usize, flags: i32) -> isize;
 	fn lwip_close(fd: i32) -> i32;
 	fn c_strlen(buf: *const u8) -> usize;
 }
 
 
-const HERMIT_MAGIC: i32	= 0x7E317;
+const HERMIT_MAGIC: i32	= 0x7E318;
+/// Magic number of proxies that do not negotiate the version of the wire format.
+const LEGACY_HERMIT_MAGIC: i32 = 0x7E317;
+/// Version of the wire format, which is negotiated after HERMIT_MAGIC.
+const PROTOCOL_VERSION: u32 = 1;
+
+/// lwIP flag letting a socket call fail with EWOULDBLOCK instead of blocking.
+const MSG_DONTWAIT: i32 = 0x08;
 
 const NR_EXIT: i32 = 0;
 const NR_WRITE: i32	= 1;
@@ -46,11 +79,81 @@ const NR_OPEN: i32 = 2;
 const NR_CLOSE: i32 = 3;
 const NR_READ: i32 = 4;
 const NR_LSEEK: i32 = 5;
+const NR_STAT: i32 = 6;
+const NR_FSTAT: i32 = 7;
+const NR_LSTAT: i32 = 8;
+const NR_UNLINK: i32 = 9;
+const NR_MKDIR: i32 = 10;
+const NR_RMDIR: i32 = 11;
+const NR_RENAME: i32 = 12;
+const NR_GETDENTS: i32 = 13;
+const NR_ACCESS: i32 = 14;
+const NR_PREAD: i32 = 15;
+const NR_PWRITE: i32 = 16;
 
 static mut LIBC_SD: i32 = -1 as i32;
 
+/// Id of the next request sent to the proxy.
+static NEXT_REQUEST_ID: AtomicUsize = AtomicUsize::new(1);
+/// Set while a task is receiving replies on behalf of all waiting tasks.
+static RECEIVER_ACTIVE: AtomicBool = AtomicBool::new(false);
+/// Set when sending or receiving has failed, after which the connection is unusable.
+static CONNECTION_BROKEN: AtomicBool = AtomicBool::new(false);
+
+
+/// A request waiting for its reply.
+struct PendingRequest {
+	/// Buffers receiving the data following the reply header
+	reply_iov: Vec<IoVec>,
+	/// Result of the request, valid once `done` has been set
+	ret: AtomicIsize,
+	done: AtomicBool,
+	/// Released when the reply has arrived or when this task shall become the receiver
+	wakeup: Semaphore,
+}
+
+// The buffers belong to the waiting task, which does not return before the reply has been received.
+// The semaphore only accesses its queue of waiting tasks under its lock.
+unsafe impl Send for PendingRequest {}
+unsafe impl Sync for PendingRequest {}
+
+/// Serializes sending requests, so that their bytes are not interleaved.
+/// A semaphore is used instead of a spinlock, because sending may block.
+struct SendLock(Semaphore);
+
+// The semaphore only accesses its queue of waiting tasks under its lock.
+unsafe impl Send for SendLock {}
+unsafe impl Sync for SendLock {}
+
+impl PendingRequest {
+	fn new(reply_iov: &[IoVec]) -> Self {
+		Self {
+			reply_iov: reply_iov.to_vec(),
+			ret: AtomicIsize::new(0),
+			done: AtomicBool::new(false),
+			wakeup: Semaphore::new(0),
+		}
+	}
+
+	/// Stores the result of the request and wakes up the waiting task.
+	fn finish(&self, ret: isize) {
+		self.ret.store(ret, Ordering::SeqCst);
+		self.done.store(true, Ordering::SeqCst);
+		self.wakeup.release();
+	}
+}
+
+lazy_static! {
+	/// Requests that have been sent, but not yet answered, by their id.
+	static ref PENDING_REQUESTS: SpinlockIrqSave<BTreeMap<u32, Arc<PendingRequest>>> = SpinlockIrqSave::new(BTreeMap::new());
+
+	static ref SEND_LOCK: SendLock = SendLock(Semaphore::new(1));
+}
+
+
 fn proxy_close()
 {
+	// take lock to protect LwIP
 	let _guard = LWIP_LOCK.lock();
 
 	unsafe {
@@ -59,323 +162,520 @@ fn proxy_close()
 	}
 }
 
-fn proxy_read<T>(buf: *mut T) {
-	let mut len: usize = 0;
+fn proxy_read<T>(buf: *mut T) -> Result<(), i32> {
+	proxy_read_bytes(buf as *mut u8, mem::size_of::<T>())
+}
 
-	while len < mem::size_of::<T>() {
-		unsafe {
-			let ret = lwip_read(LIBC_SD, (buf as usize + len) as *mut u8, mem::size_of::<T>()-len);
+fn proxy_write<T>(buf: *const T) -> Result<(), i32> {
+	proxy_write_bytes(buf as *const u8, mem::size_of::<T>())
+}
 
-			if ret > 0 {
-				len = len + ret as usize;
-			}
+/// Performs a non-blocking socket call until it does not fail with EWOULDBLOCK.
+/// LWIP_LOCK is only held during each call, so that other tasks (including the lwIP thread) run in between.
+fn retry_nonblocking<F: FnMut() -> isize>(mut f: F) -> isize {
+	loop {
+		let ret = {
+			// take lock to protect LwIP
+			let _guard = LWIP_LOCK.lock();
+			f()
+		};
+
+		if ret >= 0 || sys_lwip_get_errno() != EWOULDBLOCK {
+			return ret;
 		}
+
+		sys_yield();
 	}
 }
 
-fn proxy_write<T>(buf: *const T) {
-	let mut len: usize = 0;
+/// Sends `len` bytes of the buffer without any header.
+fn proxy_write_bytes(buf: *const u8, len: usize) -> Result<(), i32> {
+	let mut i: usize = 0;
 
-	while len < mem::size_of::<T>() {
-		unsafe {
-			let ret = lwip_write(LIBC_SD, (buf as usize + len) as *const u8, mem::size_of::<T>()-len);
+	while i < len {
+		let ret = retry_nonblocking(|| unsafe { lwip_send(LIBC_SD, (buf as usize + i) as *const u8, len-i, MSG_DONTWAIT) });
 
-			if ret > 0 {
-				len = len + ret as usize;
-			}
+		if ret <= 0 {
+			return Err(connection_broken("send"));
 		}
+
+		i = i + ret as usize;
 	}
+
+	Ok(())
 }
 
-fn setup_connection(fd: i32) {
-	info!("Setup connection to proxy!");
+/// Receives exactly `len` bytes into the buffer.
+fn proxy_read_bytes(buf: *mut u8, len: usize) -> Result<(), i32> {
+	let mut i: usize = 0;
 
-	unsafe {
-		LIBC_SD = fd;
-	}
+	while i < len {
+		let ret = retry_nonblocking(|| unsafe { lwip_recv(LIBC_SD, (buf as usize + i) as *mut u8, len-i, MSG_DONTWAIT) });
 
-	let mut magic: i32 = 0;
-	proxy_read(&mut magic as *mut i32);
+		// The proxy never closes the connection, so the end of the stream is an error as well.
+		if ret <= 0 {
+			return Err(connection_broken("receive"));
+		}
 
-	if magic != HERMIT_MAGIC {
-		proxy_close();
-		panic!("Invalid magic number {}", magic);
+		i = i + ret as usize;
 	}
 
-	debug!("Receive magic number {}", magic);
-
-	//let mut argc: i32 = 0;
-	//proxy_read(&mut argc as *mut i32);
+	Ok(())
 }
 
+/// Receives and drops `len` bytes.
+fn proxy_discard_bytes(mut len: usize) -> Result<(), i32> {
+	let mut buffer = [0u8; 64];
 
-//
-// TODO: Use a big "tagged union" (aka Rust "enum") with #[repr(C)] for these structures
-// once https://github.com/rust-lang/rfcs/blob/master/text/2195-really-tagged-unions.md
-// has been implemented.
-//
-#[repr(C, packed)]
-struct SysWrite {
-	sysnr: i32,
-	fd: i32,
-	len: usize
+	while len > 0 {
+		let count = cmp::min(len, buffer.len());
+		proxy_read_bytes(buffer.as_mut_ptr(), count)?;
+		len -= count;
+	}
+
+	Ok(())
 }
 
-impl SysWrite {
-	fn new(fd: i32, len: usize) -> SysWrite {
-		SysWrite {
-			sysnr: NR_WRITE,
-			fd: fd,
-			len: len
-		}
+/// Marks the connection as broken and returns the error reported for all requests from now on.
+fn connection_broken(operation: &str) -> i32 {
+	if !CONNECTION_BROKEN.swap(true, Ordering::SeqCst) {
+		error!("Unable to {} on the connection to the proxy", operation);
 	}
-}
 
-#[repr(C, packed)]
-struct SysClose {
-	sysnr: i32,
-	fd: i32
+	-EIO
 }
 
-impl SysClose {
-	fn new(fd: i32) -> SysClose {
-		SysClose {
-			sysnr: NR_CLOSE,
-			fd: fd
-		}
+/// Lets all requests waiting for a reply fail, because the connection is broken.
+fn fail_pending_requests() {
+	let mut pending_requests = PENDING_REQUESTS.lock();
+
+	for (_, pending) in pending_requests.iter() {
+		pending.finish(-EIO as isize);
 	}
-}
 
-#[repr(C, packed)]
-struct SysOpen {
-	sysnr: i32
+	pending_requests.clear();
 }
 
-impl SysOpen {
-	fn new() -> SysOpen {
-		SysOpen {
-			sysnr: NR_OPEN
-		}
+fn setup_connection(fd: i32) -> Result<(), i32> {
+	info!("Setup connection to proxy!");
+
+	unsafe {
+		LIBC_SD = fd;
 	}
-}
 
-#[repr(C, packed)]
-struct SysExit {
-	sysnr: i32,
-	arg: i32
+	let result = negotiate_version();
+	if result.is_err() {
+		proxy_close();
+	}
+
+	result
 }
 
-impl SysExit {
-	fn new(arg: i32) -> SysExit {
-		SysExit {
-			sysnr: NR_EXIT,
-			arg: arg
-		}
+/// Checks the magic number of the proxy and negotiates the version of the wire format.
+/// Both sides send their version, so that both of them can report a mismatch.
+fn negotiate_version() -> Result<(), i32> {
+	let mut magic: i32 = 0;
+	proxy_read(&mut magic as *mut i32)?;
+
+	if magic == LEGACY_HERMIT_MAGIC {
+		error!("The proxy is too old for this kernel, please update it");
+		return Err(-EPROTONOSUPPORT);
+	} else if magic != HERMIT_MAGIC {
+		error!("Invalid magic number {:#X} received from the proxy", magic);
+		return Err(-EPROTO);
+	}
+
+	debug!("Receive magic number {:#X}", magic);
+
+	let mut proxy_version: u32 = 0;
+	proxy_read(&mut proxy_version as *mut u32)?;
+
+	let version = PROTOCOL_VERSION;
+	proxy_write(&version as *const u32)?;
+
+	if proxy_version != PROTOCOL_VERSION {
+		error!("The proxy uses protocol version {}, but the kernel supports version {}", proxy_version, PROTOCOL_VERSION);
+		return Err(-EPROTONOSUPPORT);
 	}
+
+	Ok(())
 }
 
+
+/// Header of each reply of the proxy, followed by `len` bytes of data.
 #[repr(C, packed)]
-struct SysRead {
-	sysnr: i32,
-	fd: i32,
-	len: usize
+#[derive(Default)]
+struct ReplyHeader {
+	id: u32,
+	ret: i64,
+	len: u64
 }
 
-impl SysRead {
-	fn new(fd: i32, len: usize) -> SysRead {
-		SysRead {
-			sysnr: NR_READ,
-			fd: fd,
-			len: len
-		}
-	}
+/// Header of each message sent to the proxy, followed by `len` bytes of arguments and data.
+#[repr(C, packed)]
+struct MessageHeader {
+	tag: u32,
+	id: u32,
+	len: u64
 }
 
-#[repr(C, packed)]
-struct SysLseek {
-	sysnr: i32,
-	fd: i32,
-	offset: isize,
-	whence: i32
+/// A message to the proxy in version PROTOCOL_VERSION of the wire format.
+/// The arguments are serialized in the order of the fields, strings are preceded by their length.
+/// Buffers (`data`) are sent behind all other arguments, so the proxy derives their length from the message length.
+enum Message<'a> {
+	Exit { arg: i32 },
+	Write { fd: i32, data: &'a [IoVec] },
+	Open { name: *const u8, flags: i32, mode: i32 },
+	Close { fd: i32 },
+	Read { fd: i32, len: usize },
+	Lseek { fd: i32, offset: isize, whence: i32 },
+	Stat { name: *const u8 },
+	Fstat { fd: i32 },
+	Lstat { name: *const u8 },
+	Unlink { name: *const u8 },
+	Mkdir { name: *const u8, mode: u32 },
+	Rmdir { name: *const u8 },
+	Rename { old_name: *const u8, new_name: *const u8 },
+	Getdents { fd: i32, len: usize },
+	Access { name: *const u8, mode: i32 },
+	Pread { fd: i32, len: usize, offset: isize },
+	Pwrite { fd: i32, offset: isize, data: &'a [IoVec] },
 }
 
-impl SysLseek {
-	fn new(fd: i32, offset: isize, whence: i32) -> SysLseek {
-		SysLseek {
-			sysnr: NR_LSEEK,
-			fd: fd,
-			offset: offset,
-			whence: whence
+impl<'a> Message<'a> {
+	fn tag(&self) -> u32 {
+		let tag = match *self {
+			Message::Exit { .. } => NR_EXIT,
+			Message::Write { .. } => NR_WRITE,
+			Message::Open { .. } => NR_OPEN,
+			Message::Close { .. } => NR_CLOSE,
+			Message::Read { .. } => NR_READ,
+			Message::Lseek { .. } => NR_LSEEK,
+			Message::Stat { .. } => NR_STAT,
+			Message::Fstat { .. } => NR_FSTAT,
+			Message::Lstat { .. } => NR_LSTAT,
+			Message::Unlink { .. } => NR_UNLINK,
+			Message::Mkdir { .. } => NR_MKDIR,
+			Message::Rmdir { .. } => NR_RMDIR,
+			Message::Rename { .. } => NR_RENAME,
+			Message::Getdents { .. } => NR_GETDENTS,
+			Message::Access { .. } => NR_ACCESS,
+			Message::Pread { .. } => NR_PREAD,
+			Message::Pwrite { .. } => NR_PWRITE,
+		};
+
+		tag as u32
+	}
+
+	/// Serializes all arguments except for the buffers.
+	fn encode_arguments(&self) -> Vec<u8> {
+		let mut encoder = Encoder { data: Vec::new() };
+
+		match *self {
+			Message::Exit { arg } => encoder.push(arg),
+			Message::Write { fd, .. } => encoder.push(fd),
+			Message::Open { name, flags, mode } => {
+				encoder.push_string(name);
+				encoder.push(flags);
+				encoder.push(mode);
+			},
+			Message::Close { fd } => encoder.push(fd),
+			Message::Read { fd, len } | Message::Getdents { fd, len } => {
+				encoder.push(fd);
+				encoder.push(len);
+			},
+			Message::Lseek { fd, offset, whence } => {
+				encoder.push(fd);
+				encoder.push(offset);
+				encoder.push(whence);
+			},
+			Message::Stat { name } | Message::Lstat { name } | Message::Unlink { name } | Message::Rmdir { name } => {
+				encoder.push_string(name);
+			},
+			Message::Fstat { fd } => encoder.push(fd),
+			Message::Mkdir { name, mode } => {
+				encoder.push_string(name);
+				encoder.push(mode);
+			},
+			Message::Rename { old_name, new_name } => {
+				encoder.push_string(old_name);
+				encoder.push_string(new_name);
+			},
+			Message::Access { name, mode } => {
+				encoder.push_string(name);
+				encoder.push(mode);
+			},
+			Message::Pread { fd, len, offset } => {
+				encoder.push(fd);
+				encoder.push(len);
+				encoder.push(offset);
+			},
+			Message::Pwrite { fd, offset, .. } => {
+				encoder.push(fd);
+				encoder.push(offset);
+			},
+		}
+
+		encoder.data
+	}
+
+	/// Returns the buffers sent behind the arguments.
+	fn data(&self) -> &'a [IoVec] {
+		match *self {
+			Message::Write { data, .. } | Message::Pwrite { data, .. } => data,
+			_ => &[],
 		}
 	}
-}
 
+	/// Sends the message with the given id.
+	fn send(&self, id: u32) -> Result<(), i32> {
+		let arguments = self.encode_arguments();
+		let data = self.data();
+		let data_len = data.iter().fold(0, |sum, vec| sum + vec.iov_len);
 
-pub struct Proxy;
+		let header = MessageHeader {
+			tag: self.tag(),
+			id: id,
+			len: (arguments.len() + data_len) as u64
+		};
 
-impl SyscallInterface for Proxy {
-	fn init(&self) {
-		let fd = unsafe { get_proxy_socket() };
-		assert!(fd >= 0);
-		setup_connection(fd);
+		SEND_LOCK.0.acquire(None);
+
+		let result = if CONNECTION_BROKEN.load(Ordering::SeqCst) {
+			Err(-EIO)
+		} else {
+			write_message(&header, &arguments, data)
+		};
+
+		SEND_LOCK.0.release();
+		result
 	}
 
-	fn shutdown(&self) -> ! {
-		let _guard = LWIP_LOCK.lock();
+	/// Sends the message and waits for the reply.
+	/// The data of the reply is received into the given buffers.
+	fn call(&self, reply_iov: &[IoVec]) -> isize {
+		let id = NEXT_REQUEST_ID.fetch_add(1, Ordering::SeqCst) as u32;
 
-		let sysargs = SysExit::new(scheduler::get_last_exit_code());
-		proxy_write(&sysargs as *const SysExit);
+		// Register the request before sending it, because any task may receive the reply.
+		let pending = Arc::new(PendingRequest::new(reply_iov));
+		PENDING_REQUESTS.lock().insert(id, pending.clone());
 
-		loop {
-			arch::processor::halt();
+		if let Err(e) = self.send(id) {
+			// Nobody may receive replies on a broken connection, so don't wait.
+			fail_pending_requests();
+			return e as isize;
 		}
+
+		wait_for_reply(&pending)
 	}
+}
 
-	fn open(&self, name: *const u8, flags: i32, mode: i32) -> i32 {
-		let _guard = LWIP_LOCK.lock();
-		let sysargs = SysOpen::new();
-		proxy_write(&sysargs as *const SysOpen);
+/// Writes the header, the arguments, and the buffers of a message.
+/// The caller has to hold SEND_LOCK.
+fn write_message(header: &MessageHeader, arguments: &[u8], data: &[IoVec]) -> Result<(), i32> {
+	proxy_write(header as *const MessageHeader)?;
+	proxy_write_bytes(arguments.as_ptr(), arguments.len())?;
+	for vec in data {
+		proxy_write_bytes(vec.iov_base, vec.iov_len)?;
+	}
 
-		let len;
-		unsafe { len = c_strlen(name) + 1; }
-		proxy_write(&len as *const usize);
+	Ok(())
+}
 
-		let mut i: usize = 0;
-		while i < len {
-			let ret;
+/// Serializes the arguments of a message.
+struct Encoder {
+	data: Vec<u8>,
+}
 
-			unsafe {
-				ret = lwip_write(LIBC_SD, (name as usize + i) as *const u8, len-i);
-			}
+impl Encoder {
+	/// Appends the bytes of a plain value.
+	fn push<T: Copy>(&mut self, value: T) {
+		let bytes = unsafe { slice::from_raw_parts(&value as *const T as *const u8, mem::size_of::<T>()) };
+		self.data.extend_from_slice(bytes);
+	}
 
-			if ret > 0 {
-				i = i + ret as usize;
-			}
-		}
+	/// Appends a NUL-terminated string preceded by its length (including the NUL character).
+	fn push_string(&mut self, name: *const u8) {
+		let len = unsafe { c_strlen(name) + 1 };
+		self.push(len as u64);
+		self.data.extend_from_slice(unsafe { slice::from_raw_parts(name, len) });
+	}
+}
 
-		proxy_write(&flags as *const i32);
-		proxy_write(&mode as *const i32);
+/// Receives a single reply and passes it to the task waiting for it.
+fn receive_reply() -> Result<(), i32> {
+	let mut header = ReplyHeader::default();
+	proxy_read(&mut header as *mut ReplyHeader)?;
 
-		let mut ret: i32 = 0;
-		proxy_read(&mut ret as *mut i32);
+	let id = header.id;
+	let mut remaining = header.len as usize;
 
-		ret
-	}
+	let pending = PENDING_REQUESTS.lock().remove(&id);
+	match pending {
+		Some(pending) => {
+			for vec in pending.reply_iov.iter() {
+				if remaining == 0 {
+					break;
+				}
 
-	fn close(&self, fd: i32) -> i32 {
-		// take lock to protect LwIP
-		let _guard = LWIP_LOCK.lock();
+				let len = cmp::min(vec.iov_len, remaining);
+				if let Err(e) = proxy_read_bytes(vec.iov_base, len) {
+					// This request has already been removed, so it has to fail on its own.
+					pending.finish(e as isize);
+					return Err(e);
+				}
 
-		// do we have an LwIP file descriptor?
-		if (fd & LWIP_FD_BIT) != 0 {
-			let ret;
+				remaining -= len;
+			}
 
-			unsafe { ret = lwip_close(fd & !LWIP_FD_BIT); }
-			if ret < 0 {
-				return -sys_lwip_get_errno() as i32;
+			if remaining > 0 {
+				warn!("Reply to proxy request {} contains {} unexpected bytes", id, remaining);
+				if let Err(e) = proxy_discard_bytes(remaining) {
+					pending.finish(e as isize);
+					return Err(e);
+				}
 			}
 
-			return ret as i32;
+			pending.finish(header.ret as isize);
+			Ok(())
+		},
+		None => {
+			warn!("Received a reply to the unknown proxy request {}", id);
+			proxy_discard_bytes(remaining)
 		}
+	}
+}
 
-		let sysargs = SysClose::new(fd);
-		proxy_write(&sysargs as *const SysClose);
+/// Waits until the reply to the given request has arrived and returns its result.
+fn wait_for_reply(pending: &PendingRequest) -> isize {
+	while !pending.done.load(Ordering::SeqCst) {
+		if RECEIVER_ACTIVE.compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst).is_ok() {
+			// This task receives the replies for all tasks until its own one has arrived.
+			while !pending.done.load(Ordering::SeqCst) {
+				if receive_reply().is_err() {
+					fail_pending_requests();
+				}
+			}
 
-		let mut ret: i32 = 0;
-		proxy_read(&mut ret as *mut i32);
+			RECEIVER_ACTIVE.store(false, Ordering::SeqCst);
 
-		ret
+			// Hand over the receiver role to a task that is still waiting.
+			if let Some(next) = PENDING_REQUESTS.lock().values().next() {
+				next.wakeup.release();
+			}
+		} else {
+			// Another task is receiving and wakes us up when our reply has arrived or when we shall take over.
+			pending.wakeup.acquire(None);
+		}
 	}
 
-	fn read(&self, fd: i32, buf: *mut u8, len: usize) -> isize {
-		// take lock to protect LwIP
-		let _guard = LWIP_LOCK.lock();
+	pending.ret.load(Ordering::SeqCst)
+}
 
-		// do we have an LwIP file descriptor?
-		if (fd & LWIP_FD_BIT) != 0 {
-			let ret;
+/// Returns a buffer descriptor for a single buffer.
+fn buffer(buf: *const u8, len: usize) -> IoVec {
+	IoVec { iov_base: buf as *mut u8, iov_len: len }
+}
 
-			unsafe { ret = lwip_read(fd & !LWIP_FD_BIT, buf as *mut u8, len); }
-			if ret < 0 {
-				return -sys_lwip_get_errno() as isize;
-			}
+/// Returns a buffer descriptor for a Stat structure filled by the proxy.
+fn stat_buffer(stat: &mut Stat) -> IoVec {
+	buffer(stat as *mut Stat as *const u8, mem::size_of::<Stat>())
+}
 
-			return ret as isize;
-		}
+pub struct Proxy;
 
-		let sysargs = SysRead::new(fd, len);
-		proxy_write(&sysargs as *const SysRead);
+impl SyscallInterface for Proxy {
+	fn init(&self) -> Result<(), i32> {
+		let fd = unsafe { get_proxy_socket() };
+		if fd < 0 {
+			error!("Unable to get the socket connected to the proxy");
+			return Err(fd);
+		}
 
-		let mut j: isize = 0;
-		proxy_read(&mut j as *mut isize);
+		setup_connection(fd)
+	}
 
-		if j > 0 {
-			let mut i: isize = 0;
+	fn shutdown(&self) -> ! {
+		// There is no reply to this message and nothing left to do if the connection is broken.
+		Message::Exit { arg: scheduler::get_last_exit_code() }.send(0).ok();
 
-			while i < j {
-				let ret;
+		loop {
+			arch::processor::halt();
+		}
+	}
 
-				unsafe {
-					ret = lwip_read(LIBC_SD, (buf as isize + i) as *mut u8, (j-i) as usize);
-				}
+	fn open(&self, name: *const u8, flags: i32, mode: i32) -> i32 {
+		Message::Open { name: name, flags: flags, mode: mode }.call(&[]) as i32
+	}
 
-				if ret > 0 {
-					i = i + ret as isize;
-				}
-			}
-		}
+	fn close(&self, fd: i32) -> i32 {
+		Message::Close { fd: fd }.call(&[]) as i32
+	}
 
-		j
+	fn read(&self, fd: i32, buf: *mut u8, len: usize) -> isize {
+		Message::Read { fd: fd, len: len }.call(&[buffer(buf, len)])
 	}
 
 	fn write(&self, fd: i32, buf: *const u8, len: usize) -> isize {
-		// take lock to protect LwIP
-		let _guard = LWIP_LOCK.lock();
+		Message::Write { fd: fd, data: &[buffer(buf, len)] }.call(&[])
+	}
 
-		// do we have an LwIP file descriptor?
-		if (fd & LWIP_FD_BIT) != 0 {
-			let ret;
+	fn lseek(&self, fd: i32, offset: isize, whence: i32) -> isize {
+		Message::Lseek { fd: fd, offset: offset, whence: whence }.call(&[])
+	}
 
-			unsafe { ret = lwip_write(fd & !LWIP_FD_BIT, buf as *const u8, len); }
-			if ret < 0 {
-				return -sys_lwip_get_errno() as isize;
-			}
+	fn readv(&self, fd: i32, iov: &[IoVec]) -> isize {
+		// Request the total length with a single read and scatter the received bytes over the buffers.
+		let total = iov.iter().fold(0, |sum, vec| sum + vec.iov_len);
+		Message::Read { fd: fd, len: total }.call(iov)
+	}
 
-			return ret as isize;
-		}
+	fn writev(&self, fd: i32, iov: &[IoVec]) -> isize {
+		// Gather all buffers into a single write message.
+		Message::Write { fd: fd, data: iov }.call(&[])
+	}
 
-		let sysargs = SysWrite::new(fd, len);
-		proxy_write(&sysargs as *const SysWrite);
+	fn pread(&self, fd: i32, buf: *mut u8, len: usize, offset: isize) -> isize {
+		Message::Pread { fd: fd, len: len, offset: offset }.call(&[buffer(buf, len)])
+	}
 
-		let mut i: usize = 0;
+	fn pwrite(&self, fd: i32, buf: *const u8, len: usize, offset: isize) -> isize {
+		Message::Pwrite { fd: fd, offset: offset, data: &[buffer(buf, len)] }.call(&[])
+	}
 
-		while i < len {
-			let ret;
+	fn stat(&self, name: *const u8, stat: &mut Stat) -> i32 {
+		Message::Stat { name: name }.call(&[stat_buffer(stat)]) as i32
+	}
 
-			unsafe {
-				ret = lwip_write(LIBC_SD, (buf as usize + i) as *const u8, len-i);
-			}
+	fn lstat(&self, name: *const u8, stat: &mut Stat) -> i32 {
+		Message::Lstat { name: name }.call(&[stat_buffer(stat)]) as i32
+	}
 
-			if ret > 0 {
-				i = i + ret as usize;
-			}
-		}
+	fn fstat(&self, fd: i32, stat: &mut Stat) -> i32 {
+		Message::Fstat { fd: fd }.call(&[stat_buffer(stat)]) as i32
+	}
 
-		if fd > 2 {
-			proxy_read(&mut i as *mut usize);
-		}
+	fn unlink(&self, name: *const u8) -> i32 {
+		Message::Unlink { name: name }.call(&[]) as i32
+	}
 
-		i as isize
+	fn mkdir(&self, name: *const u8, mode: u32) -> i32 {
+		Message::Mkdir { name: name, mode: mode }.call(&[]) as i32
 	}
 
-	fn lseek(&self, fd: i32, offset: isize, whence: i32) -> isize {
-		let _guard = LWIP_LOCK.lock();
-		let sysargs = SysLseek::new(fd, offset, whence);
-		proxy_write(&sysargs as *const SysLseek);
+	fn rmdir(&self, name: *const u8) -> i32 {
+		Message::Rmdir { name: name }.call(&[]) as i32
+	}
+
+	fn rename(&self, old_name: *const u8, new_name: *const u8) -> i32 {
+		Message::Rename { old_name: old_name, new_name: new_name }.call(&[]) as i32
+	}
 
-		let mut ret: isize = 0;
-		proxy_read(&mut ret as *mut isize);
+	fn getdents(&self, fd: i32, buf: *mut u8, len: usize) -> isize {
+		Message::Getdents { fd: fd, len: len }.call(&[buffer(buf, len)])
+	}
 
-		ret
+	fn access(&self, name: *const u8, mode: i32) -> i32 {
+		Message::Access { name: name, mode: mode }.call(&[]) as i32
 	}
 }
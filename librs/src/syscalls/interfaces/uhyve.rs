@@ -23,23 +23,33 @@
 
 use arch;
 use arch::mm::paging;
+use arch::mm::paging::{BasePageSize, PageSize};
+use core::{cmp, ptr};
+use fs::{IoVec, Stat};
 use scheduler;
-use syscalls::{LWIP_FD_BIT,LWIP_LOCK};
 use syscalls::interfaces::SyscallInterface;
-use syscalls::lwip::sys_lwip_get_errno;
 use x86::shared::io::*;
 
-const UHYVE_PORT_WRITE: u16 = 0x400;
 const UHYVE_PORT_OPEN:	u16 = 0x440;
 const UHYVE_PORT_CLOSE:	u16 = 0x480;
-const UHYVE_PORT_READ:	u16 = 0x500;
 const UHYVE_PORT_EXIT:	u16 = 0x540;
 const UHYVE_PORT_LSEEK:	u16 = 0x580;
-
-extern "C" {
-	fn lwip_write(fd: i32, buf: *const u8, len: usize) -> i32;
-	fn lwip_read(fd: i32, buf: *mut u8, len: usize) -> i32;
-}
+const UHYVE_PORT_STAT:	u16 = 0x7C0;
+const UHYVE_PORT_FSTAT:	u16 = 0x800;
+const UHYVE_PORT_LSTAT:	u16 = 0x840;
+const UHYVE_PORT_UNLINK:	u16 = 0x880;
+const UHYVE_PORT_MKDIR:	u16 = 0x8C0;
+const UHYVE_PORT_RMDIR:	u16 = 0x900;
+const UHYVE_PORT_RENAME:	u16 = 0x940;
+const UHYVE_PORT_GETDENTS:	u16 = 0x980;
+const UHYVE_PORT_ACCESS:	u16 = 0x9C0;
+const UHYVE_PORT_PREAD:	u16 = 0xA00;
+const UHYVE_PORT_PWRITE:	u16 = 0xA40;
+const UHYVE_PORT_READV:	u16 = 0xA80;
+const UHYVE_PORT_WRITEV:	u16 = 0xAC0;
+
+/// Maximum number of physical extents passed to uhyve in a single I/O request.
+const UHYVE_IOV_MAX: usize = 16;
 
 
 /// forward a request to the hypervisor uhyve
@@ -96,58 +106,255 @@ impl SysClose {
 }
 
 #[repr(C)]
-struct SysRead {
+struct SysLseek {
 	fd: i32,
-	buf: *const u8,
-	len: usize,
-	ret: isize
+	offset: isize,
+	whence: i32
 }
 
-impl SysRead {
-	fn new(fd: i32, buf: *const u8, len: usize) -> SysRead {
-		SysRead {
+impl SysLseek {
+	fn new(fd: i32, offset: isize, whence: i32) -> SysLseek {
+		SysLseek {
 			fd: fd,
-			buf: paging::virtual_to_physical(buf as usize) as *const u8,
-			len: len,
+			offset: offset,
+			whence: whence
+		}
+	}
+}
+
+#[repr(C)]
+struct SysStat {
+	name: *const u8,
+	stat: *mut Stat,
+	ret: i32
+}
+
+impl SysStat {
+	fn new(name: *const u8, stat: *mut Stat) -> SysStat {
+		SysStat {
+			name: paging::virtual_to_physical(name as usize) as *const u8,
+			stat: paging::virtual_to_physical(stat as usize) as *mut Stat,
 			ret: -1
 		}
 	}
 }
 
+/// Receives the file status from uhyve, which writes it through a single physical address.
+/// The structure is smaller than its alignment, which divides the page size, so it never straddles a page boundary.
+#[repr(C, align(256))]
+struct StatBuffer(Stat);
+
 #[repr(C)]
-struct SysWrite {
+struct SysFstat {
 	fd: i32,
-	buf: *const u8,
-	len: usize
+	stat: *mut Stat,
+	ret: i32
 }
 
-impl SysWrite {
-	fn new(fd: i32, buf: *const u8, len: usize) -> SysWrite {
-		SysWrite {
+impl SysFstat {
+	fn new(fd: i32, stat: *mut Stat) -> SysFstat {
+		SysFstat {
 			fd: fd,
-			buf: paging::virtual_to_physical(buf as usize) as *const u8,
-			len: len
+			stat: paging::virtual_to_physical(stat as usize) as *mut Stat,
+			ret: -1
 		}
 	}
 }
 
+/// Arguments of all requests that take a path and an optional mode (unlink, mkdir, rmdir, access).
 #[repr(C)]
-struct SysLseek {
+struct SysPath {
+	name: *const u8,
+	mode: i32,
+	ret: i32
+}
+
+impl SysPath {
+	fn new(name: *const u8, mode: i32) -> SysPath {
+		SysPath {
+			name: paging::virtual_to_physical(name as usize) as *const u8,
+			mode: mode,
+			ret: -1
+		}
+	}
+}
+
+#[repr(C)]
+struct SysRename {
+	old_name: *const u8,
+	new_name: *const u8,
+	ret: i32
+}
+
+impl SysRename {
+	fn new(old_name: *const u8, new_name: *const u8) -> SysRename {
+		SysRename {
+			old_name: paging::virtual_to_physical(old_name as usize) as *const u8,
+			new_name: paging::virtual_to_physical(new_name as usize) as *const u8,
+			ret: -1
+		}
+	}
+}
+
+/// Arguments of all I/O requests, which pass the buffers as a list of physical extents.
+/// The offset is only used by positional requests (pread, pwrite).
+#[repr(C)]
+struct SysIov {
 	fd: i32,
+	iov: *const IoVec,
+	iovcnt: i32,
 	offset: isize,
-	whence: i32
+	ret: isize
 }
 
-impl SysLseek {
-	fn new(fd: i32, offset: isize, whence: i32) -> SysLseek {
-		SysLseek {
+impl SysIov {
+	fn new(fd: i32, extents: &PhysicalExtents, offset: isize) -> SysIov {
+		SysIov {
 			fd: fd,
+			iov: paging::virtual_to_physical(extents.extents.as_ptr() as usize) as *const IoVec,
+			iovcnt: extents.count as i32,
 			offset: offset,
-			whence: whence
+			ret: -1
+		}
+	}
+}
+
+/// Physically contiguous parts of one or more buffers in guest memory.
+/// Buffers are only virtually contiguous, so each page has to be translated on its own.
+struct PhysicalExtents {
+	extents: [IoVec; UHYVE_IOV_MAX],
+	count: usize,
+	/// Total length of all extents in bytes
+	len: usize,
+}
+
+impl PhysicalExtents {
+	fn new() -> Self {
+		Self {
+			extents: [IoVec { iov_base: ptr::null_mut(), iov_len: 0 }; UHYVE_IOV_MAX],
+			count: 0,
+			len: 0,
+		}
+	}
+
+	/// Appends a physically contiguous range, merging it with the last extent if possible.
+	/// Returns false if the list is full.
+	fn push(&mut self, physical_address: usize, len: usize) -> bool {
+		let mergeable = self.count > 0 && {
+			let last = &self.extents[self.count - 1];
+			last.iov_base as usize + last.iov_len == physical_address
+		};
+
+		if mergeable {
+			self.extents[self.count - 1].iov_len += len;
+		} else if self.count < UHYVE_IOV_MAX {
+			self.extents[self.count] = IoVec { iov_base: physical_address as *mut u8, iov_len: len };
+			self.count += 1;
+		} else {
+			return false;
+		}
+
+		self.len += len;
+		true
+	}
+
+	fn clear(&mut self) {
+		self.count = 0;
+		self.len = 0;
+	}
+}
+
+/// Sends a single I/O request for the given extents and returns the result of the host.
+fn uhyve_send_extents(port: u16, fd: i32, extents: &PhysicalExtents, offset: isize) -> isize {
+	let mut sysiov = SysIov::new(fd, extents, offset);
+	let raw_mut = &mut sysiov as *mut SysIov;
+
+	uhyve_send(port, paging::virtual_to_physical(raw_mut as usize));
+
+	sysiov.ret
+}
+
+/// Lets uhyve fill the file status for a path (stat, lstat).
+fn uhyve_stat_request(port: u16, name: *const u8, stat: &mut Stat) -> i32 {
+	let mut buffer = StatBuffer(Stat::default());
+	let mut sysstat = SysStat::new(name, &mut buffer.0);
+	let raw_mut = &mut sysstat as *mut SysStat;
+
+	uhyve_send(port, paging::virtual_to_physical(raw_mut as usize));
+
+	if sysstat.ret == 0 {
+		*stat = buffer.0;
+	}
+
+	sysstat.ret
+}
+
+/// Transfers the buffers using as many I/O requests as needed to pass all their physical extents.
+/// Stops at the first request that transfers less than requested, like a single readv or writev would.
+fn uhyve_iov_request(port: u16, fd: i32, iov: &[IoVec], offset: isize) -> isize {
+	let mut extents = PhysicalExtents::new();
+	let mut total: isize = 0;
+
+	for vec in iov {
+		let mut address = vec.iov_base as usize;
+		let end = address + vec.iov_len;
+
+		while address < end {
+			// Translate the buffer page by page.
+			let next_page = align_down!(address, BasePageSize::SIZE) + BasePageSize::SIZE;
+			let len = cmp::min(end, next_page) - address;
+			let physical_address = paging::virtual_to_physical(address);
+
+			if !extents.push(physical_address, len) {
+				// The list is full, so transfer the collected extents before continuing.
+				let ret = uhyve_send_extents(port, fd, &extents, offset + total);
+				if ret < 0 {
+					// Report an error only if nothing has been transferred yet.
+					return if total > 0 { total } else { ret };
+				}
+
+				total += ret;
+				if (ret as usize) < extents.len {
+					return total;
+				}
+
+				extents.clear();
+				extents.push(physical_address, len);
+			}
+
+			address += len;
 		}
 	}
+
+	if extents.count > 0 || total == 0 {
+		let ret = uhyve_send_extents(port, fd, &extents, offset + total);
+		if ret < 0 {
+			return if total > 0 { total } else { ret };
+		}
+
+		total += ret;
+	}
+
+	total
 }
 
+/// Transfers a single buffer like uhyve_iov_request.
+fn uhyve_buffer_request(port: u16, fd: i32, buf: *const u8, len: usize, offset: isize) -> isize {
+	let iov = [IoVec { iov_base: buf as *mut u8, iov_len: len }];
+	uhyve_iov_request(port, fd, &iov, offset)
+}
+
+/// Sends a request taking a path and an optional mode to the given port and returns the result.
+fn uhyve_path_request(port: u16, name: *const u8, mode: i32) -> i32 {
+	let mut syspath = SysPath::new(name, mode);
+	let raw_mut = &mut syspath as *mut SysPath;
+
+	uhyve_send(port, paging::virtual_to_physical(raw_mut as usize));
+
+	syspath.ret
+}
+
+
 
 pub struct Uhyve;
 
@@ -182,57 +389,86 @@ impl SyscallInterface for Uhyve {
 	}
 
 	fn read(&self, fd: i32, buf: *mut u8, len: usize) -> isize {
-		// do we have an LwIP file descriptor?
-		if (fd & LWIP_FD_BIT) != 0 {
-			// take lock to protect LwIP
-			let _guard = LWIP_LOCK.lock();
-			let ret;
-
-			unsafe { ret = lwip_read(fd & !LWIP_FD_BIT, buf as *mut u8, len); }
-			if ret < 0 {
-				return -sys_lwip_get_errno() as isize;
-			}
+		uhyve_buffer_request(UHYVE_PORT_READV, fd, buf, len, 0)
+	}
 
-			return ret as isize;
-		}
+	fn write(&self, fd: i32, buf: *const u8, len: usize) -> isize {
+		uhyve_buffer_request(UHYVE_PORT_WRITEV, fd, buf, len, 0)
+	}
 
-		let mut sysread = SysRead::new(fd, buf, len);
-		let raw_mut = &mut sysread as *mut SysRead;
+	fn lseek(&self, fd: i32, offset: isize, whence: i32) -> isize {
+		let mut syslseek = SysLseek::new(fd, offset, whence);
+		let raw_mut = &mut syslseek as *mut SysLseek;
 
-		uhyve_send(UHYVE_PORT_READ, paging::virtual_to_physical(raw_mut as usize));
+		uhyve_send(UHYVE_PORT_LSEEK, paging::virtual_to_physical(raw_mut as usize));
 
-		sysread.ret
+		syslseek.offset
 	}
 
-	fn write(&self, fd: i32, buf: *const u8, len: usize) -> isize {
-		// do we have an LwIP file descriptor?
-		if (fd & LWIP_FD_BIT) != 0 {
-			// take lock to protect LwIP
-			let _guard = LWIP_LOCK.lock();
-			let ret;
-
-			unsafe { ret = lwip_write(fd & !LWIP_FD_BIT, buf as *const u8, len); }
-			if ret < 0 {
-				return -sys_lwip_get_errno() as isize;
-			}
+	fn readv(&self, fd: i32, iov: &[IoVec]) -> isize {
+		uhyve_iov_request(UHYVE_PORT_READV, fd, iov, 0)
+	}
 
-			return ret as isize;
+	fn writev(&self, fd: i32, iov: &[IoVec]) -> isize {
+		uhyve_iov_request(UHYVE_PORT_WRITEV, fd, iov, 0)
+	}
+
+	fn pread(&self, fd: i32, buf: *mut u8, len: usize, offset: isize) -> isize {
+		uhyve_buffer_request(UHYVE_PORT_PREAD, fd, buf, len, offset)
+	}
+
+	fn pwrite(&self, fd: i32, buf: *const u8, len: usize, offset: isize) -> isize {
+		uhyve_buffer_request(UHYVE_PORT_PWRITE, fd, buf, len, offset)
+	}
+
+	fn stat(&self, name: *const u8, stat: &mut Stat) -> i32 {
+		uhyve_stat_request(UHYVE_PORT_STAT, name, stat)
+	}
+
+	fn lstat(&self, name: *const u8, stat: &mut Stat) -> i32 {
+		uhyve_stat_request(UHYVE_PORT_LSTAT, name, stat)
+	}
+
+	fn fstat(&self, fd: i32, stat: &mut Stat) -> i32 {
+		let mut buffer = StatBuffer(Stat::default());
+		let mut sysfstat = SysFstat::new(fd, &mut buffer.0);
+		let raw_mut = &mut sysfstat as *mut SysFstat;
+
+		uhyve_send(UHYVE_PORT_FSTAT, paging::virtual_to_physical(raw_mut as usize));
+
+		if sysfstat.ret == 0 {
+			*stat = buffer.0;
 		}
 
-		let mut syswrite = SysWrite::new(fd, buf, len);
-		let raw_mut = &mut syswrite as *mut SysWrite;
+		sysfstat.ret
+	}
 
-		uhyve_send(UHYVE_PORT_WRITE, paging::virtual_to_physical(raw_mut as usize));
+	fn unlink(&self, name: *const u8) -> i32 {
+		uhyve_path_request(UHYVE_PORT_UNLINK, name, 0)
+	}
 
-		syswrite.len as isize
+	fn mkdir(&self, name: *const u8, mode: u32) -> i32 {
+		uhyve_path_request(UHYVE_PORT_MKDIR, name, mode as i32)
 	}
 
-	fn lseek(&self, fd: i32, offset: isize, whence: i32) -> isize {
-		let mut syslseek = SysLseek::new(fd, offset, whence);
-		let raw_mut = &mut syslseek as *mut SysLseek;
+	fn rmdir(&self, name: *const u8) -> i32 {
+		uhyve_path_request(UHYVE_PORT_RMDIR, name, 0)
+	}
 
-		uhyve_send(UHYVE_PORT_LSEEK, paging::virtual_to_physical(raw_mut as usize));
+	fn rename(&self, old_name: *const u8, new_name: *const u8) -> i32 {
+		let mut sysrename = SysRename::new(old_name, new_name);
+		let raw_mut = &mut sysrename as *mut SysRename;
 
-		syslseek.offset
+		uhyve_send(UHYVE_PORT_RENAME, paging::virtual_to_physical(raw_mut as usize));
+
+		sysrename.ret
+	}
+
+	fn getdents(&self, fd: i32, buf: *mut u8, len: usize) -> isize {
+		uhyve_buffer_request(UHYVE_PORT_GETDENTS, fd, buf, len, 0)
+	}
+
+	fn access(&self, name: *const u8, mode: i32) -> i32 {
+		uhyve_path_request(UHYVE_PORT_ACCESS, name, mode)
 	}
 }
@@ -21,9 +21,21 @@
 // OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
 // WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
 
+use alloc::arc::Arc;
 use arch::percore::*;
+use errno::*;
+use fs;
+use syscalls::LWIP_FD_BIT;
 use syscalls::tasks::Tid;
 
+// lwIP is compiled with its socket(), accept(), and close() renamed (see librs/CMakeLists.txt),
+// so that the kernel can register every new socket in the descriptor table and remove every closed one.
+extern "C" {
+	fn lwip_socket_unregistered(domain: i32, socket_type: i32, protocol: i32) -> i32;
+	fn lwip_accept_unregistered(s: i32, addr: *mut u8, addrlen: *mut u32) -> i32;
+	pub fn lwip_close_unregistered(s: i32) -> i32;
+}
+
 /// Task ID of the single TCP/IP Task spawned by lwIP.
 /// Can be safely initialized to zero, because this is the ID of the idle task.
 static mut LWIP_TCPIP_TASK_ID: Tid = 0;
@@ -47,3 +59,38 @@ pub extern "C" fn sys_lwip_get_errno() -> i32 {
 pub extern "C" fn sys_lwip_set_errno(errno: i32) {
 	core_scheduler().current_task.borrow_mut().lwip_errno = errno;
 }
+
+/// Adds a socket returned by lwIP to the descriptor table, so that it is read, written, and closed like any other object.
+/// Follows the lwIP conventions of returning -1 and setting the errno on failure.
+fn register_socket(fd: i32) -> i32 {
+	if fd < 0 {
+		return fd;
+	}
+
+	match fs::insert_object_at(fd, Arc::new(fs::Socket::new(fd & !LWIP_FD_BIT))) {
+		Ok(()) => fd,
+		Err(_) => {
+			unsafe { lwip_close_unregistered(fd & !LWIP_FD_BIT); }
+			sys_lwip_set_errno(EMFILE);
+			-1
+		}
+	}
+}
+
+#[no_mangle]
+pub extern "C" fn lwip_socket(domain: i32, socket_type: i32, protocol: i32) -> i32 {
+	register_socket(unsafe { lwip_socket_unregistered(domain, socket_type, protocol) })
+}
+
+#[no_mangle]
+pub extern "C" fn lwip_accept(s: i32, addr: *mut u8, addrlen: *mut u32) -> i32 {
+	register_socket(unsafe { lwip_accept_unregistered(s, addr, addrlen) })
+}
+
+/// Closes a socket through lwIP instead of close(), like closesocket() does.
+/// Its descriptor is removed as well, so that it is free when lwIP hands out the socket again.
+#[no_mangle]
+pub extern "C" fn lwip_close(s: i32) -> i32 {
+	fs::remove_object(s | LWIP_FD_BIT);
+	unsafe { lwip_close_unregistered(s & !LWIP_FD_BIT) }
+}
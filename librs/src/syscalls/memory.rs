@@ -0,0 +1,48 @@
+// Copyright (c) 2018 Colin Finck, RWTH Aachen University
+//
+// MIT License
+//
+// Permission is hereby granted, free of charge, to any person obtaining
+// a copy of this software and associated documentation files (the
+// "Software"), to deal in the Software without restriction, including
+// without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to
+// permit persons to whom the Software is furnished to do so, subject to
+// the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE
+// LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION
+// OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
+// WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+use arch::percore::*;
+use errno::*;
+use mm::stats;
+use mm::stats::MemInfo;
+
+
+/// Fills the structure pointed to by `info` with the current memory statistics.
+#[no_mangle]
+pub extern "C" fn sys_meminfo(info: *mut MemInfo) -> i32 {
+	if info.is_null() {
+		return -EINVAL;
+	}
+
+	let mut meminfo = stats::get();
+
+	// Add the heap size of the calling task.
+	if let Some(ref heap) = core_scheduler().current_task.borrow().heap {
+		let heap_borrowed = heap.borrow();
+		let heap_locked = heap_borrowed.read();
+		meminfo.task_heap_size = heap_locked.end - heap_locked.start;
+	}
+
+	unsafe { *info = meminfo; }
+	0
+}
@@ -23,47 +23,80 @@
 // WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
 
 mod interfaces;
+#[cfg(not(feature = "smoltcp"))]
 mod lwip;
+mod memory;
 mod processor;
 mod random;
 mod recmutex;
 mod semaphore;
+#[cfg(feature = "smoltcp")]
+mod socket;
 mod spinlock;
 mod tasks;
 mod timer;
 
+#[cfg(not(feature = "smoltcp"))]
 pub use self::lwip::*;
+pub use self::memory::*;
 pub use self::processor::*;
 pub use self::random::*;
 pub use self::recmutex::*;
 pub use self::semaphore::*;
+#[cfg(feature = "smoltcp")]
+pub use self::socket::*;
 pub use self::spinlock::*;
 pub use self::tasks::*;
 pub use self::timer::*;
+pub use self::interfaces::SyscallInterface;
+use alloc::arc::Arc;
+use core::{ptr, slice};
 use environment;
+use errno::*;
+use fs;
+#[cfg(not(feature = "smoltcp"))]
 use synch::spinlock::SpinlockIrqSave;
-use syscalls::interfaces::SyscallInterface;
 
-const LWIP_FD_BIT: i32	= (1 << 30);
+#[cfg(not(feature = "smoltcp"))]
+pub const LWIP_FD_BIT: i32	= (1 << 30);
 
+#[cfg(not(feature = "smoltcp"))]
 pub static LWIP_LOCK: SpinlockIrqSave<()> = SpinlockIrqSave::new(());
 static mut SYS: &'static SyscallInterface = &interfaces::Generic;
 
-pub fn enable_networking() {
+/// Switches to the SyscallInterface for the host, which may require networking.
+/// Returns an error and keeps the generic interface if the host cannot be reached.
+pub fn enable_networking() -> Result<(), i32> {
 	unsafe {
 		// We know that HermitCore has successfully initialized a network interface.
 		// Now check if we can load a more specific SyscallInterface to make use of networking.
-		if environment::is_proxy() {
-			SYS = &interfaces::Proxy;
-		} else if environment::is_uhyve() {
+		if environment::is_uhyve() {
 			SYS = &interfaces::Uhyve;
 		}
 
+		// The proxy is reached through an lwIP socket, so initd refuses to start under the proxy without lwIP.
+		#[cfg(not(feature = "smoltcp"))]
+		{
+			if environment::is_proxy() {
+				SYS = &interfaces::Proxy;
+			}
+		}
+
 		// Perform interface-specific initialization steps.
-		SYS.init();
+		let result = SYS.init();
+		if result.is_err() {
+			SYS = &interfaces::Generic;
+		}
+
+		result
 	}
 }
 
+/// Returns the active SyscallInterface for accessing the host.
+pub fn get_interface() -> &'static SyscallInterface {
+	unsafe { SYS }
+}
+
 #[no_mangle]
 pub extern "C" fn sys_shutdown() -> ! {
 	unsafe { SYS.shutdown() }
@@ -71,32 +104,293 @@ pub extern "C" fn sys_shutdown() -> ! {
 
 #[no_mangle]
 pub extern "C" fn sys_open(name: *const u8, flags: i32, mode: i32) -> i32 {
-	unsafe { SYS.open(name, flags, mode) }
+	let path = match unsafe { fs::path_from_c_str(name) } {
+		Ok(path) => path,
+		Err(e) => return e,
+	};
+
+	// Is this path handled by a file system in the kernel?
+	if let Some((filesystem, relative_path)) = fs::find_mount(path) {
+		return filesystem.open(&relative_path, flags, mode as u32)
+			.or_else(|e| {
+				// Directories may be opened read-only to list their entries.
+				if e == -EISDIR && flags & fs::O_ACCMODE == fs::O_RDONLY {
+					open_directory(&*filesystem, &relative_path)
+				} else {
+					Err(e)
+				}
+			})
+			.and_then(|object| fs::insert_object(object))
+			.unwrap_or_else(|e| e);
+	}
+
+	let host_fd = unsafe { SYS.open(name, flags, mode) };
+	if host_fd < 0 {
+		return host_fd;
+	}
+
+	match fs::insert_object(Arc::new(fs::HostFile::new(host_fd))) {
+		Ok(fd) => fd,
+		Err(e) => {
+			unsafe { SYS.close(host_fd); }
+			e
+		}
+	}
+}
+
+fn open_directory(filesystem: &fs::FileSystemInterface, path: &str) -> Result<Arc<fs::ObjectInterface>, i32> {
+	let stat = filesystem.stat(path)?;
+	let entries = filesystem.readdir(path)?;
+
+	// The parent of the root directory of a mounted file system lies outside of it,
+	// so ".." refers to the root directory itself like in the root directory of Linux.
+	let parent_ino = if path.is_empty() {
+		stat.st_ino
+	} else {
+		let parent_path = path.rfind('/').map(|position| &path[..position]).unwrap_or("");
+		filesystem.stat(parent_path)?.st_ino
+	};
+
+	Ok(Arc::new(fs::DirectoryObject::new(stat, parent_ino, entries)))
 }
 
 #[no_mangle]
 pub extern "C" fn sys_close(fd: i32) -> i32 {
-	unsafe { SYS.close(fd) }
+	fs::close_object(fd)
 }
 
 #[no_mangle]
 pub extern "C" fn sys_read(fd: i32, buf: *mut u8, len: usize) -> isize {
-	unsafe { SYS.read(fd, buf, len) }
+	match fs::get_object(fd) {
+		Ok(object) => object.read(buf, len),
+		Err(e) => e as isize,
+	}
 }
 
 #[no_mangle]
 pub extern "C" fn sys_write(fd: i32, buf: *const u8, len: usize) -> isize {
-	unsafe { SYS.write(fd, buf, len) }
+	match fs::get_object(fd) {
+		Ok(object) => object.write(buf, len),
+		Err(e) => e as isize,
+	}
+}
+
+/// Checks the array of buffers passed to sys_readv or sys_writev and returns it as a slice.
+unsafe fn iovec_slice<'a>(iov: *const fs::IoVec, iovcnt: i32) -> Result<&'a [fs::IoVec], isize> {
+	if iovcnt < 0 || iovcnt as usize > fs::IOV_MAX {
+		return Err(-EINVAL as isize);
+	}
+
+	if iov.is_null() {
+		return Err(-EFAULT as isize);
+	}
+
+	Ok(slice::from_raw_parts(iov, iovcnt as usize))
+}
+
+#[no_mangle]
+pub extern "C" fn sys_readv(fd: i32, iov: *const fs::IoVec, iovcnt: i32) -> isize {
+	let iov = match unsafe { iovec_slice(iov, iovcnt) } {
+		Ok(iov) => iov,
+		Err(e) => return e,
+	};
+
+	match fs::get_object(fd) {
+		Ok(object) => object.readv(iov),
+		Err(e) => e as isize,
+	}
+}
+
+#[no_mangle]
+pub extern "C" fn sys_writev(fd: i32, iov: *const fs::IoVec, iovcnt: i32) -> isize {
+	let iov = match unsafe { iovec_slice(iov, iovcnt) } {
+		Ok(iov) => iov,
+		Err(e) => return e,
+	};
+
+	match fs::get_object(fd) {
+		Ok(object) => object.writev(iov),
+		Err(e) => e as isize,
+	}
+}
+
+#[no_mangle]
+pub extern "C" fn sys_pread(fd: i32, buf: *mut u8, len: usize, offset: isize) -> isize {
+	match fs::get_object(fd) {
+		Ok(object) => object.pread(buf, len, offset),
+		Err(e) => e as isize,
+	}
+}
+
+#[no_mangle]
+pub extern "C" fn sys_pwrite(fd: i32, buf: *const u8, len: usize, offset: isize) -> isize {
+	match fs::get_object(fd) {
+		Ok(object) => object.pwrite(buf, len, offset),
+		Err(e) => e as isize,
+	}
 }
 
 #[no_mangle]
 pub extern "C" fn sys_lseek(fd: i32, offset: isize, whence: i32) -> isize {
-	unsafe { SYS.lseek(fd, offset, whence) }
+	match fs::get_object(fd) {
+		Ok(object) => object.lseek(offset, whence),
+		Err(e) => e as isize,
+	}
 }
 
 #[no_mangle]
-pub extern "C" fn sys_stat(file: *const u8, st: usize) -> i32 {
-	unsafe { SYS.stat(file, st) }
+pub extern "C" fn sys_getdents(fd: i32, buf: *mut u8, len: usize) -> isize {
+	match fs::get_object(fd) {
+		Ok(object) => object.getdents(buf, len),
+		Err(e) => e as isize,
+	}
+}
+
+#[no_mangle]
+pub extern "C" fn sys_ftruncate(fd: i32, length: usize) -> i32 {
+	match fs::get_object(fd) {
+		Ok(object) => object.truncate(length),
+		Err(e) => e,
+	}
+}
+
+/// Common implementation of sys_stat and sys_lstat.
+fn stat_path(name: *const u8, st: *mut fs::Stat, follow_links: bool) -> i32 {
+	if st.is_null() {
+		return -EFAULT;
+	}
+
+	let path = match unsafe { fs::path_from_c_str(name) } {
+		Ok(path) => path,
+		Err(e) => return e,
+	};
+
+	// Is this path handled by a file system in the kernel?
+	if let Some((filesystem, relative_path)) = fs::find_mount(path) {
+		let result = if follow_links {
+			filesystem.stat(&relative_path)
+		} else {
+			filesystem.lstat(&relative_path)
+		};
+
+		return match result {
+			Ok(stat) => {
+				unsafe { *st = stat; }
+				0
+			},
+			Err(e) => e,
+		};
+	}
+
+	// Let the host fill a structure on our stack instead of the buffer of the application.
+	let mut stat = fs::Stat::default();
+	let ret = if follow_links {
+		unsafe { SYS.stat(name, &mut stat) }
+	} else {
+		unsafe { SYS.lstat(name, &mut stat) }
+	};
+
+	if ret == 0 {
+		unsafe { *st = stat; }
+	}
+
+	ret
+}
+
+#[no_mangle]
+pub extern "C" fn sys_stat(name: *const u8, st: *mut fs::Stat) -> i32 {
+	stat_path(name, st, true)
+}
+
+#[no_mangle]
+pub extern "C" fn sys_lstat(name: *const u8, st: *mut fs::Stat) -> i32 {
+	stat_path(name, st, false)
+}
+
+#[no_mangle]
+pub extern "C" fn sys_fstat(fd: i32, st: *mut fs::Stat) -> i32 {
+	if st.is_null() {
+		return -EFAULT;
+	}
+
+	let object = match fs::get_object(fd) {
+		Ok(object) => object,
+		Err(e) => return e,
+	};
+
+	let mut stat = fs::Stat::default();
+	let ret = object.fstat(&mut stat);
+	if ret == 0 {
+		unsafe { *st = stat; }
+	}
+
+	ret
+}
+
+/// Calls `f` with the file system and relative path for the given path.
+/// Paths not handled by a file system in the kernel are passed to `host`.
+fn with_mounted_fs<F, H>(path: *const u8, f: F, host: H) -> i32
+	where F: FnOnce(&fs::FileSystemInterface, &str) -> Result<(), i32>,
+	      H: FnOnce() -> i32 {
+	let path = match unsafe { fs::path_from_c_str(path) } {
+		Ok(path) => path,
+		Err(e) => return e,
+	};
+
+	match fs::find_mount(path) {
+		Some((filesystem, relative_path)) => f(&*filesystem, &relative_path).map(|_| 0).unwrap_or_else(|e| e),
+		None => host(),
+	}
+}
+
+#[no_mangle]
+pub extern "C" fn sys_unlink(name: *const u8) -> i32 {
+	with_mounted_fs(name, |filesystem, path| filesystem.unlink(path), || unsafe { SYS.unlink(name) })
+}
+
+#[no_mangle]
+pub extern "C" fn sys_mkdir(name: *const u8, mode: u32) -> i32 {
+	with_mounted_fs(name, |filesystem, path| filesystem.mkdir(path, mode), || unsafe { SYS.mkdir(name, mode) })
+}
+
+#[no_mangle]
+pub extern "C" fn sys_rmdir(name: *const u8) -> i32 {
+	with_mounted_fs(name, |filesystem, path| filesystem.rmdir(path), || unsafe { SYS.rmdir(name) })
+}
+
+#[no_mangle]
+pub extern "C" fn sys_access(name: *const u8, mode: i32) -> i32 {
+	with_mounted_fs(name, |filesystem, path| filesystem.access(path, mode), || unsafe { SYS.access(name, mode) })
+}
+
+#[no_mangle]
+pub extern "C" fn sys_truncate(name: *const u8, length: usize) -> i32 {
+	// Truncating a file on the host by name is not part of the host interfaces.
+	with_mounted_fs(name, |filesystem, path| filesystem.truncate(path, length), || -ENOSYS)
+}
+
+#[no_mangle]
+pub extern "C" fn sys_rename(old_name: *const u8, new_name: *const u8) -> i32 {
+	let new_path = match unsafe { fs::path_from_c_str(new_name) } {
+		Ok(path) => path,
+		Err(e) => return e,
+	};
+
+	with_mounted_fs(old_name, |old_filesystem, old_path| {
+		match fs::find_mount(new_path) {
+			// Both paths must belong to the same file system.
+			Some((ref new_filesystem, ref new_relative_path)) if ptr::eq(old_filesystem, &**new_filesystem) => {
+				old_filesystem.rename(old_path, new_relative_path)
+			},
+			_ => Err(-EXDEV),
+		}
+	}, || {
+		if fs::find_mount(new_path).is_some() {
+			-EXDEV
+		} else {
+			unsafe { SYS.rename(old_name, new_name) }
+		}
+	})
 }
 
 #[no_mangle]
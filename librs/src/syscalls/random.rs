@@ -21,27 +21,219 @@
 // OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
 // WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
 
+
+//! Kernel random number generator.
+//!
+//! Random numbers are taken from the keystream of ChaCha20, whose key is replaced by fresh
+//! keystream after every request ("fast key erasure"), so that earlier output cannot be
+//! reconstructed from the generator state. The key is seeded from the hardware random number
+//! generator of the processor (RDSEED/RDRAND), the jitter of the timestamp counter, and a
+//! virtio entropy device if present, and reseeded after RESEED_INTERVAL bytes.
+//!
+//! Large requests are split into chunks of GENERATE_CHUNK_SIZE bytes. The key is replaced after
+//! every chunk and the lock is released in between, so that a large request neither delays
+//! other callers for long nor misses a reseed.
+
 use arch;
+use core::{isize, ptr, slice};
+use drivers::virtio;
+use errno::*;
 use synch::spinlock::Spinlock;
 
-lazy_static! {
-	static ref PARK_MILLER_LEHMER_SEED: Spinlock<u32> =
-		Spinlock::new(arch::processor::get_timestamp() as u32);
+
+/// Return immediately instead of blocking until the generator is seeded (ignored, we never block).
+const GRND_NONBLOCK: u32 = 0x0001;
+/// Draw from the "random" instead of the "urandom" source (ignored, both are the same here).
+const GRND_RANDOM: u32 = 0x0002;
+
+/// Number of bytes generated before the key is mixed with fresh entropy.
+const RESEED_INTERVAL: usize = 1024 * 1024;
+
+/// Number of bytes generated at most with the generator locked.
+const GENERATE_CHUNK_SIZE: usize = 4096;
+
+/// Number of timestamp differences collected for seeding.
+const JITTER_SAMPLES: usize = 1024;
+
+/// ChaCha20 constant "expand 32-byte k".
+const CHACHA20_CONSTANTS: [u32; 4] = [0x6170_7865, 0x3320_646e, 0x7962_2d32, 0x6b20_6574];
+
+
+fn quarter_round(state: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+	state[a] = state[a].wrapping_add(state[b]);
+	state[d] = (state[d] ^ state[a]).rotate_left(16);
+	state[c] = state[c].wrapping_add(state[d]);
+	state[b] = (state[b] ^ state[c]).rotate_left(12);
+	state[a] = state[a].wrapping_add(state[b]);
+	state[d] = (state[d] ^ state[a]).rotate_left(8);
+	state[c] = state[c].wrapping_add(state[d]);
+	state[b] = (state[b] ^ state[c]).rotate_left(7);
+}
+
+/// Computes the keystream block with the given 64-bit block counter and a zero nonce.
+fn chacha20_block(key: &[u32; 8], counter: u64, output: &mut [u32; 16]) {
+	let mut input = [0u32; 16];
+	input[0..4].copy_from_slice(&CHACHA20_CONSTANTS);
+	input[4..12].copy_from_slice(key);
+	input[12] = counter as u32;
+	input[13] = (counter >> 32) as u32;
+
+	*output = input;
+	for _ in 0..10 {
+		// Column rounds
+		quarter_round(output, 0, 4, 8, 12);
+		quarter_round(output, 1, 5, 9, 13);
+		quarter_round(output, 2, 6, 10, 14);
+		quarter_round(output, 3, 7, 11, 15);
+
+		// Diagonal rounds
+		quarter_round(output, 0, 5, 10, 15);
+		quarter_round(output, 1, 6, 11, 12);
+		quarter_round(output, 2, 7, 8, 13);
+		quarter_round(output, 3, 4, 9, 14);
+	}
+
+	for (word, input_word) in output.iter_mut().zip(input.iter()) {
+		*word = word.wrapping_add(*input_word);
+	}
+}
+
+/// Mixes the differences between consecutive timestamps into the seed.
+/// Their least significant bits vary with cache and memory effects as well as interrupts.
+fn gather_timestamp_jitter(seed: &mut [u32; 8]) {
+	let mut previous = arch::processor::get_timestamp();
+	let mut scratch = 0u64;
+
+	for i in 0..JITTER_SAMPLES {
+		// Do a varying amount of work the compiler cannot remove.
+		for _ in 0..(previous & 0xF) {
+			scratch = unsafe { ptr::read_volatile(&scratch) }.rotate_left(7) ^ previous;
+		}
+
+		let now = arch::processor::get_timestamp();
+		let word = &mut seed[i % seed.len()];
+		*word = word.rotate_left(5) ^ (now.wrapping_sub(previous) as u32);
+		previous = now;
+	}
+}
+
+/// Collects entropy from all available sources.
+/// The sources are combined by XOR, so the seed is at least as good as the best one of them.
+fn gather_seed() -> [u32; 8] {
+	let mut seed = [0u32; 8];
+
+	for i in 0..seed.len() / 2 {
+		if let Some(value) = arch::processor::generate_random_seed() {
+			seed[2 * i] ^= value as u32;
+			seed[2 * i + 1] ^= (value >> 32) as u32;
+		}
+	}
+
+	gather_timestamp_jitter(&mut seed);
+
+	let mut bytes = [0u8; 32];
+	let length = virtio::rng::read(&mut bytes);
+	for (i, byte) in bytes[..length].iter().enumerate() {
+		seed[i / 4] ^= (*byte as u32) << (8 * (i % 4));
+	}
+
+	seed
 }
 
 
-fn generate_park_miller_lehmer_random_number() -> u32 {
-	let mut seed = PARK_MILLER_LEHMER_SEED.lock();
-	let random = (((*seed) as u64 * 48271) % 2147483647) as u32;
-	*seed = random;
-	random
+struct ChaCha20Rng {
+	key: [u32; 8],
+	/// Number of bytes generated since the last reseed or None if the generator has never been seeded.
+	generated: Option<usize>,
+}
+
+impl ChaCha20Rng {
+	const fn new() -> Self {
+		Self {
+			key: [0; 8],
+			generated: None,
+		}
+	}
+
+	fn needs_reseed(&self) -> bool {
+		match self.generated {
+			Some(generated) => generated >= RESEED_INTERVAL,
+			None => true,
+		}
+	}
+
+	/// Fills buf, which must not be larger than GENERATE_CHUNK_SIZE, and replaces the key afterwards.
+	/// The generator must have been seeded before.
+	fn fill_bytes(&mut self, buf: &mut [u8]) {
+		let mut block = [0u32; 16];
+		let mut counter = 0;
+		for chunk in buf.chunks_mut(64) {
+			chacha20_block(&self.key, counter, &mut block);
+			counter += 1;
+
+			for (i, byte) in chunk.iter_mut().enumerate() {
+				*byte = (block[i / 4] >> (8 * (i % 4))) as u8;
+			}
+		}
+
+		// Replace the key by keystream, which has not been handed out.
+		chacha20_block(&self.key, counter, &mut block);
+		self.key.copy_from_slice(&block[0..8]);
+
+		self.generated = self.generated.map(|generated| generated.saturating_add(buf.len()));
+	}
+
+	fn reseed(&mut self, seed: &[u32; 8]) {
+		for (word, seed_word) in self.key.iter_mut().zip(seed.iter()) {
+			*word ^= *seed_word;
+		}
+
+		// Mix the seed words into the whole key.
+		let mut block = [0u32; 16];
+		chacha20_block(&self.key, 0, &mut block);
+		self.key.copy_from_slice(&block[0..8]);
+		self.generated = Some(0);
+	}
+}
+
+static GENERATOR: Spinlock<ChaCha20Rng> = Spinlock::new(ChaCha20Rng::new());
+
+
+/// Fills buf with cryptographically secure random numbers.
+pub fn fill_random_bytes(buf: &mut [u8]) {
+	for chunk in buf.chunks_mut(GENERATE_CHUNK_SIZE) {
+		// Gathering entropy takes a while, so do it without holding the lock.
+		// If another task reseeds meanwhile, mixing in our seed as well does no harm.
+		let seed = if GENERATOR.lock().needs_reseed() { Some(gather_seed()) } else { None };
+
+		let mut generator = GENERATOR.lock();
+		if let Some(ref seed) = seed {
+			generator.reseed(seed);
+		}
+		generator.fill_bytes(chunk);
+	}
 }
 
 #[no_mangle]
 pub extern "C" fn sys_rand() -> u32 {
-	if let Some(value) = arch::processor::generate_random_number() {
-		value
-	} else {
-		generate_park_miller_lehmer_random_number()
+	let mut bytes = [0u8; 4];
+	fill_random_bytes(&mut bytes);
+	bytes.iter().rev().fold(0, |value, byte| (value << 8) | *byte as u32)
+}
+
+/// Fills the buffer pointed to by `buf` with `len` cryptographically secure random bytes.
+/// Returns the number of bytes written, which is always `len` for valid arguments.
+#[no_mangle]
+pub extern "C" fn sys_getrandom(buf: *mut u8, len: usize, flags: u32) -> isize {
+	if flags & !(GRND_NONBLOCK | GRND_RANDOM) != 0 || len > isize::MAX as usize {
+		return -EINVAL as isize;
 	}
+
+	if buf.is_null() {
+		return -EFAULT as isize;
+	}
+
+	let buffer = unsafe { slice::from_raw_parts_mut(buf, len) };
+	fill_random_bytes(buffer);
+	len as isize
 }
@@ -0,0 +1,163 @@
+// Copyright (c) 2018 Stefan Lankes, RWTH Aachen University
+//                    Colin Finck, RWTH Aachen University
+//
+// MIT License
+//
+// Permission is hereby granted, free of charge, to any person obtaining
+// a copy of this software and associated documentation files (the
+// "Software"), to deal in the Software without restriction, including
+// without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to
+// permit persons to whom the Software is furnished to do so, subject to
+// the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE
+// LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION
+// OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
+// WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! System calls for the TCP sockets of the network stack written in Rust.
+//! Addresses use the layout of "struct sockaddr_in" in lwIP, but the lwIP socket functions
+//! (lwip_socket() and friends) are not provided, so applications have to call these system calls.
+
+use alloc::arc::Arc;
+use core::{cmp, mem, ptr};
+use errno::*;
+use fs;
+use fs::SocketAddress;
+use net::tcp;
+
+
+const AF_INET: i32 = 2;
+const SOCK_STREAM: i32 = 1;
+const IPPROTO_TCP: i32 = 6;
+
+pub type Socklen = u32;
+
+/// IPv4 socket address with the layout of "struct sockaddr_in" in lwIP.
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct SockaddrIn {
+	sin_len: u8,
+	sin_family: u8,
+	/// Port in network byte order
+	sin_port: u16,
+	/// Address in network byte order
+	sin_addr: [u8; 4],
+	sin_zero: [u8; 8],
+}
+
+
+unsafe fn read_sockaddr(addr: *const u8, addrlen: Socklen) -> Result<SocketAddress, i32> {
+	if addr.is_null() {
+		return Err(-EFAULT);
+	}
+
+	if (addrlen as usize) < mem::size_of::<SockaddrIn>() {
+		return Err(-EINVAL);
+	}
+
+	let sockaddr = ptr::read_unaligned(addr as *const SockaddrIn);
+	if sockaddr.sin_family as i32 != AF_INET {
+		return Err(-EAFNOSUPPORT);
+	}
+
+	Ok(SocketAddress { address: sockaddr.sin_addr, port: u16::from_be(sockaddr.sin_port) })
+}
+
+/// Writes the address to `addr` if it is not NULL and truncates it to the buffer size given in `addrlen`.
+unsafe fn write_sockaddr(address: SocketAddress, addr: *mut u8, addrlen: *mut Socklen) -> Result<(), i32> {
+	if addr.is_null() {
+		return Ok(());
+	}
+
+	if addrlen.is_null() {
+		return Err(-EFAULT);
+	}
+
+	let sockaddr = SockaddrIn {
+		sin_len: mem::size_of::<SockaddrIn>() as u8,
+		sin_family: AF_INET as u8,
+		sin_port: address.port.to_be(),
+		sin_addr: address.address,
+		sin_zero: [0; 8],
+	};
+
+	let length = cmp::min(*addrlen as usize, mem::size_of::<SockaddrIn>());
+	ptr::copy_nonoverlapping(&sockaddr as *const SockaddrIn as *const u8, addr, length);
+	*addrlen = mem::size_of::<SockaddrIn>() as Socklen;
+	Ok(())
+}
+
+#[no_mangle]
+pub extern "C" fn sys_socket(domain: i32, socket_type: i32, protocol: i32) -> i32 {
+	if domain != AF_INET {
+		return -EAFNOSUPPORT;
+	}
+
+	if socket_type != SOCK_STREAM {
+		return -ESOCKTNOSUPPORT;
+	}
+
+	if protocol != 0 && protocol != IPPROTO_TCP {
+		return -EPROTONOSUPPORT;
+	}
+
+	tcp::Socket::new()
+		.and_then(|socket| fs::insert_object(Arc::new(socket)))
+		.unwrap_or_else(|e| e)
+}
+
+#[no_mangle]
+pub extern "C" fn sys_bind(fd: i32, addr: *const u8, addrlen: Socklen) -> i32 {
+	let address = match unsafe { read_sockaddr(addr, addrlen) } {
+		Ok(address) => address,
+		Err(e) => return e,
+	};
+
+	match fs::get_object(fd) {
+		Ok(object) => object.bind(address),
+		Err(e) => e,
+	}
+}
+
+#[no_mangle]
+pub extern "C" fn sys_listen(fd: i32, backlog: i32) -> i32 {
+	match fs::get_object(fd) {
+		Ok(object) => object.listen(backlog),
+		Err(e) => e,
+	}
+}
+
+#[no_mangle]
+pub extern "C" fn sys_accept(fd: i32, addr: *mut u8, addrlen: *mut Socklen) -> i32 {
+	let (connection, address) = match fs::get_object(fd).and_then(|object| object.accept()) {
+		Ok(accepted) => accepted,
+		Err(e) => return e,
+	};
+
+	if let Err(e) = unsafe { write_sockaddr(address, addr, addrlen) } {
+		return e;
+	}
+
+	fs::insert_object(connection).unwrap_or_else(|e| e)
+}
+
+#[no_mangle]
+pub extern "C" fn sys_connect(fd: i32, addr: *const u8, addrlen: Socklen) -> i32 {
+	let address = match unsafe { read_sockaddr(addr, addrlen) } {
+		Ok(address) => address,
+		Err(e) => return e,
+	};
+
+	match fs::get_object(fd) {
+		Ok(object) => object.connect(address),
+		Err(e) => e,
+	}
+}
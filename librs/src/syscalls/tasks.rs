@@ -85,6 +85,32 @@ pub extern "C" fn sys_sbrk(incr: isize) -> usize {
 	old_end
 }
 
+#[no_mangle]
+pub extern "C" fn sys_set_heap_pagesize(page_size: usize) -> i32 {
+	if !arch::mm::paging::is_supported_task_heap_page_size(page_size) {
+		debug!("Task heap page size {:#X} is not supported, returning -EINVAL", page_size);
+		return -EINVAL;
+	}
+
+	// Get the heap of the current task on the current core.
+	let mut current_task_borrowed = core_scheduler().current_task.borrow_mut();
+	let heap = match current_task_borrowed.heap.as_mut() {
+		Some(heap) => heap,
+		None => return -EINVAL
+	};
+
+	// The page size can only be changed as long as no page of the heap has been mapped.
+	let heap_borrowed = heap.borrow();
+	let mut heap_locked = heap_borrowed.write();
+	if heap_locked.end != heap_locked.start {
+		debug!("Task heap is already in use, returning -EBUSY");
+		return -EBUSY;
+	}
+
+	heap_locked.page_size = page_size;
+	0
+}
+
 // TODO: Rename this function to sys_usleep for consistency and change the call in GCC's libgo/runtime/yield.c
 // This is a breaking change though!
 // Not doing this yet allows us to use the same GCC for the HermitCore C version and HermitCore-rs.
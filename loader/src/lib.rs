@@ -55,7 +55,7 @@ mod serial;
 // IMPORTS
 use core::ptr;
 use elf::*;
-use hermit_multiboot::Multiboot;
+use hermit_multiboot::{Module, Multiboot};
 use paging::{BasePageSize, LargePageSize, PageSize, PageTableEntryFlags};
 use serial::SerialPort;
 
@@ -95,6 +95,27 @@ unsafe fn sections_init() {
 	);
 }
 
+/// Copies a module to newly allocated physical memory and updates its Multiboot information.
+/// The new location must not overlap the application image ranging from `image_start` to `image_end`.
+unsafe fn relocate_module(m: &mut Module, image_start: usize, image_end: usize) {
+	let size = m.end_address() - m.start_address();
+	let page_address = align_down!(m.start_address(), BasePageSize::SIZE);
+	let page_count = (align_up!(m.end_address(), BasePageSize::SIZE) - page_address) / BasePageSize::SIZE;
+	paging::map::<BasePageSize>(page_address, page_address, page_count, PageTableEntryFlags::empty());
+
+	let new_address = physicalmem::allocate(align_up!(size, BasePageSize::SIZE));
+	let new_page_count = align_up!(size, BasePageSize::SIZE) / BasePageSize::SIZE;
+	assert!(
+		new_address >= image_end || new_address + new_page_count * BasePageSize::SIZE <= image_start,
+		"Module would be moved into the application image"
+	);
+	paging::map::<BasePageSize>(new_address, new_address, new_page_count, PageTableEntryFlags::WRITABLE);
+
+	loaderlog!("Moving module at {:#X} to {:#X}", m.start_address(), new_address);
+	ptr::copy_nonoverlapping(m.start_address() as *const u8, new_address as *mut u8, size);
+	m.relocate(new_address);
+}
+
 /// Entry Point of the HermitCore Loader
 /// (called from entry.asm)
 #[no_mangle]
@@ -111,10 +132,11 @@ pub unsafe extern "C" fn loader_main() {
 	paging::map::<BasePageSize>(page_address, page_address, 1, PageTableEntryFlags::empty());
 
 	// Load the Multiboot information and identity-map the modules information.
+	// It is writable, because modules may have to be moved.
 	let mb = Multiboot::new(mb_info);
 	let modules_address = mb.modules_address().expect("Could not find module information in the Multiboot information");
 	let page_address = align_down!(modules_address, BasePageSize::SIZE);
-	paging::map::<BasePageSize>(page_address, page_address, 1, PageTableEntryFlags::empty());
+	paging::map::<BasePageSize>(page_address, page_address, 1, PageTableEntryFlags::WRITABLE);
 
 	// Iterate through all modules.
 	// Collect the start address of the first module and the highest end address of all modules.
@@ -192,9 +214,23 @@ pub unsafe extern "C" fn loader_main() {
 	// First calculate the displacement and map a range large enough to span the original code and its new location.
 	let new_physical_address = align_up!(physical_address, LargePageSize::SIZE);
 	let displacement = new_physical_address - physical_address;
+	let application_end_address = align_up!(new_physical_address + mem_size, LargePageSize::SIZE);
+
+	// The application including its BSS may extend beyond the start of the free physical memory.
+	// Keep all following allocations (page tables and relocated modules) above its end.
+	physicalmem::reserve_until(application_end_address);
+
 	let page_count = (file_size + displacement) / BasePageSize::SIZE;
 	paging::map::<BasePageSize>(virtual_address, physical_address, page_count, PageTableEntryFlags::WRITABLE);
 
+	// Additional modules (like an initrd) are passed to the HermitCore application.
+	// Move those overlapping the new location of the application out of the way.
+	for m in mb.modules_mut().unwrap().iter_mut().skip(1) {
+		if m.start_address() < application_end_address && m.end_address() > new_physical_address {
+			relocate_module(m, new_physical_address, application_end_address);
+		}
+	}
+
 	// Supply the parameters to the HermitCore application.
 	*((virtual_address + HERMIT_KERNEL_OFFSET_BASE) as *mut usize) = new_physical_address;
 	*((virtual_address + HERMIT_KERNEL_OFFSET_IMAGE_SIZE) as *mut usize) = mem_size;
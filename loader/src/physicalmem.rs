@@ -30,6 +30,16 @@ pub fn init(address: usize) {
 	unsafe { CURRENT_ADDRESS = address; }
 }
 
+/// Ensures that no future allocation returns memory below `address`.
+pub fn reserve_until(address: usize) {
+	unsafe {
+		assert!(CURRENT_ADDRESS > 0, "Trying to reserve physical memory before the Physical Memory Manager has been initialized");
+		if CURRENT_ADDRESS < address {
+			CURRENT_ADDRESS = address;
+		}
+	}
+}
+
 pub fn allocate(size: usize) -> usize {
 	assert!(size > 0);
 	assert!(size % BasePageSize::SIZE == 0, "Size {:#X} is a multiple of {:#X}", size, BasePageSize::SIZE);
@@ -161,6 +161,17 @@ fn main() {
 	}
 
 
+	// The kernel looks up entries by binary search.
+	classes.sort_by_key(|c| c.id);
+	for c in &mut classes {
+		c.subclasses.sort_by_key(|sc| sc.id);
+	}
+
+	vendors.sort_by_key(|v| v.id);
+	for v in &mut vendors {
+		v.devices.sort_by_key(|d| d.id);
+	}
+
 	let mut output =
 "
 struct Class {
@@ -187,12 +198,13 @@ struct Device {
 
 ".to_string();
 
+	output += &format!("/// All PCI classes sorted by ID, each with its subclasses sorted by ID.\n");
 	output += &format!("static CLASSES: &[Class] = &[\n");
 	for c in &classes {
-		output += &format!("\tClass {{ id: 0x{:02X}, name: \"{}\", subclasses: &[\n", c.id, c.name);
+		output += &format!("\tClass {{ id: 0x{:02X}, name: \"{}\", subclasses: &[\n", c.id, sanitize(&c.name));
 
 		for sc in &c.subclasses {
-			output += &format!("\t\tSubclass {{ id: 0x{:02X}, name: \"{}\" }},\n", sc.id, sc.name);
+			output += &format!("\t\tSubclass {{ id: 0x{:02X}, name: \"{}\" }},\n", sc.id, sanitize(&sc.name));
 		}
 
 		output += &format!("\t] }},\n");
@@ -200,6 +212,7 @@ struct Device {
 
 	output += &format!("];\n\n");
 
+	output += &format!("/// All PCI vendors sorted by ID, each with its devices sorted by ID.\n");
 	output += &format!("static VENDORS: &[Vendor] = &[\n");
 	for v in &vendors {
 		output += &format!("\tVendor {{ id: 0x{:04X}, name: \"{}\", devices: &[\n", v.id, sanitize(&v.name));
@@ -33,6 +33,7 @@
 #include <linux/tcp.h>
 #include <net/if.h>
 #include <netinet/in.h>
+#include <pthread.h>
 #include <sched.h>
 #include <signal.h>
 #include <stdint.h>
@@ -43,6 +44,8 @@
 #include <sys/inotify.h>
 #include <sys/ioctl.h>
 #include <sys/socket.h>
+#include <sys/stat.h>
+#include <sys/syscall.h>
 #include <sys/types.h>
 #include <sys/wait.h>
 #include <unistd.h>
@@ -55,7 +58,7 @@
 
 #define HERMIT_PORT	0x494E
 #define HERMIT_IP(isle)	INADDR(192, 168, 28, isle + 2)
-#define HERMIT_MAGIC	0x7E317
+#define HERMIT_MAGIC	0x7E318
 
 #define EVENT_SIZE	(sizeof (struct inotify_event))
 #define BUF_LEN		(1024 * (EVENT_SIZE + 16))
@@ -74,6 +77,9 @@ typedef enum {
 
 bool verbose = false;
 
+/* requests are served concurrently, so the bytes of their replies must not interleave */
+static pthread_mutex_t reply_lock = PTHREAD_MUTEX_INITIALIZER;
+
 static monitor_t monitor = BAREMETAL;
 static int sobufsize = 131072;
 static unsigned int isle_nr = 0;
@@ -577,306 +583,401 @@ static void stop_hermit(void)
 }
 
 /*
- * in principle, HermitCore forwards basic system calls to
- * this proxy, which mapped these call to Linux system calls.
+ * helper functions to transfer complete buffers over the socket,
+ * returning 0 on success and -1 if the connection failed
  */
-int handle_syscalls(int s)
+static int read_in_full(int s, void* buf, size_t len)
 {
-	int sysnr;
-	ssize_t sret;
-	size_t j;
+	size_t j = 0;
 
-	while(1)
+	while (j < len)
 	{
-		j = 0;
-		while(j < sizeof(sysnr)) {
-			sret = read(s, ((char*)&sysnr)+j, sizeof(sysnr)-j);
-			if (sret < 0)
-				goto out;
-			j += sret;
-		}
+		ssize_t sret = read(s, ((char*)buf)+j, len-j);
+		if (sret <= 0)
+			return -1;
+		j += sret;
+	}
 
-		switch(sysnr)
-		{
-		case __HERMIT_exit: {
-			int arg = 0;
-
-			j = 0;
-			while(j < sizeof(arg)) {
-				sret = read(s, ((char*)&arg)+j, sizeof(arg)-j);
-				if (sret < 0)
-					goto out;
-				j += sret;
-			}
-			close(s);
+	return 0;
+}
 
-			// already called by fini_env
-			//dump_log();
-			//stop_hermit();
+static int write_in_full(int s, const void* buf, size_t len)
+{
+	size_t j = 0;
 
-			if (arg == -14)
-				fprintf(stderr, "Did HermitCore receive an exception?\n");
-			exit(arg);
-			break;
+	while (j < len)
+	{
+		ssize_t sret = write(s, ((const char*)buf)+j, len-j);
+		if (sret < 0)
+			return -1;
+		j += sret;
+	}
+
+	return 0;
+}
+
+/*
+ * send the reply to the request with the given id,
+ * the header is followed by len bytes of data (e.g. the bytes read)
+ */
+static int send_reply(int s, uint32_t id, int64_t ret, const void* data, size_t len)
+{
+	reply_header_t header;
+	int result = 0;
+
+	header.id = id;
+	header.ret = ret;
+	header.len = len;
+
+	pthread_mutex_lock(&reply_lock);
+
+	if (write_in_full(s, &header, sizeof(header)) < 0)
+		result = -1;
+	else if (len > 0 && write_in_full(s, data, len) < 0)
+		result = -1;
+
+	pthread_mutex_unlock(&reply_lock);
+
+	return result;
+}
+
+/* cursor over the arguments of a received message */
+typedef struct {
+	const char* buf;
+	size_t len;
+	size_t pos;
+} message_t;
+
+/* copy the next argument of the message, returns -1 if the message is too short */
+static int get_arg(message_t* msg, void* out, size_t size)
+{
+	if (msg->len - msg->pos < size)
+		return -1;
+
+	memcpy(out, msg->buf + msg->pos, size);
+	msg->pos += size;
+
+	return 0;
+}
+
+/*
+ * return the next argument of the message as string, which is
+ * encoded as length (including the NUL character) followed by the characters
+ */
+static const char* get_string(message_t* msg)
+{
+	uint64_t len;
+	const char* str;
+
+	if (get_arg(msg, &len, sizeof(len)) < 0)
+		return NULL;
+
+	if (len == 0 || msg->len - msg->pos < len)
+		return NULL;
+
+	str = msg->buf + msg->pos;
+	if (str[len-1] != '\0')
+		return NULL;
+
+	msg->pos += len;
+	return str;
+}
+
+/* return the remaining bytes of the message, e.g. the data of a write request */
+static const char* get_data(message_t* msg, size_t* len)
+{
+	const char* data = msg->buf + msg->pos;
+
+	*len = msg->len - msg->pos;
+	msg->pos = msg->len;
+
+	return data;
+}
+
+/*
+ * perform the system call requested by a message and send the reply,
+ * returns -1 if the connection failed
+ */
+static int handle_message(int s, const request_header_t* header, message_t* msg)
+{
+	uint32_t tag = header->tag;
+	int64_t ret = 0;
+	struct stat st;
+	const void* reply = NULL;
+	size_t reply_len = 0;
+	char* buff = NULL;
+	int result;
+
+	switch(tag)
+	{
+	case __HERMIT_exit: {
+		int arg = 0;
+
+		get_arg(msg, &arg, sizeof(arg));
+		close(s);
+
+		// already called by fini_env
+		//dump_log();
+		//stop_hermit();
+
+		if (arg == -14)
+			fprintf(stderr, "Did HermitCore receive an exception?\n");
+		exit(arg);
+		break;
+	}
+	case __HERMIT_write:
+	case __HERMIT_pwrite: {
+		int fd;
+		off_t offset = 0;
+		size_t len;
+		const char* data;
+
+		if (get_arg(msg, &fd, sizeof(fd)) < 0)
+			goto malformed;
+		if (tag == __HERMIT_pwrite && get_arg(msg, &offset, sizeof(offset)) < 0)
+			goto malformed;
+		data = get_data(msg, &len);
+
+		if (tag == __HERMIT_pwrite)
+			ret = pwrite(fd, data, len, offset);
+		else if (fd > 2)
+			ret = write(fd, data, len);
+		else
+			ret = write_in_full(fd, data, len) < 0 ? -1 : (int64_t) len;
+		break;
+	}
+	case __HERMIT_open: {
+		const char* fname = get_string(msg);
+		int flags, mode;
+
+		if (!fname || get_arg(msg, &flags, sizeof(flags)) < 0 || get_arg(msg, &mode, sizeof(mode)) < 0)
+			goto malformed;
+
+		//printf("flags 0x%x, mode 0x%x\n", flags, mode);
+
+		ret = open(fname, flags, mode);
+		break;
+	}
+	case __HERMIT_close: {
+		int fd;
+
+		if (get_arg(msg, &fd, sizeof(fd)) < 0)
+			goto malformed;
+
+		if (fd > 2)
+			ret = close(fd);
+		break;
+	}
+	case __HERMIT_read:
+	case __HERMIT_pread:
+	case __HERMIT_getdents: {
+		int fd;
+		size_t len;
+		off_t offset = 0;
+
+		if (get_arg(msg, &fd, sizeof(fd)) < 0 || get_arg(msg, &len, sizeof(len)) < 0)
+			goto malformed;
+		if (tag == __HERMIT_pread && get_arg(msg, &offset, sizeof(offset)) < 0)
+			goto malformed;
+
+		buff = malloc(len ? len : 1);
+		if (!buff) {
+			ret = -ENOMEM;
+			goto send;
 		}
-		case __HERMIT_write: {
+
+		if (tag == __HERMIT_read)
+			ret = read(fd, buff, len);
+		else if (tag == __HERMIT_pread)
+			ret = pread(fd, buff, len, offset);
+		else // HermitCore expects the entries in the format of "struct linux_dirent64"
+			ret = syscall(SYS_getdents64, fd, buff, len);
+
+		reply = buff;
+		reply_len = ret > 0 ? ret : 0;
+		break;
+	}
+	case __HERMIT_lseek: {
+		int fd, whence;
+		off_t offset;
+
+		if (get_arg(msg, &fd, sizeof(fd)) < 0
+		    || get_arg(msg, &offset, sizeof(offset)) < 0
+		    || get_arg(msg, &whence, sizeof(whence)) < 0)
+			goto malformed;
+
+		ret = lseek(fd, offset, whence);
+		break;
+	}
+	case __HERMIT_stat:
+	case __HERMIT_lstat:
+	case __HERMIT_fstat: {
+		if (tag == __HERMIT_fstat) {
 			int fd;
-			size_t len;
-			char* buff;
-
-			j = 0;
-			while (j < sizeof(fd)) {
-				sret = read(s, ((char*)&fd)+j, sizeof(fd)-j);
-				if (sret < 0)
-					goto out;
-				j += sret;
-			}
-
-			j = 0;
-			while (j < sizeof(len)) {
-				sret = read(s, ((char*)&len)+j, sizeof(len)-j);
-				if (sret < 0)
-					goto out;
-				j += sret;
-			}
-
-			buff = malloc(len);
-			if (!buff) {
-				fprintf(stderr,"Proxy: not enough memory");
-				return 1;
-			}
-
-			j=0;
-			while(j < len)
-			{
-				sret = read(s, buff+j, len-j);
-				if (sret < 0)
-					goto out;
-				j += sret;
-			}
-
-			if (fd > 2) {
-				sret = write(fd, buff, len);
-				j = 0;
-				while(j < sizeof(sret))
-				{
-					int l = write(s, ((char*)&sret)+j, sizeof(sret)-j);
-					if (l < 0)
-						goto out;
-					j += l;
-				}
-			} else {
-				j = 0;
-				while(j < len)
-				{
-					sret = write(fd, buff+j, len-j);
-					if (sret < 0)
-						goto out;
-					j += sret;
-				}
-			}
-
-			free(buff);
-			break;
-		}
-		case __HERMIT_open: {
-			size_t len;
-			char* fname;
-			int flags, mode, ret;
-
-			j = 0;
-			while (j < sizeof(len))
-			{
-				sret = read(s, ((char*)&len)+j, sizeof(len)-j);
-				if (sret < 0)
-					goto out;
-				j += sret;
-			}
-
-			fname = malloc(len);
+
+			if (get_arg(msg, &fd, sizeof(fd)) < 0)
+				goto malformed;
+
+			ret = fstat(fd, &st);
+		} else {
+			const char* fname = get_string(msg);
+
 			if (!fname)
-				goto out;
+				goto malformed;
 
-			j = 0;
-			while (j < len)
-			{
-				sret = read(s, fname+j, len-j);
-				if (sret < 0)
-					goto out;
-				j += sret;
-			}
-
-			j = 0;
-			while (j < sizeof(flags))
-			{
-				sret = read(s, ((char*)&flags)+j, sizeof(flags)-j);
-				if (sret < 0)
-					goto out;
-				j += sret;
-			}
-
-			j = 0;
-			while (j < sizeof(mode))
-			{
-				sret = read(s, ((char*)&mode)+j, sizeof(mode)-j);
-				if (sret < 0)
-					goto out;
-				j += sret;
-			}
-
-			//printf("flags 0x%x, mode 0x%x\n", flags, mode);
-
-			ret = open(fname, flags, mode);
-			j = 0;
-			while(j < sizeof(ret))
-			{
-				sret = write(s, ((char*)&ret)+j, sizeof(ret)-j);
-				if (sret < 0)
-					goto out;
-				j += sret;
-			}
-
-			free(fname);
-			break;
-		}
-		case __HERMIT_close: {
-			int fd, ret;
-
-			j = 0;
-			while(j < sizeof(fd))
-			{
-				sret = read(s, ((char*)&fd)+j, sizeof(fd)-j);
-				if (sret < 0)
-					goto out;
-				j += sret;
-			}
-
-			if (fd > 2)
-				ret = close(fd);
+			if (tag == __HERMIT_stat)
+				ret = stat(fname, &st);
 			else
-				ret = 0;
-
-			j = 0;
-			while (j < sizeof(ret))
-			{
-				sret = write(s, ((char*)&ret)+j, sizeof(ret)-j);
-				if (sret < 0)
-					goto out;
-				j += sret;
-			}
-			break;
+				ret = lstat(fname, &st);
 		}
-		case __HERMIT_read: {
-			int fd, flag;
-			size_t len;
-			ssize_t sj;
-			char* buff;
-
-			j = 0;
-			while(j < sizeof(fd))
-			{
-				sret = read(s, ((char*)&fd)+j, sizeof(fd)-j);
-				if (sret < 0)
-					goto out;
-				j += sret;
-			}
-
-			j = 0;
-			while(j < sizeof(len))
-			{
-				sret = read(s, ((char*)&len)+j, sizeof(len)-j);
-				if (sret < 0)
-					goto out;
-				j += sret;
-			}
-
-			buff = malloc(len);
-			if (!buff)
-				goto out;
 
-			sj = read(fd, buff, len);
+		if (ret == 0) {
+			reply = &st;
+			reply_len = sizeof(st);
+		}
+		break;
+	}
+	case __HERMIT_unlink:
+	case __HERMIT_rmdir: {
+		const char* fname = get_string(msg);
+
+		if (!fname)
+			goto malformed;
+
+		if (tag == __HERMIT_unlink)
+			ret = unlink(fname);
+		else
+			ret = rmdir(fname);
+		break;
+	}
+	case __HERMIT_mkdir:
+	case __HERMIT_access: {
+		const char* fname = get_string(msg);
+		int mode;
+
+		if (!fname || get_arg(msg, &mode, sizeof(mode)) < 0)
+			goto malformed;
+
+		if (tag == __HERMIT_mkdir)
+			ret = mkdir(fname, mode);
+		else
+			ret = access(fname, mode);
+		break;
+	}
+	case __HERMIT_rename: {
+		const char* old_name = get_string(msg);
+		const char* new_name = get_string(msg);
 
-			flag = 0;
-			setsockopt(s, IPPROTO_TCP, TCP_NODELAY, (char *) &flag, sizeof(int));
+		if (!old_name || !new_name)
+			goto malformed;
 
-			j = 0;
-			while (j < sizeof(sj))
-			{
-				sret = write(s, ((char*)&sj)+j, sizeof(sj)-j);
-				if (sret < 0)
-					goto out;
-				j += sret;
-			}
+		ret = rename(old_name, new_name);
+		break;
+	}
+	default:
+		// the length of the message is known, so the connection stays usable
+		fprintf(stderr, "Proxy: unknown message tag %u\n", tag);
+		ret = -ENOSYS;
+		goto send;
+	}
 
-			if (sj > 0)
-			{
-				size_t i = 0;
+	if (ret < 0)
+		ret = -errno;
 
-				while (i < sj)
-				{
-					sret = write(s, buff+i, sj-i);
-					if (sret < 0)
-						goto out;
+	if (msg->pos != msg->len)
+		goto malformed;
 
-					i += sret;
-				}
-			}
+send:
+	result = send_reply(s, header->id, ret, reply, reply_len);
+	free(buff);
 
-			flag = 1;
-			setsockopt(s, IPPROTO_TCP, TCP_NODELAY, (char *) &flag, sizeof(int));
+	return result;
 
-			free(buff);
-			break;
+malformed:
+	fprintf(stderr, "Proxy: malformed message with tag %u and length %zu\n", tag, msg->len);
+	ret = -EINVAL;
+	reply_len = 0;
+	goto send;
+}
+
+/* a received message, which is served by its own thread */
+typedef struct {
+	int s;
+	request_header_t header;
+	message_t msg;
+} request_t;
+
+static void* request_thread(void* arg)
+{
+	request_t* request = (request_t*) arg;
+
+	// let handle_syscalls notice the broken connection as well
+	if (handle_message(request->s, &request->header, &request->msg) < 0)
+		shutdown(request->s, SHUT_RDWR);
+
+	free((char*) request->msg.buf);
+	free(request);
+
+	return NULL;
+}
+
+/*
+ * in principle, HermitCore forwards basic system calls to
+ * this proxy, which mapped these call to Linux system calls.
+ *
+ * Every message starts with a header containing a tag (the system call),
+ * an id chosen by HermitCore, and the length of the following arguments.
+ * The reply carries the same id, because several tasks of HermitCore
+ * may wait for replies at the same time.
+ *
+ * Each request is served by its own thread, so that a blocking system call
+ * (e.g. reading from stdin) does not delay the requests of other tasks.
+ */
+int handle_syscalls(int s)
+{
+	request_header_t header;
+	request_t* request;
+	pthread_t thread;
+	char* buf;
+	int flag = 1;
+
+	// replies are sent as soon as possible to unblock the waiting task
+	setsockopt(s, IPPROTO_TCP, TCP_NODELAY, (char *) &flag, sizeof(int));
+
+	while(1)
+	{
+		if (read_in_full(s, &header, sizeof(header)) < 0)
+			goto out;
+
+		buf = malloc(header.len ? header.len : 1);
+		if (!buf) {
+			fprintf(stderr, "Proxy: not enough memory for a message of %zu bytes\n", (size_t) header.len);
+			return 1;
 		}
-		case __HERMIT_lseek: {
-			int fd, whence;
-			off_t offset;
-
-			j = 0;
-			while (j < sizeof(fd))
-			{
-				sret = read(s, ((char*)&fd)+j, sizeof(fd)-j);
-				if (sret < 0)
-					goto out;
-				j += sret;
-			}
-
-			j = 0;
-			while (j < sizeof(offset))
-			{
-				sret = read(s, ((char*)&offset)+j, sizeof(offset)-j);
-				if (sret < 0)
-					goto out;
-				j += sret;
-			}
-
-			j = 0;
-			while (j < sizeof(whence))
-			{
-				sret = read(s, ((char*)&whence)+j, sizeof(whence)-j);
-				if (sret < 0)
-					goto out;
-				j += sret;
-			}
-
-			offset = lseek(fd, offset, whence);
-
-			j = 0;
-			while (j < sizeof(offset))
-			{
-				sret = write(s, ((char*)&offset)+j, sizeof(offset)-j);
-				if (sret < 0)
-					goto out;
-				j += sret;
-			}
-			break;
+
+		if (read_in_full(s, buf, header.len) < 0) {
+			free(buf);
+			goto out;
 		}
-		default:
-			fprintf(stderr, "Proxy: invalid syscall number %d, errno %d, ret %zd\n", sysnr, errno, sret);
-			close(s);
-			exit(1);
-			break;
+
+		request = malloc(sizeof(request_t));
+		if (!request) {
+			fprintf(stderr, "Proxy: not enough memory for a request\n");
+			free(buf);
+			return 1;
 		}
+
+		request->s = s;
+		request->header = header;
+		request->msg.buf = buf;
+		request->msg.len = header.len;
+		request->msg.pos = 0;
+
+		// exit terminates the proxy and is served here, so that it does not race with the error handling below
+		if (header.tag == __HERMIT_exit || pthread_create(&thread, NULL, request_thread, request) != 0)
+			request_thread(request);
+		else
+			pthread_detach(thread);
 	}
 
 out:
@@ -885,6 +986,30 @@ out:
 	return 1;
 }
 
+/*
+ * negotiate the version of the wire format:
+ * the proxy sends its version after HERMIT_MAGIC and HermitCore answers with its own one
+ */
+static int negotiate_version(int s)
+{
+	uint32_t version = HERMIT_PROTOCOL_VERSION;
+	uint32_t kernel_version;
+
+	if (write_in_full(s, &version, sizeof(version)) < 0)
+		return -1;
+
+	if (read_in_full(s, &kernel_version, sizeof(kernel_version)) < 0)
+		return -1;
+
+	if (kernel_version != version) {
+		fprintf(stderr, "Proxy: HermitCore uses protocol version %u, but the proxy supports version %u\n", kernel_version, version);
+		errno = EPROTO;
+		return -1;
+	}
+
+	return 0;
+}
+
 int socket_loop(int argc, char **argv)
 {
 	int i, j, ret, s;
@@ -952,8 +1077,10 @@ retry:
 		exit(1);
 	}
 
-	ret = write(s, &magic, sizeof(magic));
-	if (ret < 0)
+	if (write_in_full(s, &magic, sizeof(magic)) < 0)
+		goto out;
+
+	if (negotiate_version(s) < 0)
 		goto out;
 #if 0
 	// forward program arguments to HermitCore
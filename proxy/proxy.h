@@ -38,12 +38,41 @@
 
 #define HERMIT_ELFOSABI	0x42
 
+/* tags of the messages */
 #define __HERMIT_exit	0
 #define __HERMIT_write	1
 #define __HERMIT_open	2
 #define __HERMIT_close	3
 #define __HERMIT_read	4
 #define __HERMIT_lseek	5
+#define __HERMIT_stat	6
+#define __HERMIT_fstat	7
+#define __HERMIT_lstat	8
+#define __HERMIT_unlink	9
+#define __HERMIT_mkdir	10
+#define __HERMIT_rmdir	11
+#define __HERMIT_rename	12
+#define __HERMIT_getdents	13
+#define __HERMIT_access	14
+#define __HERMIT_pread	15
+#define __HERMIT_pwrite	16
+
+/* version of the wire format, which is negotiated after HERMIT_MAGIC */
+#define HERMIT_PROTOCOL_VERSION	1
+
+/* header of each message sent by HermitCore, followed by len bytes of arguments */
+typedef struct {
+	uint32_t tag;
+	uint32_t id;
+	uint64_t len;
+} __attribute__ ((packed)) request_header_t;
+
+/* header of each reply, followed by len bytes of data */
+typedef struct {
+	uint32_t id;
+	int64_t ret;
+	uint64_t len;
+} __attribute__ ((packed)) reply_header_t;
 
 int uhyve_init(char *path);
 int uhyve_loop(int argc, char **argv);
@@ -20,6 +20,8 @@
 
 #include <unistd.h>
 #include <stddef.h>
+#include <sys/stat.h>
+#include <sys/uio.h>
 
 typedef struct {
 	int fd;
@@ -52,4 +54,43 @@ typedef struct {
 	int whence;
 } uhyve_lseek_t;
 
+typedef struct {
+	const char* name;
+	struct stat* st;
+	int ret;
+} uhyve_stat_t;
+
+typedef struct {
+	int fd;
+	struct stat* st;
+	int ret;
+} uhyve_fstat_t;
+
+typedef struct {
+	const char* name;
+	int mode;
+	int ret;
+} uhyve_path_t;
+
+typedef struct {
+	const char* old_name;
+	const char* new_name;
+	int ret;
+} uhyve_rename_t;
+
+/*
+ * I/O requests pass the buffer as a list of guest physical extents,
+ * because a buffer is only contiguous in the virtual address space of the guest.
+ * The offset is only used by UHYVE_PORT_PREAD and UHYVE_PORT_PWRITE.
+ */
+#define UHYVE_IOV_MAX	16
+
+typedef struct {
+	int fd;
+	struct iovec* iov;
+	int iovcnt;
+	off_t offset;
+	ssize_t ret;
+} uhyve_iov_t;
+
 #endif // UHYVE_SYSCALLS_H
@@ -56,6 +56,7 @@
 #include <sys/ioctl.h>
 #include <sys/mman.h>
 #include <sys/stat.h>
+#include <sys/syscall.h>
 #include <sys/types.h>
 #include <sys/time.h>
 #include <sys/eventfd.h>
@@ -200,6 +201,95 @@ static void uhyve_atexit(void)
 	close_fd(&kvm);
 }
 
+/*
+ * translate the list of guest physical extents of an I/O request into host addresses,
+ * returns the number of extents or -EINVAL
+ */
+static int translate_iov(uhyve_iov_t* uhyve_iov, struct iovec* iov)
+{
+	struct iovec* guest_iov = (struct iovec*) (guest_mem+(size_t)uhyve_iov->iov);
+	int i, iovcnt = uhyve_iov->iovcnt;
+
+	if (iovcnt < 0 || iovcnt > UHYVE_IOV_MAX)
+		return -EINVAL;
+
+	/* the list itself has to lie within the guest memory as well */
+	if ((size_t)uhyve_iov->iov > guest_size - iovcnt * sizeof(struct iovec))
+		return -EINVAL;
+
+	/* compare without adding base and length, which could overflow */
+	for (i = 0; i < iovcnt; i++) {
+		if ((size_t)guest_iov[i].iov_base > guest_size
+		    || guest_iov[i].iov_len > guest_size - (size_t)guest_iov[i].iov_base)
+			return -EINVAL;
+
+		iov[i].iov_base = guest_mem+(size_t)guest_iov[i].iov_base;
+		iov[i].iov_len = guest_iov[i].iov_len;
+	}
+
+	return iovcnt;
+}
+
+/*
+ * translates a guest buffer of the given size into a host pointer,
+ * returns NULL if it does not lie within the guest memory
+ */
+static void* translate_buffer(size_t addr, size_t size)
+{
+	/* compare without adding address and size, which could overflow */
+	if (size > guest_size || addr > guest_size - size)
+		return NULL;
+
+	return guest_mem+addr;
+}
+
+/*
+ * translates a guest path into a host pointer,
+ * returns NULL if it is not terminated within the guest memory
+ */
+static const char* translate_path(size_t addr)
+{
+	if (addr >= guest_size)
+		return NULL;
+
+	if (!memchr(guest_mem+addr, '\0', guest_size - addr))
+		return NULL;
+
+	return (const char*)guest_mem+addr;
+}
+
+/*
+ * getdents64 needs a contiguous buffer, so the entries are read into
+ * a bounce buffer and afterwards scattered over the extents of the guest buffer
+ */
+static ssize_t getdents_iov(int fd, struct iovec* iov, int iovcnt)
+{
+	size_t len = 0, pos = 0;
+	ssize_t ret;
+	char* buf;
+	int i;
+
+	for (i = 0; i < iovcnt; i++)
+		len += iov[i].iov_len;
+
+	buf = malloc(len);
+	if (!buf) {
+		errno = ENOMEM;
+		return -1;
+	}
+
+	ret = syscall(SYS_getdents64, fd, buf, len);
+	for (i = 0; i < iovcnt && ret > 0 && pos < (size_t)ret; i++) {
+		size_t n = iov[i].iov_len < (size_t)ret - pos ? iov[i].iov_len : (size_t)ret - pos;
+
+		memcpy(iov[i].iov_base, buf+pos, n);
+		pos += n;
+	}
+
+	free(buf);
+	return ret;
+}
+
 static void* wait_for_packet(void* arg)
 {
 	int ret;
@@ -401,6 +491,111 @@ static int vcpu_loop(void)
 					break;
 				}
 
+			case UHYVE_PORT_STAT:
+			case UHYVE_PORT_LSTAT: {
+					uhyve_stat_t* uhyve_stat = (uhyve_stat_t*) (guest_mem+raddr);
+					const char* name = translate_path((size_t)uhyve_stat->name);
+					struct stat* st = (struct stat*) translate_buffer((size_t)uhyve_stat->st, sizeof(struct stat));
+
+					if (!name || !st) {
+						uhyve_stat->ret = -EFAULT;
+						break;
+					}
+
+					if (port == UHYVE_PORT_STAT)
+						ret = stat(name, st);
+					else
+						ret = lstat(name, st);
+
+					uhyve_stat->ret = (ret < 0) ? -errno : 0;
+					break;
+				}
+
+			case UHYVE_PORT_FSTAT: {
+					uhyve_fstat_t* uhyve_fstat = (uhyve_fstat_t*) (guest_mem+raddr);
+
+					struct stat* st = (struct stat*) translate_buffer((size_t)uhyve_fstat->st, sizeof(struct stat));
+
+					if (!st) {
+						uhyve_fstat->ret = -EFAULT;
+						break;
+					}
+
+					ret = fstat(uhyve_fstat->fd, st);
+					uhyve_fstat->ret = (ret < 0) ? -errno : 0;
+					break;
+				}
+
+			case UHYVE_PORT_UNLINK:
+			case UHYVE_PORT_MKDIR:
+			case UHYVE_PORT_RMDIR:
+			case UHYVE_PORT_ACCESS: {
+					uhyve_path_t* uhyve_path = (uhyve_path_t*) (guest_mem+raddr);
+					const char* name = translate_path((size_t)uhyve_path->name);
+
+					if (!name) {
+						uhyve_path->ret = -EFAULT;
+						break;
+					}
+
+					if (port == UHYVE_PORT_UNLINK)
+						ret = unlink(name);
+					else if (port == UHYVE_PORT_MKDIR)
+						ret = mkdir(name, uhyve_path->mode);
+					else if (port == UHYVE_PORT_RMDIR)
+						ret = rmdir(name);
+					else
+						ret = access(name, uhyve_path->mode);
+
+					uhyve_path->ret = (ret < 0) ? -errno : 0;
+					break;
+				}
+
+			case UHYVE_PORT_RENAME: {
+					uhyve_rename_t* uhyve_rename = (uhyve_rename_t*) (guest_mem+raddr);
+
+					const char* old_name = translate_path((size_t)uhyve_rename->old_name);
+					const char* new_name = translate_path((size_t)uhyve_rename->new_name);
+
+					if (!old_name || !new_name) {
+						uhyve_rename->ret = -EFAULT;
+						break;
+					}
+
+					ret = rename(old_name, new_name);
+					uhyve_rename->ret = (ret < 0) ? -errno : 0;
+					break;
+				}
+
+			case UHYVE_PORT_READV:
+			case UHYVE_PORT_WRITEV:
+			case UHYVE_PORT_PREAD:
+			case UHYVE_PORT_PWRITE:
+			case UHYVE_PORT_GETDENTS: {
+					uhyve_iov_t* uhyve_iov = (uhyve_iov_t*) (guest_mem+raddr);
+					struct iovec iov[UHYVE_IOV_MAX];
+					int iovcnt = translate_iov(uhyve_iov, iov);
+
+					if (iovcnt < 0) {
+						uhyve_iov->ret = iovcnt;
+						break;
+					}
+
+					if (port == UHYVE_PORT_READV)
+						uhyve_iov->ret = readv(uhyve_iov->fd, iov, iovcnt);
+					else if (port == UHYVE_PORT_WRITEV)
+						uhyve_iov->ret = writev(uhyve_iov->fd, iov, iovcnt);
+					else if (port == UHYVE_PORT_PREAD)
+						uhyve_iov->ret = preadv(uhyve_iov->fd, iov, iovcnt, uhyve_iov->offset);
+					else if (port == UHYVE_PORT_PWRITE)
+						uhyve_iov->ret = pwritev(uhyve_iov->fd, iov, iovcnt, uhyve_iov->offset);
+					else
+						uhyve_iov->ret = getdents_iov(uhyve_iov->fd, iov, iovcnt);
+					if (uhyve_iov->ret < 0)
+						uhyve_iov->ret = -errno;
+					break;
+				}
+
 			case UHYVE_PORT_CMDSIZE: {
 					int i;
 					uhyve_cmdsize_t *val = (uhyve_cmdsize_t *) (guest_mem+raddr);
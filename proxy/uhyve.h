@@ -37,6 +37,19 @@
 #define UHYVE_PORT_READ			0x500
 #define UHYVE_PORT_EXIT			0x540
 #define UHYVE_PORT_LSEEK		0x580
+#define UHYVE_PORT_STAT			0x7C0
+#define UHYVE_PORT_FSTAT		0x800
+#define UHYVE_PORT_LSTAT		0x840
+#define UHYVE_PORT_UNLINK		0x880
+#define UHYVE_PORT_MKDIR		0x8C0
+#define UHYVE_PORT_RMDIR		0x900
+#define UHYVE_PORT_RENAME		0x940
+#define UHYVE_PORT_GETDENTS		0x980
+#define UHYVE_PORT_ACCESS		0x9C0
+#define UHYVE_PORT_PREAD		0xA00
+#define UHYVE_PORT_PWRITE		0xA40
+#define UHYVE_PORT_READV		0xA80
+#define UHYVE_PORT_WRITEV		0xAC0
 
 // Networkports
 #define UHYVE_PORT_NETINFO              0x600